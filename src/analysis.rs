@@ -0,0 +1,601 @@
+// Semantic analysis: catches undefined variables and functions before execution
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::ast::{walk_expr, Expr, ExprVisitor, UserFunctions};
+use crate::builtins::ArgKind;
+use crate::compiler::pure_value;
+use crate::lexer::SpecialFunction;
+
+/// Whether a diagnostic stops compilation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// The category of a warning, by which `LintLevels` allows or denies it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lint {
+    /// A variable assigned but never read.
+    Unused,
+    /// A sum/product parameter with the name of an existing variable.
+    Shadowing,
+    /// A sum/product over a constant range with no integers in it.
+    EmptyRange,
+    /// A program without `output` statements whose last statement leaves no
+    /// result, so that only `--dump-vars` shows what it computed.
+    NoResult,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 4] = [Lint::Unused, Lint::Shadowing, Lint::EmptyRange, Lint::NoResult];
+
+    /// The name of the lint in `--allow`, `--warn` and `--deny`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::Unused => "unused",
+            Lint::Shadowing => "shadowing",
+            Lint::EmptyRange => "empty-range",
+            Lint::NoResult => "no-result",
+        }
+    }
+}
+
+/// What to do with the warnings of a lint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    /// Drop them.
+    Allow,
+    /// Report them as warnings, the default.
+    Warn,
+    /// Report them as errors, so the program does not compile.
+    Deny,
+}
+
+/// The level of each lint, as set by `--allow`, `--warn` and `--deny`. A
+/// setting for the group `warnings` covers every lint; a later setting
+/// overrides an earlier one for the lints both cover.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LintLevels {
+    /// Each setting in order; `None` stands for every lint
+    settings: Vec<(Option<Lint>, Level)>,
+}
+
+impl LintLevels {
+    /// Sets the level of the lint `name`, or of all of them for `warnings`.
+    pub fn set(&mut self, name: &str, level: Level) -> Result<(), String> {
+        let lint = match name {
+            "warnings" => None,
+            _ => Some(Lint::ALL.into_iter().find(|lint| lint.name() == name).ok_or_else(|| {
+                let names: Vec<&str> = Lint::ALL.iter().map(|lint| lint.name()).collect();
+                format!("unknown lint `{}` (expected warnings, {})", name, names.join(", "))
+            })?),
+        };
+        self.settings.push((lint, level));
+        Ok(())
+    }
+
+    pub fn level(&self, lint: Lint) -> Level {
+        let setting = self.settings.iter().rev().find(|(l, _)| l.is_none_or(|l| l == lint));
+        setting.map_or(Level::Warn, |&(_, level)| level)
+    }
+
+    /// Drops the allowed warnings of `diagnostics` and makes the denied ones
+    /// errors; errors and unclassified warnings are kept as they are.
+    pub fn apply(&self, diagnostics: Vec<Diagnostic>) -> Vec<Diagnostic> {
+        diagnostics
+            .into_iter()
+            .filter_map(|mut d| {
+                match d.lint.map(|lint| self.level(lint)) {
+                    Some(Level::Allow) => return None,
+                    Some(Level::Deny) => d.severity = Severity::Error,
+                    _ => {}
+                }
+                Some(d)
+            })
+            .collect()
+    }
+}
+
+/// A problem found by analysis, reported before any code runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// 1-based source line of the statement, if known.
+    pub line: Option<usize>,
+    /// User function whose body contains the problem, if any.
+    pub function: Option<String>,
+    pub message: String,
+    /// The lint a warning belongs to (kept when `LintLevels` denies it).
+    pub lint: Option<Lint>,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(name) = &self.function {
+            write!(f, " in body of `{}`", name)?;
+        }
+        if let Some(line) = self.line {
+            write!(f, " on line {}", line)?;
+        }
+        if let Some(lint) = self.lint
+            && self.is_error()
+        {
+            write!(f, " (denied lint `{}`)", lint.name())?;
+        }
+        Ok(())
+    }
+}
+
+impl Diagnostic {
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+/// Checks top-level statements (in source order) and user function bodies.
+///
+/// Errors: variables read before assignment, calls to unknown functions,
+/// assignments to constants, lists used where a number is expected (or the
+/// other way around) anywhere but as the result.
+/// Warnings: variables assigned but never read (names starting with `_` are
+/// exempt), sum/product parameters that shadow an existing variable, constant
+/// sum/product ranges that are empty (such as `from: 10, to: 1`), and a last
+/// statement that leaves no result in a program without `output` statements.
+pub fn analyze(statements: &[(usize, Expr)], functions: &UserFunctions) -> Vec<Diagnostic> {
+    analyze_with_inputs(statements, functions, &[])
+}
+
+/// Like `analyze`, for a program whose `inputs` are set by the host before it
+/// runs (as `fmath plot` does for its variables), so reading them is fine.
+pub fn analyze_with_inputs(statements: &[(usize, Expr)], functions: &UserFunctions, inputs: &[&str]) -> Vec<Diagnostic> {
+    let inputs: HashSet<String> = inputs.iter().map(|name| name.to_string()).collect();
+    let mut checker = Checker {
+        functions,
+        defined: inputs.clone(),
+        diagnostics: Vec::new(),
+        line: None,
+        function: None,
+        first_assignment: HashMap::new(),
+        read: HashSet::new(),
+        constants: HashSet::new(),
+        lists: HashSet::new(),
+        holders: HashMap::new(),
+    };
+    for (i, (line, expr)) in statements.iter().enumerate() {
+        checker.line = Some(*line);
+        // The last statement's value is the result, which may also be a list
+        match expr {
+            _ if i + 1 == statements.len() && expr.is_list() => checker.visit_list(expr),
+            Expr::Ident(name) if i + 1 == statements.len() && checker.lists.contains(name) => checker.visit_list(expr),
+            _ => checker.visit(expr),
+        }
+    }
+    // The last statement's value is the result, so a program ending in an
+    // assignment fails once it runs, unless it has outputs to print instead or
+    // is a worksheet run with --dump-vars
+    if let Some((_, last)) = statements.last()
+        && last.is_statement()
+        && !statements.iter().any(|(_, expr)| matches!(expr, Expr::Output { .. }))
+    {
+        checker.warn(Lint::NoResult, "the last statement leaves no result; end the program with an expression or run it with --dump-vars".to_string());
+    }
+
+    // Function bodies see the global variables of the caller, so any name the
+    // script assigns somewhere counts as defined inside a body.
+    let mut globals = inputs;
+    for (_, expr) in statements {
+        collect_assigned(expr, &mut globals);
+    }
+    let mut names: Vec<&String> = functions.keys().collect();
+    names.sort();
+    for name in names {
+        for function in functions[name].iter() {
+            checker.line = None;
+            checker.function = Some(name.clone());
+            checker.defined = globals.clone();
+            // A default sees the parameters before it
+            for param in &function.params {
+                if let Some(default) = &param.default {
+                    checker.visit(default);
+                }
+                checker.defined.insert(param.name.clone());
+            }
+            checker.visit(&function.body);
+        }
+    }
+
+    let mut unused: Vec<(&String, &usize)> = checker
+        .first_assignment
+        .iter()
+        .filter(|(name, _)| !name.starts_with('_') && !checker.read.contains(*name))
+        .collect();
+    unused.sort_by_key(|(name, line)| (**line, (*name).clone()));
+    let warnings: Vec<Diagnostic> = unused
+        .into_iter()
+        .map(|(name, line)| Diagnostic {
+            severity: Severity::Warning,
+            line: Some(*line),
+            function: None,
+            message: format!("variable `{}` is assigned but never read", name),
+            lint: Some(Lint::Unused),
+        })
+        .collect();
+    checker.diagnostics.extend(warnings);
+    checker.diagnostics
+}
+
+struct Checker<'a> {
+    functions: &'a UserFunctions,
+    defined: HashSet<String>,
+    diagnostics: Vec<Diagnostic>,
+    line: Option<usize>,
+    function: Option<String>,
+    /// Line of the first top-level assignment of each variable
+    first_assignment: HashMap<String, usize>,
+    /// Every variable name read anywhere
+    read: HashSet<String>,
+    /// Names bound with `const`
+    constants: HashSet<String>,
+    /// Variables currently holding a list
+    lists: HashSet<String>,
+    /// Variables currently holding a function, with the function's name and
+    /// how many leading arguments `bind` fixed
+    holders: HashMap<String, (String, usize)>,
+}
+
+impl Checker<'_> {
+    fn report(&mut self, message: String) {
+        self.report_with(Severity::Error, None, message);
+    }
+
+    fn warn(&mut self, lint: Lint, message: String) {
+        self.report_with(Severity::Warning, Some(lint), message);
+    }
+
+    fn report_with(&mut self, severity: Severity, lint: Option<Lint>, message: String) {
+        let diagnostic = Diagnostic { severity, line: self.line, function: self.function.clone(), message, lint };
+        if !self.diagnostics.contains(&diagnostic) {
+            self.diagnostics.push(diagnostic);
+        }
+    }
+
+    fn visit(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Number(_) | Expr::Text(_) | Expr::Precision(_) => {}
+            Expr::Ident(name) => {
+                self.read.insert(name.clone());
+                if !self.defined.contains(name) {
+                    self.report(format!("variable `{}` used before assignment", name));
+                } else if self.lists.contains(name) {
+                    self.report(format!("list `{}` used where a number is expected", name));
+                } else if self.holders.contains_key(name) {
+                    self.report(format!("function `{}` used where a number is expected", name));
+                }
+            }
+            Expr::List(_) | Expr::Solve { .. } | Expr::SolveN { .. } | Expr::Extremum { .. } | Expr::Derivatives { .. } => self.report("list used where a number is expected".to_string()),
+            Expr::FunctionRef(name) => self.report(format!("function `{}` used where a number is expected", name)),
+            Expr::Bind { .. } => {
+                self.function_value(expr);
+                self.report("function used where a number is expected".to_string());
+            }
+            Expr::Assign { name, expr: value } | Expr::Const { name, expr: value } | Expr::Output { name, expr: value, .. } => {
+                let is_const = matches!(expr, Expr::Const { .. });
+                if matches!(expr, Expr::Output { .. }) {
+                    // Printed after the run, which counts as reading it
+                    self.read.insert(name.clone());
+                    if value.is_list() || value.is_function() {
+                        self.report(format!("output `{}` must be a number", name));
+                    }
+                }
+                self.holders.remove(name);
+                if value.is_list() {
+                    self.visit_list(value);
+                    if is_const {
+                        self.report(format!("constant `{}` must be a number, not a list", name));
+                    }
+                    self.lists.insert(name.clone());
+                } else if value.is_function() {
+                    if let Some(function) = self.function_value(value) {
+                        self.holders.insert(name.clone(), function);
+                    }
+                    self.lists.remove(name);
+                } else {
+                    self.visit(value);
+                    self.lists.remove(name);
+                }
+                if self.constants.contains(name) {
+                    self.report(format!("cannot assign to constant `{}`", name));
+                } else if is_const && self.defined.contains(name) {
+                    self.report(format!("cannot redefine variable `{}` as a constant", name));
+                }
+                if is_const {
+                    self.constants.insert(name.clone());
+                }
+                self.defined.insert(name.clone());
+                if self.function.is_none()
+                    && let Some(line) = self.line
+                {
+                    self.first_assignment.entry(name.clone()).or_insert(line);
+                }
+            }
+            Expr::BinaryOp { left, right, .. } => {
+                self.visit(left);
+                self.visit(right);
+            }
+            Expr::UnaryOp { operand, .. } => self.visit(operand),
+            Expr::Function { func, arg } => {
+                if crate::builtins::returns_list(*func) {
+                    self.report("list used where a number is expected".to_string());
+                }
+                self.visit_args(*func, arg);
+            }
+            Expr::FunctionDef { .. } => {}
+            Expr::FunctionCall { name, args } => {
+                for arg in args {
+                    self.visit(arg);
+                }
+                if let Some(overloads) = self.functions.get(name) {
+                    // A user function takes precedence over a list of the same name
+                    if overloads.resolve(args.len()).is_none() {
+                        let expected = overloads.describe_arity();
+                        self.report(format!("`{}` expects {}, got {}", name, expected, args.len()));
+                    }
+                } else if self.lists.contains(name) {
+                    // Calling a list evaluates it as a polynomial
+                    self.read.insert(name.clone());
+                    if args.len() != 1 {
+                        self.report(format!("polynomial `{}` expects 1 argument, got {}", name, args.len()));
+                    }
+                } else {
+                    self.report(format!("unknown function `{}`", name));
+                }
+            }
+            Expr::Apply { callee, args } => {
+                for arg in args {
+                    self.visit(arg);
+                }
+                if !callee.is_function() {
+                    self.report("`apply` expects a function as its first argument".to_string());
+                } else if let Some((function, bound)) = self.function_value(callee)
+                    && let Some(overloads) = self.functions.get(&function)
+                    && overloads.resolve(bound + args.len()).is_none()
+                {
+                    let expected = overloads.describe_arity();
+                    self.report(format!("`{}` expects {}, got {}", function, expected, bound + args.len()));
+                }
+            }
+            Expr::Sequence(exprs) => {
+                for e in exprs {
+                    self.visit(e);
+                }
+            }
+            Expr::Sum { from, to, param, cond, body, .. } | Expr::Product { from, to, param, cond, body } => {
+                self.visit(from);
+                self.visit(to);
+                if let Expr::Sum { eps: Some(eps), .. } = expr {
+                    self.visit(eps);
+                }
+                let kind = if matches!(expr, Expr::Sum { .. }) { "sum" } else { "product" };
+                // Integers from ceil(from) to floor(to); none if that range is reversed
+                if let (Some(start), Some(end)) = (pure_value(from), pure_value(to))
+                    && start.ceil() > end.floor()
+                {
+                    let empty = if kind == "sum" { 0 } else { 1 };
+                    self.warn(Lint::EmptyRange, format!("{} range from {} to {} is empty, so the {} is {}", kind, start, end, kind, empty));
+                }
+                // A product has no eps, so only its condition can end it
+                if kind == "product" && cond.is_none() && pure_value(to) == Some(f64::INFINITY) {
+                    self.report("product to inf needs a `while:` condition to end it".to_string());
+                }
+                // The loop parameter is only bound inside the body
+                let was_defined = !self.defined.insert(param.clone());
+                if self.constants.contains(param) {
+                    self.report(format!("cannot use constant `{}` as a loop parameter", param));
+                } else if was_defined {
+                    self.warn(Lint::Shadowing, format!("{} parameter `{}` shadows an existing variable", kind, param));
+                }
+                if let Some(cond) = cond {
+                    self.visit(cond);
+                }
+                self.visit(body);
+                if !was_defined {
+                    self.defined.remove(param);
+                }
+            }
+            Expr::Conditional { cond, then, otherwise } => {
+                self.visit(cond);
+                self.visit(then);
+                self.visit(otherwise);
+            }
+            Expr::Let { name, value, body } => {
+                self.visit(value);
+                if self.constants.contains(name) {
+                    self.report(format!("cannot rebind constant `{}` with let", name));
+                }
+                let was_defined = !self.defined.insert(name.clone());
+                self.visit(body);
+                if !was_defined {
+                    self.defined.remove(name);
+                }
+            }
+            Expr::Return { cond, value } => {
+                if let Some(cond) = cond {
+                    self.visit(cond);
+                }
+                self.visit(value);
+            }
+            Expr::Assert { cond, .. } => self.visit(cond),
+            Expr::AssertEq { left, right, eps, .. } => {
+                self.visit(left);
+                self.visit(right);
+                self.visit(eps);
+            }
+        }
+    }
+
+    // The user function a function value calls and how many leading
+    // arguments are fixed; a variable holding it counts as read
+    fn function_value(&mut self, expr: &Expr) -> Option<(String, usize)> {
+        match expr {
+            Expr::FunctionRef(name) => {
+                if let Some(function) = self.holders.get(name) {
+                    self.read.insert(name.to_string());
+                    return Some(function.clone());
+                }
+                if self.functions.contains_key(name) {
+                    return Some((name.to_string(), 0));
+                }
+                self.report(format!("unknown function `{}`", name));
+                None
+            }
+            Expr::Bind { callee, args } => {
+                for arg in args {
+                    self.visit(arg);
+                }
+                if !callee.is_function() {
+                    self.report("`bind` expects a function as its first argument".to_string());
+                    return None;
+                }
+                let (function, bound) = self.function_value(callee)?;
+                let bound = bound + args.len();
+                let most = self.functions.get(&function).and_then(|o| o.iter().map(|f| f.params.len()).max());
+                if let Some(most) = most
+                    && bound > most
+                {
+                    self.report(format!("`{}` takes at most {} arguments, but {} are bound", function, most, bound));
+                }
+                Some((function, bound))
+            }
+            _ => None,
+        }
+    }
+
+    // Visit an expression in a position that expects a list
+    fn visit_list(&mut self, expr: &Expr) {
+        match expr {
+            Expr::List(items) => {
+                for item in items {
+                    self.visit(item);
+                }
+            }
+            Expr::Ident(name) => {
+                self.read.insert(name.clone());
+                if !self.defined.contains(name) {
+                    self.report(format!("variable `{}` used before assignment", name));
+                } else if !self.lists.contains(name) {
+                    self.report(format!("variable `{}` is not a list", name));
+                }
+            }
+            Expr::Function { func, arg } if expr.is_list() => self.visit_args(*func, arg),
+            Expr::Solve { param, range, lhs, rhs } => {
+                if let Some((from, to)) = range {
+                    self.visit(from);
+                    self.visit(to);
+                }
+                self.with_unknowns("solve", std::slice::from_ref(param), |checker| {
+                    checker.visit(lhs);
+                    checker.visit(rhs);
+                });
+            }
+            Expr::SolveN { equations, params, guess } => {
+                self.visit_list(guess);
+                if let Expr::List(items) = &**guess
+                    && items.len() != params.len()
+                {
+                    self.report(format!("solveN has {} unknowns but {} values in its guess", params.len(), items.len()));
+                }
+                self.with_unknowns("solveN", params, |checker| {
+                    for equation in equations {
+                        checker.visit(equation);
+                    }
+                });
+            }
+            Expr::Extremum { maximize, body, param, from, to } => {
+                self.visit(from);
+                self.visit(to);
+                let construct = if *maximize { "maximize" } else { "minimize" };
+                self.with_unknowns(construct, std::slice::from_ref(param), |checker| checker.visit(body));
+            }
+            Expr::Derivatives { hessian, body, params, point } => {
+                let construct = if *hessian { "hessian" } else { "grad" };
+                self.visit_list(point);
+                if let Expr::List(items) = &**point
+                    && items.len() != params.len()
+                {
+                    self.report(format!("{} has {} variables but {} values in its point", construct, params.len(), items.len()));
+                }
+                self.with_unknowns(construct, params, |checker| checker.visit(body));
+            }
+            _ => self.visit(expr),
+        }
+    }
+
+    // Visit the equations or expression of `construct` with its unknowns `params` bound only there
+    fn with_unknowns(&mut self, construct: &str, params: &[String], visit: impl FnOnce(&mut Self)) {
+        let mut bound = Vec::new();
+        for param in params {
+            let was_defined = !self.defined.insert(param.clone());
+            if self.constants.contains(param) {
+                self.report(format!("constant `{}` cannot be an unknown of {}", param, construct));
+            } else if was_defined {
+                self.warn(Lint::Shadowing, format!("{} unknown `{}` shadows an existing variable", construct, param));
+            } else {
+                bound.push(param);
+            }
+        }
+        visit(self);
+        for param in bound {
+            self.defined.remove(param);
+        }
+    }
+
+    // Visit builtin arguments, each according to its parameter kind
+    fn visit_args(&mut self, func: SpecialFunction, arg: &Expr) {
+        let params = crate::builtins::entry(func).params;
+        let args = match arg {
+            Expr::Sequence(args) => args.as_slice(),
+            single => std::slice::from_ref(single),
+        };
+        for (i, a) in args.iter().enumerate() {
+            if let Some((_, ArgKind::List)) = params.get(i) {
+                self.visit_list(a);
+            } else {
+                self.visit(a);
+            }
+        }
+    }
+}
+
+/// The error `analyze` reports for evaluating `expr` as a number when its
+/// syntax alone shows it is a list or a function, for callers such as
+/// `Session` that check one statement at a time.
+pub(crate) fn number_expected(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::FunctionRef(name) => Some(format!("function `{}` used where a number is expected", name)),
+        _ if expr.is_function() => Some("function used where a number is expected".to_string()),
+        _ if expr.is_list() => Some("list used where a number is expected".to_string()),
+        _ => None,
+    }
+}
+
+/// Collects every variable name assigned anywhere in `expr`.
+pub(crate) fn collect_assigned(expr: &Expr, names: &mut HashSet<String>) {
+    struct Assigned<'a>(&'a mut HashSet<String>);
+    impl ExprVisitor for Assigned<'_> {
+        fn visit_expr(&mut self, expr: &Expr) {
+            match expr {
+                Expr::Assign { name, .. } | Expr::Const { name, .. } | Expr::Output { name, .. } => {
+                    self.0.insert(name.clone());
+                }
+                // A definition's body assigns its own locals
+                Expr::FunctionDef { .. } => return,
+                _ => {}
+            }
+            walk_expr(self, expr);
+        }
+    }
+    Assigned(names).visit_expr(expr);
+}
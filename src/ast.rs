@@ -1,4 +1,18 @@
 // AST for math interpreter
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+use std::sync::OnceLock;
+
+use crate::lexer::BinaryOperator;
+
+/// A prefix operator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOperator {
+    /// `-x`, the negation, which keeps the sign of zero and NaN flipped
+    /// (unlike `0 - x`).
+    Neg,
+}
+
 /// The abstract syntax tree for math expressions.
 #[derive(Debug, Clone)]
 pub enum Expr {
@@ -6,47 +20,742 @@ pub enum Expr {
     Number(f64),
     /// A variable reference.
     Ident(String),
+    /// A string literal, only ever a builtin argument: the "HOME" of env("HOME", 0)
+    Text(String),
     /// An assignment: variable = value
     Assign {
         name: String,
         expr: Box<Expr>,
     },
+    /// A constant definition: const name = value (cannot be reassigned)
+    Const {
+        name: String,
+        expr: Box<Expr>,
+    },
+    /// An assignment whose variable is one of the program's named results:
+    /// output name = value, or output name = value @ "unit" to label it
+    Output {
+        name: String,
+        expr: Box<Expr>,
+        unit: Option<String>,
+    },
     /// A binary operation (e.g., +, -, *, /).
     BinaryOp {
         left: Box<Expr>,
         op: crate::lexer::BinaryOperator,
         right: Box<Expr>,
     },
+    /// A prefix operation, as `-x`.
+    UnaryOp {
+        op: UnaryOperator,
+        operand: Box<Expr>,
+    },
+    /// A conditional: cond ? then : otherwise (cond is true when nonzero)
+    Conditional {
+        cond: Box<Expr>,
+        then: Box<Expr>,
+        otherwise: Box<Expr>,
+    },
+    /// A scoped binding: let name = value in body (name is only visible in body)
+    Let {
+        name: String,
+        value: Box<Expr>,
+        body: Box<Expr>,
+    },
     /// A special function call (e.g., sin, cos, exp).
     Function {
         func: crate::lexer::SpecialFunction,
         arg: Box<Expr>,
     },
-    /// A user-defined function definition: def name(arg) = body
+    /// A user-defined function definition: def name(a, b = 2) = body
     FunctionDef {
         name: String,
-        arg: String,
+        params: Vec<Param>,
         body: Box<Expr>,
     },
-    /// A user-defined function call: name(expr)
+    /// A user-defined function call: name(a, b); a call may leave out
+    /// parameters that have defaults
     FunctionCall {
         name: String,
-        arg: Box<Expr>,
+        args: Vec<Expr>,
+    },
+    /// A function value: the user function `name`, or the one the variable
+    /// `name` holds, as in `var f = square`
+    FunctionRef(String),
+    /// A call through a function value: `apply(f, 3)`, or `f(3)` where `f`
+    /// is a variable holding a function
+    Apply {
+        callee: Box<Expr>,
+        args: Vec<Expr>,
+    },
+    /// A function value with its leading arguments fixed: `bind(f, a)` is
+    /// the function taking the arguments of `f` after the first
+    Bind {
+        callee: Box<Expr>,
+        args: Vec<Expr>,
     },
     /// A sequence of expressions (comma-separated)
     Sequence(Vec<Expr>),
-    /// Sum(from, to, param, expr)
+    /// A list literal: [a, b, c]
+    List(Vec<Expr>),
+    /// Sum(from, to, param, expr); `eps` is the convergence tolerance when `to` is inf,
+    /// and the loop ends before the first term where `cond` (`while: cond`) is false
     Sum {
         from: Box<Expr>,
         to: Box<Expr>,
+        eps: Option<Box<Expr>>,
         param: String,
+        cond: Option<Box<Expr>>,
         body: Box<Expr>,
     },
-    /// Product(from, to, param, expr)
+    /// Product(from, to, param, expr), with an optional `while: cond` like Sum
     Product {
         from: Box<Expr>,
         to: Box<Expr>,
         param: String,
+        cond: Option<Box<Expr>>,
+        body: Box<Expr>,
+    },
+    /// solve x: lhs = rhs, or solve x in [a, b]: lhs = rhs: the list of
+    /// values of `param` in the range (`numeric::DEFAULT_SOLVE_RANGE` if none
+    /// is given) where the two sides are equal
+    Solve {
+        param: String,
+        range: Option<(Box<Expr>, Box<Expr>)>,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    /// solveN([f1, f2], [x, y], [gx, gy]): the list of values of `params`
+    /// near the guess, a list expression, where every equation is zero
+    SolveN {
+        equations: Vec<Expr>,
+        params: Vec<String>,
+        guess: Box<Expr>,
+    },
+    /// minimize(expr, x, a, b), or maximize: the list [x, expr] where `body`
+    /// is least (greatest) for `param` in [from, to]
+    Extremum {
+        maximize: bool,
+        body: Box<Expr>,
+        param: String,
+        from: Box<Expr>,
+        to: Box<Expr>,
+    },
+    /// grad(expr, [x, y], point), or hessian: the gradient of `body` in
+    /// `params` at the point, a list expression, or its Hessian row by row
+    Derivatives {
+        hessian: bool,
         body: Box<Expr>,
+        params: Vec<String>,
+        point: Box<Expr>,
+    },
+    /// return value, or with `cond`, if cond return value: ends a multi-line
+    /// function body with `value` (when `cond` is true)
+    Return {
+        cond: Option<Box<Expr>>,
+        value: Box<Expr>,
+    },
+    /// assert(cond): fails at runtime when cond is 0 or NaN
+    Assert {
+        cond: Box<Expr>,
+        line: usize,
+    },
+    /// assert_eq(left, right, eps): fails at runtime when |left - right| > eps
+    AssertEq {
+        left: Box<Expr>,
+        right: Box<Expr>,
+        eps: Box<Expr>,
+        line: usize,
     },
+    /// `#pragma precision n`: results of the program print with n digits
+    /// after the point
+    Precision(usize),
+}
+
+impl Expr {
+    /// Whether this expression evaluates to a list rather than a number,
+    /// judged from its syntax alone (list variables are tracked by analysis).
+    pub fn is_list(&self) -> bool {
+        match self {
+            Expr::List(_) | Expr::Solve { .. } | Expr::SolveN { .. } | Expr::Extremum { .. } | Expr::Derivatives { .. } => true,
+            Expr::Function { func, .. } => crate::builtins::returns_list(*func),
+            _ => false,
+        }
+    }
+
+    /// Whether this expression evaluates to a function rather than a number.
+    pub fn is_function(&self) -> bool {
+        matches!(self, Expr::FunctionRef(_) | Expr::Bind { .. })
+    }
+
+    /// Whether this is a statement that leaves no value behind: an assignment,
+    /// `const`, `output`, `return` or `#pragma precision`.
+    pub fn is_statement(&self) -> bool {
+        matches!(self, Expr::Assign { .. } | Expr::Const { .. } | Expr::Output { .. } | Expr::Return { .. } | Expr::Precision(_))
+    }
+
+    /// The canonical form equality and hashing compare: the operands of `+`,
+    /// `*`, `==` and `!=` in a fixed order, `a > b` as `b < a` (and `>=`
+    /// likewise), and `-0` and every NaN as one number each. Operands are
+    /// only swapped, never regrouped, since that would change rounding.
+    pub fn normalized(&self) -> Expr {
+        Normalize.fold_expr(self.clone())
+    }
+
+    /// A hash of the canonical form that stays the same across runs and
+    /// builds, for keying caches of compiled programs kept on disk.
+    pub fn fingerprint(&self) -> u64 {
+        // FNV-1a, unlike `DefaultHasher`, is fixed
+        self.encoded().iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+
+    // The canonical form written out: `FINGERPRINT_VERSION`, then each node
+    // as `encode_node` writes it, before its children
+    fn encoded(&self) -> Vec<u8> {
+        fn encode(expr: &Expr, out: &mut Vec<u8>) {
+            expr.encode_node(out);
+            expr.children().into_iter().for_each(|child| encode(child, out));
+        }
+        let mut out = vec![FINGERPRINT_VERSION];
+        encode(&self.normalized(), &mut out);
+        out
+    }
+
+    // The node without its children: a tag for the variant, then its other
+    // fields, with a count or a presence flag wherever the number of children
+    // can vary, so that the tree can be read back from the nodes in order
+    fn encode_node(&self, out: &mut Vec<u8>) {
+        fn len(out: &mut Vec<u8>, n: usize) {
+            out.extend_from_slice(&(n as u64).to_le_bytes());
+        }
+        fn text(out: &mut Vec<u8>, s: &str) {
+            len(out, s.len());
+            out.extend_from_slice(s.as_bytes());
+        }
+        fn texts(out: &mut Vec<u8>, names: &[String]) {
+            len(out, names.len());
+            names.iter().for_each(|name| text(out, name));
+        }
+        match self {
+            Expr::Number(n) => {
+                out.push(0);
+                out.extend_from_slice(&n.to_bits().to_le_bytes());
+            }
+            Expr::Ident(name) => {
+                out.push(1);
+                text(out, name);
+            }
+            Expr::Text(s) => {
+                out.push(2);
+                text(out, s);
+            }
+            Expr::Assign { name, .. } => {
+                out.push(3);
+                text(out, name);
+            }
+            Expr::Const { name, .. } => {
+                out.push(4);
+                text(out, name);
+            }
+            Expr::Output { name, unit, .. } => {
+                out.push(5);
+                text(out, name);
+                out.push(unit.is_some() as u8);
+                if let Some(unit) = unit {
+                    text(out, unit);
+                }
+            }
+            Expr::BinaryOp { op, .. } => out.extend_from_slice(&[6, *op as u8]),
+            Expr::UnaryOp { op, .. } => out.extend_from_slice(&[7, *op as u8]),
+            Expr::Conditional { .. } => out.push(8),
+            Expr::Let { name, .. } => {
+                out.push(9);
+                text(out, name);
+            }
+            Expr::Function { func, .. } => {
+                out.push(10);
+                out.extend_from_slice(&(*func as u16).to_le_bytes());
+            }
+            Expr::FunctionDef { name, params, .. } => {
+                out.push(11);
+                text(out, name);
+                len(out, params.len());
+                for param in params {
+                    text(out, &param.name);
+                    out.push(param.default.is_some() as u8);
+                }
+            }
+            Expr::FunctionCall { name, args } => {
+                out.push(12);
+                text(out, name);
+                len(out, args.len());
+            }
+            Expr::FunctionRef(name) => {
+                out.push(13);
+                text(out, name);
+            }
+            Expr::Apply { args, .. } => {
+                out.push(14);
+                len(out, args.len());
+            }
+            Expr::Bind { args, .. } => {
+                out.push(15);
+                len(out, args.len());
+            }
+            Expr::Sequence(exprs) => {
+                out.push(16);
+                len(out, exprs.len());
+            }
+            Expr::List(exprs) => {
+                out.push(17);
+                len(out, exprs.len());
+            }
+            Expr::Sum { eps, param, cond, .. } => {
+                out.push(18);
+                text(out, param);
+                out.extend_from_slice(&[eps.is_some() as u8, cond.is_some() as u8]);
+            }
+            Expr::Product { param, cond, .. } => {
+                out.push(19);
+                text(out, param);
+                out.push(cond.is_some() as u8);
+            }
+            Expr::Solve { param, range, .. } => {
+                out.push(20);
+                text(out, param);
+                out.push(range.is_some() as u8);
+            }
+            Expr::SolveN { equations, params, .. } => {
+                out.push(21);
+                len(out, equations.len());
+                texts(out, params);
+            }
+            Expr::Extremum { maximize, param, .. } => {
+                out.extend_from_slice(&[22, *maximize as u8]);
+                text(out, param);
+            }
+            Expr::Derivatives { hessian, params, .. } => {
+                out.extend_from_slice(&[23, *hessian as u8]);
+                texts(out, params);
+            }
+            Expr::Return { cond, .. } => out.extend_from_slice(&[24, cond.is_some() as u8]),
+            Expr::Assert { line, .. } => {
+                out.push(25);
+                len(out, *line);
+            }
+            Expr::AssertEq { line, .. } => {
+                out.push(26);
+                len(out, *line);
+            }
+            Expr::Precision(digits) => {
+                out.push(27);
+                len(out, *digits);
+            }
+        }
+    }
+
+    // The direct children, in the order `walk_expr` visits them
+    fn children(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Number(_) | Expr::Ident(_) | Expr::Text(_) | Expr::FunctionRef(_) | Expr::Precision(_) => Vec::new(),
+            Expr::Assign { expr, .. }
+            | Expr::Const { expr, .. }
+            | Expr::Output { expr, .. }
+            | Expr::Function { arg: expr, .. }
+            | Expr::UnaryOp { operand: expr, .. }
+            | Expr::Assert { cond: expr, .. } => vec![expr],
+            Expr::BinaryOp { left, right, .. } => vec![left, right],
+            Expr::Conditional { cond, then, otherwise } => vec![cond, then, otherwise],
+            Expr::Let { value, body, .. } => vec![value, body],
+            Expr::FunctionDef { params, body, .. } => {
+                params.iter().filter_map(|p| p.default.as_ref()).chain([&**body]).collect()
+            }
+            Expr::FunctionCall { args: exprs, .. } | Expr::Sequence(exprs) | Expr::List(exprs) => exprs.iter().collect(),
+            Expr::Apply { callee, args } | Expr::Bind { callee, args } => args.iter().chain([&**callee]).collect(),
+            Expr::Sum { from, to, eps, cond, body, .. } => {
+                [Some(from), Some(to), eps.as_ref(), cond.as_ref(), Some(body)].into_iter().flatten().map(|e| &**e).collect()
+            }
+            Expr::Product { from, to, cond, body, .. } => {
+                [Some(from), Some(to), cond.as_ref(), Some(body)].into_iter().flatten().map(|e| &**e).collect()
+            }
+            Expr::Solve { range, lhs, rhs, .. } => match range {
+                Some((from, to)) => vec![from, to, lhs, rhs],
+                None => vec![lhs, rhs],
+            },
+            Expr::SolveN { equations, guess, .. } => equations.iter().chain([&**guess]).collect(),
+            Expr::Extremum { body, from, to, .. } => vec![body, from, to],
+            Expr::Derivatives { body, point, .. } => vec![body, point],
+            Expr::Return { cond, value } => cond.iter().chain([value]).map(|e| &**e).collect(),
+            Expr::AssertEq { left, right, eps, .. } => vec![left, right, eps],
+        }
+    }
+}
+
+/// The version of the encoding `fingerprint` hashes, which leads it. It goes
+/// up whenever the encoding changes, including when operators or builtins are
+/// reordered, so that a fingerprint is never reused for a different tree.
+pub const FINGERPRINT_VERSION: u8 = 1;
+
+// A total order on trees: by each node as `encode_node` writes it, then by
+// the children in order, stopping at the first node that differs
+fn compare(a: &Expr, b: &Expr) -> Ordering {
+    let (mut left, mut right) = (Vec::new(), Vec::new());
+    a.encode_node(&mut left);
+    b.encode_node(&mut right);
+    left.cmp(&right).then_with(|| {
+        let pairs = a.children().into_iter().zip(b.children());
+        pairs.map(|(a, b)| compare(a, b)).find(|order| order.is_ne()).unwrap_or(Ordering::Equal)
+    })
+}
+
+/// Two expressions are equal when their `normalized` forms are, so `x + 1`
+/// equals `1 + x`.
+impl PartialEq for Expr {
+    fn eq(&self, other: &Expr) -> bool {
+        compare(&self.normalized(), &other.normalized()).is_eq()
+    }
+}
+
+impl Eq for Expr {}
+
+impl Hash for Expr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write(&self.encoded());
+    }
+}
+
+// Rewrites a tree bottom-up into its canonical form
+struct Normalize;
+
+impl ExprFolder for Normalize {
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        match walk_fold(self, expr) {
+            Expr::Number(n) if n.is_nan() => Expr::Number(f64::NAN),
+            Expr::Number(0.0) => Expr::Number(0.0),
+            Expr::BinaryOp { left, op: op @ (BinaryOperator::Greater | BinaryOperator::GreaterEq), right } => {
+                let op = if op == BinaryOperator::Greater { BinaryOperator::Less } else { BinaryOperator::LessEq };
+                Expr::BinaryOp { left: right, op, right: left }
+            }
+            Expr::BinaryOp {
+                left,
+                op: op @ (BinaryOperator::Plus | BinaryOperator::Star | BinaryOperator::Equal | BinaryOperator::NotEqual),
+                right,
+            } if compare(&left, &right).is_gt() => Expr::BinaryOp { left: right, op, right: left },
+            expr => expr,
+        }
+    }
+}
+
+/// A read-only traversal of an expression tree, for tools such as linters.
+/// Override `visit_expr` to look at the nodes of interest, calling `walk_expr`
+/// wherever the traversal should go on into the children.
+pub trait ExprVisitor {
+    fn visit_expr(&mut self, expr: &Expr) {
+        walk_expr(self, expr);
+    }
+}
+
+/// Visits each direct child of `expr` in evaluation order, including the
+/// parameter defaults and body of a `FunctionDef`.
+pub fn walk_expr<V: ExprVisitor + ?Sized>(visitor: &mut V, expr: &Expr) {
+    match expr {
+        Expr::Number(_) | Expr::Ident(_) | Expr::Text(_) | Expr::FunctionRef(_) | Expr::Precision(_) => {}
+        Expr::Assign { expr, .. }
+        | Expr::Const { expr, .. }
+        | Expr::Output { expr, .. }
+        | Expr::Function { arg: expr, .. }
+        | Expr::UnaryOp { operand: expr, .. } => visitor.visit_expr(expr),
+        Expr::BinaryOp { left, right, .. } => {
+            visitor.visit_expr(left);
+            visitor.visit_expr(right);
+        }
+        Expr::Conditional { cond, then, otherwise } => {
+            visitor.visit_expr(cond);
+            visitor.visit_expr(then);
+            visitor.visit_expr(otherwise);
+        }
+        Expr::Let { value, body, .. } => {
+            visitor.visit_expr(value);
+            visitor.visit_expr(body);
+        }
+        Expr::FunctionDef { params, body, .. } => {
+            params.iter().filter_map(|p| p.default.as_ref()).for_each(|d| visitor.visit_expr(d));
+            visitor.visit_expr(body);
+        }
+        Expr::FunctionCall { args: exprs, .. } | Expr::Sequence(exprs) | Expr::List(exprs) => {
+            exprs.iter().for_each(|e| visitor.visit_expr(e));
+        }
+        Expr::Apply { callee, args } | Expr::Bind { callee, args } => {
+            args.iter().for_each(|e| visitor.visit_expr(e));
+            visitor.visit_expr(callee);
+        }
+        Expr::Sum { from, to, eps, cond, body, .. } => {
+            for e in [Some(from), Some(to), eps.as_ref(), cond.as_ref(), Some(body)].into_iter().flatten() {
+                visitor.visit_expr(e);
+            }
+        }
+        Expr::Product { from, to, cond, body, .. } => {
+            for e in [Some(from), Some(to), cond.as_ref(), Some(body)].into_iter().flatten() {
+                visitor.visit_expr(e);
+            }
+        }
+        Expr::Solve { range, lhs, rhs, .. } => {
+            if let Some((from, to)) = range {
+                visitor.visit_expr(from);
+                visitor.visit_expr(to);
+            }
+            visitor.visit_expr(lhs);
+            visitor.visit_expr(rhs);
+        }
+        Expr::SolveN { equations, guess, .. } => {
+            for e in equations {
+                visitor.visit_expr(e);
+            }
+            visitor.visit_expr(guess);
+        }
+        Expr::Extremum { body, from, to, .. } => {
+            visitor.visit_expr(body);
+            visitor.visit_expr(from);
+            visitor.visit_expr(to);
+        }
+        Expr::Derivatives { body, point, .. } => {
+            visitor.visit_expr(body);
+            visitor.visit_expr(point);
+        }
+        Expr::Return { cond, value } => {
+            if let Some(cond) = cond {
+                visitor.visit_expr(cond);
+            }
+            visitor.visit_expr(value);
+        }
+        Expr::Assert { cond, .. } => visitor.visit_expr(cond),
+        Expr::AssertEq { left, right, eps, .. } => {
+            visitor.visit_expr(left);
+            visitor.visit_expr(right);
+            visitor.visit_expr(eps);
+        }
+    }
+}
+
+/// A rewrite of an expression tree, for tools such as custom optimizers.
+/// Override `fold_expr` to replace the nodes of interest, calling `walk_fold`
+/// to rebuild a node from its rewritten children.
+pub trait ExprFolder {
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        walk_fold(self, expr)
+    }
+}
+
+/// Rebuilds `expr` with each direct child replaced by its `fold_expr`, in the
+/// order `walk_expr` visits them.
+pub fn walk_fold<F: ExprFolder + ?Sized>(folder: &mut F, expr: Expr) -> Expr {
+    let mut fold = |e: Box<Expr>| Box::new(folder.fold_expr(*e));
+    match expr {
+        Expr::Number(_) | Expr::Ident(_) | Expr::Text(_) | Expr::FunctionRef(_) | Expr::Precision(_) => expr,
+        Expr::Assign { name, expr } => Expr::Assign { name, expr: fold(expr) },
+        Expr::Const { name, expr } => Expr::Const { name, expr: fold(expr) },
+        Expr::Output { name, expr, unit } => Expr::Output { name, expr: fold(expr), unit },
+        Expr::Function { func, arg } => Expr::Function { func, arg: fold(arg) },
+        Expr::UnaryOp { op, operand } => Expr::UnaryOp { op, operand: fold(operand) },
+        Expr::BinaryOp { left, op, right } => {
+            let left = fold(left);
+            Expr::BinaryOp { left, op, right: fold(right) }
+        }
+        Expr::Conditional { cond, then, otherwise } => {
+            let (cond, then) = (fold(cond), fold(then));
+            Expr::Conditional { cond, then, otherwise: fold(otherwise) }
+        }
+        Expr::Let { name, value, body } => {
+            let value = fold(value);
+            Expr::Let { name, value, body: fold(body) }
+        }
+        Expr::FunctionDef { name, params, body } => {
+            let params = params
+                .into_iter()
+                .map(|p| Param { name: p.name, default: p.default.map(|d| folder.fold_expr(d)) })
+                .collect();
+            Expr::FunctionDef { name, params, body: Box::new(folder.fold_expr(*body)) }
+        }
+        Expr::FunctionCall { name, args } => {
+            Expr::FunctionCall { name, args: args.into_iter().map(|e| folder.fold_expr(e)).collect() }
+        }
+        Expr::Sequence(exprs) => Expr::Sequence(exprs.into_iter().map(|e| folder.fold_expr(e)).collect()),
+        Expr::List(exprs) => Expr::List(exprs.into_iter().map(|e| folder.fold_expr(e)).collect()),
+        Expr::Apply { callee, args } => {
+            let args = args.into_iter().map(|e| folder.fold_expr(e)).collect();
+            Expr::Apply { callee: Box::new(folder.fold_expr(*callee)), args }
+        }
+        Expr::Bind { callee, args } => {
+            let args = args.into_iter().map(|e| folder.fold_expr(e)).collect();
+            Expr::Bind { callee: Box::new(folder.fold_expr(*callee)), args }
+        }
+        Expr::Sum { from, to, eps, param, cond, body } => {
+            let (from, to) = (fold(from), fold(to));
+            let eps = eps.map(&mut fold);
+            let cond = cond.map(&mut fold);
+            Expr::Sum { from, to, eps, param, cond, body: fold(body) }
+        }
+        Expr::Product { from, to, param, cond, body } => {
+            let (from, to) = (fold(from), fold(to));
+            let cond = cond.map(&mut fold);
+            Expr::Product { from, to, param, cond, body: fold(body) }
+        }
+        Expr::Solve { param, range, lhs, rhs } => {
+            let range = range.map(|(from, to)| {
+                let from = fold(from);
+                (from, fold(to))
+            });
+            let lhs = fold(lhs);
+            Expr::Solve { param, range, lhs, rhs: fold(rhs) }
+        }
+        Expr::SolveN { equations, params, guess } => {
+            let equations = equations.into_iter().map(|e| folder.fold_expr(e)).collect();
+            Expr::SolveN { equations, params, guess: Box::new(folder.fold_expr(*guess)) }
+        }
+        Expr::Extremum { maximize, body, param, from, to } => {
+            let (body, from) = (fold(body), fold(from));
+            Expr::Extremum { maximize, body, param, from, to: fold(to) }
+        }
+        Expr::Derivatives { hessian, body, params, point } => {
+            let body = fold(body);
+            Expr::Derivatives { hessian, body, params, point: fold(point) }
+        }
+        Expr::Return { cond, value } => {
+            let cond = cond.map(&mut fold);
+            Expr::Return { cond, value: fold(value) }
+        }
+        Expr::Assert { cond, line } => Expr::Assert { cond: fold(cond), line },
+        Expr::AssertEq { left, right, eps, line } => {
+            let (left, right) = (fold(left), fold(right));
+            Expr::AssertEq { left, right, eps: fold(eps), line }
+        }
+    }
+}
+
+/// A parameter of a user function, with the value a call that leaves it out
+/// passes instead. Only trailing parameters have defaults.
+#[derive(Debug, Clone)]
+pub struct Param {
+    pub name: String,
+    /// Evaluated at each call that needs it, after the parameters before it are bound.
+    pub default: Option<Expr>,
+}
+
+/// A user-defined function: `def name(params) = body`.
+#[derive(Debug, Clone)]
+pub struct UserFunction {
+    pub params: Vec<Param>,
+    pub body: Expr,
+    /// The body and defaults as bytecode, compiled by the first call
+    code: OnceLock<FunctionCode>,
+}
+
+impl UserFunction {
+    pub fn new(params: Vec<Param>, body: Expr) -> Self {
+        UserFunction { params, body, code: OnceLock::new() }
+    }
+
+    /// The compiled body and defaults the VM runs for a call.
+    pub(crate) fn code(&self) -> &FunctionCode {
+        self.code.get_or_init(|| FunctionCode::compile(self))
+    }
+
+    /// Forgets the compiled code after `body` or a default was rewritten.
+    pub(crate) fn recompile(&mut self) {
+        self.code = OnceLock::new();
+    }
+
+    /// Fewest arguments a call must pass: the parameters without a default.
+    pub fn required(&self) -> usize {
+        self.params.iter().filter(|p| p.default.is_none()).count()
+    }
+
+    /// Whether a call may pass `argc` arguments.
+    pub fn accepts(&self, argc: usize) -> bool {
+        (self.required()..=self.params.len()).contains(&argc)
+    }
+}
+
+/// A user function compiled like top-level code, so that both run the
+/// same builtins: the body, and the default of each parameter that has one.
+#[derive(Debug, Clone)]
+pub(crate) struct FunctionCode {
+    pub body: crate::bytecode::Program,
+    pub defaults: Vec<Option<crate::bytecode::Program>>,
+}
+
+impl FunctionCode {
+    fn compile(function: &UserFunction) -> Self {
+        let compile = |expr: &Expr| {
+            let mut program = Vec::new();
+            crate::compiler::compile(expr, &mut program);
+            program
+        };
+        FunctionCode {
+            body: compile(&function.body),
+            defaults: function.params.iter().map(|p| p.default.as_ref().map(compile)).collect(),
+        }
+    }
+}
+
+/// The definitions of one user function name, at most one per parameter count,
+/// as in `def area(r) = ...` next to `def area(w, h) = ...`.
+#[derive(Debug, Clone, Default)]
+pub struct Overloads(Vec<UserFunction>);
+
+impl Overloads {
+    /// Adds `function`, replacing the definition with as many parameters.
+    pub fn insert(&mut self, function: UserFunction) {
+        match self.0.iter_mut().find(|f| f.params.len() == function.params.len()) {
+            Some(existing) => *existing = function,
+            None => {
+                self.0.push(function);
+                self.0.sort_by_key(|f| f.params.len());
+            }
+        }
+    }
+
+    /// Adds every definition of `other`, replacing those with as many parameters.
+    pub fn merge(&mut self, other: Overloads) {
+        other.0.into_iter().for_each(|function| self.insert(function));
+    }
+
+    /// The definition a call with `argc` arguments runs: the one with exactly
+    /// `argc` parameters, else the one with the fewest that takes `argc`
+    /// arguments by using defaults.
+    pub fn resolve(&self, argc: usize) -> Option<&UserFunction> {
+        self.0.iter().find(|f| f.params.len() == argc).or_else(|| self.0.iter().find(|f| f.accepts(argc)))
+    }
+
+    /// The definitions, fewest parameters first.
+    pub fn iter(&self) -> impl Iterator<Item = &UserFunction> {
+        self.0.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut UserFunction> {
+        self.0.iter_mut()
+    }
+
+    /// The accepted argument counts of all definitions, as in "1 or 2 to 3 arguments".
+    pub fn describe_arity(&self) -> String {
+        let counts: Vec<String> = self
+            .0
+            .iter()
+            .map(|f| match (f.required(), f.params.len()) {
+                (required, total) if required == total => total.to_string(),
+                (required, total) => format!("{} to {}", required, total),
+            })
+            .collect();
+        let most = self.0.last().map_or(0, |f| f.params.len());
+        format!("{} argument{}", counts.join(" or "), if most == 1 { "" } else { "s" })
+    }
 }
+
+/// User-defined functions by name, each with its definitions by parameter count.
+/// Built once by the parser and only read afterwards, so it can be shared between threads.
+pub type UserFunctions = std::collections::HashMap<String, Overloads>;
+
+/// Top-level statements in source order, each tagged with its 1-based source line.
+pub type Statements = Vec<(usize, Expr)>;
@@ -0,0 +1,108 @@
+// Project builds: every .mth under a directory compiled to .mthc, skipping those already current
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::analysis::{Diagnostic, LintLevels};
+use crate::compiler::CompiledProgram;
+use crate::mthc::{self, MthcFormat};
+
+/// What building one source file did.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BuildOutcome {
+    /// Written to its `.mthc`, with the warnings of analysis that `lints` does
+    /// not allow.
+    Compiled { warnings: Vec<Diagnostic>, precomputed: bool },
+    /// Its `.mthc` is newer than the source, so it was left alone.
+    UpToDate,
+    /// Not compiled: the errors (including the warnings `lints` denies), or
+    /// why the files could not be read or written.
+    Failed(Vec<String>),
+}
+
+/// The outcome for each source file, sorted by path.
+#[derive(Debug, Default)]
+pub struct BuildReport {
+    pub results: Vec<(PathBuf, BuildOutcome)>,
+}
+
+impl BuildReport {
+    pub fn compiled(&self) -> usize {
+        self.results.iter().filter(|(_, o)| matches!(o, BuildOutcome::Compiled { .. })).count()
+    }
+
+    pub fn up_to_date(&self) -> usize {
+        self.results.iter().filter(|(_, o)| *o == BuildOutcome::UpToDate).count()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.results.iter().filter(|(_, o)| matches!(o, BuildOutcome::Failed(_))).count()
+    }
+}
+
+/// The `.mthc` file a source compiles to: `dir/f.mth` to `dir/f.mthc`.
+pub fn mthc_path(source: &Path) -> PathBuf {
+    source.with_extension("mthc")
+}
+
+/// Writes the program of `compiled` to `path` as a `.mthc` file in `format`,
+/// marked as format `version`, which `mthc::check_version` has accepted.
+/// User functions are not stored; running the file reads them from its source.
+pub fn write_mthc(compiled: &CompiledProgram, path: &Path, format: MthcFormat, version: u64) -> io::Result<()> {
+    fs::write(path, mthc::encode(&compiled.program, format, version))
+}
+
+/// Compiles every `.mth` file under `dir` (or `dir` itself, if it is a file)
+/// whose `.mthc` is missing or older than the source, or all of them with
+/// `force`. Each file stands alone, since a source cannot include another,
+/// so only the changed ones are recompiled. Programs with a fixed result are
+/// stored as that result, as a single-file compile does. A file that needs
+/// instructions newer than format `version` fails.
+pub fn build_dir(dir: &Path, force: bool, lints: &LintLevels, format: MthcFormat, version: u64) -> io::Result<BuildReport> {
+    let mut files = Vec::new();
+    crate::golden::collect_files(dir, &mut files)?;
+    files.sort();
+    let results = files
+        .into_iter()
+        .map(|path| {
+            let outcome = build_file(&path, force, lints, format, version);
+            (path, outcome)
+        })
+        .collect();
+    Ok(BuildReport { results })
+}
+
+fn build_file(source: &Path, force: bool, lints: &LintLevels, format: MthcFormat, version: u64) -> BuildOutcome {
+    let target = mthc_path(source);
+    if !force && is_up_to_date(source, &target) {
+        return BuildOutcome::UpToDate;
+    }
+    let input = match fs::read_to_string(source) {
+        Ok(input) => input,
+        Err(e) => return BuildOutcome::Failed(vec![format!("cannot read: {}", e)]),
+    };
+    let (mut compiled, warnings) = match CompiledProgram::try_from_source(&input) {
+        Ok(result) => result,
+        Err(diagnostics) => {
+            return BuildOutcome::Failed(diagnostics.iter().filter(|d| d.is_error()).map(|d| d.to_string()).collect());
+        }
+    };
+    let warnings = lints.apply(warnings);
+    if warnings.iter().any(Diagnostic::is_error) {
+        return BuildOutcome::Failed(warnings.iter().filter(|d| d.is_error()).map(|d| d.to_string()).collect());
+    }
+    let precomputed = compiled.precompute();
+    if let Err(e) = mthc::check_version(&compiled.program, version) {
+        return BuildOutcome::Failed(vec![e]);
+    }
+    match write_mthc(&compiled, &target, format, version) {
+        Ok(()) => BuildOutcome::Compiled { warnings, precomputed },
+        Err(e) => BuildOutcome::Failed(vec![format!("cannot write {}: {}", target.display(), e)]),
+    }
+}
+
+// Whether `target` was written after `source` was last changed
+fn is_up_to_date(source: &Path, target: &Path) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    matches!((modified(source), modified(target)), (Some(source), Some(target)) if target >= source)
+}
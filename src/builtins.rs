@@ -0,0 +1,270 @@
+// Builtin function catalog: names, signatures, and arity checking
+use crate::ast::Expr;
+use crate::lexer::SpecialFunction;
+use crate::error::ParseError;
+
+/// Kind of value a builtin argument must be.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArgKind {
+    Number,
+    /// A list literal `[a, b, ...]`, a list variable, or a list-valued builtin
+    List,
+    /// Any number of numbers (last parameter only), as in `poly(1, -3, 2)`
+    Numbers,
+    /// A string literal, as the variable name in `env("N", 10)`
+    Text,
+}
+
+/// One builtin signature. A name may appear more than once with different
+/// arities (e.g. `log(x)` and `log(base, x)`).
+#[derive(Debug, Clone, Copy)]
+pub struct Builtin {
+    pub name: &'static str,
+    pub func: SpecialFunction,
+    pub params: &'static [(&'static str, ArgKind)],
+}
+
+use ArgKind::{List, Number, Numbers, Text};
+use SpecialFunction as F;
+
+const X: &[(&str, ArgKind)] = &[("x", Number)];
+
+/// Every builtin callable by name. The lexer resolves names through this table
+/// and the parser validates argument counts against it.
+pub static BUILTINS: &[Builtin] = &[
+    Builtin { name: "sin", func: F::Sin, params: X },
+    Builtin { name: "cos", func: F::Cos, params: X },
+    Builtin { name: "tan", func: F::Tan, params: X },
+    Builtin { name: "cot", func: F::Cot, params: X },
+    Builtin { name: "sec", func: F::Sec, params: X },
+    Builtin { name: "csc", func: F::Csc, params: X },
+    Builtin { name: "sinh", func: F::Sinh, params: X },
+    Builtin { name: "cosh", func: F::Cosh, params: X },
+    Builtin { name: "tanh", func: F::Tanh, params: X },
+    Builtin { name: "asinh", func: F::Asinh, params: X },
+    Builtin { name: "acosh", func: F::Acosh, params: X },
+    Builtin { name: "atanh", func: F::Atanh, params: X },
+    Builtin { name: "exp", func: F::Exp, params: X },
+    Builtin { name: "log", func: F::Log, params: X },
+    Builtin { name: "log", func: F::LogBase, params: &[("base", Number), ("x", Number)] },
+    Builtin { name: "log10", func: F::Log10, params: X },
+    Builtin { name: "log2", func: F::Log2, params: X },
+    Builtin { name: "sqrt", func: F::Sqrt, params: X },
+    Builtin { name: "abs", func: F::Abs, params: X },
+    Builtin { name: "asin", func: F::Asin, params: X },
+    Builtin { name: "acos", func: F::Acos, params: X },
+    Builtin { name: "atan", func: F::Atan, params: X },
+    Builtin { name: "atan2", func: F::Atan2, params: &[("y", Number), ("x", Number)] },
+    Builtin { name: "acot", func: F::Acot, params: X },
+    Builtin { name: "asec", func: F::Asec, params: X },
+    Builtin { name: "acsc", func: F::Acsc, params: X },
+    Builtin { name: "deg", func: F::Deg, params: X },
+    Builtin { name: "rad", func: F::Rad, params: X },
+    Builtin { name: "grad", func: F::Grad, params: X },
+    Builtin { name: "pow", func: F::Pow, params: &[("base", Number), ("exponent", Number)] },
+    Builtin { name: "fact", func: F::Fact, params: X },
+    Builtin { name: "floor", func: F::Floor, params: X },
+    Builtin { name: "rand", func: F::Rand, params: &[] },
+    Builtin { name: "randint", func: F::RandInt, params: &[("min", Number), ("max", Number)] },
+    Builtin { name: "root", func: F::Root, params: &[("x", Number), ("n", Number)] },
+    Builtin { name: "expm1", func: F::Expm1, params: X },
+    Builtin { name: "ln1p", func: F::Ln1p, params: X },
+    Builtin { name: "exp2", func: F::Exp2, params: X },
+    Builtin { name: "round", func: F::Round, params: X },
+    Builtin { name: "round", func: F::RoundTo, params: &[("x", Number), ("n", Number)] },
+    Builtin { name: "roundeven", func: F::RoundEven, params: X },
+    Builtin { name: "roundeven", func: F::RoundEvenTo, params: &[("x", Number), ("n", Number)] },
+    Builtin { name: "quantile", func: F::Quantile, params: &[("data", List), ("p", Number)] },
+    Builtin { name: "quantile", func: F::QuantileType, params: &[("data", List), ("p", Number), ("type", Number)] },
+    Builtin { name: "percentile", func: F::Percentile, params: &[("data", List), ("p", Number)] },
+    Builtin { name: "erf", func: F::Erf, params: X },
+    Builtin { name: "erfc", func: F::Erfc, params: X },
+    Builtin { name: "besselj", func: F::BesselJ, params: &[("n", Number), ("x", Number)] },
+    Builtin { name: "bessely", func: F::BesselY, params: &[("n", Number), ("x", Number)] },
+    Builtin { name: "zeta", func: F::Zeta, params: &[("s", Number)] },
+    Builtin { name: "lambertw", func: F::LambertW, params: X },
+    Builtin { name: "beta", func: F::Beta, params: &[("a", Number), ("b", Number)] },
+    Builtin { name: "betainc", func: F::BetaInc, params: &[("a", Number), ("b", Number), ("x", Number)] },
+    Builtin { name: "gammainc", func: F::GammaInc, params: &[("s", Number), ("x", Number)] },
+    Builtin { name: "normcdf", func: F::NormCdf, params: X },
+    Builtin { name: "normcdf", func: F::NormCdfScaled, params: &[("x", Number), ("mu", Number), ("sigma", Number)] },
+    Builtin { name: "normpdf", func: F::NormPdf, params: X },
+    Builtin { name: "normpdf", func: F::NormPdfScaled, params: &[("x", Number), ("mu", Number), ("sigma", Number)] },
+    Builtin { name: "norminv", func: F::NormInv, params: &[("p", Number)] },
+    Builtin { name: "norminv", func: F::NormInvScaled, params: &[("p", Number), ("mu", Number), ("sigma", Number)] },
+    Builtin { name: "tcdf", func: F::TCdf, params: &[("t", Number), ("nu", Number)] },
+    Builtin { name: "tinv", func: F::TInv, params: &[("p", Number), ("nu", Number)] },
+    Builtin { name: "chi2cdf", func: F::Chi2Cdf, params: &[("x", Number), ("k", Number)] },
+    Builtin { name: "chi2inv", func: F::Chi2Inv, params: &[("p", Number), ("k", Number)] },
+    Builtin { name: "isprime", func: F::IsPrime, params: &[("n", Number)] },
+    Builtin { name: "nextprime", func: F::NextPrime, params: &[("n", Number)] },
+    Builtin { name: "primepi", func: F::PrimePi, params: &[("n", Number)] },
+    Builtin { name: "factorint", func: F::Factorint, params: &[("n", Number)] },
+    Builtin { name: "len", func: F::Len, params: &[("data", List)] },
+    Builtin { name: "at", func: F::At, params: &[("data", List), ("i", Number)] },
+    Builtin { name: "mod", func: F::Mod, params: &[("a", Number), ("m", Number)] },
+    Builtin { name: "powmod", func: F::PowMod, params: &[("a", Number), ("b", Number), ("m", Number)] },
+    Builtin { name: "invmod", func: F::InvMod, params: &[("a", Number), ("m", Number)] },
+    Builtin { name: "fib", func: F::Fib, params: &[("n", Number)] },
+    Builtin { name: "choose", func: F::Choose, params: &[("n", Number), ("k", Number)] },
+    Builtin { name: "dfact", func: F::DFact, params: X },
+    Builtin { name: "subfact", func: F::SubFact, params: &[("n", Number)] },
+    Builtin { name: "recurrence", func: F::Recurrence, params: &[("coeffs", List), ("initial", List), ("n", Number)] },
+    Builtin { name: "poly", func: F::Poly, params: &[("coeffs", Numbers)] },
+    Builtin { name: "polyadd", func: F::PolyAdd, params: &[("p", List), ("q", List)] },
+    Builtin { name: "polysub", func: F::PolySub, params: &[("p", List), ("q", List)] },
+    Builtin { name: "polymul", func: F::PolyMul, params: &[("p", List), ("q", List)] },
+    Builtin { name: "polyder", func: F::PolyDer, params: &[("p", List)] },
+    Builtin { name: "polyval", func: F::PolyVal, params: &[("p", List), ("x", Number)] },
+    Builtin { name: "roots", func: F::Roots, params: &[("p", List)] },
+    Builtin { name: "quadroots", func: F::QuadRoots, params: &[("a", Number), ("b", Number), ("c", Number)] },
+    Builtin { name: "cubicroots", func: F::CubicRoots, params: &[("a", Number), ("b", Number), ("c", Number), ("d", Number)] },
+    Builtin { name: "fit", func: F::Fit, params: &[("xs", List), ("ys", List), ("degree", Number)] },
+    Builtin { name: "fitexp", func: F::FitExp, params: &[("xs", List), ("ys", List)] },
+    Builtin { name: "fitlog", func: F::FitLog, params: &[("xs", List), ("ys", List)] },
+    Builtin { name: "arg", func: F::Arg, params: &[("n", Number)] },
+    Builtin { name: "argc", func: F::Argc, params: &[] },
+    Builtin { name: "time", func: F::Time, params: &[("expr", Number)] },
+    Builtin { name: "seed", func: F::Seed, params: &[("n", Number)] },
+    Builtin { name: "randstate", func: F::RandState, params: &[] },
+    Builtin { name: "env", func: F::Env, params: &[("name", Text), ("default", Number)] },
+    Builtin { name: "loadcsv", func: F::LoadCsv, params: &[("path", Text), ("column", Number)] },
+    Builtin { name: "savecsv", func: F::SaveCsv, params: &[("path", Text), ("xs", List)] },
+    Builtin { name: "savecsv", func: F::SaveCsvXy, params: &[("path", Text), ("xs", List), ("ys", List)] },
+    Builtin { name: "format", func: F::Format, params: &[("template", Text), ("values", Numbers)] },
+    Builtin { name: "print", func: F::Print, params: &[("text", Text), ("values", Numbers)] },
+];
+
+/// Looks up a builtin by (case-insensitive) name.
+pub fn lookup(name: &str) -> Option<SpecialFunction> {
+    let name = name.to_ascii_lowercase();
+    BUILTINS.iter().find(|b| b.name == name).map(|b| b.func)
+}
+
+/// The catalog entry for a builtin.
+pub fn entry(func: SpecialFunction) -> &'static Builtin {
+    BUILTINS.iter().find(|b| b.func == func).expect("every SpecialFunction has a catalog entry")
+}
+
+/// Picks the overload of `func`'s name that takes `argc` arguments, or explains
+/// which argument lists are accepted.
+pub fn resolve(func: SpecialFunction, argc: usize) -> Result<SpecialFunction, ParseError> {
+    let name = entry(func).name;
+    let overloads: Vec<&Builtin> = BUILTINS.iter().filter(|b| b.name == name).collect();
+    if let Some(b) = overloads.iter().find(|b| accepts(b, argc)) {
+        return Ok(b.func);
+    }
+    let expected: Vec<String> = overloads.iter().map(|b| describe(b)).collect();
+    Err(ParseError::ArgumentCount { function: name, expected: expected.join(" or "), got: argc })
+}
+
+/// Puts the arguments of a call to `func` in parameter order. Positional
+/// arguments come first; each named one (`max: 10`) goes to the parameter of
+/// that name, as listed in the catalog.
+pub fn order_args(func: SpecialFunction, args: Vec<(Option<String>, Expr)>) -> Result<Vec<Expr>, ParseError> {
+    let b = entry(func);
+    if args.iter().all(|(name, _)| name.is_none()) {
+        return Ok(args.into_iter().map(|(_, arg)| arg).collect());
+    }
+    // Only fixed parameters can be named, not a trailing `Numbers` one
+    let named = match b.params.split_last() {
+        Some(((_, Numbers), fixed)) => fixed,
+        _ => b.params,
+    };
+    let mut slots: Vec<Option<Expr>> = vec![None; args.len()];
+    for (position, (name, arg)) in args.into_iter().enumerate() {
+        let index = match name {
+            None => position,
+            Some(name) => match named.iter().position(|(param, _)| *param == name) {
+                Some(index) => index,
+                None => return Err(ParseError::UnknownArgument { function: b.name, name }),
+            },
+        };
+        match slots.get_mut(index) {
+            Some(slot @ None) => *slot = Some(arg),
+            _ => return Err(ParseError::DuplicateArgument { function: b.name, param: b.params[index].0 }),
+        }
+    }
+    // Each of the `argc` slots holds at most one argument, so all are filled
+    Ok(slots.into_iter().flatten().collect())
+}
+
+fn accepts(b: &Builtin, argc: usize) -> bool {
+    match b.params.split_last() {
+        Some(((_, Numbers), fixed)) => argc >= fixed.len(),
+        _ => b.params.len() == argc,
+    }
+}
+
+/// Whether a builtin's first parameter is a string, which compiles into its
+/// opcode rather than onto the stack.
+pub fn takes_text(func: SpecialFunction) -> bool {
+    entry(func).params.first().is_some_and(|(_, kind)| *kind == Text)
+}
+
+/// Builtins that produce a list instead of a number.
+const LIST_RESULTS: &[SpecialFunction] = &[
+    F::Factorint,
+    F::Poly,
+    F::PolyAdd,
+    F::PolySub,
+    F::PolyMul,
+    F::PolyDer,
+    F::Roots,
+    F::QuadRoots,
+    F::CubicRoots,
+    F::Fit,
+    F::FitExp,
+    F::FitLog,
+    F::LoadCsv,
+];
+
+/// Whether a builtin produces a list instead of a number.
+pub fn returns_list(func: SpecialFunction) -> bool {
+    LIST_RESULTS.contains(&func)
+}
+
+/// Checks that list parameters get list arguments and number parameters do not.
+/// A plain variable name is accepted for a list parameter; analysis checks
+/// that it actually holds a list.
+pub fn check_args(func: SpecialFunction, args: &[Expr]) -> Result<(), ParseError> {
+    let b = entry(func);
+    // A trailing `Numbers` parameter covers every remaining argument
+    let rest = b.params.last().filter(|(_, kind)| *kind == Numbers).into_iter().cycle();
+    let is_text = |arg: &Expr| matches!(arg, Expr::Text(_) | Expr::Function { func: F::Format, .. });
+    for (arg, (param, kind)) in args.iter().zip(b.params.iter().chain(rest)) {
+        match kind {
+            ArgKind::List if !(arg.is_list() || matches!(arg, Expr::Ident(_))) => {
+                return Err(ParseError::ListExpected { function: b.name, param });
+            }
+            ArgKind::Number | ArgKind::Numbers if arg.is_list() => {
+                return Err(ParseError::NumberExpected { function: b.name, param });
+            }
+            ArgKind::Number | ArgKind::Numbers if is_text(arg) => {
+                return Err(ParseError::UnexpectedText { function: b.name, param });
+            }
+            ArgKind::Text if !matches!(arg, Expr::Text(_)) => {
+                return Err(ParseError::TextExpected { function: b.name, param });
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn describe(b: &Builtin) -> String {
+    if b.params.is_empty() {
+        return "no arguments".to_string();
+    }
+    if let Some(((last, Numbers), fixed)) = b.params.split_last() {
+        let mut names: Vec<String> = fixed.iter().map(|(n, _)| n.to_string()).collect();
+        names.push(format!("{}...", last));
+        return format!("{} or more arguments ({})", fixed.len(), names.join(", "));
+    }
+    let names: Vec<&str> = b.params.iter().map(|(n, _)| *n).collect();
+    format!("{} ({})", plural(b.params.len(), "argument"), names.join(", "))
+}
+
+fn plural(n: usize, word: &str) -> String {
+    if n == 1 { format!("{} {}", n, word) } else { format!("{} {}s", n, word) }
+}
@@ -1,8 +1,9 @@
 // Bytecode instructions for the math compiler/interpreter
 use bincode::{Encode, Decode};
-#[derive(Debug, Clone, Encode, Decode)]
+use serde::{Deserialize, Serialize};
+#[derive(Debug, Clone, Encode, Decode, Serialize, Deserialize)]
 pub enum Bytecode {
-    PushNumber(f64),
+    PushNumber(#[serde(with = "crate::mthc::number")] f64),
     Add,
     Sub,
     Mul,
@@ -37,19 +38,145 @@ pub enum Bytecode {
     Floor,
     Rand,
     RandInt,
-    StoreVar(String),
-    LoadVar(String),
+    StoreVar(usize), // slot in the program's Frame
+    LoadVar(usize),
     CallUserFunction(String),
     SumLoop {
         from: Box<Program>,
         to: Box<Program>,
-        param: String,
+        eps: Option<Box<Program>>,
+        param: usize,
         body: Box<Program>,
     },
     ProductLoop {
         from: Box<Program>,
         to: Box<Program>,
-        param: String,
+        param: usize,
+        body: Box<Program>,
+    },
+    Assert(usize),   // source line, for the error message
+    AssertEq(usize), // source line, for the error message
+    StoreConst(usize), // like StoreVar, but later stores to the name fail
+    Less,
+    LessEq,
+    Greater,
+    GreaterEq,
+    Equal,
+    NotEqual,
+    JumpIfFalse(usize), // pop; if 0 or NaN, skip the next n instructions
+    Jump(usize),        // skip the next n instructions
+    LetIn {             // pop a value, bind it to `slot` while running `body`
+        slot: usize,
+        body: Box<Program>,
+    },
+    Atan2,
+    Root,
+    Expm1,
+    Ln1p,
+    Exp2,
+    Round,
+    RoundTo,
+    RoundEven,
+    RoundEvenTo,
+    MakeList(usize),   // pop n numbers into a list on the list stack
+    StoreList(String), // pop the list stack into a list variable
+    LoadList(String),  // push a list variable onto the list stack
+    Quantile,
+    QuantileType,
+    Percentile,
+    Erf,
+    Erfc,
+    BesselJ,
+    BesselY,
+    Zeta,
+    LambertW,
+    Beta,
+    BetaInc,
+    GammaInc,
+    NormCdf,
+    NormPdf,
+    NormInv,
+    NormCdfScaled,
+    NormPdfScaled,
+    NormInvScaled,
+    TCdf,
+    TInv,
+    Chi2Cdf,
+    Chi2Inv,
+    IsPrime,
+    NextPrime,
+    PrimePi,
+    Factorint,
+    Len,
+    At,
+    Mod,
+    PowMod,
+    InvMod,
+    Fib,
+    Recurrence,
+    PolyAdd,
+    PolySub,
+    PolyMul,
+    PolyDer,
+    PolyVal,
+    Roots,
+    QuadRoots,
+    CubicRoots,
+    Fit,
+    FitExp,
+    FitLog,
+    PowI(i32), // x^n for a constant small integer n
+    Precomputed(#[serde(with = "crate::mthc::number")] f64), // the result of a whole program evaluated at compile time
+    Frame(Vec<String>), // first instruction: the variable name of each slot
+    Choose, // binomial coefficient C(n, k)
+    DFact,
+    SubFact,
+    Deg, // radians to degrees
+    Rad, // degrees to radians
+    Grad, // radians to gradians
+    CallUserFunctionN(String, usize), // call with this many arguments; CallUserFunction passes one
+    Return, // end the running body with the value on top of the stack
+    LoopWhile, // pop; if 0 or NaN, end the enclosing sum or product before this term
+    PushFunction(String),  // push a user function, or the one a function variable holds, onto the function stack
+    StoreFunction(String), // pop the function stack into a function variable
+    CallIndirect(usize),   // pop the function stack and call it with the top n values
+    Bind(usize),           // pop the function stack and push it with the top n values fixed as its next arguments
+    Arg,  // pop n; push script argument n (1-based)
+    Argc, // push the number of script arguments
+    Pop,  // discard the value on top of the stack, as a statement's result that is not the program's
+    Dup,  // push a copy of the value on top of the stack
+    Swap, // exchange the top two values
+    Neg,  // negate the value on top of the stack
+    StoreOutput(usize), // like StoreVar, and adds the name to the program's named results
+    Timed(Box<Program>), // run the body and push its value, printing how long it took to stderr
+    Seed,      // pop n; restart the random number generator from seed n, and push n
+    RandState, // push a seed drawn from the random number generator, which goes on from that seed
+    Env(String), // pop default; push the environment variable of this name as a number, or default if unset
+    LoadCsv(String), // pop column; push that column of the CSV file at this path as a list
+    SaveCsv(String, usize), // pop n lists; write them as the columns of a CSV file at this path, and push its row count
+    Precision(usize), // results of the program print with this many digits after the point
+    Unit(usize, String), // label the named result in this slot with a unit, as in `m/s`
+    Print(String, usize), // pop n values; print this format string with them filled in, and push the last value (or 0)
+    Solve {               // push the list of values of `param` in [from, to] where the body is zero
+        from: Box<Program>,
+        to: Box<Program>,
+        param: usize,
+        body: Box<Program>,
+    },
+    SolveN {              // pop the guess list; push the values of `params` near it where every equation is zero
+        params: Vec<usize>,
+        equations: Vec<Program>,
+    },
+    Extremum {            // push the list [param, body] where the body is least (greatest if `maximize`) for `param` in [from, to]
+        maximize: bool,
+        from: Box<Program>,
+        to: Box<Program>,
+        param: usize,
+        body: Box<Program>,
+    },
+    Derivatives {         // pop the point list; push the gradient of the body in `params` there (the Hessian, row by row, if `hessian`)
+        hessian: bool,
+        params: Vec<usize>,
         body: Box<Program>,
     },
     // Add more as needed
@@ -1,36 +1,718 @@
-use crate::ast::Expr;
+use crate::analysis::{analyze_with_inputs, Diagnostic, Severity};
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use crate::ast::{Expr, Statements, UnaryOperator, UserFunctions};
+use crate::builtins::ArgKind;
 use crate::bytecode::{Bytecode, Program};
-use crate::lexer::BinaryOperator;
+use crate::engine::CancellationToken;
+use crate::error::{FmathError, RuntimeError};
+use crate::interpreter::{is_true, Value, VmState, DEFAULT_CHECK_INTERVAL};
+use crate::lexer::{BinaryOperator, SpecialFunction};
+
+/// A compiled program together with the user functions it calls.
+///
+/// Nothing here is mutated during execution and both parts are `Send + Sync`,
+/// so one `CompiledProgram` can be put behind an `Arc` and evaluated from many
+/// threads at once, each thread bringing its own `VmState`.
+#[derive(Debug, Clone)]
+pub struct CompiledProgram {
+    pub program: Program,
+    pub functions: UserFunctions,
+}
+
+impl CompiledProgram {
+    /// Tokenizes, parses, and compiles `.mth` source text, failing with the
+    /// first lexer or parser error.
+    pub fn from_source(input: &str) -> Result<Self, FmathError> {
+        let lines = crate::lexer::try_tokenize_numbered(input).map_err(|mut errors| errors.remove(0))?;
+        let (ast, functions) = crate::parser::parse(lines)?;
+        let mut program = Vec::new();
+        compile(&ast, &mut program);
+        log::debug!(target: "compiler", "{} instructions", program.len());
+        Ok(CompiledProgram { program, functions })
+    }
+
+    /// Like `from_source`, but runs semantic analysis first. Returns the program
+    /// with any warnings, or every diagnostic if there was at least one error.
+    pub fn try_from_source(input: &str) -> Result<(Self, Vec<Diagnostic>), Vec<Diagnostic>> {
+        Self::try_from_source_with_inputs(input, &[])
+    }
+
+    /// Like `try_from_source`, for a program that reads the variables `inputs`
+    /// without assigning them; the host binds them with `VmState::set_var`.
+    pub fn try_from_source_with_inputs(input: &str, inputs: &[&str]) -> Result<(Self, Vec<Diagnostic>), Vec<Diagnostic>> {
+        let lines = crate::lexer::try_tokenize_numbered(input).map_err(|errors| {
+            errors
+                .into_iter()
+                .map(|e| Diagnostic { severity: Severity::Error, line: Some(e.line()), function: None, message: e.to_string(), lint: None })
+                .collect::<Vec<_>>()
+        })?;
+        let (statements, functions) = crate::parser::parse_statements(lines)?;
+        let diagnostics = analyze_with_inputs(&statements, &functions, inputs);
+        log::debug!(target: "compiler", "analysis found {} diagnostics", diagnostics.len());
+        if diagnostics.iter().any(Diagnostic::is_error) {
+            return Err(diagnostics);
+        }
+        let mut statements = statements;
+        fold_constants(&mut statements);
+        let mut exprs: Vec<Expr> = statements.into_iter().map(|(_, expr)| expr).collect();
+        let ast = if exprs.len() == 1 { exprs.pop().unwrap() } else { Expr::Sequence(exprs) };
+        let mut program = Vec::new();
+        compile(&ast, &mut program);
+        log::debug!(target: "compiler", "{} instructions", program.len());
+        Ok((CompiledProgram { program, functions }, diagnostics))
+    }
+
+    /// Evaluates the program using the given execution state.
+    pub fn eval(&self, state: &mut VmState) -> Result<f64, RuntimeError> {
+        state.run(&self.program, &self.functions)
+    }
+
+    /// Like `eval`, for a program whose result may be a list.
+    pub fn eval_value(&self, state: &mut VmState) -> Result<Value, RuntimeError> {
+        state.run_value(&self.program, &self.functions)
+    }
+
+    /// Replaces the program with `Precomputed(result)` if its result depends on
+    /// nothing but the source: neither it nor any user function uses `rand` or
+    /// `randint`, it has no `output` statements or `#pragma precision` whose
+    /// effect on the state would be lost, and
+    /// it runs without error (reading no outside variables) within
+    /// `PRECOMPUTE_BUDGET`. Returns whether it did.
+    pub fn precompute(&mut self) -> bool {
+        let sets_state = self.program.iter().any(|code| matches!(code, Bytecode::StoreOutput(_) | Bytecode::Precision(_)));
+        if self.is_precomputed() || sets_state || !is_deterministic(&self.program) {
+            return false;
+        }
+        for function in self.functions.values().flat_map(|overloads| overloads.iter()) {
+            let code = function.code();
+            if !code.defaults.iter().flatten().chain([&code.body]).all(is_deterministic) {
+                return false;
+            }
+        }
+        // Cancel the run if it outlasts the budget
+        let token = CancellationToken::new();
+        let (finished, wait) = mpsc::channel::<()>();
+        let watchdog = {
+            let token = token.clone();
+            thread::spawn(move || {
+                if wait.recv_timeout(PRECOMPUTE_BUDGET) == Err(RecvTimeoutError::Timeout) {
+                    token.cancel();
+                }
+            })
+        };
+        let mut state = compile_time_state();
+        state.set_cancellation(token, DEFAULT_CHECK_INTERVAL);
+        let result = self.eval(&mut state);
+        drop(finished);
+        let _ = watchdog.join();
+        match result {
+            Ok(value) => {
+                self.program = vec![Bytecode::Precomputed(value)];
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Whether the program is just a result stored by `precompute`.
+    pub fn is_precomputed(&self) -> bool {
+        is_precomputed(&self.program)
+    }
+
+    /// A copy with the variables of `values` fixed, for a formula evaluated
+    /// many times while some of its parameters stay the same: reads of them
+    /// become constants, in user function bodies too, and operations on
+    /// nothing but constants are evaluated now, as are conditionals on one.
+    /// A variable the program assigns itself is not fixed.
+    pub fn specialize(&self, values: &[(&str, f64)]) -> CompiledProgram {
+        let Some(Bytecode::Frame(names)) = self.program.first() else {
+            return self.clone();
+        };
+        let mut assigned = HashSet::new();
+        assigned_slots(&self.program, &mut assigned);
+        let fixed: HashMap<usize, f64> = values
+            .iter()
+            .filter_map(|&(name, value)| Some((names.iter().position(|n| n == name)?, value)))
+            .filter(|(slot, _)| !assigned.contains(slot))
+            .collect();
+        let consts: HashMap<String, f64> = fixed.iter().map(|(&slot, &value)| (names[slot].clone(), value)).collect();
+        let mut functions = self.functions.clone();
+        for function in functions.values_mut().flat_map(|overloads| overloads.iter_mut()) {
+            // Parameters, and locals of a multi-line body, hide the variables
+            let mut hidden: HashSet<String> = function.params.iter().map(|p| p.name.clone()).collect();
+            crate::analysis::collect_assigned(&function.body, &mut hidden);
+            let mut visible = consts.clone();
+            visible.retain(|name, _| !hidden.contains(name));
+            for default in function.params.iter_mut().filter_map(|p| p.default.as_mut()) {
+                substitute_consts(default, &visible);
+            }
+            substitute_consts(&mut function.body, &visible);
+            function.recompile();
+        }
+        log::debug!(target: "compiler", "specialized {} variables", fixed.len());
+        CompiledProgram { program: specialize_code(&self.program, &fixed), functions }
+    }
+}
+
+// Slots a program, or a body nested in it, binds or assigns
+fn assigned_slots(program: &[Bytecode], slots: &mut HashSet<usize>) {
+    for code in program {
+        match code {
+            Bytecode::StoreVar(slot) | Bytecode::StoreConst(slot) | Bytecode::StoreOutput(slot) => {
+                slots.insert(*slot);
+            }
+            Bytecode::LetIn { slot, body } => {
+                slots.insert(*slot);
+                assigned_slots(body, slots);
+            }
+            Bytecode::Timed(body) => assigned_slots(body, slots),
+            Bytecode::SumLoop { from, to, eps, param, body } => {
+                slots.insert(*param);
+                for nested in [Some(from), Some(to), eps.as_ref(), Some(body)].into_iter().flatten() {
+                    assigned_slots(nested, slots);
+                }
+            }
+            Bytecode::ProductLoop { from, to, param, body }
+            | Bytecode::Solve { from, to, param, body }
+            | Bytecode::Extremum { from, to, param, body, .. } => {
+                slots.insert(*param);
+                for nested in [from, to, body] {
+                    assigned_slots(nested, slots);
+                }
+            }
+            Bytecode::Derivatives { params, body, .. } => {
+                slots.extend(params);
+                assigned_slots(body, slots);
+            }
+            Bytecode::SolveN { params, equations } => {
+                slots.extend(params);
+                for nested in equations {
+                    assigned_slots(nested, slots);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// `program` and its nested bodies with the `fixed` slots read as constants,
+// then folded
+fn specialize_code(program: &Program, fixed: &HashMap<usize, f64>) -> Program {
+    let nested = |body: &Program| Box::new(specialize_code(body, fixed));
+    let code: Program = program
+        .iter()
+        .map(|code| match code {
+            Bytecode::LoadVar(slot) if fixed.contains_key(slot) => Bytecode::PushNumber(fixed[slot]),
+            Bytecode::SumLoop { from, to, eps, param, body } => Bytecode::SumLoop {
+                from: nested(from),
+                to: nested(to),
+                eps: eps.as_deref().map(nested),
+                param: *param,
+                body: nested(body),
+            },
+            Bytecode::ProductLoop { from, to, param, body } => {
+                Bytecode::ProductLoop { from: nested(from), to: nested(to), param: *param, body: nested(body) }
+            }
+            Bytecode::Solve { from, to, param, body } => {
+                Bytecode::Solve { from: nested(from), to: nested(to), param: *param, body: nested(body) }
+            }
+            Bytecode::Extremum { maximize, from, to, param, body } => Bytecode::Extremum {
+                maximize: *maximize,
+                from: nested(from),
+                to: nested(to),
+                param: *param,
+                body: nested(body),
+            },
+            Bytecode::Derivatives { hessian, params, body } => {
+                Bytecode::Derivatives { hessian: *hessian, params: params.clone(), body: nested(body) }
+            }
+            Bytecode::SolveN { params, equations } => Bytecode::SolveN {
+                params: params.clone(),
+                equations: equations.iter().map(|e| *nested(e)).collect(),
+            },
+            Bytecode::LetIn { slot, body } => Bytecode::LetIn { slot: *slot, body: nested(body) },
+            Bytecode::Timed(body) => Bytecode::Timed(nested(body)),
+            other => other.clone(),
+        })
+        .collect();
+    fold_code(&code)
+}
+
+// Evaluates each operation whose operands are all pushed as constants right
+// before it (within `FOLD_FUEL`, or else it is kept), and each conditional
+// jump on a constant, then drops the code a jump always skips, keeping jump
+// offsets right. Nothing is folded where another path joins through a jump,
+// since the stack there may differ.
+fn fold_code(code: &[Bytecode]) -> Program {
+    // How many jumps land on each index of `code`
+    let mut targets: HashMap<usize, usize> = HashMap::new();
+    for (i, code) in code.iter().enumerate() {
+        if let Bytecode::Jump(n) | Bytecode::JumpIfFalse(n) = code {
+            *targets.entry(i + 1 + n).or_default() += 1;
+        }
+    }
+    let unjump = |targets: &mut HashMap<usize, usize>, target: usize| {
+        if let Some(count) = targets.get_mut(&target) {
+            *count -= 1;
+        }
+    };
+    let is_target = |targets: &HashMap<usize, usize>, i: usize| targets.get(&i).is_some_and(|&count| count > 0);
+    let mut out: Program = Vec::with_capacity(code.len());
+    // The index in `code` each instruction of `out` came from
+    let mut origin: Vec<usize> = Vec::with_capacity(code.len());
+    // Where each instruction of `code` ended up in `out`
+    let mut new_index = vec![0; code.len() + 1];
+    // Jumps in `out`, with the index in `code` of their target
+    let mut jumps: Vec<(usize, usize)> = Vec::new();
+    // The last `n` values pushed as constants before `code[i]`, if no jump lands between them
+    let constants = |targets: &HashMap<usize, usize>, out: &Program, origin: &[usize], n: usize, i: usize| {
+        let start = out.len().checked_sub(n)?;
+        if is_target(targets, i) || origin[start..].iter().skip(1).any(|&o| is_target(targets, o)) {
+            return None;
+        }
+        out[start..].iter().map(|code| if let Bytecode::PushNumber(x) = code { Some(*x) } else { None }).collect::<Option<Vec<f64>>>()
+    };
+    let mut i = 0;
+    while let Some(instr) = code.get(i) {
+        new_index[i] = out.len();
+        // Copying or exchanging constants gives constants
+        let shuffled = match instr {
+            Bytecode::Dup => constants(&targets, &out, &origin, 1, i).map(|c| (1, [c[0], c[0]])),
+            Bytecode::Swap => constants(&targets, &out, &origin, 2, i).map(|c| (2, [c[1], c[0]])),
+            _ => None,
+        };
+        if let Some((n, values)) = shuffled {
+            out.truncate(out.len() - n);
+            out.extend(values.into_iter().map(Bytecode::PushNumber));
+            origin.resize(out.len(), i);
+            i += 1;
+            continue;
+        }
+        if let Some(n) = pure_arity(instr)
+            && let Some(args) = constants(&targets, &out, &origin, n, i)
+        {
+            let mut program: Program = args.iter().map(|&x| Bytecode::PushNumber(x)).collect();
+            program.push(instr.clone());
+            if let Ok(value) = folding_state().run(&program, &UserFunctions::new()) {
+                let start = out.len() - n;
+                new_index[i] = start;
+                out.truncate(start);
+                out.push(Bytecode::PushNumber(value));
+                origin.truncate(start + 1);
+                i += 1;
+                continue;
+            }
+        }
+        let skip = match instr {
+            Bytecode::JumpIfFalse(n) => match constants(&targets, &out, &origin, 1, i) {
+                Some(cond) => {
+                    out.pop();
+                    origin.pop();
+                    new_index[i] = out.len();
+                    if is_true(cond[0]) {
+                        unjump(&mut targets, i + 1 + n);
+                        i += 1;
+                        continue;
+                    }
+                    Some(*n)
+                }
+                None => {
+                    jumps.push((out.len(), i + 1 + n));
+                    None
+                }
+            },
+            Bytecode::Jump(n) => Some(*n),
+            _ => None,
+        };
+        if let Some(n) = skip {
+            // Code only reached by this jump's skipping it is dead
+            if !(i + 1..=i + n).any(|j| is_target(&targets, j)) {
+                for j in i..=i + n {
+                    new_index[j] = out.len();
+                    if let Bytecode::Jump(m) | Bytecode::JumpIfFalse(m) = &code[j] {
+                        unjump(&mut targets, j + 1 + m);
+                    }
+                }
+                i += n + 1;
+                continue;
+            }
+            jumps.push((out.len(), i + 1 + n));
+            out.push(Bytecode::Jump(n));
+            origin.push(i);
+            i += 1;
+            continue;
+        }
+        out.push(instr.clone());
+        origin.push(i);
+        i += 1;
+    }
+    new_index[code.len()] = out.len();
+    for (at, target) in jumps {
+        let n = new_index[target] - at - 1;
+        match &mut out[at] {
+            Bytecode::Jump(offset) | Bytecode::JumpIfFalse(offset) => *offset = n,
+            _ => unreachable!("recorded jumps are jumps"),
+        }
+    }
+    out
+}
+
+// How many numbers an instruction that depends on nothing but them pops
+fn pure_arity(code: &Bytecode) -> Option<usize> {
+    use Bytecode::*;
+    match code {
+        Sin | Cos | Tan | Cot | Sec | Csc | Sinh | Cosh | Tanh | Asinh | Acosh | Atanh | Exp | Log | Log10 | Log2
+        | Sqrt | Abs | Asin | Acos | Atan | Acot | Asec | Acsc | Fact | Floor | Expm1 | Ln1p | Exp2 | Round
+        | RoundEven | Erf | Erfc | Zeta | LambertW | IsPrime | NextPrime | PrimePi | Fib | DFact | SubFact | Deg
+        | Rad | Grad | Neg | PowI(_) => Some(1),
+        Add | Sub | Mul | Div | Pow | LogBase | Less | LessEq | Greater | GreaterEq | Equal | NotEqual | Atan2
+        | Root | RoundTo | RoundEvenTo | BesselJ | BesselY | Beta | Mod | Choose => Some(2),
+        _ => None,
+    }
+}
+
+/// Longest `CompiledProgram::precompute` lets a program run before leaving it
+/// to be evaluated normally.
+pub const PRECOMPUTE_BUDGET: Duration = Duration::from_millis(500);
+
+/// Most steps evaluating one constant expression at compile time may take;
+/// past it the expression is left to be evaluated when the program runs.
+const FOLD_FUEL: u64 = 10_000;
+
+/// Whether `program` is just a result stored by `CompiledProgram::precompute`,
+/// so running it needs no user functions.
+pub fn is_precomputed(program: &Program) -> bool {
+    matches!(program.as_slice(), [Bytecode::Precomputed(_)])
+}
+
+// Whether a program, including nested loop and let bodies, never draws random
+// numbers, reads script arguments, the environment or files, or times or prints anything (which is
+// only worth doing when the program runs)
+fn is_deterministic(program: &Program) -> bool {
+    program.iter().all(|code| match code {
+        Bytecode::Rand | Bytecode::RandInt | Bytecode::RandState | Bytecode::Arg | Bytecode::Argc | Bytecode::Env(_) | Bytecode::Timed(_) => false,
+        Bytecode::LoadCsv(_) | Bytecode::SaveCsv(..) | Bytecode::Print(..) => false,
+        Bytecode::SumLoop { from, to, eps, body, .. } => {
+            is_deterministic(from)
+                && is_deterministic(to)
+                && eps.as_deref().is_none_or(is_deterministic)
+                && is_deterministic(body)
+        }
+        Bytecode::ProductLoop { from, to, body, .. } | Bytecode::Solve { from, to, body, .. } | Bytecode::Extremum { from, to, body, .. } => {
+            is_deterministic(from) && is_deterministic(to) && is_deterministic(body)
+        }
+        Bytecode::SolveN { equations, .. } => equations.iter().all(is_deterministic),
+        Bytecode::Derivatives { body, .. } => is_deterministic(body),
+        Bytecode::LetIn { body, .. } => is_deterministic(body),
+        _ => true,
+    })
+}
+
+// Compile-time guarantee that compiled programs can be shared across threads.
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Program>();
+    assert_send_sync::<UserFunctions>();
+    assert_send_sync::<CompiledProgram>();
+};
+
+/// Replaces reads of `const` names with their values wherever the defining
+/// expression is pure (numbers, operators, deterministic builtins, and earlier
+/// constants) and evaluates within `FOLD_FUEL` steps; a longer one is left
+/// to the run. The definitions themselves are kept so the VM still binds the
+/// name and rejects reassignment.
+pub fn fold_constants(statements: &mut Statements) {
+    let mut consts: HashMap<String, f64> = HashMap::new();
+    for (_, stmt) in statements.iter_mut() {
+        substitute_consts(stmt, &consts);
+        if let Expr::Const { name, expr } = stmt
+            && let Some(value) = pure_value(expr)
+        {
+            consts.insert(name.clone(), value);
+        }
+    }
+}
 
-/// Compile an AST expression into bytecode instructions.
+/// Evaluates an expression at compile time if it cannot depend on runtime state.
+pub(crate) fn pure_value(expr: &Expr) -> Option<f64> {
+    fn is_pure(expr: &Expr) -> bool {
+        match expr {
+            Expr::Number(_) => true,
+            Expr::BinaryOp { left, right, .. } => is_pure(left) && is_pure(right),
+            Expr::UnaryOp { operand, .. } => is_pure(operand),
+            Expr::Function { func, arg } => {
+                !matches!(
+                    func,
+                    SpecialFunction::Rand
+                        | SpecialFunction::RandInt
+                        | SpecialFunction::Seed
+                        | SpecialFunction::RandState
+                        | SpecialFunction::Arg
+                        | SpecialFunction::Argc
+                        | SpecialFunction::Time
+                        | SpecialFunction::Env
+                        | SpecialFunction::LoadCsv
+                        | SpecialFunction::SaveCsv
+                        | SpecialFunction::SaveCsvXy
+                        | SpecialFunction::Print
+                ) && is_pure(arg)
+            }
+            Expr::Sequence(exprs) => exprs.iter().all(is_pure),
+            Expr::Conditional { cond, then, otherwise } => is_pure(cond) && is_pure(then) && is_pure(otherwise),
+            _ => false,
+        }
+    }
+    if !is_pure(expr) {
+        return None;
+    }
+    let mut program = Vec::new();
+    compile(expr, &mut program);
+    folding_state().run(&program, &UserFunctions::new()).ok()
+}
+
+// A state for evaluating at compile time. It is strict, so that what fails
+// in strict mode, as `csc(0)`, is left to fail (or not) when the program runs.
+fn compile_time_state() -> VmState {
+    let mut state = VmState::new();
+    state.set_strict(true);
+    state
+}
+
+// A compile-time state for folding one expression, with fuel so that
+// compiling never takes longer than a short run would
+fn folding_state() -> VmState {
+    let mut state = compile_time_state();
+    state.set_fuel(FOLD_FUEL);
+    state
+}
+
+// Replace identifiers bound in `consts`, respecting sum/product parameters
+fn substitute_consts(expr: &mut Expr, consts: &HashMap<String, f64>) {
+    if consts.is_empty() {
+        return;
+    }
+    match expr {
+        Expr::Ident(name) => {
+            if let Some(value) = consts.get(name) {
+                *expr = Expr::Number(*value);
+            }
+        }
+        Expr::Assign { expr, .. } | Expr::Const { expr, .. } | Expr::Output { expr, .. } => substitute_consts(expr, consts),
+        Expr::BinaryOp { left, right, .. } => {
+            substitute_consts(left, consts);
+            substitute_consts(right, consts);
+        }
+        Expr::Function { arg, .. } | Expr::UnaryOp { operand: arg, .. } => substitute_consts(arg, consts),
+        Expr::FunctionCall { args: exprs, .. } | Expr::Apply { args: exprs, .. } | Expr::Bind { args: exprs, .. } | Expr::Sequence(exprs) | Expr::List(exprs) => exprs.iter_mut().for_each(|e| substitute_consts(e, consts)),
+        Expr::Sum { from, to, eps, param, cond, body } => {
+            if let Some(eps) = eps {
+                substitute_consts(eps, consts);
+            }
+            substitute_consts(from, consts);
+            substitute_consts(to, consts);
+            if let Some(cond) = cond {
+                substitute_shadowed(cond, param, consts);
+            }
+            substitute_shadowed(body, param, consts);
+        }
+        Expr::Product { from, to, param, cond, body } => {
+            substitute_consts(from, consts);
+            substitute_consts(to, consts);
+            if let Some(cond) = cond {
+                substitute_shadowed(cond, param, consts);
+            }
+            substitute_shadowed(body, param, consts);
+        }
+        Expr::Let { name, value, body } => {
+            substitute_consts(value, consts);
+            substitute_shadowed(body, name, consts);
+        }
+        Expr::Solve { param, range, lhs, rhs } => {
+            if let Some((from, to)) = range {
+                substitute_consts(from, consts);
+                substitute_consts(to, consts);
+            }
+            substitute_shadowed(lhs, param, consts);
+            substitute_shadowed(rhs, param, consts);
+        }
+        Expr::SolveN { equations, params, guess } => {
+            let mut inner = consts.clone();
+            inner.retain(|name, _| !params.contains(name));
+            equations.iter_mut().for_each(|e| substitute_consts(e, &inner));
+            substitute_consts(guess, consts);
+        }
+        Expr::Derivatives { body, params, point, .. } => {
+            let mut inner = consts.clone();
+            inner.retain(|name, _| !params.contains(name));
+            substitute_consts(body, &inner);
+            substitute_consts(point, consts);
+        }
+        Expr::Extremum { body, param, from, to, .. } => {
+            substitute_consts(from, consts);
+            substitute_consts(to, consts);
+            substitute_shadowed(body, param, consts);
+        }
+        Expr::Conditional { cond, then, otherwise } => {
+            substitute_consts(cond, consts);
+            substitute_consts(then, consts);
+            substitute_consts(otherwise, consts);
+        }
+        Expr::Return { cond, value } => {
+            if let Some(cond) = cond {
+                substitute_consts(cond, consts);
+            }
+            substitute_consts(value, consts);
+        }
+        Expr::Assert { cond, .. } => substitute_consts(cond, consts),
+        Expr::AssertEq { left, right, eps, .. } => {
+            substitute_consts(left, consts);
+            substitute_consts(right, consts);
+            substitute_consts(eps, consts);
+        }
+        Expr::Number(_) | Expr::Text(_) | Expr::FunctionRef(_) | Expr::FunctionDef { .. } | Expr::Precision(_) => {}
+    }
+}
+
+// Substitute inside a scope where `name` is rebound and must not be replaced
+fn substitute_shadowed(body: &mut Expr, name: &str, consts: &HashMap<String, f64>) {
+    if consts.contains_key(name) {
+        let mut inner = consts.clone();
+        inner.remove(name);
+        substitute_consts(body, &inner);
+    } else {
+        substitute_consts(body, consts);
+    }
+}
+
+/// Compile an AST expression into bytecode instructions, starting with the
+/// `Frame` that names the variable slots the code refers to.
 pub fn compile(expr: &Expr, program: &mut Program) {
+    let mut slots = SlotTable::default();
+    let mut code = Vec::new();
+    compile_expr(expr, &mut code, &mut slots);
+    log::trace!(target: "compiler", "slots {:?}, code {:?}", slots.names, code);
+    program.push(Bytecode::Frame(slots.names));
+    program.extend(code);
+}
+
+/// Variable names in the order their slots were handed out.
+#[derive(Default)]
+struct SlotTable {
+    names: Vec<String>,
+    index: HashMap<String, usize>,
+}
+
+impl SlotTable {
+    fn slot(&mut self, name: &str) -> usize {
+        if let Some(&slot) = self.index.get(name) {
+            return slot;
+        }
+        self.names.push(name.to_string());
+        self.index.insert(name.to_string(), self.names.len() - 1);
+        self.names.len() - 1
+    }
+}
+
+fn compile_expr(expr: &Expr, program: &mut Program, slots: &mut SlotTable) {
     match expr {
-        Expr::Sum { from, to, param, body } => {
-            // Compile from, to, and body as sub-programs
+        Expr::Sum { from, to, eps, param, cond, body } => {
+            // Compile from, to, eps, and body as sub-programs
             let mut from_prog = Vec::new();
             let mut to_prog = Vec::new();
             let mut body_prog = Vec::new();
-            compile(from, &mut from_prog);
-            compile(to, &mut to_prog);
-            compile(body, &mut body_prog);
+            compile_expr(from, &mut from_prog, slots);
+            compile_expr(to, &mut to_prog, slots);
+            compile_loop_body(cond, body, &mut body_prog, slots);
+            let eps_prog = eps.as_ref().map(|eps| {
+                let mut eps_prog = Vec::new();
+                compile_expr(eps, &mut eps_prog, slots);
+                Box::new(eps_prog)
+            });
             program.push(Bytecode::SumLoop {
                 from: Box::new(from_prog),
                 to: Box::new(to_prog),
-                param: param.clone(),
+                eps: eps_prog,
+                param: slots.slot(param),
                 body: Box::new(body_prog),
             });
         }
-        Expr::Product { from, to, param, body } => {
+        Expr::Solve { param, range, lhs, rhs } => {
+            // Roots of lhs - rhs, searched for over [from, to]
+            let (mut from_prog, mut to_prog, mut body_prog) = (Vec::new(), Vec::new(), Vec::new());
+            match range {
+                Some((from, to)) => {
+                    compile_expr(from, &mut from_prog, slots);
+                    compile_expr(to, &mut to_prog, slots);
+                }
+                None => {
+                    let (from, to) = crate::numeric::DEFAULT_SOLVE_RANGE;
+                    from_prog.push(Bytecode::PushNumber(from));
+                    to_prog.push(Bytecode::PushNumber(to));
+                }
+            }
+            compile_expr(lhs, &mut body_prog, slots);
+            compile_expr(rhs, &mut body_prog, slots);
+            body_prog.push(Bytecode::Sub);
+            program.push(Bytecode::Solve {
+                from: Box::new(from_prog),
+                to: Box::new(to_prog),
+                param: slots.slot(param),
+                body: Box::new(body_prog),
+            });
+        }
+        Expr::SolveN { equations, params, guess } => {
+            match &**guess {
+                Expr::Ident(name) => program.push(Bytecode::LoadList(name.clone())),
+                guess => compile_expr(guess, program, slots),
+            }
+            let equations = equations
+                .iter()
+                .map(|e| {
+                    let mut prog = Vec::new();
+                    compile_expr(e, &mut prog, slots);
+                    prog
+                })
+                .collect();
+            program.push(Bytecode::SolveN { params: params.iter().map(|p| slots.slot(p)).collect(), equations });
+        }
+        Expr::Derivatives { hessian, body, params, point } => {
+            match &**point {
+                Expr::Ident(name) => program.push(Bytecode::LoadList(name.clone())),
+                point => compile_expr(point, program, slots),
+            }
+            let mut body_prog = Vec::new();
+            compile_expr(body, &mut body_prog, slots);
+            let params = params.iter().map(|p| slots.slot(p)).collect();
+            program.push(Bytecode::Derivatives { hessian: *hessian, params, body: Box::new(body_prog) });
+        }
+        Expr::Extremum { maximize, body, param, from, to } => {
+            let (mut from_prog, mut to_prog, mut body_prog) = (Vec::new(), Vec::new(), Vec::new());
+            compile_expr(from, &mut from_prog, slots);
+            compile_expr(to, &mut to_prog, slots);
+            compile_expr(body, &mut body_prog, slots);
+            program.push(Bytecode::Extremum {
+                maximize: *maximize,
+                from: Box::new(from_prog),
+                to: Box::new(to_prog),
+                param: slots.slot(param),
+                body: Box::new(body_prog),
+            });
+        }
+        Expr::Product { from, to, param, cond, body } => {
             let mut from_prog = Vec::new();
             let mut to_prog = Vec::new();
             let mut body_prog = Vec::new();
-            compile(from, &mut from_prog);
-            compile(to, &mut to_prog);
-            compile(body, &mut body_prog);
+            compile_expr(from, &mut from_prog, slots);
+            compile_expr(to, &mut to_prog, slots);
+            compile_loop_body(cond, body, &mut body_prog, slots);
             program.push(Bytecode::ProductLoop {
                 from: Box::new(from_prog),
                 to: Box::new(to_prog),
-                param: param.clone(),
+                param: slots.slot(param),
                 body: Box::new(body_prog),
             });
         }
@@ -38,107 +720,378 @@ pub fn compile(expr: &Expr, program: &mut Program) {
                 program.push(Bytecode::PushNumber(*n));
             }
             Expr::Ident(name) => {
-                program.push(Bytecode::LoadVar(name.clone()));
+                program.push(Bytecode::LoadVar(slots.slot(name)));
+            }
+            Expr::Text(_) => unreachable!("a string is only ever a builtin argument compiled into its opcode"),
+            Expr::Assign { name, expr } if expr.is_list() => {
+                compile_expr(expr, program, slots);
+                program.push(Bytecode::StoreList(name.clone()));
+            }
+            Expr::Assign { name, expr } if expr.is_function() => {
+                compile_expr(expr, program, slots);
+                program.push(Bytecode::StoreFunction(name.clone()));
             }
             Expr::Assign { name, expr } => {
-                compile(expr, program);
-                program.push(Bytecode::StoreVar(name.clone()));
+                compile_expr(expr, program, slots);
+                program.push(Bytecode::StoreVar(slots.slot(name)));
+            }
+            Expr::List(items) => {
+                for item in items {
+                    compile_expr(item, program, slots);
+                }
+                program.push(Bytecode::MakeList(items.len()));
+            }
+            Expr::Const { name, expr } => {
+                compile_expr(expr, program, slots);
+                program.push(Bytecode::StoreConst(slots.slot(name)));
+            }
+            Expr::Output { name, expr, unit } => {
+                compile_expr(expr, program, slots);
+                program.push(Bytecode::StoreOutput(slots.slot(name)));
+                if let Some(unit) = unit {
+                    program.push(Bytecode::Unit(slots.slot(name), unit.clone()));
+                }
+            }
+            Expr::UnaryOp { op: UnaryOperator::Neg, operand } => {
+                compile_expr(operand, program, slots);
+                program.push(Bytecode::Neg);
             }
             Expr::BinaryOp { left, op, right } => {
-                compile(left, program);
-                compile(right, program);
+                let start = program.len();
+                compile_expr(left, program, slots);
+                if *op == BinaryOperator::Pow
+                    && let Some(code) = constant_power(right)
+                {
+                    program.push(code);
+                    return;
+                }
+                let mut right_code = Vec::new();
+                compile_expr(right, &mut right_code, slots);
+                let commutative = matches!(op, BinaryOperator::Plus | BinaryOperator::Star | BinaryOperator::Equal | BinaryOperator::NotEqual);
+                share_loaded_operand(program, start, right_code, commutative);
                 match op {
                     BinaryOperator::Plus => program.push(Bytecode::Add),
                     BinaryOperator::Minus => program.push(Bytecode::Sub),
                     BinaryOperator::Star => program.push(Bytecode::Mul),
                     BinaryOperator::Slash => program.push(Bytecode::Div),
                     BinaryOperator::Pow => program.push(Bytecode::Pow),
+                    BinaryOperator::Less => program.push(Bytecode::Less),
+                    BinaryOperator::LessEq => program.push(Bytecode::LessEq),
+                    BinaryOperator::Greater => program.push(Bytecode::Greater),
+                    BinaryOperator::GreaterEq => program.push(Bytecode::GreaterEq),
+                    BinaryOperator::Equal => program.push(Bytecode::Equal),
+                    BinaryOperator::NotEqual => program.push(Bytecode::NotEqual),
                 }
             }
+            Expr::Conditional { cond, then, otherwise } => {
+                // cond; JumpIfFalse over `then`; then; Jump over `otherwise`; otherwise
+                let mut then_prog = Vec::new();
+                let mut else_prog = Vec::new();
+                compile_expr(then, &mut then_prog, slots);
+                compile_expr(otherwise, &mut else_prog, slots);
+                compile_expr(cond, program, slots);
+                program.push(Bytecode::JumpIfFalse(then_prog.len() + 1));
+                program.extend(then_prog);
+                program.push(Bytecode::Jump(else_prog.len()));
+                program.extend(else_prog);
+            }
+            Expr::Let { name, value, body } => {
+                let mut body_prog = Vec::new();
+                compile_expr(body, &mut body_prog, slots);
+                compile_expr(value, program, slots);
+                program.push(Bytecode::LetIn { slot: slots.slot(name), body: Box::new(body_prog) });
+            }
+            Expr::Function { func: SpecialFunction::Time, arg } => {
+                let mut body_prog = Vec::new();
+                compile_expr(arg, &mut body_prog, slots);
+                program.push(Bytecode::Timed(Box::new(body_prog)));
+            }
+            Expr::Function { func, arg } if crate::builtins::takes_text(*func) => {
+                // The name or path is part of the opcode, not a stack value
+                let Expr::Sequence(args) = &**arg else { unreachable!("builtin arguments are a sequence") };
+                let Some(Expr::Text(text)) = args.first() else {
+                    unreachable!("the parser checked the first argument is a string")
+                };
+                let argc = compile_args(*func, arg, program, slots);
+                program.push(match func {
+                    SpecialFunction::Env => Bytecode::Env(text.clone()),
+                    SpecialFunction::LoadCsv => Bytecode::LoadCsv(text.clone()),
+                    SpecialFunction::SaveCsv | SpecialFunction::SaveCsvXy => Bytecode::SaveCsv(text.clone(), argc),
+                    SpecialFunction::Print => Bytecode::Print(text.clone(), argc),
+                    _ => unreachable!("no other builtin takes a string"),
+                });
+            }
             Expr::Function { func, arg } => {
-                use crate::lexer::SpecialFunction;
-                match func {
-                    SpecialFunction::Rand => {
-                        program.push(Bytecode::Rand);
-                    }
-                    SpecialFunction::RandInt => {
-                        // randint(a, b): arg is a Sequence of two expressions
-                        if let Expr::Sequence(seq) = &**arg {
-                            if seq.len() == 2 {
-                                compile(&seq[0], program);
-                                compile(&seq[1], program);
-                                program.push(Bytecode::RandInt);
-                            } else {
-                                panic!("randint expects 2 arguments");
-                            }
-                        } else {
-                            panic!("randint expects 2 arguments");
-                        }
-                    }
-                    SpecialFunction::LogBase => {
-                        // log(a, b) is handled as a binary function, so arg is a Sequence
-                        if let Expr::Sequence(seq) = &**arg {
-                            if seq.len() == 2 {
-                                compile(&seq[0], program);
-                                compile(&seq[1], program);
-                                program.push(Bytecode::LogBase);
-                            } else {
-                                panic!("log base function expects 2 arguments");
-                            }
-                        } else {
-                            panic!("log base function expects 2 arguments");
-                        }
-                    }
-                    SpecialFunction::Fact => {
-                        compile(arg, program);
-                        program.push(Bytecode::Fact);
-                    }
-                    SpecialFunction::Sin => { compile(arg, program); program.push(Bytecode::Sin); }
-                    SpecialFunction::Cos => { compile(arg, program); program.push(Bytecode::Cos); }
-                    SpecialFunction::Tan => { compile(arg, program); program.push(Bytecode::Tan); }
-                    SpecialFunction::Cot => { compile(arg, program); program.push(Bytecode::Cot); }
-                    SpecialFunction::Sec => { compile(arg, program); program.push(Bytecode::Sec); }
-                    SpecialFunction::Csc => { compile(arg, program); program.push(Bytecode::Csc); }
-                    SpecialFunction::Sinh => { compile(arg, program); program.push(Bytecode::Sinh); }
-                    SpecialFunction::Cosh => { compile(arg, program); program.push(Bytecode::Cosh); }
-                    SpecialFunction::Tanh => { compile(arg, program); program.push(Bytecode::Tanh); }
-                    SpecialFunction::Asinh => { compile(arg, program); program.push(Bytecode::Asinh); }
-                    SpecialFunction::Acosh => { compile(arg, program); program.push(Bytecode::Acosh); }
-                    SpecialFunction::Atanh => { compile(arg, program); program.push(Bytecode::Atanh); }
-                    SpecialFunction::Exp => { compile(arg, program); program.push(Bytecode::Exp); }
-                    SpecialFunction::Log => { compile(arg, program); program.push(Bytecode::Log); }
-                    SpecialFunction::Log10 => { compile(arg, program); program.push(Bytecode::Log10); }
-                    SpecialFunction::Log2 => { compile(arg, program); program.push(Bytecode::Log2); }
-                    SpecialFunction::Sqrt => { compile(arg, program); program.push(Bytecode::Sqrt); }
-                    SpecialFunction::Abs => { compile(arg, program); program.push(Bytecode::Abs); }
-                    SpecialFunction::Asin => { compile(arg, program); program.push(Bytecode::Asin); }
-                    SpecialFunction::Acos => { compile(arg, program); program.push(Bytecode::Acos); }
-                    SpecialFunction::Atan => { compile(arg, program); program.push(Bytecode::Atan); }
-                    SpecialFunction::Acot => { compile(arg, program); program.push(Bytecode::Acot); }
-                    SpecialFunction::Asec => { compile(arg, program); program.push(Bytecode::Asec); }
-                    SpecialFunction::Acsc => { compile(arg, program); program.push(Bytecode::Acsc); }
-                    SpecialFunction::Pow => { compile(arg, program); program.push(Bytecode::Pow); }
-                    SpecialFunction::Floor => { compile(arg, program); program.push(Bytecode::Floor); }
-                }
+                // Arity was checked by the parser; every argument stays on the stack
+                let argc = compile_args(*func, arg, program, slots);
+                program.push(function_opcode(*func, argc));
             }
             Expr::FunctionDef { .. } => {
                 // Do not emit code for function definitions here; handled at runtime
             }
-            Expr::FunctionCall { name, arg } => {
-                compile(arg, program);
-                program.push(Bytecode::CallUserFunction(name.clone()));
+            Expr::FunctionCall { name, args } => {
+                for arg in args {
+                    compile_expr(arg, program, slots);
+                }
+                // The VM picks the definition for this many arguments, which
+                // fills in defaults for any parameters left out
+                program.push(match args.len() {
+                    1 => Bytecode::CallUserFunction(name.clone()),
+                    argc => Bytecode::CallUserFunctionN(name.clone(), argc),
+                });
+            }
+            Expr::FunctionRef(name) => {
+                program.push(Bytecode::PushFunction(name.clone()));
+            }
+            Expr::Apply { callee, args } => {
+                for arg in args {
+                    compile_expr(arg, program, slots);
+                }
+                compile_expr(callee, program, slots);
+                program.push(Bytecode::CallIndirect(args.len()));
+            }
+            Expr::Bind { callee, args } => {
+                for arg in args {
+                    compile_expr(arg, program, slots);
+                }
+                compile_expr(callee, program, slots);
+                program.push(Bytecode::Bind(args.len()));
+            }
+            Expr::Return { cond, value } => {
+                // [cond; JumpIfFalse past the return;] value; Return
+                let mut value_prog = Vec::new();
+                compile_expr(value, &mut value_prog, slots);
+                if let Some(cond) = cond {
+                    compile_expr(cond, program, slots);
+                    program.push(Bytecode::JumpIfFalse(value_prog.len() + 1));
+                }
+                program.extend(value_prog);
+                program.push(Bytecode::Return);
+            }
+            Expr::Assert { cond, line } => {
+                compile_expr(cond, program, slots);
+                program.push(Bytecode::Assert(*line));
+            }
+            Expr::Precision(digits) => program.push(Bytecode::Precision(*digits)),
+            Expr::AssertEq { left, right, eps, line } => {
+                compile_expr(left, program, slots);
+                compile_expr(right, program, slots);
+                compile_expr(eps, program, slots);
+                program.push(Bytecode::AssertEq(*line));
             }
             Expr::Sequence(exprs) => {
                 if exprs.is_empty() { return; }
                 for (i, e) in exprs.iter().enumerate() {
-                    compile(e, program);
-                    // Only emit dummy pop for non-assignment expressions
-                    if i + 1 != exprs.len() {
-                        if !matches!(e, Expr::Assign { .. }) {
-                            program.push(Bytecode::StoreVar("_tmp".to_string()));
+                    match e {
+                        // A list variable as the result is left on the list stack
+                        Expr::Ident(name) if i + 1 == exprs.len() && holds_list(&exprs[..i], name) => {
+                            program.push(Bytecode::LoadList(name.clone()));
                         }
+                        _ => compile_expr(e, program, slots),
+                    }
+                    // Only the last statement's value is the result; assignments leave none
+                    if i + 1 != exprs.len() && !e.is_statement() {
+                        program.push(Bytecode::Pop);
                     }
                 }
             }
     }
 }
+
+// Whether the last of `statements` to assign `name` gives it a list
+fn holds_list(statements: &[Expr], name: &str) -> bool {
+    statements.iter().rev().find_map(|e| match e {
+        Expr::Assign { name: assigned, expr } if assigned == name => Some(expr.is_list()),
+        _ => None,
+    }) == Some(true)
+}
+
+// A sum or product term, led by its `while:` condition and a `LoopWhile`
+// that ends the loop when the condition is false
+fn compile_loop_body(cond: &Option<Box<Expr>>, body: &Expr, program: &mut Program, slots: &mut SlotTable) {
+    if let Some(cond) = cond {
+        compile_expr(cond, program, slots);
+        program.push(Bytecode::LoopWhile);
+    }
+    compile_expr(body, program, slots);
+}
+
+/// Largest constant integer exponent compiled to `PowI`; repeated squaring
+/// loses about one bit per step, so big exponents stay with `powf`.
+const MAX_POWI_EXPONENT: f64 = 16.0;
+
+// Appends `right_code` to the operand code from `start` on, copying a variable
+// both operands read first with `Dup` rather than loading it again: `x*x` and
+// `x + x/2` load `x` once, and so does `x^2 - x`, whose operands then come out
+// in the wrong order and are exchanged with `Swap` unless the operator is
+// `commutative`. The left operand must not assign the variable before the
+// right one would have read it.
+fn share_loaded_operand(program: &mut Program, start: usize, right_code: Program, commutative: bool) {
+    let loads = |code: Option<&Bytecode>| if let Some(Bytecode::LoadVar(slot)) = code { Some(*slot) } else { None };
+    let left_slot = loads(program.get(start));
+    if program.len() == start + 1 && left_slot.is_some() && loads(right_code.first()) == left_slot {
+        program.push(Bytecode::Dup);
+        program.extend(right_code.into_iter().skip(1));
+    } else if let [Bytecode::LoadVar(slot)] = right_code.as_slice()
+        && left_slot == Some(*slot)
+    {
+        let mut assigned = HashSet::new();
+        assigned_slots(&program[start..], &mut assigned);
+        if assigned.contains(slot) {
+            program.extend(right_code);
+            return;
+        }
+        program.insert(start + 1, Bytecode::Dup);
+        if !commutative {
+            program.push(Bytecode::Swap);
+        }
+    } else {
+        program.extend(right_code);
+    }
+}
+
+// Cheaper opcode for `x^c` with a constant exponent: small integers become
+// multiplications (`PowI`) and `x^0.5` becomes `Sqrt` (which, unlike `powf`,
+// gives NaN rather than inf for -inf)
+fn constant_power(exponent: &Expr) -> Option<Bytecode> {
+    let c = pure_value(exponent)?;
+    if c == 0.5 {
+        Some(Bytecode::Sqrt)
+    } else if c.fract() == 0.0 && c.abs() <= MAX_POWI_EXPONENT {
+        Some(Bytecode::PowI(c as i32))
+    } else {
+        None
+    }
+}
+
+// Push each argument of a builtin call in order (no pops between them).
+// List arguments go to the list stack; a bare name there is a list variable.
+// Returns the number of arguments.
+fn compile_args(func: SpecialFunction, arg: &Expr, program: &mut Program, slots: &mut SlotTable) -> usize {
+    let params = crate::builtins::entry(func).params;
+    let args = match arg {
+        Expr::Sequence(args) => args.as_slice(),
+        single => std::slice::from_ref(single),
+    };
+    let mut argc = 0;
+    for (i, a) in args.iter().enumerate() {
+        match (params.get(i), a) {
+            // A string is no stack value; the caller compiles it into the opcode
+            (_, Expr::Text(_)) => continue,
+            (Some((_, ArgKind::List)), Expr::Ident(name)) => program.push(Bytecode::LoadList(name.clone())),
+            _ => compile_expr(a, program, slots),
+        }
+        argc += 1;
+    }
+    argc
+}
+
+/// The opcode implementing a builtin, applied to its `argc` arguments on the stack.
+fn function_opcode(func: SpecialFunction, argc: usize) -> Bytecode {
+    match func {
+        SpecialFunction::Sin => Bytecode::Sin,
+        SpecialFunction::Cos => Bytecode::Cos,
+        SpecialFunction::Tan => Bytecode::Tan,
+        SpecialFunction::Cot => Bytecode::Cot,
+        SpecialFunction::Sec => Bytecode::Sec,
+        SpecialFunction::Csc => Bytecode::Csc,
+        SpecialFunction::Sinh => Bytecode::Sinh,
+        SpecialFunction::Cosh => Bytecode::Cosh,
+        SpecialFunction::Tanh => Bytecode::Tanh,
+        SpecialFunction::Asinh => Bytecode::Asinh,
+        SpecialFunction::Acosh => Bytecode::Acosh,
+        SpecialFunction::Atanh => Bytecode::Atanh,
+        SpecialFunction::Exp => Bytecode::Exp,
+        SpecialFunction::Log => Bytecode::Log,
+        SpecialFunction::Log10 => Bytecode::Log10,
+        SpecialFunction::Log2 => Bytecode::Log2,
+        SpecialFunction::Sqrt => Bytecode::Sqrt,
+        SpecialFunction::Abs => Bytecode::Abs,
+        SpecialFunction::Asin => Bytecode::Asin,
+        SpecialFunction::Acos => Bytecode::Acos,
+        SpecialFunction::Atan => Bytecode::Atan,
+        SpecialFunction::Acot => Bytecode::Acot,
+        SpecialFunction::Asec => Bytecode::Asec,
+        SpecialFunction::Acsc => Bytecode::Acsc,
+        SpecialFunction::Pow => Bytecode::Pow,
+        SpecialFunction::Fact => Bytecode::Fact,
+        SpecialFunction::LogBase => Bytecode::LogBase,
+        SpecialFunction::Floor => Bytecode::Floor,
+        SpecialFunction::Rand => Bytecode::Rand,
+        SpecialFunction::RandInt => Bytecode::RandInt,
+        SpecialFunction::Atan2 => Bytecode::Atan2,
+        SpecialFunction::Root => Bytecode::Root,
+        SpecialFunction::Expm1 => Bytecode::Expm1,
+        SpecialFunction::Ln1p => Bytecode::Ln1p,
+        SpecialFunction::Exp2 => Bytecode::Exp2,
+        SpecialFunction::Round => Bytecode::Round,
+        SpecialFunction::RoundTo => Bytecode::RoundTo,
+        SpecialFunction::RoundEven => Bytecode::RoundEven,
+        SpecialFunction::RoundEvenTo => Bytecode::RoundEvenTo,
+        SpecialFunction::Quantile => Bytecode::Quantile,
+        SpecialFunction::QuantileType => Bytecode::QuantileType,
+        SpecialFunction::Percentile => Bytecode::Percentile,
+        SpecialFunction::Erf => Bytecode::Erf,
+        SpecialFunction::Erfc => Bytecode::Erfc,
+        SpecialFunction::BesselJ => Bytecode::BesselJ,
+        SpecialFunction::BesselY => Bytecode::BesselY,
+        SpecialFunction::Zeta => Bytecode::Zeta,
+        SpecialFunction::LambertW => Bytecode::LambertW,
+        SpecialFunction::Beta => Bytecode::Beta,
+        SpecialFunction::BetaInc => Bytecode::BetaInc,
+        SpecialFunction::GammaInc => Bytecode::GammaInc,
+        SpecialFunction::NormCdf => Bytecode::NormCdf,
+        SpecialFunction::NormPdf => Bytecode::NormPdf,
+        SpecialFunction::NormInv => Bytecode::NormInv,
+        SpecialFunction::NormCdfScaled => Bytecode::NormCdfScaled,
+        SpecialFunction::NormPdfScaled => Bytecode::NormPdfScaled,
+        SpecialFunction::NormInvScaled => Bytecode::NormInvScaled,
+        SpecialFunction::TCdf => Bytecode::TCdf,
+        SpecialFunction::TInv => Bytecode::TInv,
+        SpecialFunction::Chi2Cdf => Bytecode::Chi2Cdf,
+        SpecialFunction::Chi2Inv => Bytecode::Chi2Inv,
+        SpecialFunction::IsPrime => Bytecode::IsPrime,
+        SpecialFunction::NextPrime => Bytecode::NextPrime,
+        SpecialFunction::PrimePi => Bytecode::PrimePi,
+        SpecialFunction::Factorint => Bytecode::Factorint,
+        SpecialFunction::Len => Bytecode::Len,
+        SpecialFunction::At => Bytecode::At,
+        SpecialFunction::Mod => Bytecode::Mod,
+        SpecialFunction::PowMod => Bytecode::PowMod,
+        SpecialFunction::InvMod => Bytecode::InvMod,
+        SpecialFunction::Fib => Bytecode::Fib,
+        SpecialFunction::Choose => Bytecode::Choose,
+        SpecialFunction::DFact => Bytecode::DFact,
+        SpecialFunction::SubFact => Bytecode::SubFact,
+        SpecialFunction::Deg => Bytecode::Deg,
+        SpecialFunction::Rad => Bytecode::Rad,
+        SpecialFunction::Grad => Bytecode::Grad,
+        SpecialFunction::Recurrence => Bytecode::Recurrence,
+        SpecialFunction::Poly => Bytecode::MakeList(argc),
+        SpecialFunction::PolyAdd => Bytecode::PolyAdd,
+        SpecialFunction::PolySub => Bytecode::PolySub,
+        SpecialFunction::PolyMul => Bytecode::PolyMul,
+        SpecialFunction::PolyDer => Bytecode::PolyDer,
+        SpecialFunction::PolyVal => Bytecode::PolyVal,
+        SpecialFunction::Roots => Bytecode::Roots,
+        SpecialFunction::QuadRoots => Bytecode::QuadRoots,
+        SpecialFunction::CubicRoots => Bytecode::CubicRoots,
+        SpecialFunction::Fit => Bytecode::Fit,
+        SpecialFunction::FitExp => Bytecode::FitExp,
+        SpecialFunction::FitLog => Bytecode::FitLog,
+        SpecialFunction::Arg => Bytecode::Arg,
+        SpecialFunction::Argc => Bytecode::Argc,
+        SpecialFunction::Time => unreachable!("`time` compiles to `Timed` around its argument"),
+        SpecialFunction::Seed => Bytecode::Seed,
+        SpecialFunction::RandState => Bytecode::RandState,
+        SpecialFunction::Env
+        | SpecialFunction::LoadCsv
+        | SpecialFunction::SaveCsv
+        | SpecialFunction::SaveCsvXy
+        | SpecialFunction::Format
+        | SpecialFunction::Print => {
+            unreachable!("a builtin taking a string compiles it into its opcode")
+        }
+        // Add more as needed
+    }
+}
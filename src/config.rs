@@ -0,0 +1,139 @@
+// Defaults for command-line flags, read from `.fmathrc` or `fmath.toml`
+use std::path::{Path, PathBuf};
+
+use crate::analysis::{Level, LintLevels};
+
+/// Settings a config file may give; command-line flags override them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Config {
+    /// Digits after the decimal point when printing results.
+    pub precision: Option<usize>,
+    /// Seed for `rand` and the distribution samplers.
+    pub seed: Option<u64>,
+    pub quiet: bool,
+    /// What to do with each kind of warning; `deny_warnings = true` and the
+    /// `allow`, `warn` and `deny` lists of lint names set it.
+    pub lints: LintLevels,
+    /// Fail at a pole of `cot`, `sec` or `csc` instead of giving an infinity.
+    pub strict: bool,
+    /// Make `env` give its default instead of reading the environment.
+    pub no_env: bool,
+    /// Let `loadcsv` and `savecsv` read and write files.
+    pub allow_fs: bool,
+    /// Print what the VM did (`VmStats`) to stderr after a run.
+    pub stats: bool,
+    /// Bytes a run may take for its stacks, variables and lists.
+    pub max_memory: Option<usize>,
+    /// Run within the limits of `engine::Sandbox`, as for untrusted files.
+    pub sandbox: bool,
+    /// Directories `loadcsv` may read from, even in the sandbox; each
+    /// `import_dir` line adds one.
+    pub import_dirs: Vec<PathBuf>,
+    /// Default `--steps` of `table` and `plot`.
+    pub steps: Option<usize>,
+    /// Default `--tol` of `test`.
+    pub tol: Option<f64>,
+    /// Default `--rel-tol` of `test`.
+    pub rel_tol: Option<f64>,
+}
+
+/// File names looked for in each directory, in the order applied.
+pub const FILE_NAMES: [&str; 2] = [".fmathrc", "fmath.toml"];
+
+impl Config {
+    /// Reads the config files in the home directory, then in the current
+    /// directory, a later file overriding the keys it sets. Missing files are
+    /// skipped; an unreadable or invalid one is an error naming the file.
+    pub fn load() -> Result<Self, String> {
+        let mut dirs = Vec::new();
+        if let Some(home) = std::env::var_os("HOME") {
+            dirs.push(PathBuf::from(home));
+        }
+        if let Ok(cwd) = std::env::current_dir()
+            && !dirs.contains(&cwd)
+        {
+            dirs.push(cwd);
+        }
+        let mut config = Config::default();
+        for dir in &dirs {
+            for name in FILE_NAMES {
+                let path = dir.join(name);
+                if path.is_file() {
+                    config.read(&path)?;
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    /// Applies the settings in `path` on top of these.
+    pub fn read(&mut self, path: &Path) -> Result<(), String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        self.apply(&text).map_err(|(line, e)| format!("{}:{}: {}", path.display(), line, e))
+    }
+
+    /// Applies `key = value` lines, TOML style: `#` starts a comment, and
+    /// values are integers, numbers, `true`/`false` or quoted strings. Fails with the 1-based
+    /// line of the first bad line.
+    pub fn apply(&mut self, text: &str) -> Result<(), (usize, String)> {
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err((index + 1, format!("expected `key = value`, found `{}`", line)));
+            };
+            self.set(key.trim(), value.trim()).map_err(|e| (index + 1, e))?;
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        fn parse<T: std::str::FromStr>(key: &str, value: &str, kind: &str) -> Result<T, String> {
+            value.parse().map_err(|_| format!("`{}` expects {}, found `{}`", key, kind, value))
+        }
+        match key {
+            "precision" => self.precision = Some(parse(key, value, "a digit count")?),
+            "seed" => self.seed = Some(parse(key, value, "an unsigned integer")?),
+            "quiet" => self.quiet = parse(key, value, "true or false")?,
+            "deny_warnings" => {
+                let level = if parse(key, value, "true or false")? { Level::Deny } else { Level::Warn };
+                self.lints.set("warnings", level)?;
+            }
+            "allow" | "warn" | "deny" => {
+                let level = match key {
+                    "allow" => Level::Allow,
+                    "warn" => Level::Warn,
+                    _ => Level::Deny,
+                };
+                for name in value.trim_matches('"').split(',').map(str::trim).filter(|name| !name.is_empty()) {
+                    self.lints.set(name, level)?;
+                }
+            }
+            "strict" => self.strict = parse(key, value, "true or false")?,
+            "no_env" => self.no_env = parse(key, value, "true or false")?,
+            "allow_fs" => self.allow_fs = parse(key, value, "true or false")?,
+            "stats" => self.stats = parse(key, value, "true or false")?,
+            "max_memory" => self.max_memory = Some(parse(key, value, "a byte count")?),
+            "sandbox" => self.sandbox = parse(key, value, "true or false")?,
+            "import_dir" => self.import_dirs.push(PathBuf::from(value.trim_matches('"'))),
+            "steps" => match parse(key, value, "a positive integer")? {
+                0 => return Err("`steps` expects a positive integer, found `0`".to_string()),
+                steps => self.steps = Some(steps),
+            },
+            "tol" => self.tol = Some(parse(key, value, "a number")?),
+            "rel_tol" => self.rel_tol = Some(parse(key, value, "a number")?),
+            _ => return Err(format!("unknown setting `{}`", key)),
+        }
+        Ok(())
+    }
+
+    /// `value` printed with `precision` digits after the point, if set.
+    pub fn format(&self, value: f64) -> String {
+        match self.precision {
+            Some(digits) => format!("{:.*}", digits, value),
+            None => value.to_string(),
+        }
+    }
+}
@@ -0,0 +1,36 @@
+// Comma-separated data files, as read by `loadcsv` and written by `savecsv`
+
+/// The numbers in column `column` (1-based) of every row of `text`. Blank
+/// lines are skipped, and so is a first row that is not numbers in that
+/// column, as a header naming the columns.
+pub fn column(text: &str, column: usize) -> Result<Vec<f64>, String> {
+    if column == 0 {
+        return Err("columns are numbered from 1".to_string());
+    }
+    let mut values = Vec::new();
+    let rows = text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+    for (row, (index, line)) in rows.enumerate() {
+        let Some(field) = line.split(',').nth(column - 1).map(str::trim) else {
+            return Err(format!("line {} has no column {}", index + 1, column));
+        };
+        match field.parse() {
+            Ok(value) => values.push(value),
+            Err(_) if row == 0 => {}
+            Err(_) => return Err(format!("line {}: `{}` in column {} is not a number", index + 1, field, column)),
+        }
+    }
+    Ok(values)
+}
+
+/// `columns` side by side, one row per line; a column shorter than the others
+/// leaves its field empty in the rows past its end.
+pub fn write(columns: &[&[f64]]) -> String {
+    let rows = columns.iter().map(|c| c.len()).max().unwrap_or(0);
+    let mut text = String::new();
+    for i in 0..rows {
+        let fields: Vec<String> = columns.iter().map(|c| c.get(i).map_or(String::new(), f64::to_string)).collect();
+        text.push_str(&fields.join(","));
+        text.push('\n');
+    }
+    text
+}
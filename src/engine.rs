@@ -0,0 +1,304 @@
+// Embedding entry point for hosts (GUIs, servers) that evaluate compiled programs
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+
+use crate::bytecode::Bytecode;
+use crate::compiler::CompiledProgram;
+use crate::error::RuntimeError;
+use crate::interpreter::{VmState, DEFAULT_CHECK_INTERVAL};
+
+/// A shared flag used to abort a running evaluation from another thread.
+/// Clones refer to the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests cancellation; the VM stops at its next check.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Supplies the values of variables a program reads but nothing bound, such
+/// as spreadsheet cells or database fields, so a host need not bind every
+/// input up front. Set one with `VmState::set_resolver`; closures
+/// `Fn(&str) -> Option<f64>` implement it.
+pub trait VariableResolver: Send {
+    /// The value of `name`, or `None` if the host does not know it either.
+    /// Asked on every such read, so expensive lookups should be cached here.
+    fn resolve(&self, name: &str) -> Option<f64>;
+}
+
+impl<F: Fn(&str) -> Option<f64> + Send> VariableResolver for F {
+    fn resolve(&self, name: &str) -> Option<f64> {
+        self(name)
+    }
+}
+
+impl std::fmt::Debug for dyn VariableResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("VariableResolver")
+    }
+}
+
+/// Limits for evaluating programs from untrusted users, as a server does
+/// (`Engine::with_sandbox`). Besides these caps, a sandboxed evaluation
+/// cannot read the environment (`env` gives its default) or touch files,
+/// except that `loadcsv` may read from `import_dirs`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sandbox {
+    /// Instructions and loop iterations per evaluation.
+    pub fuel: u64,
+    /// Bytes an evaluation may take (see `VmState::set_memory_limit`).
+    pub memory: usize,
+    /// User function calls that may be in progress at once.
+    pub call_depth: usize,
+    /// Directories whose files `loadcsv` may read.
+    pub import_dirs: Vec<PathBuf>,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Sandbox { fuel: 10_000_000, memory: 64 << 20, call_depth: 100, import_dirs: Vec::new() }
+    }
+}
+
+/// Evaluates compiled programs on behalf of a host application.
+#[derive(Debug, Clone)]
+pub struct Engine {
+    check_interval: u32,
+    fuel: Option<u64>,
+    memory_limit: Option<usize>,
+    strict: bool,
+    env_access: bool,
+    sandbox: Option<Sandbox>,
+    seed: Option<u64>,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Engine { check_interval: DEFAULT_CHECK_INTERVAL, fuel: None, memory_limit: None, strict: false, env_access: false, sandbox: None, seed: None }
+    }
+
+    /// Sets how many instructions run between cancellation checks.
+    /// Smaller values react faster at a small cost in throughput.
+    pub fn with_check_interval(mut self, instructions: u32) -> Self {
+        self.check_interval = instructions.max(1);
+        self
+    }
+
+    /// Limits every evaluation (every point of a batch or sample) to `steps`
+    /// instructions and loop iterations, failing with `RuntimeError::OutOfFuel`
+    /// beyond, as a server running programs from its users needs.
+    pub fn with_fuel(mut self, steps: u64) -> Self {
+        self.fuel = Some(steps);
+        self
+    }
+
+    /// Limits every evaluation to about `bytes` of memory for its stacks,
+    /// variables and lists, failing with `RuntimeError::MemoryLimitExceeded`
+    /// beyond, so a pathological program cannot exhaust the host's memory.
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Makes every evaluation fail at a pole of `cot`, `sec` or `csc` (see
+    /// `VmState::set_strict`).
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Seeds the random numbers of every new state with `seed`, so that each
+    /// point of `eval_batch` draws the same numbers and the points differ only
+    /// by their variables; `sample` and `repeat` seed each run themselves.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Sets whether `env` reads the process environment (see
+    /// `VmState::set_env_access`), off by default; a sandboxed engine never does.
+    pub fn with_env_access(mut self, allowed: bool) -> Self {
+        self.env_access = allowed;
+        self
+    }
+
+    /// Runs every evaluation within the limits of `sandbox`; a fuel or memory
+    /// limit set by `with_fuel` or `with_memory_limit` still applies where it
+    /// is the lower one.
+    pub fn with_sandbox(mut self, sandbox: Sandbox) -> Self {
+        self.sandbox = Some(sandbox);
+        self
+    }
+
+    /// The step limit of `with_fuel` or the sandbox, whichever is lower, if any.
+    pub fn fuel(&self) -> Option<u64> {
+        lower(self.fuel, self.sandbox.as_ref().map(|s| s.fuel))
+    }
+
+    /// The byte budget of `with_memory_limit` or the sandbox, whichever is
+    /// lower, if any.
+    pub fn memory_limit(&self) -> Option<usize> {
+        lower(self.memory_limit, self.sandbox.as_ref().map(|s| s.memory))
+    }
+
+    /// A fresh state for evaluating on this engine's terms, for hosts that
+    /// bind variables or a seed before running.
+    pub fn new_state(&self) -> VmState {
+        let mut state = VmState::new();
+        if let Some(fuel) = self.fuel() {
+            state.set_fuel(fuel);
+        }
+        if let Some(bytes) = self.memory_limit() {
+            state.set_memory_limit(bytes);
+        }
+        state.set_strict(self.strict);
+        state.set_env_access(self.env_access);
+        if let Some(seed) = self.seed {
+            state.seed(seed);
+        }
+        if let Some(sandbox) = &self.sandbox {
+            state.set_env_access(false);
+            state.set_fs_access(false);
+            state.set_import_dirs(sandbox.import_dirs.clone());
+            state.set_max_call_depth(sandbox.call_depth);
+        }
+        state
+    }
+
+    /// Evaluates a program to completion.
+    pub fn eval(&self, program: &CompiledProgram) -> Result<f64, RuntimeError> {
+        program.eval(&mut self.new_state())
+    }
+
+    /// Evaluates a program, returning `Err(RuntimeError::Cancelled)` soon after `token` is cancelled.
+    pub fn eval_cancellable(&self, program: &CompiledProgram, token: CancellationToken) -> Result<f64, RuntimeError> {
+        let mut state = self.new_state();
+        state.set_cancellation(token, self.check_interval);
+        program.eval(&mut state)
+    }
+
+    /// Starts evaluating on a background thread and returns the handle together
+    /// with the token that cancels it.
+    pub fn spawn(&self, program: Arc<CompiledProgram>) -> (JoinHandle<Result<f64, RuntimeError>>, CancellationToken) {
+        let token = CancellationToken::new();
+        let engine = self.clone();
+        let thread_token = token.clone();
+        let handle = thread::spawn(move || engine.eval_cancellable(&program, thread_token));
+        (handle, token)
+    }
+
+    /// Evaluates the user function `function` at every x in `xs`, spread over
+    /// all cores. Each thread runs the program once for its global variables
+    /// (random draws seeded with `seed`) on its own `VmState`, then its share of
+    /// the samples; sample `i` draws from a stream seeded with `seed + i + 1`,
+    /// so the results do not depend on the number of threads.
+    pub fn sample(&self, program: &CompiledProgram, function: &str, xs: &[f64], seed: u64) -> Vec<Result<f64, RuntimeError>> {
+        let setup = |state: &mut VmState| {
+            state.seed(seed);
+            state.exec(&program.program, &program.functions)
+        };
+        in_parallel(self, xs, setup, |state, i, &x| {
+            state.seed(seed.wrapping_add(i as u64 + 1));
+            let call = vec![Bytecode::PushNumber(x), Bytecode::CallUserFunction(function.to_string())];
+            state.run(&call, &program.functions)
+        })
+    }
+
+    /// Runs the program `runs` times, spread over all cores, as for the
+    /// results of a random experiment. Run `i` starts from a fresh state and draws
+    /// from a stream seeded with `seed + i`, so the results do not depend on the
+    /// number of threads.
+    pub fn repeat(&self, program: &CompiledProgram, runs: usize, seed: u64) -> Vec<Result<f64, RuntimeError>> {
+        let runs: Vec<u64> = (0..runs as u64).collect();
+        in_parallel(self, &runs, |_| Ok(()), |state, _, &i| {
+            *state = self.new_state();
+            state.seed(seed.wrapping_add(i));
+            program.eval(state)
+        })
+    }
+
+    /// Evaluates the program at each point of `points`, which holds one value
+    /// per variable of `vars` for every point in turn (so `[x0, y0, x1, y1, ...]`
+    /// for `vars = ["x", "y"]`). With the `gpu` feature, a program without random
+    /// numbers, loops, `let`, user functions, or lists runs on the GPU in single
+    /// precision, unless the engine is strict; otherwise the points are spread
+    /// over all cores, each evaluated on a fresh state so that nothing one
+    /// point assigns carries over to the next.
+    pub fn eval_batch(&self, program: &CompiledProgram, vars: &[&str], points: &[f64]) -> Vec<Result<f64, RuntimeError>> {
+        // The GPU cannot report a pole
+        #[cfg(feature = "gpu")]
+        if !self.strict
+            && let Ok(values) = crate::gpu::evaluate(&program.program, vars, points)
+        {
+            return values.into_iter().map(Ok).collect();
+        }
+        let points: Vec<&[f64]> = points.chunks(vars.len().max(1)).collect();
+        in_parallel(self, &points, |_| Ok(()), |state, _, point| {
+            *state = self.new_state();
+            for (var, &value) in vars.iter().zip(*point) {
+                state.set_var(var, value);
+            }
+            program.eval(state)
+        })
+    }
+}
+
+// The lower of two optional limits
+fn lower<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+// Evaluates `eval(state, i, &items[i])` for every item, splitting the items into
+// one contiguous part per core. Each part gets a fresh `VmState` of `engine`
+// prepared by `setup`; if that fails, every item of the part gets its error.
+fn in_parallel<T: Sync>(
+    engine: &Engine,
+    items: &[T],
+    setup: impl Fn(&mut VmState) -> Result<(), RuntimeError> + Sync,
+    eval: impl Fn(&mut VmState, usize, &T) -> Result<f64, RuntimeError> + Sync,
+) -> Vec<Result<f64, RuntimeError>> {
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk = items.len().div_ceil(threads).max(1);
+    let (setup, eval) = (&setup, &eval);
+    thread::scope(|scope| {
+        let handles: Vec<_> = items
+            .chunks(chunk)
+            .enumerate()
+            .map(|(k, part)| {
+                scope.spawn(move || {
+                    let mut state = engine.new_state();
+                    if let Err(e) = setup(&mut state) {
+                        return vec![Err(e); part.len()];
+                    }
+                    part.iter().enumerate().map(|(i, item)| eval(&mut state, k * chunk + i, item)).collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("sampling thread panicked"))
+            .collect()
+    })
+}
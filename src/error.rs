@@ -0,0 +1,287 @@
+// Error types of each phase, for library users to match on
+use std::fmt;
+
+use crate::analysis::Diagnostic;
+use crate::lexer::{BinaryOperator, Token};
+
+/// A character the lexer does not recognize.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LexError {
+    UnexpectedChar { line: usize, ch: char },
+    /// A `"` with no closing one on its line.
+    UnterminatedString { line: usize },
+    /// A `#pragma` line naming no known setting, with the words after `#pragma`.
+    UnknownPragma { line: usize, text: String },
+}
+
+/// Why one line failed to parse.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// A construct such as `sum(...)` or `def` not in its expected shape.
+    Malformed { construct: &'static str, expected: String },
+    /// A required token is missing, described as in "Expected `in` after let binding".
+    Expected(&'static str),
+    UnexpectedToken(Token),
+    /// The line ended where an operand was needed.
+    UnexpectedEnd,
+    /// A binary operator with no operand on one side.
+    MisplacedOperator(BinaryOperator),
+    /// A call with a number of arguments the function does not take.
+    ArgumentCount { function: &'static str, expected: String, got: usize },
+    /// A number where the function needs a list.
+    ListExpected { function: &'static str, param: &'static str },
+    /// A list where the function needs a number.
+    NumberExpected { function: &'static str, param: &'static str },
+    /// Something other than a string literal where the function needs one.
+    TextExpected { function: &'static str, param: &'static str },
+    /// A string literal where the function needs a number.
+    UnexpectedText { function: &'static str, param: &'static str },
+    /// A call giving a string, as `format(...)`, anywhere but as the text of `print`.
+    StrayText(&'static str),
+    /// A format string that does not parse, or has places for other than
+    /// as many values as it is given.
+    Format(String),
+    /// A named argument, as in `randint(min: 1, max: 6)`, that is not a parameter of the function.
+    UnknownArgument { function: &'static str, name: String },
+    /// A parameter given both positionally and by name, or twice by name.
+    DuplicateArgument { function: &'static str, param: &'static str },
+    /// A user function named after a builtin, as in `def beta(a) = a`.
+    BuiltinName(&'static str),
+    /// A keyword where a binding needs a name, as in `var end = 1`.
+    KeywordName(&'static str),
+}
+
+/// Why a program could not be compiled or loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompileError {
+    /// Semantic analysis found at least one error; warnings are included.
+    Analysis(Vec<Diagnostic>),
+    /// A `.mthc` file that does not decode as bytecode.
+    Bytecode(String),
+}
+
+/// Why a running program stopped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    /// An instruction found too few values on the stack; names the instruction.
+    StackUnderflow(&'static str),
+    /// An instruction found too few lists on the list stack.
+    ListStackUnderflow(&'static str),
+    /// An instruction found no function on the function stack.
+    FunctionStackUnderflow(&'static str),
+    /// The program (or the named nested body) left no result.
+    NoResult(Option<&'static str>),
+    UndefinedVariable(String),
+    UndefinedFunction(String),
+    NotAList(String),
+    AssignToConstant(String),
+    RebindConstant(String),
+    AssertFailed { line: usize, value: f64 },
+    AssertEqFailed { line: usize, left: f64, right: f64, eps: f64 },
+    /// Bytecode referring to a variable slot its frame does not have.
+    InvalidSlot(usize),
+    /// An infinite sum whose partial sums stopped being finite at this term.
+    Diverged(i64),
+    /// An infinite sum still above `eps` after `terms` terms.
+    NotConverged { eps: f64, terms: usize },
+    /// Stopped through a `CancellationToken`.
+    Cancelled,
+    /// Stopped after using up its fuel of this many steps (`VmState::set_fuel`).
+    OutOfFuel(u64),
+    /// A builtin that reads or writes files, such as `loadcsv`, called on a
+    /// state without access to that file (`VmState::set_fs_access`,
+    /// `VmState::set_import_dirs`); names the builtin and the path.
+    FileAccessDenied { function: &'static str, path: String },
+    /// User function calls nested deeper than the limit of
+    /// `VmState::set_max_call_depth`.
+    CallDepthExceeded(usize),
+    /// The program held more than the byte budget of `VmState::set_memory_limit`.
+    MemoryLimitExceeded(usize),
+    /// A builtin rejected its arguments, as in `invmod(2, 4)`.
+    InvalidArgument(String),
+    /// A function evaluated at a pole in strict mode, as `csc(0)`.
+    Pole { function: &'static str, arg: f64 },
+}
+
+/// An error from any phase.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FmathError {
+    Lex(LexError),
+    /// A parse error and its 1-based source line.
+    Parse { line: usize, error: ParseError },
+    Compile(CompileError),
+    Runtime(RuntimeError),
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LexError::UnexpectedChar { ch, .. } => write!(f, "unexpected character `{}`", ch),
+            LexError::UnterminatedString { .. } => write!(f, "string without a closing `\"`"),
+            LexError::UnknownPragma { text, .. } => {
+                write!(f, "unknown pragma `{}`; expected degrees, radians or precision n", text)
+            }
+        }
+    }
+}
+
+impl LexError {
+    /// The 1-based source line of the error.
+    pub fn line(&self) -> usize {
+        match self {
+            LexError::UnexpectedChar { line, .. }
+            | LexError::UnterminatedString { line }
+            | LexError::UnknownPragma { line, .. } => *line,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Malformed { construct, expected } => write!(f, "Malformed {}, expected {}", construct, expected),
+            ParseError::Expected(what) => write!(f, "Expected {}", what),
+            ParseError::UnexpectedToken(token) => write!(f, "Unexpected token: {:?}", token),
+            ParseError::UnexpectedEnd => write!(f, "Unexpected end of line, expected an operand"),
+            ParseError::MisplacedOperator(op) => write!(
+                f,
+                "Operator token {:?} in invalid position. Likely missing operand before or after operator.",
+                op
+            ),
+            ParseError::ArgumentCount { function, expected, got } => {
+                write!(f, "{} expects {}, got {} argument{}", function, expected, got, if *got == 1 { "" } else { "s" })
+            }
+            ParseError::ListExpected { function, param } => write!(f, "{} expects a list for `{}`", function, param),
+            ParseError::NumberExpected { function, param } => {
+                write!(f, "{} expects a number for `{}`, got a list", function, param)
+            }
+            ParseError::TextExpected { function, param } => write!(f, "{} expects a string for `{}`", function, param),
+            ParseError::UnexpectedText { function, param } => {
+                write!(f, "{} expects a number for `{}`, got a string", function, param)
+            }
+            ParseError::StrayText(function) => write!(f, "{} gives a string, which only print takes", function),
+            ParseError::Format(message) => write!(f, "Invalid format string: {}", message),
+            ParseError::UnknownArgument { function, name } => write!(f, "{} has no parameter `{}`", function, name),
+            ParseError::DuplicateArgument { function, param } => {
+                write!(f, "{} got more than one value for `{}`", function, param)
+            }
+            ParseError::BuiltinName(name) => write!(f, "`{}` is a builtin and cannot be redefined", name),
+            ParseError::KeywordName(name) => write!(f, "`{}` is a keyword and cannot be a variable", name),
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::Analysis(diagnostics) => {
+                let errors: Vec<String> = diagnostics.iter().filter(|d| d.is_error()).map(|d| d.to_string()).collect();
+                write!(f, "{}", errors.join("; "))
+            }
+            CompileError::Bytecode(message) => write!(f, "invalid bytecode: {}", message),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeError::StackUnderflow(instr) => write!(f, "Stack underflow on {}", instr),
+            RuntimeError::ListStackUnderflow(instr) => write!(f, "List stack underflow on {}", instr),
+            RuntimeError::FunctionStackUnderflow(instr) => write!(f, "Function stack underflow on {}", instr),
+            RuntimeError::NoResult(None) => write!(f, "No result on stack"),
+            RuntimeError::NoResult(Some(what)) => write!(f, "No result on stack ({})", what),
+            RuntimeError::UndefinedVariable(name) => write!(f, "Variable not found: {}", name),
+            RuntimeError::UndefinedFunction(name) => write!(f, "User-defined function not found: {}", name),
+            RuntimeError::NotAList(name) => write!(f, "`{}` is not a list", name),
+            RuntimeError::AssignToConstant(name) => write!(f, "cannot assign to constant `{}`", name),
+            RuntimeError::RebindConstant(name) => write!(f, "cannot rebind constant `{}` with let", name),
+            RuntimeError::AssertFailed { line, value } => {
+                write!(f, "assertion failed on line {}: condition evaluated to {}", line, value)
+            }
+            RuntimeError::AssertEqFailed { line, left, right, eps } => write!(
+                f,
+                "assert_eq failed on line {}: {} != {} (difference {} exceeds eps {})",
+                line,
+                left,
+                right,
+                (left - right).abs(),
+                eps
+            ),
+            RuntimeError::InvalidSlot(slot) => write!(f, "Variable slot {} is not in the program's frame", slot),
+            RuntimeError::Diverged(term) => write!(f, "sum diverged at term {}", term),
+            RuntimeError::NotConverged { eps, terms } => {
+                write!(f, "sum did not converge to eps {} within {} terms", eps, terms)
+            }
+            RuntimeError::Cancelled => write!(f, "Evaluation cancelled"),
+            RuntimeError::OutOfFuel(steps) => write!(f, "Evaluation stopped after its limit of {} steps", steps),
+            RuntimeError::FileAccessDenied { function, path } => write!(
+                f,
+                "{}(\"{}\") needs file access, which is off (allow it with --allow-fs, or --import-dir for reading)",
+                function, path
+            ),
+            RuntimeError::CallDepthExceeded(depth) => write!(f, "call depth limit exceeded: more than {} nested calls", depth),
+            RuntimeError::MemoryLimitExceeded(bytes) => write!(f, "memory limit exceeded: more than {} bytes", bytes),
+            RuntimeError::InvalidArgument(message) => write!(f, "{}", message),
+            RuntimeError::Pole { function, arg } => write!(f, "{}({}) is undefined: the argument is a pole", function, arg),
+        }
+    }
+}
+
+impl fmt::Display for FmathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FmathError::Lex(e) => write!(f, "{} on line {}", e, e.line()),
+            FmathError::Parse { line, error } => write!(f, "{} on line {}", error, line),
+            FmathError::Compile(e) => write!(f, "{}", e),
+            FmathError::Runtime(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for LexError {}
+impl std::error::Error for ParseError {}
+impl std::error::Error for CompileError {}
+impl std::error::Error for RuntimeError {}
+
+impl std::error::Error for FmathError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FmathError::Lex(e) => Some(e),
+            FmathError::Parse { error, .. } => Some(error),
+            FmathError::Compile(e) => Some(e),
+            FmathError::Runtime(e) => Some(e),
+        }
+    }
+}
+
+// Builtins explain bad arguments with a message
+impl From<String> for RuntimeError {
+    fn from(message: String) -> Self {
+        RuntimeError::InvalidArgument(message)
+    }
+}
+
+impl From<&'static str> for RuntimeError {
+    fn from(message: &'static str) -> Self {
+        RuntimeError::InvalidArgument(message.to_string())
+    }
+}
+
+impl From<LexError> for FmathError {
+    fn from(e: LexError) -> Self {
+        FmathError::Lex(e)
+    }
+}
+
+impl From<CompileError> for FmathError {
+    fn from(e: CompileError) -> Self {
+        FmathError::Compile(e)
+    }
+}
+
+impl From<RuntimeError> for FmathError {
+    fn from(e: RuntimeError) -> Self {
+        FmathError::Runtime(e)
+    }
+}
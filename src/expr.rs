@@ -0,0 +1,218 @@
+// Expression builder: formulas put together in Rust instead of parsed from text
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+use crate::ast::{Expr, UnaryOperator, UserFunctions};
+use crate::compiler::{compile, CompiledProgram};
+use crate::error::{ParseError, RuntimeError};
+use crate::interpreter::VmState;
+use crate::lexer::{BinaryOperator, SpecialFunction};
+
+/// A formula under construction. Combine with `+`, `-`, `*`, `/` and unary
+/// `-`, with each other or with plain numbers on either side:
+/// `num(2) * var("x") + sin(var("x"))` builds the same tree the parser
+/// makes from `2*x + sin(x)`.
+#[derive(Debug, Clone)]
+pub struct Formula(Expr);
+
+/// A number.
+pub fn num(value: impl Into<f64>) -> Formula {
+    Formula(Expr::Number(value.into()))
+}
+
+/// A variable, bound with `VmState::set_var` or by an earlier assignment.
+pub fn var(name: &str) -> Formula {
+    Formula(Expr::Ident(name.to_string()))
+}
+
+/// A call to the builtin `func`, checked against the catalog like a parsed
+/// call, which also picks the overload for that many arguments (so `Log`
+/// with two is `log(base, x)`).
+pub fn builtin(func: SpecialFunction, args: impl IntoIterator<Item = Formula>) -> Result<Formula, ParseError> {
+    let args: Vec<Expr> = args.into_iter().map(Formula::into_expr).collect();
+    let func = crate::builtins::resolve(func, args.len())?;
+    crate::builtins::check_args(func, &args)?;
+    Ok(Formula(Expr::Function { func, arg: Box::new(Expr::Sequence(args)) }))
+}
+
+/// A call to the user function `name`.
+pub fn call(name: &str, args: impl IntoIterator<Item = Formula>) -> Formula {
+    Formula(Expr::FunctionCall { name: name.to_string(), args: args.into_iter().map(Formula::into_expr).collect() })
+}
+
+/// `cond ? then : otherwise`.
+pub fn cond(cond: Formula, then: Formula, otherwise: Formula) -> Formula {
+    Formula(Expr::Conditional { cond: cond.boxed(), then: then.boxed(), otherwise: otherwise.boxed() })
+}
+
+/// `sum(from: from, to: to, para: param, body)`.
+pub fn sum(param: &str, from: impl Into<Formula>, to: impl Into<Formula>, body: Formula) -> Formula {
+    Formula(Expr::Sum {
+        from: from.into().boxed(),
+        to: to.into().boxed(),
+        eps: None,
+        param: param.to_string(),
+        cond: None,
+        body: body.boxed(),
+    })
+}
+
+/// `product(from: from, to: to, para: param, body)`.
+pub fn product(param: &str, from: impl Into<Formula>, to: impl Into<Formula>, body: Formula) -> Formula {
+    Formula(Expr::Product {
+        from: from.into().boxed(),
+        to: to.into().boxed(),
+        param: param.to_string(),
+        cond: None,
+        body: body.boxed(),
+    })
+}
+
+// One-argument builtins, the ones formulas use most
+macro_rules! unary_builtins {
+    ($($name:ident => $func:ident),* $(,)?) => {
+        $(
+            #[doc = concat!("`", stringify!($name), "(x)`.")]
+            pub fn $name(x: Formula) -> Formula {
+                Formula::function(SpecialFunction::$func, vec![x])
+            }
+        )*
+    };
+}
+
+unary_builtins! {
+    sin => Sin, cos => Cos, tan => Tan, asin => Asin, acos => Acos, atan => Atan,
+    sinh => Sinh, cosh => Cosh, tanh => Tanh,
+    exp => Exp, log => Log, log10 => Log10, log2 => Log2, sqrt => Sqrt, abs => Abs, floor => Floor,
+}
+
+impl Formula {
+    fn function(func: SpecialFunction, args: Vec<Formula>) -> Formula {
+        let args = args.into_iter().map(Formula::into_expr).collect();
+        Formula(Expr::Function { func, arg: Box::new(Expr::Sequence(args)) })
+    }
+
+    fn binary(self, op: BinaryOperator, right: Formula) -> Formula {
+        Formula(Expr::BinaryOp { left: self.boxed(), op, right: right.boxed() })
+    }
+
+    fn boxed(self) -> Box<Expr> {
+        Box::new(self.0)
+    }
+
+    /// `self ^ exponent`.
+    pub fn pow(self, exponent: impl Into<Formula>) -> Formula {
+        self.binary(BinaryOperator::Pow, exponent.into())
+    }
+
+    /// `self < other`, 1 if true and 0 if not; likewise `le`, `gt`, `ge`, `eq` and `ne`.
+    pub fn lt(self, other: impl Into<Formula>) -> Formula {
+        self.binary(BinaryOperator::Less, other.into())
+    }
+
+    pub fn le(self, other: impl Into<Formula>) -> Formula {
+        self.binary(BinaryOperator::LessEq, other.into())
+    }
+
+    pub fn gt(self, other: impl Into<Formula>) -> Formula {
+        self.binary(BinaryOperator::Greater, other.into())
+    }
+
+    pub fn ge(self, other: impl Into<Formula>) -> Formula {
+        self.binary(BinaryOperator::GreaterEq, other.into())
+    }
+
+    pub fn eq(self, other: impl Into<Formula>) -> Formula {
+        self.binary(BinaryOperator::Equal, other.into())
+    }
+
+    pub fn ne(self, other: impl Into<Formula>) -> Formula {
+        self.binary(BinaryOperator::NotEqual, other.into())
+    }
+
+    /// The syntax tree built so far.
+    pub fn expr(&self) -> &Expr {
+        &self.0
+    }
+
+    pub fn into_expr(self) -> Expr {
+        self.0
+    }
+
+    /// Compiles the formula into a program without user functions.
+    pub fn compile(&self) -> CompiledProgram {
+        let mut program = Vec::new();
+        compile(&self.0, &mut program);
+        CompiledProgram { program, functions: UserFunctions::new() }
+    }
+
+    /// Evaluates the formula once with the variables `vars` bound.
+    pub fn eval(&self, vars: &[(&str, f64)]) -> Result<f64, RuntimeError> {
+        let mut state = VmState::new();
+        for &(name, value) in vars {
+            state.set_var(name, value);
+        }
+        self.compile().eval(&mut state)
+    }
+}
+
+impl From<f64> for Formula {
+    fn from(value: f64) -> Self {
+        num(value)
+    }
+}
+
+impl From<i32> for Formula {
+    fn from(value: i32) -> Self {
+        num(value)
+    }
+}
+
+impl From<Formula> for Expr {
+    fn from(formula: Formula) -> Self {
+        formula.0
+    }
+}
+
+impl From<Expr> for Formula {
+    fn from(expr: Expr) -> Self {
+        Formula(expr)
+    }
+}
+
+// `formula op formula`, `formula op number` and `number op formula`
+macro_rules! binary_operator {
+    ($trait:ident, $method:ident, $op:ident) => {
+        impl<T: Into<Formula>> $trait<T> for Formula {
+            type Output = Formula;
+            fn $method(self, right: T) -> Formula {
+                self.binary(BinaryOperator::$op, right.into())
+            }
+        }
+
+        impl $trait<Formula> for f64 {
+            type Output = Formula;
+            fn $method(self, right: Formula) -> Formula {
+                num(self).binary(BinaryOperator::$op, right)
+            }
+        }
+
+        impl $trait<Formula> for i32 {
+            type Output = Formula;
+            fn $method(self, right: Formula) -> Formula {
+                num(self).binary(BinaryOperator::$op, right)
+            }
+        }
+    };
+}
+
+binary_operator!(Add, add, Plus);
+binary_operator!(Sub, sub, Minus);
+binary_operator!(Mul, mul, Star);
+binary_operator!(Div, div, Slash);
+
+impl Neg for Formula {
+    type Output = Formula;
+    fn neg(self) -> Formula {
+        Formula(Expr::UnaryOp { op: UnaryOperator::Neg, operand: self.boxed() })
+    }
+}
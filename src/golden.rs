@@ -0,0 +1,165 @@
+// Golden-test runner: evaluates .mth files and compares against `# expect:` comments
+use std::fs;
+use std::panic;
+use std::path::{Path, PathBuf};
+
+use crate::compiler::CompiledProgram;
+use crate::interpreter::{Value, VmState};
+
+/// Comparison tolerances: a result passes when
+/// `|actual - expected| <= abs + rel * |expected|`.
+#[derive(Debug, Clone, Copy)]
+pub struct Tolerance {
+    pub abs: f64,
+    pub rel: f64,
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Tolerance { abs: 1e-9, rel: 1e-9 }
+    }
+}
+
+impl Tolerance {
+    // A list passes when it has as many items as expected, each passing
+    fn accepts_value(&self, expected: &Value, actual: &Value) -> bool {
+        match (expected, actual) {
+            (Value::Number(e), Value::Number(a)) => self.accepts(*e, *a),
+            (Value::List(e), Value::List(a)) => e.len() == a.len() && e.iter().zip(a).all(|(&e, &a)| self.accepts(e, a)),
+            _ => false,
+        }
+    }
+
+    fn accepts(&self, expected: f64, actual: f64) -> bool {
+        if expected.is_nan() {
+            return actual.is_nan();
+        }
+        if expected.is_infinite() {
+            return expected == actual;
+        }
+        (actual - expected).abs() <= self.abs + self.rel * expected.abs()
+    }
+}
+
+/// Outcome of running one golden file.
+#[derive(Debug, Clone)]
+pub enum Outcome {
+    Pass,
+    Fail(String),
+    /// Compiling or running the file panicked, with the panic's message: a
+    /// bug in fmath rather than in the script.
+    Panic(String),
+}
+
+/// Summary of a whole test run.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub results: Vec<(PathBuf, Outcome)>,
+}
+
+impl Report {
+    pub fn passed(&self) -> usize {
+        self.results.iter().filter(|(_, o)| matches!(o, Outcome::Pass)).count()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.results.iter().filter(|(_, o)| matches!(o, Outcome::Fail(_))).count()
+    }
+
+    pub fn panicked(&self) -> usize {
+        self.results.iter().filter(|(_, o)| matches!(o, Outcome::Panic(_))).count()
+    }
+}
+
+/// Extracts the value of a `# expect: <value>` comment, if the source has
+/// one: a number, or a list such as `[1, 2.5]`. A file checks a single
+/// result, so more than one such comment is an error.
+pub fn expected_value(source: &str) -> Option<Result<Value, String>> {
+    let mut expects = source.lines().filter_map(|line| line.trim().strip_prefix('#')?.trim_start().strip_prefix("expect:"));
+    let text = expects.next()?.trim();
+    if expects.next().is_some() {
+        return Some(Err("more than one `# expect:` comment".to_string()));
+    }
+    let invalid = || format!("invalid expected value `{}`", text);
+    Some(match text.strip_prefix('[').and_then(|items| items.strip_suffix(']')) {
+        Some(items) if items.trim().is_empty() => Ok(Value::List(Vec::new())),
+        Some(items) => items.split(',').map(|item| item.trim().parse::<f64>()).collect::<Result<_, _>>().map(Value::List).map_err(|_| invalid()),
+        None => text.parse::<f64>().map(Value::Number).map_err(|_| invalid()),
+    })
+}
+
+/// Runs a single golden file. Returns `None` if it has no `# expect:` comment.
+pub fn run_file(path: &Path, tolerance: Tolerance) -> Option<Outcome> {
+    let source = match fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) => return Some(Outcome::Fail(format!("cannot read file: {}", e))),
+    };
+    let expected = match expected_value(&source)? {
+        Ok(v) => v,
+        Err(e) => return Some(Outcome::Fail(e)),
+    };
+    // Contain any panic per file so one broken script does not stop the run;
+    // the panic hook still reports it as it happens
+    let result = panic::catch_unwind(|| {
+        CompiledProgram::try_from_source(&source).map(|(compiled, _)| compiled.eval_value(&mut golden_state()))
+    });
+    Some(match result {
+        Ok(Err(diagnostics)) => {
+            let messages: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+            Outcome::Fail(format!("compile error: {}", messages.join("; ")))
+        }
+        Ok(Ok(result)) => match result {
+            Ok(actual) if tolerance.accepts_value(&expected, &actual) => Outcome::Pass,
+            Ok(actual) => Outcome::Fail(format!("expected {}, got {}", expected, actual)),
+            Err(e) => Outcome::Fail(format!("runtime error: {}", e)),
+        },
+        Err(payload) => {
+            let msg = payload
+                .downcast_ref::<String>()
+                .map(String::as_str)
+                .or_else(|| payload.downcast_ref::<&str>().copied())
+                .unwrap_or("no message");
+            Outcome::Panic(msg.to_string())
+        }
+    })
+}
+
+// A state whose results do not depend on where the tests run: `print`
+// output is kept rather than written out, and `env` gives its default
+fn golden_state() -> VmState {
+    let mut state = VmState::new();
+    state.set_print_capture(true);
+    state.set_env_access(false);
+    state
+}
+
+/// Recursively collects `.mth` files under `dir`, sorted by path.
+pub(crate) fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    if dir.is_file() {
+        files.push(dir.to_path_buf());
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else if path.extension().is_some_and(|ext| ext == "mth") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Runs every `.mth` file with an `# expect:` comment under `dir`.
+pub fn run_dir(dir: &Path, tolerance: Tolerance) -> std::io::Result<Report> {
+    let mut files = Vec::new();
+    collect_files(dir, &mut files)?;
+    files.sort();
+    let mut report = Report::default();
+    for path in files {
+        if let Some(outcome) = run_file(&path, tolerance) {
+            report.results.push((path, outcome));
+        }
+    }
+    Ok(report)
+}
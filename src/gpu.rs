@@ -0,0 +1,321 @@
+// GPU batch evaluation: a program without random numbers or loops becomes a
+// WGSL compute shader that evaluates one sample point per invocation
+
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use crate::bytecode::{Bytecode, Program};
+
+/// Invocations per workgroup; matches `@workgroup_size` in the shader.
+const WORKGROUP_SIZE: u32 = 64;
+
+/// Most workgroups a dispatch may have in one dimension.
+#[cfg(feature = "gpu")]
+const MAX_WORKGROUPS: u32 = 65535;
+
+/// WGSL source of a compute shader that evaluates `program` at each point of
+/// `xs` (binding 0), which holds one value per variable of `vars` for every
+/// point in turn, writing `out` (binding 1).
+/// The shader works in single precision. Fails for programs the GPU cannot
+/// run: random numbers, sum/product loops, `let`, user functions, lists,
+/// asserts, and builtins with no WGSL counterpart.
+pub fn to_wgsl(program: &Program, vars: &[&str]) -> Result<String, String> {
+    let mut shader = Shader { vars, names: &[], assigned: HashSet::new(), stack: Vec::new(), body: String::new(), temps: 0 };
+    shader.block(program, 0)?;
+    let result = shader.pop()?;
+    Ok(format!(
+        "@group(0) @binding(0) var<storage, read> xs: array<f32>;
+@group(0) @binding(1) var<storage, read_write> out: array<f32>;
+
+// x^y with the sign of an odd integer power of a negative base, like powf
+fn powf(a: f32, b: f32) -> f32 {{
+    if (b == 0.0) {{ return 1.0; }}
+    if (a >= 0.0) {{ return pow(a, b); }}
+    if (b != floor(b)) {{ return bitcast<f32>(0x7fc00000u); }}
+    let m = pow(-a, b);
+    return select(m, -m, b % 2.0 != 0.0);
+}}
+
+@compute @workgroup_size({WORKGROUP_SIZE})
+fn main(@builtin(global_invocation_id) id: vec3<u32>, @builtin(num_workgroups) groups: vec3<u32>) {{
+    let i = id.x + id.y * groups.x * {WORKGROUP_SIZE}u;
+    if (i >= arrayLength(&out)) {{ return; }}
+{}{}    out[i] = {};
+}}
+",
+        inputs(vars.len()), shader.body, result
+    ))
+}
+
+// Turns the stack code into one `let` per value pushed
+struct Shader<'a> {
+    vars: &'a [&'a str],
+    /// Variable names from the program's `Frame` header, by slot
+    names: &'a [String],
+    /// Slots stored so far, declared as `var v<slot>`
+    assigned: HashSet<usize>,
+    stack: Vec<String>,
+    body: String,
+    temps: usize,
+}
+
+impl<'a> Shader<'a> {
+    fn pop(&mut self) -> Result<String, String> {
+        self.stack.pop().ok_or_else(|| "Stack underflow in GPU translation".to_string())
+    }
+
+    fn push(&mut self, expr: String) {
+        let name = format!("t{}", self.temps);
+        self.temps += 1;
+        let _ = writeln!(self.body, "    let {} = {};", name, expr);
+        self.stack.push(name);
+    }
+
+    fn unary(&mut self, f: impl Fn(&str) -> String) -> Result<(), String> {
+        let a = self.pop()?;
+        self.push(f(&a));
+        Ok(())
+    }
+
+    fn binary(&mut self, f: impl Fn(&str, &str) -> String) -> Result<(), String> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        self.push(f(&a, &b));
+        Ok(())
+    }
+
+    // Translates `code`; `depth` counts the conditionals it sits in, whose
+    // branches both run on the GPU, so they may not assign variables
+    fn block(&mut self, code: &'a [Bytecode], depth: usize) -> Result<(), String> {
+        let mut pc = 0;
+        while let Some(instr) = code.get(pc) {
+            pc += 1;
+            match instr {
+                Bytecode::Frame(names) => self.names = names,
+                Bytecode::PushNumber(n) | Bytecode::Precomputed(n) => self.push(literal(*n)),
+                Bytecode::LoadVar(slot) => {
+                    let name = self.names.get(*slot).ok_or("Variable slot outside the program's frame")?;
+                    if self.assigned.contains(slot) {
+                        self.push(format!("v{}", slot));
+                    } else if let Some(k) = self.vars.iter().position(|var| var == name) {
+                        self.push(format!("in{}", k));
+                    } else {
+                        return Err(format!("`{}` is not set before it is read", name));
+                    }
+                }
+                Bytecode::StoreVar(slot) | Bytecode::StoreConst(slot) | Bytecode::StoreOutput(slot) => {
+                    if depth > 0 {
+                        return Err("assignments inside a conditional cannot run on the GPU".into());
+                    }
+                    let value = self.pop()?;
+                    if self.assigned.insert(*slot) {
+                        let _ = writeln!(self.body, "    var v{} = {};", slot, value);
+                    } else {
+                        let _ = writeln!(self.body, "    v{} = {};", slot, value);
+                    }
+                }
+                Bytecode::Pop => {
+                    self.pop()?;
+                }
+                // Stack entries name `let` temporaries, so a copy is the same name
+                Bytecode::Dup => {
+                    let top = self.pop()?;
+                    self.push(top.clone());
+                    self.push(top);
+                }
+                Bytecode::Swap => {
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    self.push(b);
+                    self.push(a);
+                }
+                Bytecode::JumpIfFalse(n) => {
+                    // cond; JumpIfFalse; then; Jump(m); otherwise (m instructions)
+                    let cond = self.pop()?;
+                    let then_end = pc + n - 1;
+                    let Some(Bytecode::Jump(m)) = code.get(then_end) else {
+                        return Err("unexpected jump layout in GPU translation".into());
+                    };
+                    self.block(&code[pc..then_end], depth + 1)?;
+                    let then = self.pop()?;
+                    let else_end = then_end + 1 + m;
+                    self.block(code.get(then_end + 1..else_end).ok_or("Jump past the end of the program")?, depth + 1)?;
+                    let otherwise = self.pop()?;
+                    self.push(format!("select({}, {}, {} != 0.0 && {} == {})", otherwise, then, cond, cond, cond));
+                    pc = else_end;
+                }
+                Bytecode::Neg => self.unary(|a| format!("-{}", a))?,
+                Bytecode::Add => self.binary(|a, b| format!("{} + {}", a, b))?,
+                Bytecode::Sub => self.binary(|a, b| format!("{} - {}", a, b))?,
+                Bytecode::Mul => self.binary(|a, b| format!("{} * {}", a, b))?,
+                Bytecode::Div => self.binary(|a, b| format!("{} / {}", a, b))?,
+                Bytecode::Pow => self.binary(|a, b| format!("powf({}, {})", a, b))?,
+                Bytecode::PowI(n) => self.unary(|a| power(a, *n))?,
+                Bytecode::Atan2 => self.binary(|y, x| format!("atan2({}, {})", y, x))?,
+                Bytecode::LogBase => self.binary(|base, a| format!("log({}) / log({})", a, base))?,
+                Bytecode::Less => self.binary(|a, b| format!("select(0.0, 1.0, {} < {})", a, b))?,
+                Bytecode::LessEq => self.binary(|a, b| format!("select(0.0, 1.0, {} <= {})", a, b))?,
+                Bytecode::Greater => self.binary(|a, b| format!("select(0.0, 1.0, {} > {})", a, b))?,
+                Bytecode::GreaterEq => self.binary(|a, b| format!("select(0.0, 1.0, {} >= {})", a, b))?,
+                Bytecode::Equal => self.binary(|a, b| format!("select(0.0, 1.0, {} == {})", a, b))?,
+                Bytecode::NotEqual => self.binary(|a, b| format!("select(0.0, 1.0, {} != {})", a, b))?,
+                Bytecode::Cot => self.unary(|a| format!("1.0 / tan({})", a))?,
+                Bytecode::Sec => self.unary(|a| format!("1.0 / cos({})", a))?,
+                Bytecode::Csc => self.unary(|a| format!("1.0 / sin({})", a))?,
+                Bytecode::Acot => self.unary(|a| format!("atan(1.0 / {})", a))?,
+                Bytecode::Asec => self.unary(|a| format!("acos(1.0 / {})", a))?,
+                Bytecode::Acsc => self.unary(|a| format!("asin(1.0 / {})", a))?,
+                Bytecode::Log10 => self.unary(|a| format!("log({}) * 0.4342944819", a))?,
+                Bytecode::Grad => self.unary(|a| format!("{} * 63.66197724", a))?,
+                // Half away from zero, like f64::round (WGSL's round ties to even)
+                Bytecode::Round => self.unary(|a| format!("sign({}) * floor(abs({}) + 0.5)", a, a))?,
+                Bytecode::Rand | Bytecode::RandInt => return Err("random numbers cannot run on the GPU".into()),
+                Bytecode::SumLoop { .. } | Bytecode::ProductLoop { .. } => {
+                    return Err("sum and product loops cannot run on the GPU".into());
+                }
+                Bytecode::CallUserFunction(name) | Bytecode::CallUserFunctionN(name, _) => return Err(format!("user function `{}` cannot run on the GPU", name)),
+                other => {
+                    let function = match other {
+                        Bytecode::Sin => "sin",
+                        Bytecode::Cos => "cos",
+                        Bytecode::Tan => "tan",
+                        Bytecode::Sinh => "sinh",
+                        Bytecode::Cosh => "cosh",
+                        Bytecode::Tanh => "tanh",
+                        Bytecode::Asinh => "asinh",
+                        Bytecode::Acosh => "acosh",
+                        Bytecode::Atanh => "atanh",
+                        Bytecode::Exp => "exp",
+                        Bytecode::Exp2 => "exp2",
+                        Bytecode::Log => "log",
+                        Bytecode::Log2 => "log2",
+                        Bytecode::Sqrt => "sqrt",
+                        Bytecode::Abs => "abs",
+                        Bytecode::Asin => "asin",
+                        Bytecode::Acos => "acos",
+                        Bytecode::Atan => "atan",
+                        Bytecode::Floor => "floor",
+                        Bytecode::Deg => "degrees",
+                        Bytecode::Rad => "radians",
+                        _ => return Err(format!("{:?} cannot run on the GPU", other)),
+                    };
+                    self.unary(|a| format!("{}({})", function, a))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// `let in<k> = ...` reading variable k of point i
+fn inputs(count: usize) -> String {
+    (0..count).map(|k| format!("    let in{} = xs[i * {}u + {}u];\n", k, count, k)).collect()
+}
+
+// An f32 literal; WGSL has none for infinities and NaN
+fn literal(n: f64) -> String {
+    let n = n as f32;
+    if n.is_nan() {
+        "bitcast<f32>(0x7fc00000u)".to_string()
+    } else if n.is_infinite() {
+        format!("bitcast<f32>({:#x}u)", n.to_bits())
+    } else {
+        format!("{:?}", n)
+    }
+}
+
+// a^n by repeated multiplication, as for `PowI`
+fn power(a: &str, n: i32) -> String {
+    if n == 0 {
+        return "1.0".to_string();
+    }
+    let product = vec![a; n.unsigned_abs() as usize].join(" * ");
+    if n < 0 { format!("1.0 / ({})", product) } else { product }
+}
+
+/// Evaluates `program` at each point of `xs` (laid out as for `to_wgsl`) on
+/// the first GPU available, in single precision. Fails if the program has no
+/// WGSL translation or no GPU can be used.
+#[cfg(feature = "gpu")]
+pub fn evaluate(program: &Program, vars: &[&str], xs: &[f64]) -> Result<Vec<f64>, String> {
+    use wgpu::util::DeviceExt;
+
+    let source = to_wgsl(program, vars)?;
+    let count = xs.len() / vars.len().max(1);
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+    let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor::default());
+    let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default()))
+        .map_err(|e| format!("no GPU available: {}", e))?;
+    let (device, queue) = pollster::block_on(adapter.request_device(&wgpu::DeviceDescriptor::default()))
+        .map_err(|e| format!("cannot open the GPU: {}", e))?;
+
+    let input: Vec<u8> = xs.iter().flat_map(|&x| (x as f32).to_le_bytes()).collect();
+    let size = (count * 4) as u64;
+    let xs_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("xs"),
+        contents: &input,
+        usage: wgpu::BufferUsages::STORAGE,
+    });
+    let out_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("out"),
+        size,
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
+    });
+    let read_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("read"),
+        size,
+        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+
+    let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: None,
+        source: wgpu::ShaderSource::Wgsl(source.into()),
+    });
+    let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: None,
+        layout: None,
+        module: &module,
+        entry_point: Some("main"),
+        compilation_options: Default::default(),
+        cache: None,
+    });
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: None,
+        layout: &pipeline.get_bind_group_layout(0),
+        entries: &[
+            wgpu::BindGroupEntry { binding: 0, resource: xs_buffer.as_entire_binding() },
+            wgpu::BindGroupEntry { binding: 1, resource: out_buffer.as_entire_binding() },
+        ],
+    });
+
+    // Rows of at most MAX_WORKGROUPS workgroups, as many rows as needed
+    let groups = (count as u64).div_ceil(WORKGROUP_SIZE as u64);
+    let (columns, rows) = (groups.min(MAX_WORKGROUPS as u64) as u32, groups.div_ceil(MAX_WORKGROUPS as u64));
+    let rows = u32::try_from(rows).ok().filter(|&r| r <= MAX_WORKGROUPS).ok_or("too many points for one dispatch")?;
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+    {
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor::default());
+        pass.set_pipeline(&pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.dispatch_workgroups(columns, rows, 1);
+    }
+    encoder.copy_buffer_to_buffer(&out_buffer, 0, &read_buffer, 0, size);
+    queue.submit([encoder.finish()]);
+
+    let slice = read_buffer.slice(..);
+    let (sender, receiver) = std::sync::mpsc::channel();
+    slice.map_async(wgpu::MapMode::Read, move |result| {
+        let _ = sender.send(result);
+    });
+    device.poll(wgpu::PollType::Wait).map_err(|e| format!("GPU evaluation failed: {}", e))?;
+    receiver
+        .recv()
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("cannot read GPU results: {}", e))?;
+    let data = slice.get_mapped_range();
+    Ok(data.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64).collect())
+}
@@ -0,0 +1,217 @@
+// Incremental re-evaluation: rerun only the statements an input change reaches
+use std::collections::{HashMap, HashSet};
+
+use crate::analysis::{analyze_with_inputs, collect_assigned, Diagnostic, Severity};
+use crate::ast::{walk_expr, Expr, ExprVisitor, UserFunctions};
+use crate::bytecode::Program;
+use crate::compiler::{compile, fold_constants};
+use crate::error::RuntimeError;
+use crate::interpreter::VmState;
+
+/// Evaluates a program whose inputs a host changes one at a time, as an
+/// interactive graph does while a slider moves.
+///
+/// Each top-level statement is compiled on its own, together with the names
+/// it reads (directly or through the user functions it calls) and the
+/// variables it assigns. After `set_input`, `eval` reruns only the statements
+/// that read a changed name, directly or through a variable assigned by
+/// another rerun statement, and reuses the cached values of the rest. A
+/// statement drawing random numbers keeps its draw until it reruns.
+#[derive(Debug)]
+pub struct IncrementalEngine {
+    nodes: Vec<Node>,
+    functions: UserFunctions,
+    state: VmState,
+    /// Names changed since the last `eval`
+    changed: HashSet<String>,
+    /// Whether every statement must run, as before the first `eval` or after one that failed
+    stale: bool,
+    recomputed: usize,
+}
+
+// One top-level statement and what it depends on
+#[derive(Debug)]
+struct Node {
+    program: Program,
+    reads: HashSet<String>,
+    writes: HashSet<String>,
+    /// Whether the statement leaves a value rather than only assigning
+    has_value: bool,
+    /// The value of its last run; `None` for an assignment
+    value: Option<f64>,
+}
+
+impl IncrementalEngine {
+    /// Parses, analyzes and compiles `source`, whose `inputs` the host binds
+    /// with `set_input` before the first `eval`. Fails with every diagnostic
+    /// if there is an error; warnings are returned with the engine.
+    pub fn new(source: &str, inputs: &[&str]) -> Result<(Self, Vec<Diagnostic>), Vec<Diagnostic>> {
+        let lines = crate::lexer::try_tokenize_numbered(source).map_err(|errors| {
+            errors
+                .into_iter()
+                .map(|e| Diagnostic { severity: Severity::Error, line: Some(e.line()), function: None, message: e.to_string(), lint: None })
+                .collect::<Vec<_>>()
+        })?;
+        let (mut statements, functions) = crate::parser::parse_statements(lines)?;
+        let diagnostics = analyze_with_inputs(&statements, &functions, inputs);
+        if diagnostics.iter().any(Diagnostic::is_error) {
+            return Err(diagnostics);
+        }
+        fold_constants(&mut statements);
+        let function_reads = function_reads(&functions);
+        let nodes: Vec<Node> = statements
+            .into_iter()
+            .map(|(_, expr)| {
+                let mut reads = HashSet::new();
+                let mut calls = HashSet::new();
+                collect_reads(&expr, &mut reads, &mut calls);
+                for name in &calls {
+                    match function_reads.get(name) {
+                        Some(names) => reads.extend(names.iter().cloned()),
+                        // A variable holding a function may hold any of them
+                        None => reads.extend(function_reads.values().flatten().cloned()),
+                    }
+                }
+                let mut writes = HashSet::new();
+                collect_assigned(&expr, &mut writes);
+                let mut program = Vec::new();
+                compile(&expr, &mut program);
+                let has_value = !matches!(
+                    expr,
+                    Expr::Assign { .. }
+                        | Expr::Const { .. }
+                        | Expr::Output { .. }
+                        | Expr::Assert { .. }
+                        | Expr::AssertEq { .. }
+                        | Expr::Precision(_)
+                )
+                    && !expr.is_list()
+                    && !expr.is_function();
+                Node { program, reads, writes, has_value, value: None }
+            })
+            .collect();
+        log::debug!(target: "compiler", "incremental engine with {} statements", nodes.len());
+        let engine = IncrementalEngine {
+            nodes,
+            functions,
+            state: VmState::new(),
+            changed: HashSet::new(),
+            stale: true,
+            recomputed: 0,
+        };
+        Ok((engine, diagnostics))
+    }
+
+    /// Binds the input `name`; the next `eval` reruns what depends on it.
+    pub fn set_input(&mut self, name: &str, value: f64) {
+        if self.state.var(name) != Some(value) {
+            self.state.set_var(name, value);
+            self.changed.insert(name.to_string());
+        }
+    }
+
+    /// The value of the program: that of its last statement, rerunning the
+    /// statements that depend on inputs changed since the last call. Stops at
+    /// the first statement that fails, and then reruns everything next time.
+    pub fn eval(&mut self) -> Result<f64, RuntimeError> {
+        let mut dirty = std::mem::take(&mut self.changed);
+        let rerun_all = std::mem::replace(&mut self.stale, false);
+        self.recomputed = 0;
+        for node in &mut self.nodes {
+            if !rerun_all && node.reads.is_disjoint(&dirty) {
+                continue;
+            }
+            self.recomputed += 1;
+            let result = if node.has_value {
+                self.state.run(&node.program, &self.functions).map(Some)
+            } else {
+                self.state.exec(&node.program, &self.functions).map(|_| None)
+            };
+            match result {
+                Ok(value) => node.value = value,
+                Err(e) => {
+                    self.stale = true;
+                    return Err(e);
+                }
+            }
+            dirty.extend(node.writes.iter().cloned());
+        }
+        self.nodes.last().and_then(|node| node.value).ok_or(RuntimeError::NoResult(None))
+    }
+
+    /// How many statements the last `eval` ran.
+    pub fn recomputed(&self) -> usize {
+        self.recomputed
+    }
+
+    /// The variable `name` as the last `eval` left it.
+    pub fn var(&self, name: &str) -> Option<f64> {
+        self.state.var(name)
+    }
+}
+
+// The global names each user function reads, including through the functions it calls
+fn function_reads(functions: &UserFunctions) -> HashMap<String, HashSet<String>> {
+    let mut reads: HashMap<String, HashSet<String>> = HashMap::new();
+    let mut calls: HashMap<String, HashSet<String>> = HashMap::new();
+    for (name, overloads) in functions {
+        let (r, c) = (reads.entry(name.clone()).or_default(), calls.entry(name.clone()).or_default());
+        for function in overloads.iter() {
+            for default in function.params.iter().filter_map(|p| p.default.as_ref()) {
+                collect_reads(default, r, c);
+            }
+            collect_reads(&function.body, r, c);
+        }
+    }
+    // Fold in the reads of callees until nothing changes
+    loop {
+        let mut grew = false;
+        for (name, callees) in &calls {
+            let inherited: HashSet<String> = callees.iter().filter_map(|c| reads.get(c)).flatten().cloned().collect();
+            let own = reads.get_mut(name).expect("every function has reads");
+            let before = own.len();
+            own.extend(inherited);
+            grew |= own.len() != before;
+        }
+        if !grew {
+            return reads;
+        }
+    }
+}
+
+// Every name `expr` reads, and every function it may call. Parameters of
+// sums, products and `let` count as reads too, which only reruns more.
+fn collect_reads(expr: &Expr, reads: &mut HashSet<String>, calls: &mut HashSet<String>) {
+    struct Reads<'a> {
+        reads: &'a mut HashSet<String>,
+        calls: &'a mut HashSet<String>,
+    }
+    impl ExprVisitor for Reads<'_> {
+        fn visit_expr(&mut self, expr: &Expr) {
+            match expr {
+                // A variable holding a function, or the function itself; only
+                // calling it reads what the function does
+                Expr::Ident(name) | Expr::FunctionRef(name) => {
+                    self.reads.insert(name.clone());
+                }
+                Expr::FunctionCall { name, .. } => {
+                    // A list called as a polynomial is read like a variable
+                    self.reads.insert(name.clone());
+                    self.calls.insert(name.clone());
+                }
+                Expr::Apply { callee, .. } => {
+                    let mut target = callee.as_ref();
+                    while let Expr::Bind { callee, .. } = target {
+                        target = callee;
+                    }
+                    if let Expr::FunctionRef(name) = target {
+                        self.calls.insert(name.clone());
+                    }
+                }
+                _ => {}
+            }
+            walk_expr(self, expr);
+        }
+    }
+    Reads { reads, calls }.visit_expr(expr);
+}
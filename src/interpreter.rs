@@ -1,363 +1,1921 @@
 use crate::bytecode::{Bytecode, Program};
-use std::collections::HashMap;
-use crate::ast::Expr;
-// use std::io::Write; // Commented out for clarity
-// Evaluate an AST expression in the interpreter context (for user function bodies)
-fn eval_expr(
-    expr: &Expr,
-    vars: &mut HashMap<String, f64>,
-    user_functions: &HashMap<String, (String, Expr)>,
-    rng: &mut impl rand::RngCore,
-) -> Result<f64, &'static str> {
-    use crate::lexer::SpecialFunction;
-    match expr {
-        Expr::Number(n) => Ok(*n),
-        Expr::Ident(name) => {
-            match vars.get(name).copied() {
-                Some(val) => Ok(val),
-                None => {
-                    Err("Variable not found in function body")
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use crate::ast::{Expr, Overloads, UserFunctions};
+use crate::engine::{CancellationToken, VariableResolver};
+use crate::error::RuntimeError;
+use crate::special::Reciprocal;
+
+/// How many instructions run between two polls of a cancellation token.
+pub const DEFAULT_CHECK_INTERVAL: u32 = 1024;
+
+/// Largest seed `seed(n)` takes and `randstate()` gives, so that every one is
+/// a number held exactly.
+const MAX_SEED: u64 = 1 << 53;
+
+/// Polls an optional cancellation token every `interval` ticks, and counts
+/// the ticks left of an optional fuel budget. Goes along with every
+/// evaluation, so it also carries whether the run is strict.
+struct CancelCheck<'a> {
+    token: Option<&'a CancellationToken>,
+    interval: u32,
+    countdown: u32,
+    fuel: Option<(u64, u64)>,
+    strict: bool,
+}
+
+impl<'a> CancelCheck<'a> {
+    fn new(token: Option<&'a CancellationToken>, interval: u32, fuel: Option<u64>, strict: bool) -> Self {
+        CancelCheck { token, interval, countdown: interval, fuel: fuel.map(|fuel| (fuel, fuel)), strict }
+    }
+
+    // `cot`, `sec` or `csc` of `x`; a pole fails in strict mode
+    fn reciprocal(&self, f: Reciprocal, x: f64) -> Result<f64, RuntimeError> {
+        if self.strict && f.pole(x).is_some() {
+            return Err(RuntimeError::Pole { function: f.name(), arg: x });
+        }
+        Ok(f.eval(x))
+    }
+
+    #[inline]
+    fn tick(&mut self) -> Result<(), RuntimeError> {
+        if let Some((budget, left)) = &mut self.fuel {
+            if *left == 0 {
+                return Err(RuntimeError::OutOfFuel(*budget));
+            }
+            *left -= 1;
+        }
+        if let Some(token) = self.token {
+            self.countdown -= 1;
+            if self.countdown == 0 {
+                self.countdown = self.interval;
+                if token.is_cancelled() {
+                    return Err(RuntimeError::Cancelled);
                 }
             }
-        },
-        Expr::Assign { name, expr } => {
-            let val = eval_expr(expr, vars, user_functions, rng)?;
-            vars.insert(name.clone(), val);
-            Ok(val)
         }
-        Expr::BinaryOp { left, op, right } => {
-            let l = eval_expr(left, vars, user_functions, rng)?;
-            let r = eval_expr(right, vars, user_functions, rng)?;
-            Ok(match op {
-                crate::lexer::BinaryOperator::Plus => l + r,
-                crate::lexer::BinaryOperator::Minus => l - r,
-                crate::lexer::BinaryOperator::Star => l * r,
-                crate::lexer::BinaryOperator::Slash => l / r,
-                crate::lexer::BinaryOperator::Pow => l.powf(r),
-            })
+        Ok(())
+    }
+}
+/// Counts of what the runs on a `VmState` did, for embedders planning
+/// capacity. Collected once `VmState::set_stats` turns them on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VmStats {
+    /// Instructions executed, including those of nested bodies.
+    pub instructions: u64,
+    /// The most values one value stack held at a time.
+    pub max_stack_depth: usize,
+    /// Terms of `sum` and `product` loops run.
+    pub loop_iterations: u64,
+    /// User function calls made.
+    pub user_calls: u64,
+}
+
+/// List values: named list variables and a separate operand stack for list
+/// arguments, so the number stack stays plain `f64`. Both change only through
+/// the methods below, which keep `bytes` up to date for the memory limit.
+#[derive(Debug, Default)]
+struct Lists {
+    vars: HashMap<String, Arc<[f64]>>,
+    stack: Vec<Arc<[f64]>>,
+    /// Bytes the items of every list in `vars` and `stack` take
+    bytes: usize,
+}
+
+impl Lists {
+    fn push(&mut self, list: Arc<[f64]>) {
+        self.bytes += size_of_val(&*list);
+        self.stack.push(list);
+    }
+
+    fn pop(&mut self) -> Option<Arc<[f64]>> {
+        let list = self.stack.pop()?;
+        self.bytes -= size_of_val(&*list);
+        Some(list)
+    }
+
+    /// The top `n` lists of the stack, bottom first.
+    fn pop_n(&mut self, n: usize) -> Option<Vec<Arc<[f64]>>> {
+        let start = self.stack.len().checked_sub(n)?;
+        let lists = self.stack.split_off(start);
+        self.bytes -= lists.iter().map(|list| size_of_val(&**list)).sum::<usize>();
+        Some(lists)
+    }
+
+    fn clear_stack(&mut self) {
+        while self.pop().is_some() {}
+    }
+
+    fn insert(&mut self, name: String, list: Arc<[f64]>) {
+        self.bytes += size_of_val(&*list);
+        if let Some(old) = self.vars.insert(name, list) {
+            self.bytes -= size_of_val(&*old);
         }
-        Expr::Function { func, arg } => {
-            let val = eval_expr(arg, vars, user_functions, rng)?;
-            Ok(match func {
-                SpecialFunction::Sin => val.sin(),
-                SpecialFunction::Cos => val.cos(),
-                SpecialFunction::Tan => val.tan(),
-                SpecialFunction::Cot => 1.0 / val.tan(),
-                SpecialFunction::Sec => 1.0 / val.cos(),
-                SpecialFunction::Csc => 1.0 / val.sin(),
-                SpecialFunction::Sinh => val.sinh(),
-                SpecialFunction::Cosh => val.cosh(),
-                SpecialFunction::Tanh => val.tanh(),
-                SpecialFunction::Asinh => val.asinh(),
-                SpecialFunction::Acosh => val.acosh(),
-                SpecialFunction::Atanh => val.atanh(),
-                SpecialFunction::Exp => val.exp(),
-                SpecialFunction::Log => val.ln(),
-                SpecialFunction::Log10 => val.log10(),
-                SpecialFunction::Log2 => val.log2(),
-                SpecialFunction::Sqrt => val.sqrt(),
-                SpecialFunction::Abs => val.abs(),
-                SpecialFunction::Asin => val.asin(),
-                SpecialFunction::Acos => val.acos(),
-                SpecialFunction::Atan => val.atan(),
-                SpecialFunction::Acot => (1.0 / val).atan(),
-                SpecialFunction::Asec => (1.0 / val).acos(),
-                SpecialFunction::Acsc => (1.0 / val).asin(),
-                SpecialFunction::Pow => val, // Not used here
-                SpecialFunction::Fact => factorial(val),
-                SpecialFunction::LogBase => return Err("log base not supported in user function body"),
-                SpecialFunction::Floor => val.floor(),
-                SpecialFunction::Rand => rand::Rng::random(rng),
-                SpecialFunction::RandInt => return Err("randint not supported in user function body"),
-            })
+    }
+
+    fn remove(&mut self, name: &str) {
+        if let Some(old) = self.vars.remove(name) {
+            self.bytes -= size_of_val(&*old);
         }
-        Expr::FunctionCall { name, arg } => {
-            let arg_val = eval_expr(arg, vars, user_functions, rng)?;
-            let (param, body) = user_functions.get(name).ok_or("User-defined function not found in body")?;
-            let old = vars.insert(param.clone(), arg_val);
-            let result = eval_expr(body, vars, user_functions, rng)?;
-            if let Some(v) = old {
-                vars.insert(param.clone(), v);
-            } else {
-                vars.remove(param);
-            }
-            Ok(result)
+    }
+}
+
+/// Function values: variables holding a user function, and an operand stack
+/// for the function `CallIndirect` calls, changed only through the methods
+/// below like `Lists`.
+#[derive(Debug, Default)]
+struct Functions {
+    vars: HashMap<String, FunctionValue>,
+    stack: Vec<FunctionValue>,
+    /// Bytes the bound arguments of every function in `vars` and `stack` take
+    bytes: usize,
+}
+
+impl Functions {
+    fn push(&mut self, function: FunctionValue) {
+        self.bytes += size_of_val(&*function.bound);
+        self.stack.push(function);
+    }
+
+    fn pop(&mut self) -> Option<FunctionValue> {
+        let function = self.stack.pop()?;
+        self.bytes -= size_of_val(&*function.bound);
+        Some(function)
+    }
+
+    fn insert(&mut self, name: String, function: FunctionValue) {
+        self.bytes += size_of_val(&*function.bound);
+        if let Some(old) = self.vars.insert(name, function) {
+            self.bytes -= size_of_val(&*old.bound);
         }
-        Expr::Sequence(exprs) => {
-            let mut last = 0.0;
-            for e in exprs {
-                last = eval_expr(e, vars, user_functions, rng)?;
-            }
-            Ok(last)
+    }
+
+    fn remove(&mut self, name: &str) {
+        if let Some(old) = self.vars.remove(name) {
+            self.bytes -= size_of_val(&*old.bound);
+        }
+    }
+}
+
+/// A user function by name, with the leading arguments `bind` fixed.
+#[derive(Debug, Clone)]
+struct FunctionValue {
+    name: String,
+    bound: Vec<f64>,
+}
+
+/// Number variables, one slot each. Compiled code reaches a slot by index
+/// through its program's `Frame` header; `set_var` and user function bodies
+/// go by name, and the names also label slots in error messages.
+#[derive(Debug, Default)]
+struct Frame {
+    values: Vec<Option<f64>>,
+    /// Slots bound by `StoreConst`, which `StoreVar` may not overwrite
+    constant: Vec<bool>,
+    names: Vec<String>,
+    index: HashMap<String, usize>,
+    /// Total length of `names`, for `bytes`
+    name_bytes: usize,
+}
+
+impl Frame {
+    /// The slot of `name`, adding an unbound one the first time.
+    fn slot(&mut self, name: &str) -> usize {
+        if let Some(&slot) = self.index.get(name) {
+            return slot;
+        }
+        self.values.push(None);
+        self.constant.push(false);
+        self.names.push(name.to_string());
+        self.index.insert(name.to_string(), self.names.len() - 1);
+        self.name_bytes += name.len();
+        self.names.len() - 1
+    }
+
+    fn get(&self, name: &str) -> Option<f64> {
+        self.index.get(name).and_then(|&slot| self.values[slot])
+    }
+
+    /// Roughly the memory the slots take, counting each name twice for the index.
+    fn bytes(&self) -> usize {
+        self.values.len() * (size_of::<Option<f64>>() + size_of::<bool>() + 2 * size_of::<String>()) + 2 * self.name_bytes
+    }
+
+    /// Binds `name` and returns its previous value, for `restore`.
+    fn insert(&mut self, name: &str, value: f64) -> Option<f64> {
+        let slot = self.slot(name);
+        self.values[slot].replace(value)
+    }
+
+    /// Makes `name` assignable for a call that binds it or assigns it
+    /// locally, returning what `unshadow` puts back when the call ends.
+    fn shadow(&mut self, name: &str) -> Shadowed {
+        let slot = self.slot(name);
+        let constant = std::mem::replace(&mut self.constant[slot], false);
+        Shadowed { slot, value: self.values[slot], constant }
+    }
+
+    fn unshadow(&mut self, hidden: Shadowed) {
+        self.values[hidden.slot] = hidden.value;
+        self.constant[hidden.slot] = hidden.constant;
+    }
+
+    fn remove(&mut self, name: &str) {
+        if let Some(&slot) = self.index.get(name) {
+            self.values[slot] = None;
+        }
+    }
+
+    fn is_constant(&self, name: &str) -> bool {
+        self.index.get(name).is_some_and(|&slot| self.constant[slot])
+    }
+
+    /// The variables that currently have a value, with it.
+    fn bound(&self) -> impl Iterator<Item = (&str, f64)> {
+        self.names.iter().zip(&self.values).filter_map(|(n, v)| Some((n.as_str(), (*v)?)))
+    }
+}
+
+/// A variable as it was before a user function call shadowed it.
+#[derive(Debug)]
+struct Shadowed {
+    slot: usize,
+    value: Option<f64>,
+    constant: bool,
+}
+
+/// Everything a run shares with the loop and `let` bodies nested in it: the
+/// bindings, one random number generator, and operand stacks to reuse.
+#[derive(Debug)]
+struct Env {
+    frame: Frame,
+    /// The frame slot for each slot index of the running program, set by its
+    /// `Frame` header
+    slots: Vec<usize>,
+    lists: Lists,
+    functions: Functions,
+    /// Asked for variables that have no value when compiled code reads them
+    resolver: Option<Box<dyn VariableResolver>>,
+    rng: StdRng,
+    /// Cleared stacks returned by finished nested bodies
+    spare_stacks: Vec<Vec<f64>>,
+    /// Set by a `LoopWhile` whose condition is false, for the loop running the term
+    loop_ended: bool,
+    /// Command-line arguments of a script, read by `arg` and `argc`
+    args: Vec<String>,
+    /// Whether `env` reads the process environment; if not, it gives its default
+    read_env: bool,
+    /// Whether `loadcsv` and `savecsv` may touch files
+    fs_access: bool,
+    /// Directories `loadcsv` may read from even without `fs_access`
+    import_dirs: Vec<PathBuf>,
+    /// User function calls in progress, and how many may be
+    call_depth: usize,
+    max_call_depth: Option<usize>,
+    /// Bytes the stack, variables and lists may take in all
+    memory_limit: Option<usize>,
+    /// Bytes held for the calls in progress: their arguments and the values
+    /// their callers left on the stack
+    call_bytes: usize,
+    /// What the runs did, if asked for
+    stats: Option<VmStats>,
+    /// Frame slots of the variables `output` statements named, first named first
+    outputs: Vec<usize>,
+    /// Digits after the point a `#pragma precision` asked results to print with
+    precision: Option<usize>,
+    /// Units labelling named results, by frame slot
+    units: HashMap<usize, String>,
+    /// Lines `print` wrote, if kept for the host rather than written to stdout
+    printed: Option<Vec<String>>,
+}
+
+impl Env {
+    // Script argument `n` (1-based) as a number
+    fn arg(&self, n: f64) -> Result<f64, RuntimeError> {
+        let text = Some(n)
+            .filter(|n| *n >= 1.0 && n.fract() == 0.0)
+            .and_then(|n| self.args.get(n as usize - 1))
+            .ok_or_else(|| format!("arg({}) out of range: the script got {} arguments", n, self.args.len()))?;
+        text.trim().parse().map_err(|_| format!("argument {} (`{}`) is not a number", n, text).into())
+    }
+
+    // Column `column` (1-based) of the CSV file at `path`
+    fn load_csv(&self, path: &str, column: f64) -> Result<Vec<f64>, RuntimeError> {
+        if !self.fs_access && !self.may_import(Path::new(path)) {
+            return Err(RuntimeError::FileAccessDenied { function: "loadcsv", path: path.to_string() });
+        }
+        let column = crate::numtheory::as_u64(column)
+            .filter(|&c| c >= 1)
+            .ok_or_else(|| format!("loadcsv column {} must be a whole number from 1", column))?;
+        let text = std::fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        crate::csv::column(&text, column as usize).map_err(|e| format!("{}: {}", path, e).into())
+    }
+
+    // Writes `columns` as the CSV file at `path`, returning its row count
+    fn save_csv(&self, path: &str, columns: &[Arc<[f64]>]) -> Result<f64, RuntimeError> {
+        if !self.fs_access {
+            return Err(RuntimeError::FileAccessDenied { function: "savecsv", path: path.to_string() });
         }
-        Expr::FunctionDef { .. } => Err("Nested function definitions not supported in body"),
-        Expr::Sum { from, to, param, body } => {
-            let from_val = eval_expr(from, vars, user_functions, rng)?;
-            let to_val = eval_expr(to, vars, user_functions, rng)?;
-            let from_i = from_val.ceil() as i64;
-            let to_i = to_val.floor() as i64;
-            let mut acc = 0.0;
-            for i in from_i..=to_i {
-                let old = vars.insert(param.clone(), i as f64);
-                acc += eval_expr(body, vars, user_functions, rng)?;
-                if let Some(v) = old { vars.insert(param.clone(), v); } else { vars.remove(param); }
+        let columns: Vec<&[f64]> = columns.iter().map(|c| &**c).collect();
+        std::fs::write(path, crate::csv::write(&columns)).map_err(|e| format!("cannot write {}: {}", path, e))?;
+        Ok(columns.iter().map(|c| c.len()).max().unwrap_or(0) as f64)
+    }
+
+    // Whether `path` is a file under one of the import directories, after
+    // resolving `..` and symbolic links
+    fn may_import(&self, path: &Path) -> bool {
+        let Ok(path) = path.canonicalize() else {
+            return false;
+        };
+        self.import_dirs.iter().filter_map(|dir| dir.canonicalize().ok()).any(|dir| path.starts_with(dir))
+    }
+
+    // Roughly the bytes a run takes while `stack` is its value stack: the
+    // stacks of the calls in progress, the variables, and every list
+    fn memory_used(&self, stack: &[f64]) -> usize {
+        size_of_val(stack) + self.call_bytes + self.frame.bytes() + self.lists.bytes + self.functions.bytes
+    }
+
+    // Fails if the run takes more than the memory limit
+    fn check_memory(&self, stack: &[f64]) -> Result<(), RuntimeError> {
+        match self.memory_limit {
+            Some(limit) if self.memory_used(stack) > limit => Err(RuntimeError::MemoryLimitExceeded(limit)),
+            _ => Ok(()),
+        }
+    }
+
+    // Puts a newly made list on the list stack, within the memory limit
+    fn push_list(&mut self, list: Arc<[f64]>, stack: &[f64]) -> Result<(), RuntimeError> {
+        self.lists.push(list);
+        self.check_memory(stack)
+    }
+
+    // The environment variable `name` as a number, or `default` if it is unset
+    fn env_var(&self, name: &str, default: f64) -> Result<f64, RuntimeError> {
+        let Some(text) = std::env::var_os(name).filter(|_| self.read_env) else {
+            return Ok(default);
+        };
+        let text = text.to_string_lossy();
+        // The value stays out of the error, which may reach whoever sent the program
+        text.trim().parse().map_err(|_| format!("environment variable {} is not a number", name).into())
+    }
+
+    #[inline]
+    fn slot(&self, i: usize) -> Result<usize, RuntimeError> {
+        self.slots.get(i).copied().ok_or(RuntimeError::InvalidSlot(i))
+    }
+
+    /// Calls the user function `name` with the top `argc` values of `stack`
+    /// as its arguments, first to last, and pushes the result. Without such a
+    /// function, a list variable `name` is evaluated as a polynomial.
+    fn call(
+        &mut self,
+        name: &str,
+        argc: usize,
+        user_functions: &UserFunctions,
+        stack: &mut Vec<f64>,
+        cancel: &mut CancelCheck,
+    ) -> Result<(), RuntimeError> {
+        let Some(overloads) = user_functions.get(name) else {
+            let p = self.lists.vars.get(name).ok_or_else(|| RuntimeError::UndefinedFunction(name.to_string()))?;
+            if argc != 1 {
+                return Err(format!("polynomial `{}` expects 1 argument, got {}", name, argc).into());
+            }
+            let x = stack.pop().ok_or(RuntimeError::StackUnderflow("user function call"))?;
+            stack.push(crate::poly::eval(p, x));
+            return Ok(());
+        };
+        let start = stack.len().checked_sub(argc).ok_or(RuntimeError::StackUnderflow("user function call"))?;
+        let args = stack.split_off(start);
+        // The caller's stack waits for the call, which holds its arguments
+        let held = size_of_val(&**stack) + size_of_val(&*args);
+        self.call_bytes += held;
+        let result = self.check_memory(&[]).and_then(|()| self.call_function(name, overloads, &args, user_functions, cancel));
+        self.call_bytes -= held;
+        stack.push(result?);
+        Ok(())
+    }
+
+    // Picks the definition of `name` for this many arguments and binds `args` to
+    // its parameters, running the defaults of the ones left out in order, then
+    // runs the compiled body with those bindings shadowing any variables of the same names
+    fn call_function(
+        &mut self,
+        name: &str,
+        overloads: &Overloads,
+        args: &[f64],
+        user_functions: &UserFunctions,
+        cancel: &mut CancelCheck,
+    ) -> Result<f64, RuntimeError> {
+        let Some(function) = overloads.resolve(args.len()) else {
+            return Err(format!("`{}` expects {}, got {}", name, overloads.describe_arity(), args.len()).into());
+        };
+        if self.max_call_depth.is_some_and(|max| self.call_depth >= max) {
+            return Err(RuntimeError::CallDepthExceeded(self.call_depth));
+        }
+        log::trace!(target: "vm", "call {}{:?}", name, args);
+        if let Some(stats) = &mut self.stats {
+            stats.user_calls += 1;
+        }
+        self.call_depth += 1;
+        let code = function.code();
+        // Variables the statements of a multi-line body assign are local to the call
+        let locals: Vec<&String> = match &function.body {
+            Expr::Sequence(statements) => statements
+                .iter()
+                .filter_map(|s| match s {
+                    Expr::Assign { name, .. } | Expr::Const { name, .. } => Some(name),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        // The body and defaults bring their own `Frame`; the caller's slots come back after
+        let caller_slots = std::mem::take(&mut self.slots);
+        let mut shadowed: Vec<Shadowed> = locals.into_iter().map(|name| self.frame.shadow(name)).collect();
+        let mut bound = Ok(());
+        for (i, param) in function.params.iter().enumerate() {
+            let value = match (args.get(i), &code.defaults[i]) {
+                (Some(&value), _) => Ok(value),
+                (None, Some(default)) => self.run_nested(default, user_functions, cancel, "default"),
+                // `resolve` left out only parameters with defaults
+                (None, None) => unreachable!("missing argument without a default"),
+            };
+            match value {
+                Ok(value) => {
+                    let hidden = self.frame.shadow(&param.name);
+                    self.frame.values[hidden.slot] = Some(value);
+                    shadowed.push(hidden);
+                }
+                Err(e) => {
+                    bound = Err(e);
+                    break;
+                }
             }
-            Ok(acc)
         }
-        Expr::Product { from, to, param, body } => {
-            let from_val = eval_expr(from, vars, user_functions, rng)?;
-            let to_val = eval_expr(to, vars, user_functions, rng)?;
-            let from_i = from_val.ceil() as i64;
-            let to_i = to_val.floor() as i64;
-            let mut acc = 1.0;
-            for i in from_i..=to_i {
-                let old = vars.insert(param.clone(), i as f64);
-                acc *= eval_expr(body, vars, user_functions, rng)?;
-                if let Some(v) = old { vars.insert(param.clone(), v); } else { vars.remove(param); }
+        let result = bound.and_then(|()| self.run_nested(&code.body, user_functions, cancel, "function body"));
+        for hidden in shadowed.into_iter().rev() {
+            self.frame.unshadow(hidden);
+        }
+        self.slots = caller_slots;
+        self.call_depth -= 1;
+        result
+    }
+
+    /// The value the resolver, if any, supplies for the unbound `slot`.
+    fn resolve(&self, slot: usize) -> Option<f64> {
+        self.resolver.as_ref()?.resolve(&self.frame.names[slot])
+    }
+
+    /// Runs one term of a sum or product; `None` if its `while:` condition
+    /// ended the loop instead.
+    fn run_term(
+        &mut self,
+        body: &Program,
+        user_functions: &UserFunctions,
+        cancel: &mut CancelCheck,
+    ) -> Result<Option<f64>, RuntimeError> {
+        if let Some(stats) = &mut self.stats {
+            stats.loop_iterations += 1;
+        }
+        let result = self.run_nested(body, user_functions, cancel, "body");
+        // An ended term leaves no value, which is not an error
+        if std::mem::take(&mut self.loop_ended) {
+            return Ok(None);
+        }
+        result.map(Some)
+    }
+
+    /// Runs a nested body on a spare stack and returns the value it leaves.
+    /// `what` names the body in the error if it leaves nothing.
+    fn run_nested(
+        &mut self,
+        body: &Program,
+        user_functions: &UserFunctions,
+        cancel: &mut CancelCheck,
+        what: &'static str,
+    ) -> Result<f64, RuntimeError> {
+        let mut stack = self.spare_stacks.pop().unwrap_or_default();
+        let result = self.execute(body, user_functions, &mut stack, cancel);
+        let value = stack.pop();
+        stack.clear();
+        self.spare_stacks.push(stack);
+        result?;
+        value.ok_or(RuntimeError::NoResult(Some(what)))
+    }
+
+    /// Runs `program` on `stack`: the one dispatch loop behind `VmState::run`,
+    /// nested bodies and loop terms.
+    fn execute(
+        &mut self,
+        program: &Program,
+        user_functions: &UserFunctions,
+        stack: &mut Vec<f64>,
+        cancel: &mut CancelCheck,
+    ) -> Result<(), RuntimeError> {
+        let env = self;
+        let mut pc = 0;
+        while let Some(instr) = program.get(pc) {
+            pc += 1;
+            cancel.tick()?;
+            // Checked where fuel is, so that growing the value stack or the
+            // variables cannot slip past the limit between list pushes and calls
+            if env.memory_limit.is_some() {
+                env.check_memory(stack)?;
+            }
+            if let Some(stats) = &mut env.stats {
+                stats.instructions += 1;
+                stats.max_stack_depth = stats.max_stack_depth.max(stack.len());
+            }
+            match instr {
+                Bytecode::CallUserFunction(name) => env.call(name, 1, user_functions, stack, cancel)?,
+                Bytecode::CallUserFunctionN(name, argc) => env.call(name, *argc, user_functions, stack, cancel)?,
+                Bytecode::Rand => {
+                    stack.push(rand::Rng::random(&mut env.rng));
+                }
+                Bytecode::Seed => {
+                    let n = stack.pop().ok_or(RuntimeError::StackUnderflow("Seed"))?;
+                    if !(0.0..=MAX_SEED as f64).contains(&n) || n.fract() != 0.0 {
+                        return Err(format!("seed({}) must be a whole number from 0 to 2^53", n).into());
+                    }
+                    env.rng = StdRng::seed_from_u64(n as u64);
+                    stack.push(n);
+                }
+                Bytecode::RandState => {
+                    // Going on from the seed read makes `seed` of it replay what follows
+                    let state = rand::Rng::random_range(&mut env.rng, 0..=MAX_SEED);
+                    env.rng = StdRng::seed_from_u64(state);
+                    stack.push(state as f64);
+                }
+                Bytecode::Arg => {
+                    let n = stack.pop().ok_or(RuntimeError::StackUnderflow("Arg"))?;
+                    stack.push(env.arg(n)?);
+                }
+                Bytecode::Argc => stack.push(env.args.len() as f64),
+                Bytecode::Env(name) => {
+                    let default = stack.pop().ok_or(RuntimeError::StackUnderflow("Env"))?;
+                    stack.push(env.env_var(name, default)?);
+                }
+                Bytecode::LoadCsv(path) => {
+                    let column = stack.pop().ok_or(RuntimeError::StackUnderflow("LoadCsv"))?;
+                    let data = env.load_csv(path, column)?;
+                    env.push_list(data.into(), stack)?;
+                }
+                Bytecode::SaveCsv(path, n) => {
+                    let columns = env.lists.pop_n(*n).ok_or(RuntimeError::ListStackUnderflow("SaveCsv"))?;
+                    stack.push(env.save_csv(path, &columns)?);
+                }
+                Bytecode::Precision(digits) => env.precision = Some(*digits),
+                Bytecode::Unit(i, unit) => {
+                    let slot = env.slot(*i)?;
+                    env.units.insert(slot, unit.clone());
+                }
+                Bytecode::Print(template, n) => {
+                    if stack.len() < *n {
+                        return Err(RuntimeError::StackUnderflow("Print"));
+                    }
+                    let values = stack.split_off(stack.len() - n);
+                    let line = crate::text::format(template, &values);
+                    match &mut env.printed {
+                        Some(printed) => printed.push(line),
+                        None => println!("{}", line),
+                    }
+                    stack.push(values.last().copied().unwrap_or(0.0));
+                }
+                Bytecode::RandInt => {
+                    let b = stack.pop().ok_or(RuntimeError::StackUnderflow("RandInt (b)"))?;
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("RandInt (a)"))?;
+                    let (amin, amax) = if a <= b { (a, b) } else { (b, a) };
+                    let amin = amin.ceil() as i64;
+                    let amax = amax.floor() as i64;
+                    if amin > amax {
+                        return Err("Invalid range for randint: min > max".into());
+                    }
+                    let val = rand::Rng::random_range(&mut env.rng, amin..=amax);
+                    stack.push(val as f64);
+                }
+                Bytecode::LogBase => {
+                    let b = stack.pop().ok_or(RuntimeError::StackUnderflow("LogBase (b)"))?;
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("LogBase (a)"))?;
+                    stack.push(b.log(a));
+                }
+                Bytecode::Assert(line) => {
+                    let c = stack.pop().ok_or(RuntimeError::StackUnderflow("Assert"))?;
+                    stack.push(check_assert(c, *line)?);
+                }
+                Bytecode::AssertEq(line) => {
+                    let eps = stack.pop().ok_or(RuntimeError::StackUnderflow("AssertEq (eps)"))?;
+                    let b = stack.pop().ok_or(RuntimeError::StackUnderflow("AssertEq (b)"))?;
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("AssertEq (a)"))?;
+                    stack.push(check_assert_eq(a, b, eps, *line)?);
+                }
+                Bytecode::Less => {
+                    let b = stack.pop().ok_or(RuntimeError::StackUnderflow("Less"))?;
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Less"))?;
+                    stack.push(truth(a < b));
+                }
+                Bytecode::LessEq => {
+                    let b = stack.pop().ok_or(RuntimeError::StackUnderflow("LessEq"))?;
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("LessEq"))?;
+                    stack.push(truth(a <= b));
+                }
+                Bytecode::Greater => {
+                    let b = stack.pop().ok_or(RuntimeError::StackUnderflow("Greater"))?;
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Greater"))?;
+                    stack.push(truth(a > b));
+                }
+                Bytecode::GreaterEq => {
+                    let b = stack.pop().ok_or(RuntimeError::StackUnderflow("GreaterEq"))?;
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("GreaterEq"))?;
+                    stack.push(truth(a >= b));
+                }
+                Bytecode::Equal => {
+                    let b = stack.pop().ok_or(RuntimeError::StackUnderflow("Equal"))?;
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Equal"))?;
+                    stack.push(truth(a == b));
+                }
+                Bytecode::NotEqual => {
+                    let b = stack.pop().ok_or(RuntimeError::StackUnderflow("NotEqual"))?;
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("NotEqual"))?;
+                    stack.push(truth(a != b));
+                }
+                Bytecode::JumpIfFalse(n) => {
+                    let cond = stack.pop().ok_or(RuntimeError::StackUnderflow("JumpIfFalse"))?;
+                    if !is_true(cond) {
+                        pc += n;
+                    }
+                }
+                Bytecode::Jump(n) => pc += n,
+                // The value stays on top of the stack as the result
+                Bytecode::Return => return Ok(()),
+                Bytecode::LoopWhile => {
+                    let cond = stack.pop().ok_or(RuntimeError::StackUnderflow("LoopWhile"))?;
+                    if !is_true(cond) {
+                        env.loop_ended = true;
+                        return Ok(());
+                    }
+                }
+                Bytecode::Timed(body) => {
+                    let start = std::time::Instant::now();
+                    let value = env.run_nested(body, user_functions, cancel, "time")?;
+                    eprintln!("time: {:?}", start.elapsed());
+                    stack.push(value);
+                }
+                Bytecode::LetIn { slot, body } => {
+                    let slot = env.slot(*slot)?;
+                    if env.frame.constant[slot] {
+                        return Err(RuntimeError::RebindConstant(env.frame.names[slot].clone()));
+                    }
+                    let val = stack.pop().ok_or(RuntimeError::StackUnderflow("LetIn"))?;
+                    let old = env.frame.values[slot].replace(val);
+                    let result = env.run_nested(body, user_functions, cancel, "let body");
+                    env.frame.values[slot] = old;
+                    stack.push(result?);
+                }
+                Bytecode::Atan2 => {
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("Atan2 (x)"))?;
+                    let y = stack.pop().ok_or(RuntimeError::StackUnderflow("Atan2 (y)"))?;
+                    stack.push(y.atan2(x));
+                }
+                Bytecode::Root => {
+                    let n = stack.pop().ok_or(RuntimeError::StackUnderflow("Root (n)"))?;
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("Root (x)"))?;
+                    stack.push(nth_root(x, n));
+                }
+                Bytecode::Expm1 => {
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("Expm1"))?;
+                    stack.push(x.exp_m1());
+                }
+                Bytecode::Ln1p => {
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("Ln1p"))?;
+                    stack.push(x.ln_1p());
+                }
+                Bytecode::Exp2 => {
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("Exp2"))?;
+                    stack.push(x.exp2());
+                }
+                Bytecode::Round => {
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("Round"))?;
+                    stack.push(x.round());
+                }
+                Bytecode::RoundTo => {
+                    let n = stack.pop().ok_or(RuntimeError::StackUnderflow("RoundTo (n)"))?;
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("RoundTo (x)"))?;
+                    stack.push(round_to(x, n, false));
+                }
+                Bytecode::RoundEven => {
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("RoundEven"))?;
+                    stack.push(x.round_ties_even());
+                }
+                Bytecode::RoundEvenTo => {
+                    let n = stack.pop().ok_or(RuntimeError::StackUnderflow("RoundEvenTo (n)"))?;
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("RoundEvenTo (x)"))?;
+                    stack.push(round_to(x, n, true));
+                }
+                Bytecode::MakeList(n) => {
+                    let start = stack.len().checked_sub(*n).ok_or(RuntimeError::StackUnderflow("MakeList"))?;
+                    env.push_list(stack.split_off(start).into(), stack)?;
+                }
+                Bytecode::StoreList(name) => {
+                    if env.frame.is_constant(name) {
+                        return Err(RuntimeError::AssignToConstant(name.clone()));
+                    }
+                    let list = env.lists.pop().ok_or(RuntimeError::ListStackUnderflow("StoreList"))?;
+                    env.frame.remove(name);
+                    env.functions.remove(name);
+                    env.lists.insert(name.clone(), list);
+                    env.check_memory(stack)?;
+                }
+                Bytecode::LoadList(name) => {
+                    let list = env.lists.vars.get(name).ok_or_else(|| RuntimeError::NotAList(name.clone()))?;
+                    env.lists.push(list.clone());
+                }
+                Bytecode::PushFunction(name) => {
+                    let function = match env.functions.vars.get(name) {
+                        Some(function) => function.clone(),
+                        None => FunctionValue { name: name.clone(), bound: Vec::new() },
+                    };
+                    env.functions.push(function);
+                }
+                Bytecode::StoreFunction(name) => {
+                    if env.frame.is_constant(name) {
+                        return Err(RuntimeError::AssignToConstant(name.clone()));
+                    }
+                    let function = env.functions.pop().ok_or(RuntimeError::FunctionStackUnderflow("StoreFunction"))?;
+                    env.frame.remove(name);
+                    env.lists.remove(name);
+                    env.functions.insert(name.clone(), function);
+                }
+                Bytecode::Bind(n) => {
+                    let mut function = env.functions.pop().ok_or(RuntimeError::FunctionStackUnderflow("Bind"))?;
+                    let start = stack.len().checked_sub(*n).ok_or(RuntimeError::StackUnderflow("Bind"))?;
+                    function.bound.extend(stack.drain(start..));
+                    env.functions.push(function);
+                }
+                Bytecode::CallIndirect(argc) => {
+                    let function = env.functions.pop().ok_or(RuntimeError::FunctionStackUnderflow("CallIndirect"))?;
+                    // Fixed arguments come before the ones the call passes
+                    let start = stack.len().checked_sub(*argc).ok_or(RuntimeError::StackUnderflow("CallIndirect"))?;
+                    stack.splice(start..start, function.bound.iter().copied());
+                    env.call(&function.name, function.bound.len() + argc, user_functions, stack, cancel)?;
+                }
+                Bytecode::Quantile => {
+                    let p = stack.pop().ok_or(RuntimeError::StackUnderflow("Quantile (p)"))?;
+                    let data = env.lists.pop().ok_or(RuntimeError::ListStackUnderflow("Quantile"))?;
+                    stack.push(crate::stats::quantile(&data, p, crate::stats::DEFAULT_QUANTILE_TYPE));
+                }
+                Bytecode::QuantileType => {
+                    let kind = stack.pop().ok_or(RuntimeError::StackUnderflow("QuantileType (type)"))?;
+                    let p = stack.pop().ok_or(RuntimeError::StackUnderflow("QuantileType (p)"))?;
+                    let data = env.lists.pop().ok_or(RuntimeError::ListStackUnderflow("QuantileType"))?;
+                    if kind.fract() != 0.0 || !(1.0..=9.0).contains(&kind) {
+                        return Err(format!("quantile type must be an integer from 1 to 9, got {}", kind).into());
+                    }
+                    stack.push(crate::stats::quantile(&data, p, kind as u8));
+                }
+                Bytecode::Percentile => {
+                    let p = stack.pop().ok_or(RuntimeError::StackUnderflow("Percentile (p)"))?;
+                    let data = env.lists.pop().ok_or(RuntimeError::ListStackUnderflow("Percentile"))?;
+                    stack.push(crate::stats::quantile(&data, p / 100.0, crate::stats::DEFAULT_QUANTILE_TYPE));
+                }
+                Bytecode::Erf => {
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("Erf"))?;
+                    stack.push(crate::stats::erf(x));
+                }
+                Bytecode::Erfc => {
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("Erfc"))?;
+                    stack.push(crate::stats::erfc(x));
+                }
+                Bytecode::BesselJ => {
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("BesselJ (x)"))?;
+                    let n = stack.pop().ok_or(RuntimeError::StackUnderflow("BesselJ (n)"))?;
+                    stack.push(crate::special::bessel_j(n, x));
+                }
+                Bytecode::BesselY => {
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("BesselY (x)"))?;
+                    let n = stack.pop().ok_or(RuntimeError::StackUnderflow("BesselY (n)"))?;
+                    stack.push(crate::special::bessel_y(n, x));
+                }
+                Bytecode::Zeta => {
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("Zeta"))?;
+                    stack.push(crate::special::zeta(x));
+                }
+                Bytecode::LambertW => {
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("LambertW"))?;
+                    stack.push(crate::special::lambert_w(x));
+                }
+                Bytecode::Beta => {
+                    let b = stack.pop().ok_or(RuntimeError::StackUnderflow("Beta (b)"))?;
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Beta (a)"))?;
+                    stack.push(crate::special::beta(a, b));
+                }
+                Bytecode::BetaInc => {
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("BetaInc (x)"))?;
+                    let b = stack.pop().ok_or(RuntimeError::StackUnderflow("BetaInc (b)"))?;
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("BetaInc (a)"))?;
+                    stack.push(crate::special::beta_inc(a, b, x));
+                }
+                Bytecode::GammaInc => {
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("GammaInc (x)"))?;
+                    let s = stack.pop().ok_or(RuntimeError::StackUnderflow("GammaInc (s)"))?;
+                    stack.push(crate::special::gamma_inc(s, x));
+                }
+                Bytecode::NormCdf => {
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("NormCdf"))?;
+                    stack.push(crate::stats::norm_cdf(x));
+                }
+                Bytecode::NormPdf => {
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("NormPdf"))?;
+                    stack.push(crate::stats::norm_pdf(x));
+                }
+                Bytecode::NormInv => {
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("NormInv"))?;
+                    stack.push(crate::stats::norm_inv(x));
+                }
+                Bytecode::NormCdfScaled => {
+                    let sigma = stack.pop().ok_or(RuntimeError::StackUnderflow("NormCdfScaled (sigma)"))?;
+                    let mu = stack.pop().ok_or(RuntimeError::StackUnderflow("NormCdfScaled (mu)"))?;
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("NormCdfScaled (x)"))?;
+                    stack.push(crate::stats::norm_cdf((x - mu) / sigma));
+                }
+                Bytecode::NormPdfScaled => {
+                    let sigma = stack.pop().ok_or(RuntimeError::StackUnderflow("NormPdfScaled (sigma)"))?;
+                    let mu = stack.pop().ok_or(RuntimeError::StackUnderflow("NormPdfScaled (mu)"))?;
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("NormPdfScaled (x)"))?;
+                    stack.push(crate::stats::norm_pdf((x - mu) / sigma) / sigma);
+                }
+                Bytecode::NormInvScaled => {
+                    let sigma = stack.pop().ok_or(RuntimeError::StackUnderflow("NormInvScaled (sigma)"))?;
+                    let mu = stack.pop().ok_or(RuntimeError::StackUnderflow("NormInvScaled (mu)"))?;
+                    let p = stack.pop().ok_or(RuntimeError::StackUnderflow("NormInvScaled (p)"))?;
+                    stack.push(mu + sigma * crate::stats::norm_inv(p));
+                }
+                Bytecode::TCdf => {
+                    let nu = stack.pop().ok_or(RuntimeError::StackUnderflow("TCdf (nu)"))?;
+                    let t = stack.pop().ok_or(RuntimeError::StackUnderflow("TCdf (t)"))?;
+                    stack.push(crate::stats::t_cdf(t, nu));
+                }
+                Bytecode::TInv => {
+                    let nu = stack.pop().ok_or(RuntimeError::StackUnderflow("TInv (nu)"))?;
+                    let p = stack.pop().ok_or(RuntimeError::StackUnderflow("TInv (p)"))?;
+                    stack.push(crate::stats::t_inv(p, nu));
+                }
+                Bytecode::Chi2Cdf => {
+                    let k = stack.pop().ok_or(RuntimeError::StackUnderflow("Chi2Cdf (k)"))?;
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("Chi2Cdf (x)"))?;
+                    stack.push(crate::stats::chi2_cdf(x, k));
+                }
+                Bytecode::Chi2Inv => {
+                    let k = stack.pop().ok_or(RuntimeError::StackUnderflow("Chi2Inv (k)"))?;
+                    let p = stack.pop().ok_or(RuntimeError::StackUnderflow("Chi2Inv (p)"))?;
+                    stack.push(crate::stats::chi2_inv(p, k));
+                }
+                Bytecode::IsPrime => {
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("IsPrime"))?;
+                    stack.push(truth(crate::numtheory::as_u64(x).is_some_and(crate::numtheory::is_prime)));
+                }
+                Bytecode::NextPrime => {
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("NextPrime"))?;
+                    stack.push(next_prime_after(x));
+                }
+                Bytecode::PrimePi => {
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("PrimePi"))?;
+                    stack.push(prime_pi_upto(x)?);
+                }
+                Bytecode::Factorint => {
+                    let n = stack.pop().ok_or(RuntimeError::StackUnderflow("Factorint"))?;
+                    let n = crate::numtheory::as_u64(n).ok_or_else(|| format!("factorint expects a non-negative integer, got {}", n))?;
+                    env.push_list(crate::numtheory::factorize(n).into_iter().map(|f| f as f64).collect(), stack)?;
+                }
+                Bytecode::Len => {
+                    let data = env.lists.pop().ok_or(RuntimeError::ListStackUnderflow("Len"))?;
+                    stack.push(data.len() as f64);
+                }
+                Bytecode::At => {
+                    let i = stack.pop().ok_or(RuntimeError::StackUnderflow("At (i)"))?;
+                    let data = env.lists.pop().ok_or(RuntimeError::ListStackUnderflow("At"))?;
+                    let item = crate::numtheory::as_u64(i).and_then(|i| data.get(i as usize));
+                    stack.push(*item.ok_or_else(|| format!("index {} out of range for list of length {}", i, data.len()))?);
+                }
+                Bytecode::Mod => {
+                    let m = stack.pop().ok_or(RuntimeError::StackUnderflow("Mod (m)"))?;
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Mod (a)"))?;
+                    stack.push(crate::numtheory::mod_floor(a, m));
+                }
+                Bytecode::PowMod => {
+                    let m = stack.pop().ok_or(RuntimeError::StackUnderflow("PowMod (m)"))?;
+                    let b = stack.pop().ok_or(RuntimeError::StackUnderflow("PowMod (b)"))?;
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("PowMod (a)"))?;
+                    stack.push(pow_mod_value(a, b, m)?);
+                }
+                Bytecode::InvMod => {
+                    let m = stack.pop().ok_or(RuntimeError::StackUnderflow("InvMod (m)"))?;
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("InvMod (a)"))?;
+                    stack.push(inv_mod_value(a, m)?);
+                }
+                Bytecode::Fib => {
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("Fib"))?;
+                    stack.push(crate::numtheory::fib(x));
+                }
+                Bytecode::Choose => {
+                    let k = stack.pop().ok_or(RuntimeError::StackUnderflow("Choose (k)"))?;
+                    let n = stack.pop().ok_or(RuntimeError::StackUnderflow("Choose (n)"))?;
+                    stack.push(crate::numtheory::binomial(n, k));
+                }
+                Bytecode::DFact => {
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("DFact"))?;
+                    stack.push(crate::numtheory::double_factorial(x));
+                }
+                Bytecode::SubFact => {
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("SubFact"))?;
+                    stack.push(crate::numtheory::subfactorial(x));
+                }
+                Bytecode::Deg => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Deg"))?;
+                    stack.push(a.to_degrees());
+                }
+                Bytecode::Rad => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Rad"))?;
+                    stack.push(a.to_radians());
+                }
+                Bytecode::Grad => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Grad"))?;
+                    stack.push(a * 200.0 / std::f64::consts::PI);
+                }
+                Bytecode::Recurrence => {
+                    let n = stack.pop().ok_or(RuntimeError::StackUnderflow("Recurrence (n)"))?;
+                    let initial = env.lists.pop().ok_or(RuntimeError::ListStackUnderflow("Recurrence (initial)"))?;
+                    let coeffs = env.lists.pop().ok_or(RuntimeError::ListStackUnderflow("Recurrence (coeffs)"))?;
+                    stack.push(recurrence_value(&coeffs, &initial, n)?);
+                }
+                Bytecode::PolyAdd | Bytecode::PolySub | Bytecode::PolyMul => {
+                    let q = env.lists.pop().ok_or(RuntimeError::ListStackUnderflow("polynomial arithmetic (q)"))?;
+                    let p = env.lists.pop().ok_or(RuntimeError::ListStackUnderflow("polynomial arithmetic (p)"))?;
+                    let result = match instr {
+                        Bytecode::PolyAdd => crate::poly::add(&p, &q),
+                        Bytecode::PolySub => crate::poly::sub(&p, &q),
+                        _ => crate::poly::mul(&p, &q),
+                    };
+                    env.push_list(result.into(), stack)?;
+                }
+                Bytecode::PolyDer => {
+                    let p = env.lists.pop().ok_or(RuntimeError::ListStackUnderflow("PolyDer"))?;
+                    env.push_list(crate::poly::derivative(&p).into(), stack)?;
+                }
+                Bytecode::PolyVal => {
+                    let x = stack.pop().ok_or(RuntimeError::StackUnderflow("PolyVal (x)"))?;
+                    let p = env.lists.pop().ok_or(RuntimeError::ListStackUnderflow("PolyVal"))?;
+                    stack.push(crate::poly::eval(&p, x));
+                }
+                Bytecode::Roots => {
+                    let p = env.lists.pop().ok_or(RuntimeError::ListStackUnderflow("Roots"))?;
+                    env.push_list(crate::poly::real_roots(&p).into(), stack)?;
+                }
+                Bytecode::QuadRoots => {
+                    let c = stack.pop().ok_or(RuntimeError::StackUnderflow("QuadRoots (c)"))?;
+                    let b = stack.pop().ok_or(RuntimeError::StackUnderflow("QuadRoots (b)"))?;
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("QuadRoots (a)"))?;
+                    env.push_list(crate::poly::quadratic_roots(a, b, c).into(), stack)?;
+                }
+                Bytecode::CubicRoots => {
+                    let d = stack.pop().ok_or(RuntimeError::StackUnderflow("CubicRoots (d)"))?;
+                    let c = stack.pop().ok_or(RuntimeError::StackUnderflow("CubicRoots (c)"))?;
+                    let b = stack.pop().ok_or(RuntimeError::StackUnderflow("CubicRoots (b)"))?;
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("CubicRoots (a)"))?;
+                    env.push_list(crate::poly::cubic_roots(a, b, c, d).into(), stack)?;
+                }
+                Bytecode::Fit => {
+                    let degree = stack.pop().ok_or(RuntimeError::StackUnderflow("Fit (degree)"))?;
+                    let ys = env.lists.pop().ok_or(RuntimeError::ListStackUnderflow("Fit (ys)"))?;
+                    let xs = env.lists.pop().ok_or(RuntimeError::ListStackUnderflow("Fit (xs)"))?;
+                    env.push_list(fit_polynomial(&xs, &ys, degree)?.into(), stack)?;
+                }
+                Bytecode::FitExp => {
+                    let ys = env.lists.pop().ok_or(RuntimeError::ListStackUnderflow("FitExp (ys)"))?;
+                    let xs = env.lists.pop().ok_or(RuntimeError::ListStackUnderflow("FitExp (xs)"))?;
+                    env.push_list(fit_exponential(&xs, &ys)?.into(), stack)?;
+                }
+                Bytecode::FitLog => {
+                    let ys = env.lists.pop().ok_or(RuntimeError::ListStackUnderflow("FitLog (ys)"))?;
+                    let xs = env.lists.pop().ok_or(RuntimeError::ListStackUnderflow("FitLog (xs)"))?;
+                    env.push_list(fit_logarithmic(&xs, &ys)?.into(), stack)?;
+                }
+                Bytecode::PowI(n) => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("PowI"))?;
+                    stack.push(a.powi(*n));
+                }
+                Bytecode::PushNumber(n) | Bytecode::Precomputed(n) => stack.push(*n),
+                Bytecode::Frame(names) => {
+                    env.slots = names.iter().map(|name| env.frame.slot(name)).collect();
+                }
+                Bytecode::Add => {
+                    let b = stack.pop().ok_or(RuntimeError::StackUnderflow("Add"))?;
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Add"))?;
+                    stack.push(a + b);
+                }
+                Bytecode::Mul => {
+                    let b = stack.pop().ok_or(RuntimeError::StackUnderflow("Mul"))?;
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Mul"))?;
+                    stack.push(a * b);
+                }
+                Bytecode::Div => {
+                    let b = stack.pop().ok_or(RuntimeError::StackUnderflow("Div"))?;
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Div"))?;
+                    stack.push(a / b);
+                }
+                Bytecode::Sin => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Sin"))?;
+                    stack.push(a.sin());
+                }
+                Bytecode::Cos => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Cos"))?;
+                    stack.push(a.cos());
+                }
+                Bytecode::Tan => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Tan"))?;
+                    stack.push(a.tan());
+                }
+                Bytecode::Cot => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Cot"))?;
+                    stack.push(cancel.reciprocal(Reciprocal::Cot, a)?);
+                }
+                Bytecode::Sec => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Sec"))?;
+                    stack.push(cancel.reciprocal(Reciprocal::Sec, a)?);
+                }
+                Bytecode::Csc => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Csc"))?;
+                    stack.push(cancel.reciprocal(Reciprocal::Csc, a)?);
+                }
+                Bytecode::Sinh => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Sinh"))?;
+                    stack.push(a.sinh());
+                }
+                Bytecode::Cosh => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Cosh"))?;
+                    stack.push(a.cosh());
+                }
+                Bytecode::Tanh => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Tanh"))?;
+                    stack.push(a.tanh());
+                }
+                Bytecode::Asinh => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Asinh"))?;
+                    stack.push(a.asinh());
+                }
+                Bytecode::Acosh => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Acosh"))?;
+                    stack.push(a.acosh());
+                }
+                Bytecode::Atanh => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Atanh"))?;
+                    stack.push(a.atanh());
+                }
+                Bytecode::Exp => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Exp"))?;
+                    stack.push(a.exp());
+                }
+                Bytecode::Log10 => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Log10"))?;
+                    stack.push(a.log10());
+                }
+                Bytecode::Log2 => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Log2"))?;
+                    stack.push(a.log2());
+                }
+                Bytecode::Fact => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Fact"))?;
+                    stack.push(factorial(a));
+                }
+                Bytecode::Floor => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Floor"))?;
+                    stack.push(a.floor());
+                }
+                Bytecode::Sub => {
+                    let b = stack.pop().ok_or(RuntimeError::StackUnderflow("Sub"))?;
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Sub"))?;
+                    stack.push(a - b);
+                }
+                Bytecode::Log => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Log"))?;
+                    stack.push(a.ln());
+                }
+                Bytecode::Sqrt => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Sqrt"))?;
+                    stack.push(a.sqrt());
+                }
+                Bytecode::Abs => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Abs"))?;
+                    stack.push(a.abs());
+                }
+                Bytecode::Asin => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Asin"))?;
+                    stack.push(a.asin());
+                }
+                Bytecode::Acos => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Acos"))?;
+                    stack.push(a.acos());
+                }
+                Bytecode::Atan => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Atan"))?;
+                    stack.push(a.atan());
+                }
+                Bytecode::Acot => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Acot"))?;
+                    stack.push((1.0 / a).atan());
+                }
+                Bytecode::Asec => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Asec"))?;
+                    stack.push((1.0 / a).acos());
+                }
+                Bytecode::Acsc => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Acsc"))?;
+                    stack.push((1.0 / a).asin());
+                }
+                Bytecode::Pow => {
+                    let b = stack.pop().ok_or(RuntimeError::StackUnderflow("Pow"))?;
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Pow"))?;
+                    stack.push(a.powf(b));
+                }
+                Bytecode::StoreVar(i) | Bytecode::StoreOutput(i) => {
+                    let slot = env.slot(*i)?;
+                    if env.frame.constant[slot] {
+                        return Err(RuntimeError::AssignToConstant(env.frame.names[slot].clone()));
+                    }
+                    if matches!(instr, Bytecode::StoreOutput(_)) && !env.outputs.contains(&slot) {
+                        env.outputs.push(slot);
+                    }
+                    let val = stack.pop().ok_or(RuntimeError::StackUnderflow("StoreVar"))?;
+                    env.frame.values[slot] = Some(val);
+                    if !env.lists.vars.is_empty() {
+                        env.lists.remove(&env.frame.names[slot]);
+                    }
+                    if !env.functions.vars.is_empty() {
+                        env.functions.remove(&env.frame.names[slot]);
+                    }
+                }
+                Bytecode::Neg => {
+                    let a = stack.pop().ok_or(RuntimeError::StackUnderflow("Neg"))?;
+                    stack.push(-a);
+                }
+                Bytecode::Pop => {
+                    stack.pop().ok_or(RuntimeError::StackUnderflow("Pop"))?;
+                }
+                Bytecode::Dup => {
+                    let top = *stack.last().ok_or(RuntimeError::StackUnderflow("Dup"))?;
+                    stack.push(top);
+                }
+                Bytecode::Swap => {
+                    let len = stack.len();
+                    if len < 2 {
+                        return Err(RuntimeError::StackUnderflow("Swap"));
+                    }
+                    stack.swap(len - 2, len - 1);
+                }
+                Bytecode::StoreConst(i) => {
+                    let slot = env.slot(*i)?;
+                    let val = stack.pop().ok_or(RuntimeError::StackUnderflow("StoreConst"))?;
+                    env.frame.values[slot] = Some(val);
+                    env.frame.constant[slot] = true;
+                }
+                Bytecode::LoadVar(i) => {
+                    let slot = env.slot(*i)?;
+                    let Some(val) = env.frame.values[slot].or_else(|| env.resolve(slot)) else {
+                        log::debug!(target: "vm", "variables set: {:?}", env.frame.bound().map(|(name, _)| name).collect::<Vec<_>>());
+                        return Err(RuntimeError::UndefinedVariable(env.frame.names[slot].clone()));
+                    };
+                    stack.push(val);
+                }
+                Bytecode::SumLoop { from, to, eps, param, body } => {
+                    let from_val = env.run_nested(from, user_functions, cancel, "from")?;
+                    let to_val = env.run_nested(to, user_functions, cancel, "to")?;
+                    let eps_val = match eps {
+                        Some(eps) => Some(env.run_nested(eps, user_functions, cancel, "eps")?),
+                        None => None,
+                    };
+                    let slot = env.slot(*param)?;
+                    let old = env.frame.values[slot];
+                    let acc = sum_series(from_val, to_val, eps_val, |i| {
+                        env.frame.values[slot] = Some(i);
+                        env.run_term(body, user_functions, cancel)
+                    });
+                    env.frame.values[slot] = old;
+                    stack.push(acc?);
+                }
+                Bytecode::ProductLoop { from, to, param, body } => {
+                    let from_val = env.run_nested(from, user_functions, cancel, "from")?;
+                    let to_val = env.run_nested(to, user_functions, cancel, "to")?;
+                    // Only a `while:` condition ends a product to inf, after at most as many factors as a sum has terms
+                    let endless = to_val == f64::INFINITY;
+                    if endless && !from_val.is_finite() {
+                        return Err(format!("product to inf needs a finite start, got {}", from_val).into());
+                    }
+                    let from_i = from_val.ceil() as i64;
+                    let to_i = to_val.floor() as i64;
+                    let slot = env.slot(*param)?;
+                    let old = env.frame.values[slot];
+                    let mut acc = 1.0;
+                    for i in from_i..=to_i {
+                        if endless && i - from_i == MAX_SERIES_TERMS as i64 {
+                            env.frame.values[slot] = old;
+                            return Err(format!("product to inf did not end within {} factors", MAX_SERIES_TERMS).into());
+                        }
+                        env.frame.values[slot] = Some(i as f64);
+                        match env.run_term(body, user_functions, cancel) {
+                            Ok(Some(term)) => acc *= term,
+                            Ok(None) => break,
+                            Err(e) => {
+                                env.frame.values[slot] = old;
+                                return Err(e);
+                            }
+                        }
+                    }
+                    env.frame.values[slot] = old;
+                    stack.push(acc);
+                }
+                Bytecode::Solve { from, to, param, body } => {
+                    let from_val = env.run_nested(from, user_functions, cancel, "from")?;
+                    let to_val = env.run_nested(to, user_functions, cancel, "to")?;
+                    if !(from_val.is_finite() && to_val.is_finite() && from_val < to_val) {
+                        return Err(format!("solve range [{}, {}] must be finite and increasing", from_val, to_val).into());
+                    }
+                    let slot = env.slot(*param)?;
+                    let old = env.frame.values[slot];
+                    // The first error ends the search; the sides are NaN from then on
+                    let mut error = None;
+                    let roots = crate::numeric::roots(
+                        |x| {
+                            if error.is_some() {
+                                return f64::NAN;
+                            }
+                            env.frame.values[slot] = Some(x);
+                            env.run_nested(body, user_functions, cancel, "equation").unwrap_or_else(|e| {
+                                error = Some(e);
+                                f64::NAN
+                            })
+                        },
+                        from_val,
+                        to_val,
+                    );
+                    env.frame.values[slot] = old;
+                    if let Some(e) = error {
+                        return Err(e);
+                    }
+                    env.push_list(roots.into(), stack)?;
+                }
+                Bytecode::Extremum { maximize, from, to, param, body } => {
+                    let from_val = env.run_nested(from, user_functions, cancel, "from")?;
+                    let to_val = env.run_nested(to, user_functions, cancel, "to")?;
+                    let name = if *maximize { "maximize" } else { "minimize" };
+                    if !(from_val.is_finite() && to_val.is_finite() && from_val < to_val) {
+                        return Err(format!("{} range [{}, {}] must be finite and increasing", name, from_val, to_val).into());
+                    }
+                    let slot = env.slot(*param)?;
+                    let old = env.frame.values[slot];
+                    // A maximum is the minimum of the negated body
+                    let sign = if *maximize { -1.0 } else { 1.0 };
+                    let mut error = None;
+                    let (x, value) = crate::numeric::minimize(
+                        |x| {
+                            if error.is_some() {
+                                return f64::NAN;
+                            }
+                            env.frame.values[slot] = Some(x);
+                            env.run_nested(body, user_functions, cancel, "body").map_or_else(
+                                |e| {
+                                    error = Some(e);
+                                    f64::NAN
+                                },
+                                |y| sign * y,
+                            )
+                        },
+                        from_val,
+                        to_val,
+                    );
+                    env.frame.values[slot] = old;
+                    if let Some(e) = error {
+                        return Err(e);
+                    }
+                    if x.is_nan() {
+                        return Err(format!("{}: the expression is NaN all over [{}, {}]", name, from_val, to_val).into());
+                    }
+                    env.push_list([x, sign * value].into(), stack)?;
+                }
+                Bytecode::Derivatives { hessian, params, body } => {
+                    let name = if *hessian { "hessian" } else { "grad" };
+                    let point = env.lists.pop().ok_or(RuntimeError::ListStackUnderflow("Derivatives"))?;
+                    if point.len() != params.len() {
+                        return Err(format!("{} has {} variables but {} values in its point", name, params.len(), point.len()).into());
+                    }
+                    let slots = params.iter().map(|&p| env.slot(p)).collect::<Result<Vec<_>, _>>()?;
+                    let old: Vec<_> = slots.iter().map(|&slot| env.frame.values[slot]).collect();
+                    let mut error = None;
+                    let at = |x: &[f64]| {
+                        if error.is_some() {
+                            return f64::NAN;
+                        }
+                        for (&slot, &value) in slots.iter().zip(x) {
+                            env.frame.values[slot] = Some(value);
+                        }
+                        env.run_nested(body, user_functions, cancel, "body").unwrap_or_else(|e| {
+                            error = Some(e);
+                            f64::NAN
+                        })
+                    };
+                    let derivatives = if *hessian { crate::numeric::hessian(at, &point) } else { crate::numeric::gradient(at, &point) };
+                    for (&slot, value) in slots.iter().zip(old) {
+                        env.frame.values[slot] = value;
+                    }
+                    if let Some(e) = error {
+                        return Err(e);
+                    }
+                    env.push_list(derivatives.into(), stack)?;
+                }
+                Bytecode::SolveN { params, equations } => {
+                    let guess = env.lists.pop().ok_or(RuntimeError::ListStackUnderflow("SolveN"))?;
+                    if guess.len() != params.len() {
+                        return Err(format!("solveN has {} unknowns but {} values in its guess", params.len(), guess.len()).into());
+                    }
+                    let slots = params.iter().map(|&p| env.slot(p)).collect::<Result<Vec<_>, _>>()?;
+                    let old: Vec<_> = slots.iter().map(|&slot| env.frame.values[slot]).collect();
+                    // The first error ends the search; the equations are NaN from then on
+                    let mut error = None;
+                    let solution = crate::numeric::newton(
+                        |point| {
+                            for (&slot, &value) in slots.iter().zip(point) {
+                                env.frame.values[slot] = Some(value);
+                            }
+                            equations
+                                .iter()
+                                .map(|equation| {
+                                    if error.is_some() {
+                                        return f64::NAN;
+                                    }
+                                    env.run_nested(equation, user_functions, cancel, "equation").unwrap_or_else(|e| {
+                                        error = Some(e);
+                                        f64::NAN
+                                    })
+                                })
+                                .collect()
+                        },
+                        &guess,
+                    );
+                    for (&slot, value) in slots.iter().zip(old) {
+                        env.frame.values[slot] = value;
+                    }
+                    if let Some(e) = error {
+                        return Err(e);
+                    }
+                    env.push_list(solution.map_err(|e| format!("solveN: {}", e))?.into(), stack)?;
+                }
             }
-            Ok(acc)
         }
+        // The depth after the last instruction, which the loop did not see
+        if let Some(stats) = &mut env.stats {
+            stats.max_stack_depth = stats.max_stack_depth.max(stack.len());
+        }
+        Ok(())
+    }
+}
+
+/// The result of a program: a number, or a list when its last statement is one.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Number(f64),
+    List(Vec<f64>),
+}
+
+impl Value {
+    /// The value as text, each number written by `number` and a list as
+    /// `[a, b, ...]`, as `--dump-vars` prints list variables.
+    pub fn format(&self, number: impl Fn(f64) -> String) -> String {
+        match self {
+            Value::Number(x) => number(*x),
+            Value::List(items) => format!("[{}]", items.iter().map(|&x| number(x)).collect::<Vec<_>>().join(", ")),
+        }
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.format(|x| x.to_string()))
+    }
+}
+
+/// Execution state for one evaluation: the value stack and the variable frame.
+///
+/// A compiled `Program` and its `UserFunctions` are never mutated while running,
+/// so any number of threads can evaluate the same program concurrently, each
+/// with its own `VmState`.
+#[derive(Debug)]
+pub struct VmState {
+    stack: Vec<f64>,
+    env: Env,
+    cancel: Option<CancellationToken>,
+    check_interval: u32,
+    fuel: Option<u64>,
+    strict: bool,
+}
+
+impl Default for VmState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VmState {
+    pub fn new() -> Self {
+        VmState {
+            stack: Vec::with_capacity(16),
+            env: Env {
+                frame: Frame::default(),
+                slots: Vec::new(),
+                lists: Lists::default(),
+                functions: Functions::default(),
+                resolver: None,
+                rng: StdRng::from_rng(&mut rand::rng()),
+                spare_stacks: Vec::new(),
+                loop_ended: false,
+                args: Vec::new(),
+                read_env: false,
+                fs_access: false,
+                import_dirs: Vec::new(),
+                call_depth: 0,
+                max_call_depth: None,
+                memory_limit: None,
+                call_bytes: 0,
+                stats: None,
+                outputs: Vec::new(),
+                precision: None,
+                units: HashMap::new(),
+                printed: None,
+            },
+            cancel: None,
+            check_interval: DEFAULT_CHECK_INTERVAL,
+            fuel: None,
+            strict: false,
+        }
+    }
+
+    /// Makes runs on this state abort with an error once `token` is cancelled.
+    /// The token is polled every `check_interval` instructions and loop iterations.
+    pub fn set_cancellation(&mut self, token: CancellationToken, check_interval: u32) {
+        self.cancel = Some(token);
+        self.check_interval = check_interval.max(1);
+    }
+
+    /// Makes each run on this state fail once it has executed `steps`
+    /// instructions and loop iterations, so untrusted programs cannot run forever.
+    pub fn set_fuel(&mut self, steps: u64) {
+        self.fuel = Some(steps);
+    }
+
+    /// Makes functions evaluated at a pole, as `csc(0)` or `sec(π/2)`, fail
+    /// with `RuntimeError::Pole` rather than give the infinity they tend to
+    /// from the right.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Binds a variable before running, e.g. an input of the formula.
+    pub fn set_var(&mut self, name: &str, value: f64) {
+        self.env.frame.insert(name, value);
+    }
+
+    /// Makes variables that have no value when compiled code reads them come
+    /// from `resolver` instead of failing; it fails only if the resolver
+    /// returns `None` too. Values are not stored, so each read asks again.
+    pub fn set_resolver(&mut self, resolver: impl VariableResolver + 'static) {
+        self.env.resolver = Some(Box::new(resolver));
+    }
+
+    /// The number variable `name`, if it is bound.
+    pub fn var(&self, name: &str) -> Option<f64> {
+        self.env.frame.get(name)
+    }
+
+    /// The list variable `name`, if it is bound.
+    pub fn list(&self, name: &str) -> Option<&[f64]> {
+        self.env.lists.vars.get(name).map(|list| &**list)
+    }
+
+    /// Every bound number variable by name, for a host that wants what a
+    /// worksheet computed besides the value `run` returns.
+    pub fn vars(&self) -> BTreeMap<&str, f64> {
+        self.env.frame.bound().collect()
+    }
+
+    /// The named results of the runs so far: each variable an `output`
+    /// statement assigned, in the order they were first named, with its
+    /// current value (unless something unbound it since).
+    pub fn outputs(&self) -> Vec<(&str, f64)> {
+        let frame = &self.env.frame;
+        self.env.outputs.iter().filter_map(|&slot| Some((frame.names[slot].as_str(), frame.values[slot]?))).collect()
+    }
+
+    /// The digits after the point the program's `#pragma precision` asks its
+    /// results to print with, once the run has passed it.
+    pub fn precision(&self) -> Option<usize> {
+        self.env.precision
+    }
+
+    /// The unit an `output` statement labelled the named result `name` with,
+    /// as the "m/s" of `output v = d / t @ "m/s"`.
+    pub fn unit(&self, name: &str) -> Option<&str> {
+        let slot = self.env.frame.index.get(name)?;
+        self.env.units.get(slot).map(String::as_str)
+    }
+
+    /// Every bound list variable by name.
+    pub fn lists(&self) -> BTreeMap<&str, &[f64]> {
+        self.env.lists.vars.iter().map(|(name, list)| (name.as_str(), &**list)).collect()
+    }
+
+    /// The name of the user function the variable `name` holds, if it holds
+    /// one, and the leading arguments `bind` fixed.
+    pub fn function(&self, name: &str) -> Option<(&str, &[f64])> {
+        self.env.functions.vars.get(name).map(|f| (f.name.as_str(), f.bound.as_slice()))
+    }
+
+    /// Sets the command-line arguments a script reads with `arg(n)` and `argc()`.
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.env.args = args;
+    }
+
+    /// Sets whether `env("NAME", default)` reads the process environment. Off
+    /// by default, like file access: every `env` then gives its default, as
+    /// if no variable were set, so a program learns nothing of its host unless
+    /// the host allows it, as the command line does without `--no-env`.
+    pub fn set_env_access(&mut self, allowed: bool) {
+        self.env.read_env = allowed;
+    }
+
+    /// Sets whether `loadcsv` and `savecsv` may read and write files. Off by
+    /// default, so a program a host runs cannot touch its files unless the
+    /// host allows it; they fail with `RuntimeError::FileAccessDenied` then.
+    pub fn set_fs_access(&mut self, allowed: bool) {
+        self.env.fs_access = allowed;
+    }
+
+    /// Lets `loadcsv` read files under `dirs` even without file access, as the
+    /// data a sandboxed program may import.
+    pub fn set_import_dirs(&mut self, dirs: Vec<PathBuf>) {
+        self.env.import_dirs = dirs;
+    }
+
+    /// Makes a user function call nested `depth` calls deep fail with
+    /// `RuntimeError::CallDepthExceeded`, before runaway recursion overflows
+    /// the host's stack.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.env.max_call_depth = Some(depth);
+    }
+
+    /// Makes a run fail with `RuntimeError::MemoryLimitExceeded` once it takes
+    /// more than `bytes` for its value stacks, variables and lists. The count
+    /// is an estimate of the VM's own data, kept up to date as lists and
+    /// function values are stored and pushed and checked before every
+    /// instruction, so a host can bound what a program allocates.
+    pub fn set_memory_limit(&mut self, bytes: usize) {
+        self.env.memory_limit = Some(bytes);
+    }
+
+    /// Starts counting what the runs on this state do, from zero, or stops.
+    pub fn set_stats(&mut self, enabled: bool) {
+        self.env.stats = enabled.then(VmStats::default);
+    }
+
+    /// Keeps the lines `print` writes, for `take_printed`, instead of writing
+    /// them to stdout, as a host answering over stdout needs.
+    pub fn set_print_capture(&mut self, capture: bool) {
+        self.env.printed = capture.then(Vec::new);
+    }
+
+    /// The lines `print` wrote since the last call, while capturing them.
+    pub fn take_printed(&mut self) -> Vec<String> {
+        self.env.printed.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// What the runs since `set_stats(true)` did, if counting.
+    pub fn stats(&self) -> Option<VmStats> {
+        self.env.stats
+    }
+
+    /// Restarts the random number generator from `seed`, so the `rand` and
+    /// `randint` draws of the following runs are reproducible.
+    pub fn seed(&mut self, seed: u64) {
+        self.env.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Executes a bytecode program and returns the result or an error message.
+    /// Variables assigned by earlier runs on this state remain visible.
+    pub fn run(&mut self, program: &Program, user_functions: &UserFunctions) -> Result<f64, RuntimeError> {
+        self.exec(program, user_functions)?;
+        let result = self.stack.pop().ok_or(RuntimeError::NoResult(None))?;
+        log::debug!(target: "vm", "result {}", result);
+        Ok(result)
+    }
+
+    /// Like `run`, for a program whose result may be a list.
+    pub fn run_value(&mut self, program: &Program, user_functions: &UserFunctions) -> Result<Value, RuntimeError> {
+        self.run_optional(program, user_functions)?.ok_or(RuntimeError::NoResult(None))
+    }
+
+    /// Like `run_value`, for a program that may end without a result, as one
+    /// whose last statement is an assignment does; the result is then `None`.
+    pub fn run_optional(&mut self, program: &Program, user_functions: &UserFunctions) -> Result<Option<Value>, RuntimeError> {
+        self.exec(program, user_functions)?;
+        Ok(match self.stack.pop() {
+            Some(x) => Some(Value::Number(x)),
+            None => self.env.lists.pop().map(|list| Value::List(list.to_vec())),
+        })
+    }
+
+    /// Like `run`, but for a program run only for its assignments: it need not
+    /// leave a result.
+    pub fn exec(&mut self, program: &Program, user_functions: &UserFunctions) -> Result<(), RuntimeError> {
+        log::debug!(target: "vm", "running {} instructions", program.len());
+        self.stack.clear();
+        self.env.lists.clear_stack();
+        let mut cancel = CancelCheck::new(self.cancel.as_ref(), self.check_interval, self.fuel, self.strict);
+        self.env.execute(program, user_functions, &mut self.stack, &mut cancel)
     }
 }
-/// Executes a bytecode program and returns the result or an error message.
+
+/// Executes a bytecode program on a fresh `VmState` and returns the result or an error message.
 #[inline]
 pub fn run_bytecode_with_functions(
     program: &Program,
-    user_functions: &HashMap<String, (String, Expr)>,
-) -> Result<f64, &'static str> {
-    let mut stack: Vec<f64> = Vec::with_capacity(16);
-    let mut vars: HashMap<String, f64> = HashMap::new();
-    let mut rng = rand::rng();
-    for instr in program {
-        match instr {
-            Bytecode::CallUserFunction(name) => {
-                // Look up the function definition (single-argument only)
-                let (arg_name, body) = user_functions.get(name)
-                    .ok_or("User-defined function not found")?;
-                let arg_val = stack.pop().ok_or("Stack underflow on user function call")?;
-                // Save old value if shadowing
-                let old = vars.insert(arg_name.clone(), arg_val);
-                // Evaluate the function body recursively
-                let result = eval_expr(body, &mut vars, user_functions, &mut rng)?;
-                // Restore old value
-                if let Some(v) = old {
-                    vars.insert(arg_name.clone(), v);
-                } else {
-                    vars.remove(arg_name);
-                }
-                stack.push(result);
-            }
+    user_functions: &UserFunctions,
+) -> Result<f64, RuntimeError> {
+    VmState::new().run(program, user_functions)
+}
 
-            Bytecode::Rand => {
-                stack.push(rand::Rng::random(&mut rng));
-            }
-            Bytecode::RandInt => {
-                let b = stack.pop().ok_or("Stack underflow on RandInt (b)")?;
-                let a = stack.pop().ok_or("Stack underflow on RandInt (a)")?;
-                let (amin, amax) = if a <= b { (a, b) } else { (b, a) };
-                let amin = amin.ceil() as i64;
-                let amax = amax.floor() as i64;
-                if amin > amax {
-                    return Err("Invalid range for randint: min > max");
-                }
-                let val = rand::Rng::random_range(&mut rng, amin..=amax);
-                stack.push(val as f64);
-            }
-            Bytecode::LogBase => {
-                let b = stack.pop().ok_or("Stack underflow on LogBase (b)")?;
-                let a = stack.pop().ok_or("Stack underflow on LogBase (a)")?;
-                stack.push(b.log(a));
-            }
-            // Bytecode::Fact is not used in interpreter mode
-            Bytecode::PushNumber(n) => stack.push(*n),
-            Bytecode::Add => {
-                let b = stack.pop().ok_or("Stack underflow on Add")?;
-                let a = stack.pop().ok_or("Stack underflow on Add")?;
-                stack.push(a + b);
-            }
-            Bytecode::Mul => {
-                let b = stack.pop().ok_or("Stack underflow on Mul")?;
-                let a = stack.pop().ok_or("Stack underflow on Mul")?;
-                stack.push(a * b);
-            }
-            Bytecode::Div => {
-                let b = stack.pop().ok_or("Stack underflow on Div")?;
-                let a = stack.pop().ok_or("Stack underflow on Div")?;
-                stack.push(a / b);
-            }
-            Bytecode::Sin => {
-                let a = stack.pop().ok_or("Stack underflow on Sin")?;
-                stack.push(a.sin());
-            }
-            Bytecode::Cos => {
-                let a = stack.pop().ok_or("Stack underflow on Cos")?;
-                stack.push(a.cos());
-            }
-            Bytecode::Tan => {
-                let a = stack.pop().ok_or("Stack underflow on Tan")?;
-                stack.push(a.tan());
-            }
-            Bytecode::Cot => {
-                let a = stack.pop().ok_or("Stack underflow on Cot")?;
-                stack.push(1.0 / a.tan());
-            }
-            Bytecode::Sec => {
-                let a = stack.pop().ok_or("Stack underflow on Sec")?;
-                stack.push(1.0 / a.cos());
-            }
-            Bytecode::Csc => {
-                let a = stack.pop().ok_or("Stack underflow on Csc")?;
-                stack.push(1.0 / a.sin());
-            }
-            Bytecode::Sinh => {
-                let a = stack.pop().ok_or("Stack underflow on Sinh")?;
-                stack.push(a.sinh());
-            }
-            Bytecode::Cosh => {
-                let a = stack.pop().ok_or("Stack underflow on Cosh")?;
-                stack.push(a.cosh());
-            }
-            Bytecode::Tanh => {
-                let a = stack.pop().ok_or("Stack underflow on Tanh")?;
-                stack.push(a.tanh());
-            }
-            Bytecode::Asinh => {
-                let a = stack.pop().ok_or("Stack underflow on Asinh")?;
-                stack.push(a.asinh());
-            }
-            Bytecode::Acosh => {
-                let a = stack.pop().ok_or("Stack underflow on Acosh")?;
-                stack.push(a.acosh());
-            }
-            Bytecode::Atanh => {
-                let a = stack.pop().ok_or("Stack underflow on Atanh")?;
-                stack.push(a.atanh());
-            }
-            Bytecode::Exp => {
-                let a = stack.pop().ok_or("Stack underflow on Exp")?;
-                stack.push(a.exp());
-            }
-            Bytecode::Log10 => {
-                let a = stack.pop().ok_or("Stack underflow on Log10")?;
-                stack.push(a.log10());
-            }
-            Bytecode::Log2 => {
-                let a = stack.pop().ok_or("Stack underflow on Log2")?;
-                stack.push(a.log2());
-            }
-            Bytecode::Fact => {
-                let a = stack.pop().ok_or("Stack underflow on Fact")?;
-                stack.push(factorial(a));
-            }
-            Bytecode::Floor => {
-                let a = stack.pop().ok_or("Stack underflow on Floor")?;
-                stack.push(a.floor());
-            }
-            Bytecode::Sub => {
-                let b = stack.pop().ok_or("Stack underflow on Sub")?;
-                let a = stack.pop().ok_or("Stack underflow on Sub")?;
-                stack.push(a - b);
-            }
-            Bytecode::Log => {
-                let a = stack.pop().ok_or("Stack underflow on Log")?;
-                stack.push(a.ln());
-            }
-            Bytecode::Sqrt => {
-                let a = stack.pop().ok_or("Stack underflow on Sqrt")?;
-                stack.push(a.sqrt());
-            }
-            Bytecode::Abs => {
-                let a = stack.pop().ok_or("Stack underflow on Abs")?;
-                stack.push(a.abs());
-            }
-            Bytecode::Asin => {
-                let a = stack.pop().ok_or("Stack underflow on Asin")?;
-                stack.push(a.asin());
-            }
-            Bytecode::Acos => {
-                let a = stack.pop().ok_or("Stack underflow on Acos")?;
-                stack.push(a.acos());
-            }
-            Bytecode::Atan => {
-                let a = stack.pop().ok_or("Stack underflow on Atan")?;
-                stack.push(a.atan());
-            }
-            Bytecode::Acot => {
-                let a = stack.pop().ok_or("Stack underflow on Acot")?;
-                stack.push((1.0 / a).atan());
-            }
-            Bytecode::Asec => {
-                let a = stack.pop().ok_or("Stack underflow on Asec")?;
-                stack.push((1.0 / a).acos());
-            }
-            Bytecode::Acsc => {
-                let a = stack.pop().ok_or("Stack underflow on Acsc")?;
-                stack.push((1.0 / a).asin());
-            }
-            Bytecode::Pow => {
-                let b = stack.pop().ok_or("Stack underflow on Pow")?;
-                let a = stack.pop().ok_or("Stack underflow on Pow")?;
-                stack.push(a.powf(b));
-            }
-            Bytecode::StoreVar(name) => {
-                let val = stack.pop().ok_or("Stack underflow on StoreVar")?;
-                vars.insert(name.clone(), val);
-            }
-            Bytecode::LoadVar(name) => {
-                if !vars.contains_key(name) {
-                    eprintln!("[DEBUG] Variable map: {:?}", vars);
-                }
-                let val = vars.get(name).ok_or("Variable not found")?;
-                stack.push(*val);
-            }
-            Bytecode::SumLoop { from, to, param, body } => {
-                let mut from_stack = Vec::new();
-                run_bytecode_with_functions_inner(from, user_functions, &mut vars, &mut from_stack)?;
-                let from_val = from_stack.pop().ok_or("No result on stack (from)")?;
-                let mut to_stack = Vec::new();
-                run_bytecode_with_functions_inner(to, user_functions, &mut vars, &mut to_stack)?;
-                let to_val = to_stack.pop().ok_or("No result on stack (to)")?;
-                let from_i = from_val.ceil() as i64;
-                let to_i = to_val.floor() as i64;
-                let mut acc = 0.0;
-                for i in from_i..=to_i {
-                    vars.insert(param.clone(), i as f64);
-                    let mut body_stack = Vec::new();
-                    run_bytecode_with_functions_inner(body, user_functions, &mut vars, &mut body_stack)?;
-                    let result = body_stack.pop().ok_or("No result on stack (body)")?;
-                    acc += result;
-                }
-                vars.remove(param);
-                stack.push(acc);
-            }
-            Bytecode::ProductLoop { from, to, param, body } => {
-                let mut from_stack = Vec::new();
-                run_bytecode_with_functions_inner(from, user_functions, &mut vars, &mut from_stack)?;
-                let from_val = from_stack.pop().ok_or("No result on stack (from)")?;
-                let mut to_stack = Vec::new();
-                run_bytecode_with_functions_inner(to, user_functions, &mut vars, &mut to_stack)?;
-                let to_val = to_stack.pop().ok_or("No result on stack (to)")?;
-                let from_i = from_val.ceil() as i64;
-                let to_i = to_val.floor() as i64;
-                let mut acc = 1.0;
-                for i in from_i..=to_i {
-                    vars.insert(param.clone(), i as f64);
-                    let mut body_stack = Vec::new();
-                    run_bytecode_with_functions_inner(body, user_functions, &mut vars, &mut body_stack)?;
-                    let result = body_stack.pop().ok_or("No result on stack (body)")?;
-                    acc *= result;
-                }
-                vars.remove(param);
-                stack.push(acc);
+/// Like `run_bytecode_with_functions`, but also returns what the run did.
+pub fn run_bytecode_with_stats(
+    program: &Program,
+    user_functions: &UserFunctions,
+) -> Result<(f64, VmStats), RuntimeError> {
+    let mut state = VmState::new();
+    state.set_stats(true);
+    let result = state.run(program, user_functions)?;
+    Ok((result, state.stats().unwrap_or_default()))
+}
+
+/// Numeric truth value: 1 for true, 0 for false.
+#[inline]
+fn truth(b: bool) -> f64 {
+    if b { 1.0 } else { 0.0 }
+}
+
+/// Conditions are true when nonzero; NaN counts as false.
+#[inline]
+pub fn is_true(x: f64) -> bool {
+    x != 0.0 && !x.is_nan()
+}
+
+/// Passes `cond` through, or fails when it is 0 or NaN.
+fn check_assert(cond: f64, line: usize) -> Result<f64, RuntimeError> {
+    if !is_true(cond) {
+        return Err(RuntimeError::AssertFailed { line, value: cond });
+    }
+    Ok(cond)
+}
+
+/// Passes `a` through, or fails when it differs from `b` by more than `eps`.
+fn check_assert_eq(a: f64, b: f64, eps: f64, line: usize) -> Result<f64, RuntimeError> {
+    // equal infinities pass; NaN never does
+    let within = (a - b).abs() <= eps || a == b;
+    if !within {
+        return Err(RuntimeError::AssertEqFailed { line, left: a, right: b, eps });
+    }
+    Ok(a)
+}
+
+/// Real n-th root; odd roots of negative numbers are negative instead of NaN.
+fn nth_root(x: f64, n: f64) -> f64 {
+    if n == 2.0 {
+        return x.sqrt();
+    }
+    if n == 3.0 {
+        return x.cbrt();
+    }
+    let odd_integer = n.fract() == 0.0 && n % 2.0 != 0.0;
+    if x < 0.0 && odd_integer {
+        -(-x).powf(1.0 / n)
+    } else {
+        x.powf(1.0 / n)
+    }
+}
+
+/// Rounds to `n` decimal places (negative `n` rounds to tens, hundreds, ...).
+/// Ties go away from zero, or to the even neighbour when `ties_even` is set.
+fn round_to(x: f64, n: f64, ties_even: bool) -> f64 {
+    let round = |v: f64| if ties_even { v.round_ties_even() } else { v.round() };
+    let n = n.trunc();
+    // Divide by an exact power of ten rather than multiplying by an inexact 0.1, 0.01, ...
+    if n >= 0.0 {
+        let scale = 10f64.powf(n);
+        round(x * scale) / scale
+    } else {
+        let scale = 10f64.powf(-n);
+        round(x / scale) * scale
+    }
+}
+
+/// Smallest prime greater than `x`, or NaN beyond the 64-bit range.
+fn next_prime_after(x: f64) -> f64 {
+    crate::numtheory::next_prime(x.max(0.0).floor() as u64).map_or(f64::NAN, |p| p as f64)
+}
+
+/// Number of primes `<= x`, refusing arguments that would take too long.
+fn prime_pi_upto(x: f64) -> Result<f64, String> {
+    if x > crate::numtheory::PRIME_PI_LIMIT {
+        return Err(format!("primepi supports n up to {}, got {}", crate::numtheory::PRIME_PI_LIMIT, x));
+    }
+    Ok(crate::numtheory::prime_pi(x.max(0.0).floor() as u64) as f64)
+}
+
+// Exact integer operands for the modular builtins, with a positive modulus
+fn modular_operands(name: &str, values: &[f64], m: f64) -> Result<(Vec<i128>, u64), String> {
+    let ints = values
+        .iter()
+        .map(|&v| crate::numtheory::as_int(v).ok_or_else(|| format!("{} expects integers, got {}", name, v)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let modulus = crate::numtheory::as_u64(m)
+        .filter(|&m| m > 0)
+        .ok_or_else(|| format!("{} expects a positive integer modulus, got {}", name, m))?;
+    Ok((ints, modulus))
+}
+
+/// `a^b mod m`, computed exactly on integers.
+fn pow_mod_value(a: f64, b: f64, m: f64) -> Result<f64, String> {
+    let (ints, m) = modular_operands("powmod", &[a, b], m)?;
+    let result = crate::numtheory::pow_mod_int(ints[0], ints[1], m)
+        .ok_or_else(|| format!("{} has no inverse modulo {}", a, m))?;
+    Ok(result as f64)
+}
+
+/// The inverse of `a` modulo `m`.
+fn inv_mod_value(a: f64, m: f64) -> Result<f64, String> {
+    let (ints, m) = modular_operands("invmod", &[a], m)?;
+    let result = crate::numtheory::inv_mod(ints[0], m).ok_or_else(|| format!("{} has no inverse modulo {}", a, m))?;
+    Ok(result as f64)
+}
+
+/// Term `n` of a linear recurrence, checking the shapes of its lists.
+fn recurrence_value(coeffs: &[f64], initial: &[f64], n: f64) -> Result<f64, String> {
+    if coeffs.is_empty() || coeffs.len() != initial.len() {
+        return Err(format!(
+            "recurrence needs one initial value per coefficient, got {} coefficients and {} initial values",
+            coeffs.len(),
+            initial.len()
+        ));
+    }
+    let n = crate::numtheory::as_u64(n).ok_or_else(|| format!("recurrence expects a non-negative integer n, got {}", n))?;
+    Ok(crate::numtheory::linear_recurrence(coeffs, initial, n))
+}
+
+/// Default `eps:` of a `sum` with `to: inf`.
+const DEFAULT_SERIES_EPS: f64 = 1e-12;
+
+/// Most terms a `sum` with `to: inf` adds before reporting that it does not
+/// converge, and most factors a `product` to inf multiplies.
+const MAX_SERIES_TERMS: usize = 10_000_000;
+
+/// Last terms of a `sum` with `to: inf` kept to estimate its limit.
+const SERIES_WINDOW: usize = 9;
+
+/// Adds `term(i)` for the integers `i` from `ceil(from)` to `floor(to)`, none
+/// at all if that range is reversed, stopping early at the first `i` whose
+/// term is `None` (its `while:` condition failed). With `to = inf` the limit
+/// is estimated after 16, 32, 64, ... terms (see `series_limit`) and the sum
+/// stops once two of these estimates in a row differ by less than `eps`,
+/// returning the later one.
+fn sum_series(
+    from: f64,
+    to: f64,
+    eps: Option<f64>,
+    mut term: impl FnMut(f64) -> Result<Option<f64>, RuntimeError>,
+) -> Result<f64, RuntimeError> {
+    let from_i = from.ceil() as i64;
+    let mut acc = 0.0;
+    if to != f64::INFINITY {
+        for i in from_i..=to.floor() as i64 {
+            match term(i as f64)? {
+                Some(t) => acc += t,
+                None => break,
             }
         }
+        return Ok(acc);
+    }
+    if !from.is_finite() {
+        return Err(format!("sum to inf needs a finite start, got {}", from).into());
+    }
+    let eps = eps.unwrap_or(DEFAULT_SERIES_EPS);
+    if eps.is_nan() || eps <= 0.0 {
+        return Err(format!("sum eps must be positive, got {}", eps).into());
+    }
+    let mut terms = VecDeque::with_capacity(SERIES_WINDOW);
+    let mut sums = VecDeque::with_capacity(SERIES_WINDOW);
+    let mut last_estimate: Option<f64> = None;
+    for (count, i) in (1..).zip((from_i..).take(MAX_SERIES_TERMS)) {
+        let Some(t) = term(i as f64)? else {
+            return Ok(acc);
+        };
+        acc += t;
+        if !acc.is_finite() {
+            return Err(RuntimeError::Diverged(i));
+        }
+        if terms.len() == SERIES_WINDOW {
+            terms.pop_front();
+            sums.pop_front();
+        }
+        terms.push_back(t);
+        sums.push_back(acc);
+        if count >= 16 && usize::is_power_of_two(count) {
+            let estimate = series_limit(acc, i as f64, &terms, &sums);
+            if last_estimate.is_some_and(|last| (estimate - last).abs() < eps) {
+                return Ok(estimate);
+            }
+            last_estimate = Some(estimate);
+        }
     }
-    stack.pop().ok_or("No result on stack")
+    Err(RuntimeError::NotConverged { eps, terms: MAX_SERIES_TERMS })
 }
 
+/// Estimated limit of a series whose partial sum after the term for `n` is
+/// `acc`, given its last terms and the partial sums after each. When the terms
+/// alternate in sign the partial sums are averaged pairwise until one value is
+/// left, which cancels the overshoot of each; when the last two terms shrink
+/// like `1/n^p` for some `p > 1` the Euler-Maclaurin estimate of that tail is
+/// added; otherwise the partial sum is taken as it is.
+fn series_limit(acc: f64, n: f64, terms: &VecDeque<f64>, sums: &VecDeque<f64>) -> f64 {
+    let alternating = terms.len() == SERIES_WINDOW
+        && terms.iter().zip(terms.iter().skip(1)).all(|(&a, &b)| a != 0.0 && b != 0.0 && (a < 0.0) != (b < 0.0));
+    if alternating {
+        let mut averaged: Vec<f64> = sums.iter().copied().collect();
+        while averaged.len() > 1 {
+            averaged = averaged.windows(2).map(|w| (w[0] + w[1]) / 2.0).collect();
+        }
+        return averaged[0];
+    }
+    let (last, before) = (terms[terms.len() - 1], terms[terms.len() - 2]);
+    if n > 1.0 && last != 0.0 && (last < 0.0) == (before < 0.0) && last.abs() < before.abs() {
+        let p = (before / last).ln() / (n / (n - 1.0)).ln();
+        if p > 1.0 {
+            return acc + last * (n / (p - 1.0) - 0.5 + p / (12.0 * n));
+        }
+    }
+    acc
+}
+
+/// Least-squares polynomial coefficients (highest degree first) through the points.
+fn fit_polynomial(xs: &[f64], ys: &[f64], degree: f64) -> Result<Vec<f64>, String> {
+    fit_points("fit", xs, ys)?;
+    let degree = crate::numtheory::as_u64(degree).ok_or_else(|| format!("fit expects a non-negative integer degree, got {}", degree))?;
+    crate::poly::fit(xs, ys, degree as usize)
+        .ok_or_else(|| format!("fit of degree {} needs at least {} distinct x values", degree, degree + 1))
+}
+
+/// `[a, b]` for `y = a e^(bx)`, fitted as a line through `(x, ln y)`.
+fn fit_exponential(xs: &[f64], ys: &[f64]) -> Result<Vec<f64>, String> {
+    fit_points("fitexp", xs, ys)?;
+    if ys.iter().any(|&y| y.is_nan() || y <= 0.0) {
+        return Err("fitexp needs positive y values".into());
+    }
+    let ln_ys: Vec<f64> = ys.iter().map(|y| y.ln()).collect();
+    let line = crate::poly::fit(xs, &ln_ys, 1).ok_or("fitexp needs at least 2 distinct x values")?;
+    Ok(vec![line[1].exp(), line[0]])
+}
+
+/// `[a, b]` for `y = a + b ln x`, fitted as a line through `(ln x, y)`.
+fn fit_logarithmic(xs: &[f64], ys: &[f64]) -> Result<Vec<f64>, String> {
+    fit_points("fitlog", xs, ys)?;
+    if xs.iter().any(|&x| x.is_nan() || x <= 0.0) {
+        return Err("fitlog needs positive x values".into());
+    }
+    let ln_xs: Vec<f64> = xs.iter().map(|x| x.ln()).collect();
+    let line = crate::poly::fit(&ln_xs, ys, 1).ok_or("fitlog needs at least 2 distinct x values")?;
+    Ok(vec![line[1], line[0]])
+}
+
+fn fit_points(name: &str, xs: &[f64], ys: &[f64]) -> Result<(), String> {
+    if xs.len() != ys.len() {
+        return Err(format!("{} needs one y per x, got {} x values and {} y values", name, xs.len(), ys.len()));
+    }
+    Ok(())
+}
+
+/// Largest `n` whose `n!` is finite as an f64.
+const MAX_FACTORIAL: f64 = 170.0;
+
 fn factorial(x: f64) -> f64 {
     if x < 0.0 { return f64::NAN; }
     if x == 0.0 { return 1.0; }
+    if x >= MAX_FACTORIAL + 1.0 { return f64::INFINITY; }
     let mut acc = 1.0;
     let mut n = x.floor() as u64;
     while n > 1 {
@@ -367,227 +1925,3 @@ fn factorial(x: f64) -> f64 {
     acc
 }
 
-fn run_bytecode_with_functions_inner(
-    program: &Program,
-    user_functions: &HashMap<String, (String, Expr)>,
-    vars: &mut HashMap<String, f64>,
-    stack: &mut Vec<f64>,
-) -> Result<(), &'static str> {
-    let mut rng = rand::rng();
-    for instr in program {
-        match instr {
-            Bytecode::CallUserFunction(name) => {
-                let (arg_name, body) = user_functions.get(name)
-                    .ok_or("User-defined function not found")?;
-                let arg_val = stack.pop().ok_or("Stack underflow on user function call")?;
-                let old = vars.insert(arg_name.clone(), arg_val);
-                let result = eval_expr(body, vars, user_functions, &mut rng)?;
-                if let Some(v) = old {
-                    vars.insert(arg_name.clone(), v);
-                } else {
-                    vars.remove(arg_name);
-                }
-                stack.push(result);
-            }
-            Bytecode::Rand => {
-                stack.push(rand::Rng::random(&mut rng));
-            }
-            Bytecode::RandInt => {
-                let b = stack.pop().ok_or("Stack underflow on RandInt (b)")?;
-                let a = stack.pop().ok_or("Stack underflow on RandInt (a)")?;
-                let (amin, amax) = if a <= b { (a, b) } else { (b, a) };
-                let amin = amin.ceil() as i64;
-                let amax = amax.floor() as i64;
-                if amin > amax {
-                    return Err("Invalid range for randint: min > max");
-                }
-                let val = rand::Rng::random_range(&mut rng, amin..=amax);
-                stack.push(val as f64);
-            }
-            Bytecode::LogBase => {
-                let b = stack.pop().ok_or("Stack underflow on LogBase (b)")?;
-                let a = stack.pop().ok_or("Stack underflow on LogBase (a)")?;
-                stack.push(b.log(a));
-            }
-            Bytecode::PushNumber(n) => stack.push(*n),
-            Bytecode::Add => {
-                let b = stack.pop().ok_or("Stack underflow on Add")?;
-                let a = stack.pop().ok_or("Stack underflow on Add")?;
-                stack.push(a + b);
-            }
-            Bytecode::Mul => {
-                let b = stack.pop().ok_or("Stack underflow on Mul")?;
-                let a = stack.pop().ok_or("Stack underflow on Mul")?;
-                stack.push(a * b);
-            }
-            Bytecode::Div => {
-                let b = stack.pop().ok_or("Stack underflow on Div")?;
-                let a = stack.pop().ok_or("Stack underflow on Div")?;
-                stack.push(a / b);
-            }
-            Bytecode::Sin => {
-                let a = stack.pop().ok_or("Stack underflow on Sin")?;
-                stack.push(a.sin());
-            }
-            Bytecode::Cos => {
-                let a = stack.pop().ok_or("Stack underflow on Cos")?;
-                stack.push(a.cos());
-            }
-            Bytecode::Tan => {
-                let a = stack.pop().ok_or("Stack underflow on Tan")?;
-                stack.push(a.tan());
-            }
-            Bytecode::Cot => {
-                let a = stack.pop().ok_or("Stack underflow on Cot")?;
-                stack.push(1.0 / a.tan());
-            }
-            Bytecode::Sec => {
-                let a = stack.pop().ok_or("Stack underflow on Sec")?;
-                stack.push(1.0 / a.cos());
-            }
-            Bytecode::Csc => {
-                let a = stack.pop().ok_or("Stack underflow on Csc")?;
-                stack.push(1.0 / a.sin());
-            }
-            Bytecode::Sinh => {
-                let a = stack.pop().ok_or("Stack underflow on Sinh")?;
-                stack.push(a.sinh());
-            }
-            Bytecode::Cosh => {
-                let a = stack.pop().ok_or("Stack underflow on Cosh")?;
-                stack.push(a.cosh());
-            }
-            Bytecode::Tanh => {
-                let a = stack.pop().ok_or("Stack underflow on Tanh")?;
-                stack.push(a.tanh());
-            }
-            Bytecode::Asinh => {
-                let a = stack.pop().ok_or("Stack underflow on Asinh")?;
-                stack.push(a.asinh());
-            }
-            Bytecode::Acosh => {
-                let a = stack.pop().ok_or("Stack underflow on Acosh")?;
-                stack.push(a.acosh());
-            }
-            Bytecode::Atanh => {
-                let a = stack.pop().ok_or("Stack underflow on Atanh")?;
-                stack.push(a.atanh());
-            }
-            Bytecode::Exp => {
-                let a = stack.pop().ok_or("Stack underflow on Exp")?;
-                stack.push(a.exp());
-            }
-            Bytecode::Log10 => {
-                let a = stack.pop().ok_or("Stack underflow on Log10")?;
-                stack.push(a.log10());
-            }
-            Bytecode::Log2 => {
-                let a = stack.pop().ok_or("Stack underflow on Log2")?;
-                stack.push(a.log2());
-            }
-            Bytecode::Fact => {
-                let a = stack.pop().ok_or("Stack underflow on Fact")?;
-                stack.push(factorial(a));
-            }
-            Bytecode::Floor => {
-                let a = stack.pop().ok_or("Stack underflow on Floor")?;
-                stack.push(a.floor());
-            }
-            Bytecode::Sub => {
-                let b = stack.pop().ok_or("Stack underflow on Sub")?;
-                let a = stack.pop().ok_or("Stack underflow on Sub")?;
-                stack.push(a - b);
-            }
-            Bytecode::Log => {
-                let a = stack.pop().ok_or("Stack underflow on Log")?;
-                stack.push(a.ln());
-            }
-            Bytecode::Sqrt => {
-                let a = stack.pop().ok_or("Stack underflow on Sqrt")?;
-                stack.push(a.sqrt());
-            }
-            Bytecode::Abs => {
-                let a = stack.pop().ok_or("Stack underflow on Abs")?;
-                stack.push(a.abs());
-            }
-            Bytecode::Asin => {
-                let a = stack.pop().ok_or("Stack underflow on Asin")?;
-                stack.push(a.asin());
-            }
-            Bytecode::Acos => {
-                let a = stack.pop().ok_or("Stack underflow on Acos")?;
-                stack.push(a.acos());
-            }
-            Bytecode::Atan => {
-                let a = stack.pop().ok_or("Stack underflow on Atan")?;
-                stack.push(a.atan());
-            }
-            Bytecode::Acot => {
-                let a = stack.pop().ok_or("Stack underflow on Acot")?;
-                stack.push((1.0 / a).atan());
-            }
-            Bytecode::Asec => {
-                let a = stack.pop().ok_or("Stack underflow on Asec")?;
-                stack.push((1.0 / a).acos());
-            }
-            Bytecode::Acsc => {
-                let a = stack.pop().ok_or("Stack underflow on Acsc")?;
-                stack.push((1.0 / a).asin());
-            }
-            Bytecode::Pow => {
-                let b = stack.pop().ok_or("Stack underflow on Pow")?;
-                let a = stack.pop().ok_or("Stack underflow on Pow")?;
-                stack.push(a.powf(b));
-            }
-            Bytecode::StoreVar(name) => {
-                let val = stack.pop().ok_or("Stack underflow on StoreVar")?;
-                vars.insert(name.clone(), val);
-            }
-            Bytecode::LoadVar(name) => {
-                let val = vars.get(name).ok_or("Variable not found")?;
-                stack.push(*val);
-            }
-            Bytecode::SumLoop { from, to, param, body } => {
-                let mut from_stack = Vec::new();
-                run_bytecode_with_functions_inner(from, user_functions, vars, &mut from_stack)?;
-                let from_val = from_stack.pop().ok_or("No result on stack (from)")?;
-                let mut to_stack = Vec::new();
-                run_bytecode_with_functions_inner(to, user_functions, vars, &mut to_stack)?;
-                let to_val = to_stack.pop().ok_or("No result on stack (to)")?;
-                let from_i = from_val.ceil() as i64;
-                let to_i = to_val.floor() as i64;
-                let mut acc = 0.0;
-                for i in from_i..=to_i {
-                    let old = vars.insert(param.clone(), i as f64);
-                    let mut body_stack = Vec::new();
-                    run_bytecode_with_functions_inner(body, user_functions, vars, &mut body_stack)?;
-                    let result = body_stack.pop().ok_or("No result on stack (body)")?;
-                    acc += result;
-                    if let Some(v) = old { vars.insert(param.clone(), v); } else { vars.remove(param); }
-                }
-                stack.push(acc);
-            }
-            Bytecode::ProductLoop { from, to, param, body } => {
-                let mut from_stack = Vec::new();
-                run_bytecode_with_functions_inner(from, user_functions, vars, &mut from_stack)?;
-                let from_val = from_stack.pop().ok_or("No result on stack (from)")?;
-                let mut to_stack = Vec::new();
-                run_bytecode_with_functions_inner(to, user_functions, vars, &mut to_stack)?;
-                let to_val = to_stack.pop().ok_or("No result on stack (to)")?;
-                let from_i = from_val.ceil() as i64;
-                let to_i = to_val.floor() as i64;
-                let mut acc = 1.0;
-                for i in from_i..=to_i {
-                    let old = vars.insert(param.clone(), i as f64);
-                    let mut body_stack = Vec::new();
-                    run_bytecode_with_functions_inner(body, user_functions, vars, &mut body_stack)?;
-                    let result = body_stack.pop().ok_or("No result on stack (body)")?;
-                    acc *= result;
-                    if let Some(v) = old { vars.insert(param.clone(), v); } else { vars.remove(param); }
-                }
-                stack.push(acc);
-            }
-        }
-    }
-    Ok(())
-}
@@ -0,0 +1,259 @@
+// LaTeX rendering of expressions, for reports
+use crate::ast::{Expr, Param, UnaryOperator};
+use crate::lexer::{BinaryOperator, SpecialFunction};
+
+// Binding strength of a rendered expression; an operand weaker than its
+// operator requires is wrapped in parentheses
+const CONDITIONAL: u8 = 0;
+const COMPARISON: u8 = 1;
+const ADDITIVE: u8 = 2;
+const PRODUCT: u8 = 3;
+const NEGATION: u8 = 4;
+const FUNCTION: u8 = 5;
+const ATOM: u8 = 6;
+
+const GREEK: &[&str] = &[
+    "alpha", "beta", "gamma", "delta", "epsilon", "zeta", "eta", "theta", "iota", "kappa", "lambda", "mu", "nu",
+    "xi", "pi", "rho", "sigma", "tau", "upsilon", "phi", "chi", "psi", "omega", "Gamma", "Delta", "Theta",
+    "Lambda", "Xi", "Pi", "Sigma", "Phi", "Psi", "Omega",
+];
+
+/// The expression as LaTeX math, without surrounding `$`.
+pub fn to_latex(expr: &Expr) -> String {
+    render(expr).0
+}
+
+/// A user function definition `name(a, b = 2) = body` as LaTeX math.
+pub fn definition_to_latex(name: &str, params: &[Param], body: &Expr) -> String {
+    let params: Vec<String> = params
+        .iter()
+        .map(|p| match &p.default {
+            Some(default) => format!("{} = {}", identifier(&p.name), to_latex(default)),
+            None => identifier(&p.name),
+        })
+        .collect();
+    format!("{}\\left({}\\right) = {}", function_name(name), params.join(", "), to_latex(body))
+}
+
+/// A number as LaTeX: integers without a fraction part, infinity as `\infty`.
+pub fn number(n: f64) -> String {
+    if n.is_nan() {
+        "\\mathrm{NaN}".to_string()
+    } else if n.is_infinite() {
+        if n > 0.0 { "\\infty".to_string() } else { "-\\infty".to_string() }
+    } else {
+        format!("{}", n)
+    }
+}
+
+// The LaTeX text and its binding strength
+fn render(expr: &Expr) -> (String, u8) {
+    match expr {
+        Expr::Number(n) => (number(*n), if *n < 0.0 { NEGATION } else { ATOM }),
+        Expr::Ident(name) => (identifier(name), ATOM),
+        Expr::Text(text) => (format!("\\text{{``{}''}}", text), ATOM),
+        Expr::Precision(_) => (String::new(), ATOM),
+        Expr::Assign { name, expr } => (format!("{} = {}", identifier(name), to_latex(expr)), CONDITIONAL),
+        Expr::Const { name, expr } => (format!("{} := {}", identifier(name), to_latex(expr)), CONDITIONAL),
+        Expr::Output { name, expr, unit: None } => (format!("{} = {}", identifier(name), to_latex(expr)), CONDITIONAL),
+        Expr::Output { name, expr, unit: Some(unit) } => {
+            (format!("{} = {}\\,\\mathrm{{{}}}", identifier(name), to_latex(expr), unit), CONDITIONAL)
+        }
+        Expr::BinaryOp { left, op, right } => binary(left, *op, right),
+        Expr::UnaryOp { op: UnaryOperator::Neg, operand: inner } => (format!("-{}", operand(inner, NEGATION)), NEGATION),
+        Expr::Conditional { cond, then, otherwise } => (
+            format!(
+                "\\begin{{cases}} {} & \\text{{if }} {} \\\\ {} & \\text{{otherwise}} \\end{{cases}}",
+                to_latex(then),
+                to_latex(cond),
+                to_latex(otherwise)
+            ),
+            ATOM,
+        ),
+        Expr::Let { name, value, body } => (
+            format!("{} \\quad \\text{{where }} {} = {}", to_latex(body), identifier(name), to_latex(value)),
+            CONDITIONAL,
+        ),
+        Expr::Function { func, arg } => function(*func, arguments(arg)),
+        Expr::FunctionDef { name, params, body } => (definition_to_latex(name, params, body), CONDITIONAL),
+        Expr::FunctionCall { name, args } => {
+            let args = args.iter().map(to_latex).collect::<Vec<_>>().join(", ");
+            (format!("{}\\left({}\\right)", function_name(name), args), FUNCTION)
+        }
+        Expr::FunctionRef(name) => (function_name(name), ATOM),
+        Expr::Apply { callee, args } => {
+            let args = args.iter().map(to_latex).collect::<Vec<_>>().join(", ");
+            (format!("{}\\left({}\\right)", to_latex(callee), args), FUNCTION)
+        }
+        Expr::Bind { callee, args } => {
+            // The arguments still to come show as a dot
+            let args = args.iter().map(to_latex).chain(["\\cdot".to_string()]).collect::<Vec<_>>().join(", ");
+            (format!("{}\\left({}\\right)", to_latex(callee), args), FUNCTION)
+        }
+        Expr::Sequence(items) => (items.iter().map(to_latex).collect::<Vec<_>>().join(", \\quad "), CONDITIONAL),
+        Expr::List(items) => (format!("\\left[{}\\right]", items.iter().map(to_latex).collect::<Vec<_>>().join(", ")), ATOM),
+        Expr::Sum { from, to, eps, param, cond, body } => {
+            let mut lower = vec![format!("{} = {}", identifier(param), to_latex(from))];
+            if let Some(eps) = eps {
+                // The convergence tolerance goes under the start
+                lower.push(format!("\\varepsilon = {}", to_latex(eps)));
+            }
+            (big_operator("\\sum", &lower_limit(lower, cond), to, body), ADDITIVE)
+        }
+        Expr::Product { from, to, param, cond, body } => {
+            let lower = vec![format!("{} = {}", identifier(param), to_latex(from))];
+            (big_operator("\\prod", &lower_limit(lower, cond), to, body), ADDITIVE)
+        }
+        Expr::Solve { param, range, lhs, rhs } => {
+            // The set of solutions, in set-builder notation
+            let unknown = match range {
+                Some((from, to)) => format!("{} \\in \\left[{}, {}\\right]", identifier(param), to_latex(from), to_latex(to)),
+                None => identifier(param),
+            };
+            (format!("\\left\\{{ {} : {} = {} \\right\\}}", unknown, to_latex(lhs), to_latex(rhs)), ATOM)
+        }
+        Expr::SolveN { equations, params, guess } => {
+            // The system in a brace, the unknowns and their guess underneath
+            let unknowns = params.iter().map(|p| identifier(p)).collect::<Vec<_>>().join(", ");
+            let lines = equations.iter().map(|e| format!("{} &= 0", to_latex(e))).collect::<Vec<_>>().join(" \\\\ ");
+            (
+                format!(
+                    "\\operatorname{{solve}}_{{\\left({}\\right) \\approx {}}} \\left\\{{ \\begin{{aligned}} {} \\end{{aligned}} \\right.",
+                    unknowns,
+                    to_latex(guess),
+                    lines
+                ),
+                FUNCTION,
+            )
+        }
+        Expr::Extremum { maximize, body, param, from, to } => {
+            // The list of the point and the value there
+            let (arg, extreme) = if *maximize { ("\\arg\\max", "\\max") } else { ("\\arg\\min", "\\min") };
+            let under = format!("{} \\in \\left[{}, {}\\right]", identifier(param), to_latex(from), to_latex(to));
+            let body = operand(body, PRODUCT);
+            (format!("\\left[{}_{{{}}} {}, {}_{{{}}} {}\\right]", arg, under, body, extreme, under, body), ATOM)
+        }
+        Expr::Derivatives { hessian, body, params, point } => {
+            // Taken in the variables, then evaluated at the point
+            let variables = params.iter().map(|p| identifier(p)).collect::<Vec<_>>().join(", ");
+            let operator = if *hessian { "\\operatorname{H}" } else { "\\nabla" };
+            (format!("\\left. {}_{{{}}} {} \\right|_{{{}}}", operator, variables, operand(body, PRODUCT), to_latex(point)), FUNCTION)
+        }
+        Expr::Return { cond: None, value } => (format!("\\text{{return }} {}", to_latex(value)), CONDITIONAL),
+        Expr::Return { cond: Some(cond), value } => (
+            format!("\\text{{if }} {} \\text{{ return }} {}", to_latex(cond), to_latex(value)),
+            CONDITIONAL,
+        ),
+        Expr::Assert { cond, .. } => (format!("\\text{{assert }} {}", to_latex(cond)), CONDITIONAL),
+        Expr::AssertEq { left, right, eps, .. } => (
+            format!("\\text{{assert }} {} \\approx {} \\quad (\\pm {})", to_latex(left), to_latex(right), to_latex(eps)),
+            CONDITIONAL,
+        ),
+    }
+}
+
+// `expr` rendered as an operand needing at least `strength`
+fn operand(expr: &Expr, strength: u8) -> String {
+    let (text, own) = render(expr);
+    if own < strength { format!("\\left({}\\right)", text) } else { text }
+}
+
+fn binary(left: &Expr, op: BinaryOperator, right: &Expr) -> (String, u8) {
+    let comparison = |symbol: &str| {
+        (format!("{} {} {}", operand(left, ADDITIVE), symbol, operand(right, ADDITIVE)), COMPARISON)
+    };
+    match op {
+        BinaryOperator::Plus => (format!("{} + {}", operand(left, ADDITIVE), operand(right, ADDITIVE)), ADDITIVE),
+        BinaryOperator::Minus => (format!("{} - {}", operand(left, ADDITIVE), operand(right, PRODUCT)), ADDITIVE),
+        BinaryOperator::Star => (format!("{} \\cdot {}", operand(left, PRODUCT), operand(right, NEGATION)), PRODUCT),
+        BinaryOperator::Slash => (format!("\\frac{{{}}}{{{}}}", to_latex(left), to_latex(right)), PRODUCT),
+        BinaryOperator::Pow => power(left, right),
+        BinaryOperator::Less => comparison("<"),
+        BinaryOperator::LessEq => comparison("\\le"),
+        BinaryOperator::Greater => comparison(">"),
+        BinaryOperator::GreaterEq => comparison("\\ge"),
+        BinaryOperator::Equal => comparison("="),
+        BinaryOperator::NotEqual => comparison("\\ne"),
+    }
+}
+
+fn power(base: &Expr, exponent: &Expr) -> (String, u8) {
+    (format!("{}^{{{}}}", operand(base, ATOM), to_latex(exponent)), FUNCTION)
+}
+
+fn function(func: SpecialFunction, args: &[Expr]) -> (String, u8) {
+    let arg = |i: usize| args.get(i).map(to_latex).unwrap_or_default();
+    let applied = |name: &str| (format!("{}\\left({}\\right)", name, arg(0)), FUNCTION);
+    match func {
+        SpecialFunction::Sqrt => (format!("\\sqrt{{{}}}", arg(0)), ATOM),
+        SpecialFunction::Root => (format!("\\sqrt[{}]{{{}}}", arg(1), arg(0)), ATOM),
+        SpecialFunction::Abs => (format!("\\left|{}\\right|", arg(0)), ATOM),
+        SpecialFunction::Floor => (format!("\\left\\lfloor {} \\right\\rfloor", arg(0)), ATOM),
+        SpecialFunction::Fact => (format!("{}!", args.first().map(|a| operand(a, ATOM)).unwrap_or_default()), FUNCTION),
+        SpecialFunction::Exp => (format!("e^{{{}}}", arg(0)), FUNCTION),
+        SpecialFunction::Pow if args.len() == 2 => power(&args[0], &args[1]),
+        SpecialFunction::Choose => (format!("\\binom{{{}}}{{{}}}", arg(0), arg(1)), ATOM),
+        SpecialFunction::DFact => (format!("{}!!", args.first().map(|a| operand(a, ATOM)).unwrap_or_default()), FUNCTION),
+        SpecialFunction::SubFact => (format!("!{}", args.first().map(|a| operand(a, ATOM)).unwrap_or_default()), FUNCTION),
+        SpecialFunction::Log => applied("\\ln"),
+        SpecialFunction::LogBase => (format!("\\log_{{{}}}\\left({}\\right)", arg(0), arg(1)), FUNCTION),
+        SpecialFunction::Log10 => applied("\\log_{10}"),
+        SpecialFunction::Log2 => applied("\\log_{2}"),
+        SpecialFunction::Sin => applied("\\sin"),
+        SpecialFunction::Cos => applied("\\cos"),
+        SpecialFunction::Tan => applied("\\tan"),
+        SpecialFunction::Cot => applied("\\cot"),
+        SpecialFunction::Sec => applied("\\sec"),
+        SpecialFunction::Csc => applied("\\csc"),
+        SpecialFunction::Sinh => applied("\\sinh"),
+        SpecialFunction::Cosh => applied("\\cosh"),
+        SpecialFunction::Tanh => applied("\\tanh"),
+        SpecialFunction::Asin => applied("\\arcsin"),
+        SpecialFunction::Acos => applied("\\arccos"),
+        SpecialFunction::Atan => applied("\\arctan"),
+        _ => {
+            let list = args.iter().map(to_latex).collect::<Vec<_>>().join(", ");
+            let name = crate::builtins::entry(func).name;
+            (format!("\\operatorname{{{}}}\\left({}\\right)", escape(name), list), FUNCTION)
+        }
+    }
+}
+
+// The lines under a sum or product, ending with its `while:` condition
+fn lower_limit(mut lines: Vec<String>, cond: &Option<Box<Expr>>) -> String {
+    if let Some(cond) = cond {
+        lines.push(format!("\\text{{while }} {}", to_latex(cond)));
+    }
+    if lines.len() == 1 { lines.remove(0) } else { format!("\\substack{{{}}}", lines.join(" \\\\ ")) }
+}
+
+fn big_operator(symbol: &str, lower: &str, to: &Expr, body: &Expr) -> String {
+    format!("{}_{{{}}}^{{{}}} {}", symbol, lower, to_latex(to), operand(body, PRODUCT))
+}
+
+// The arguments of a builtin call, which the parser keeps as a sequence
+fn arguments(arg: &Expr) -> &[Expr] {
+    match arg {
+        Expr::Sequence(args) => args,
+        single => std::slice::from_ref(single),
+    }
+}
+
+// Single letters stay italic, Greek names become letters, longer names upright
+fn identifier(name: &str) -> String {
+    if name.chars().count() == 1 {
+        name.to_string()
+    } else if GREEK.contains(&name) {
+        format!("\\{}", name)
+    } else {
+        format!("\\mathrm{{{}}}", escape(name))
+    }
+}
+
+fn function_name(name: &str) -> String {
+    if name.chars().count() == 1 { name.to_string() } else { format!("\\operatorname{{{}}}", escape(name)) }
+}
+
+fn escape(name: &str) -> String {
+    name.replace('_', "\\_")
+}
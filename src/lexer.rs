@@ -1,6 +1,7 @@
 // Lexer for math interpreter
 /// Supported binary operators for math expressions.
 use bincode::{Encode, Decode};
+use crate::error::LexError;
 #[derive(Debug, Clone, Copy, PartialEq, Encode, Decode)]
 pub enum BinaryOperator {
     Plus,
@@ -8,9 +9,30 @@ pub enum BinaryOperator {
     Star,
     Slash,
     Pow, // ^ operator
+    Less,      // <
+    LessEq,    // <=
+    Greater,   // >
+    GreaterEq, // >=
+    Equal,     // ==
+    NotEqual,  // !=
     // Add more operators here
 }
 
+impl BinaryOperator {
+    /// Whether this operator compares its operands, yielding 1 or 0.
+    pub fn is_comparison(self) -> bool {
+        matches!(
+            self,
+            BinaryOperator::Less
+                | BinaryOperator::LessEq
+                | BinaryOperator::Greater
+                | BinaryOperator::GreaterEq
+                | BinaryOperator::Equal
+                | BinaryOperator::NotEqual
+        )
+    }
+}
+
 /// Tokens produced by the lexer.
 #[derive(Debug, Clone, Copy, PartialEq, Encode, Decode)]
 pub enum SpecialFunction {
@@ -44,6 +66,77 @@ pub enum SpecialFunction {
     Floor,
     Rand,
     RandInt,
+    Atan2,
+    Root,
+    Expm1,
+    Ln1p,
+    Exp2,
+    Round,
+    RoundTo,
+    RoundEven,
+    RoundEvenTo,
+    Quantile,
+    QuantileType,
+    Percentile,
+    Erf,
+    Erfc,
+    BesselJ,
+    BesselY,
+    Zeta,
+    LambertW,
+    Beta,
+    BetaInc,
+    GammaInc,
+    NormCdf,
+    NormPdf,
+    NormInv,
+    NormCdfScaled,
+    NormPdfScaled,
+    NormInvScaled,
+    TCdf,
+    TInv,
+    Chi2Cdf,
+    Chi2Inv,
+    IsPrime,
+    NextPrime,
+    PrimePi,
+    Factorint,
+    Len,
+    At,
+    Mod,
+    PowMod,
+    InvMod,
+    Fib,
+    Choose,
+    DFact,
+    SubFact,
+    Deg,
+    Rad,
+    Grad,
+    Recurrence,
+    Poly,
+    PolyAdd,
+    PolySub,
+    PolyMul,
+    PolyDer,
+    PolyVal,
+    Roots,
+    QuadRoots,
+    CubicRoots,
+    Fit,
+    FitExp,
+    FitLog,
+    Arg,
+    Argc,
+    Time,
+    Seed,
+    RandState,
+    Env,
+    LoadCsv,
+    SaveCsv,
+    SaveCsvXy,
+    Format,
+    Print,
     // Add more as needed
 }
 
@@ -61,20 +154,112 @@ pub enum Token {
     EndDef,
     Arrow,
     Var, // Added for variable declaration
+    Const, // Immutable binding: const name = expr
+    Output, // Exported binding: output name = expr
+    Let, // Scoped binding: let name = value in body
+    In,
+    Where, // expr where a = ..., b = ...: bindings for a formula, after it
     Pipe, // For |expr| absolute value
     Sum,
     Product,
+    Solve, // solve x: lhs = rhs, the roots of an equation
+    Question, // ? in cond ? a : b
+    Colon,    // : in cond ? a : b and after sum/product keywords
+    Assert(usize),   // assert(cond), tagged with its source line
+    AssertEq(usize), // assert_eq(a, b, eps), tagged with its source line
+    LBracket, // [ opening a list literal
+    RBracket, // ]
+    Radical, // √ before an operand: √x is sqrt(x)
+    Percent, // postfix %: 15% is 0.15
+    Underscore, // _ after log, introducing the base in log_b(x)
+    Return, // return expr, in a multi-line function body
+    If,     // if cond return expr, in a multi-line function body
+    Str(String), // "text", as the variable name env("N", 1) reads
+    At, // @ before the unit of a named result: output v = d / t @ "m/s"
+    Pragma(Pragma), // a `#pragma` line, alone on its token line
+}
+
+/// A setting a source file gives itself on a `#pragma` line, for the lines
+/// after it, so it travels with the file rather than with the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Encode, Decode)]
+pub enum Pragma {
+    /// `#pragma degrees`: trig functions take, and inverse ones give, degrees
+    Degrees,
+    /// `#pragma radians`: back to radians, the default
+    Radians,
+    /// `#pragma precision n`: results print with `n` digits after the point
+    Precision(usize),
+}
+
+impl Pragma {
+    // The pragma of a line holding `#pragma` and `words`
+    fn parse(words: &[&str]) -> Option<Pragma> {
+        match words {
+            ["degrees"] => Some(Pragma::Degrees),
+            ["radians"] => Some(Pragma::Radians),
+            ["precision", digits] => digits.parse().ok().map(Pragma::Precision),
+            _ => None,
+        }
+    }
 }
 
 /// Tokenizes a string input into a vector of tokens.
 pub fn tokenize(input: &str) -> Vec<Vec<Token>> {
-    input
+    tokenize_numbered(input).into_iter().map(|(_, tokens)| tokens).collect()
+}
+
+/// Tokenizes a string input, keeping the 1-based source line of each token line.
+/// Characters that start no token are skipped; `try_tokenize_numbered` reports them.
+pub fn tokenize_numbered(input: &str) -> Vec<(usize, Vec<Token>)> {
+    lex(input).0
+}
+
+/// Like `tokenize_numbered`, but fails with every character that starts no token.
+pub fn try_tokenize_numbered(input: &str) -> Result<Vec<(usize, Vec<Token>)>, Vec<LexError>> {
+    let (lines, errors) = lex(input);
+    if errors.is_empty() { Ok(lines) } else { Err(errors) }
+}
+
+// The digit a superscript character stands for
+fn superscript_digit(c: char) -> Option<u32> {
+    match c {
+        '⁰' => Some(0),
+        '¹' => Some(1),
+        '²' => Some(2),
+        '³' => Some(3),
+        '⁴'..='⁹' => Some(c as u32 - '⁴' as u32 + 4),
+        _ => None,
+    }
+}
+
+// The words after `#pragma` on a pragma line
+fn pragma_words(line: &str) -> Option<Vec<&str>> {
+    let rest = line.strip_prefix("#pragma")?;
+    (rest.is_empty() || rest.starts_with(char::is_whitespace)).then(|| rest.split_whitespace().collect())
+}
+
+// The token lines, and an error for each unrecognized character
+fn lex(input: &str) -> (Vec<(usize, Vec<Token>)>, Vec<LexError>) {
+    let mut errors = Vec::new();
+    let lines = input
         .lines()
-        .filter(|line| {
+        .enumerate()
+        // Comment lines, which include a leading `#!/usr/bin/env fmath`
+        .filter(|(_, line)| {
             let trimmed = line.trim();
-            !trimmed.is_empty() && !trimmed.starts_with('#')
+            !trimmed.is_empty() && (!trimmed.starts_with('#') || pragma_words(trimmed).is_some())
         })
-        .map(|line| {
+        .map(|(index, line)| {
+            let line_no = index + 1;
+            if let Some(words) = pragma_words(line.trim()) {
+                return match Pragma::parse(&words) {
+                    Some(pragma) => (line_no, vec![Token::Pragma(pragma)]),
+                    None => {
+                        errors.push(LexError::UnknownPragma { line: line_no, text: words.join(" ") });
+                        (line_no, Vec::new())
+                    }
+                };
+            }
             let mut tokens = Vec::with_capacity(line.len() / 2);
             let mut chars = line.chars().peekable();
             while let Some(&c) = chars.peek() {
@@ -94,21 +279,94 @@ pub fn tokenize(input: &str) -> Vec<Vec<Token>> {
                         }
                     }
                     '+' => { tokens.push(Token::Operator(BinaryOperator::Plus)); chars.next(); }
-                    '-' => { tokens.push(Token::Operator(BinaryOperator::Minus)); chars.next(); }
-                    '*' => { tokens.push(Token::Operator(BinaryOperator::Star)); chars.next(); }
-                    '/' => { tokens.push(Token::Operator(BinaryOperator::Slash)); chars.next(); }
+                    // U+2212 is the minus sign of typeset formulas
+                    '-' | '\u{2212}' => { tokens.push(Token::Operator(BinaryOperator::Minus)); chars.next(); }
+                    '*' | '×' | '·' => { tokens.push(Token::Operator(BinaryOperator::Star)); chars.next(); }
+                    '/' | '÷' => { tokens.push(Token::Operator(BinaryOperator::Slash)); chars.next(); }
+                    '≤' => { tokens.push(Token::Operator(BinaryOperator::LessEq)); chars.next(); }
+                    '≥' => { tokens.push(Token::Operator(BinaryOperator::GreaterEq)); chars.next(); }
+                    '≠' => { tokens.push(Token::Operator(BinaryOperator::NotEqual)); chars.next(); }
+                    '√' => { tokens.push(Token::Radical); chars.next(); }
+                    // Superscripts are a power: x² is x^2, x⁻¹ is x^-1
+                    c if c == '⁻' || superscript_digit(c).is_some() => {
+                        tokens.push(Token::Operator(BinaryOperator::Pow));
+                        if c == '⁻' {
+                            tokens.push(Token::Operator(BinaryOperator::Minus));
+                            chars.next();
+                        }
+                        let mut exponent = None;
+                        while let Some(d) = chars.peek().and_then(|&d| superscript_digit(d)) {
+                            exponent = Some(exponent.unwrap_or(0.0) * 10.0 + d as f64);
+                            chars.next();
+                        }
+                        match exponent {
+                            Some(n) => tokens.push(Token::Number(n)),
+                            None => errors.push(LexError::UnexpectedChar { line: line_no, ch: c }),
+                        }
+                    }
                     '^' => { tokens.push(Token::Operator(BinaryOperator::Pow)); chars.next(); }
-                    '!' => { tokens.push(Token::Function(SpecialFunction::Fact)); chars.next(); }
+                    '!' => {
+                        // Support '!=' as NotEqual and '!!' as double factorial, otherwise postfix factorial
+                        chars.next();
+                        if let Some('=') = chars.peek() {
+                            chars.next();
+                            tokens.push(Token::Operator(BinaryOperator::NotEqual));
+                        } else if let Some('!') = chars.peek() {
+                            chars.next();
+                            tokens.push(Token::Function(SpecialFunction::DFact));
+                        } else {
+                            tokens.push(Token::Function(SpecialFunction::Fact));
+                        }
+                    }
+                    '<' | '>' => {
+                        chars.next();
+                        let or_equal = chars.peek() == Some(&'=');
+                        if or_equal {
+                            chars.next();
+                        }
+                        tokens.push(Token::Operator(match (c, or_equal) {
+                            ('<', false) => BinaryOperator::Less,
+                            ('<', true) => BinaryOperator::LessEq,
+                            ('>', false) => BinaryOperator::Greater,
+                            _ => BinaryOperator::GreaterEq,
+                        }));
+                    }
+                    '%' => { tokens.push(Token::Percent); chars.next(); }
+                    '@' => { tokens.push(Token::At); chars.next(); }
+                    '_' => { tokens.push(Token::Underscore); chars.next(); }
+                    '?' => { tokens.push(Token::Question); chars.next(); }
+                    ':' => { tokens.push(Token::Colon); chars.next(); }
                     '(' => { tokens.push(Token::LParen); chars.next(); }
                     ')' => { tokens.push(Token::RParen); chars.next(); }
+                    '[' => { tokens.push(Token::LBracket); chars.next(); }
+                    ']' => { tokens.push(Token::RBracket); chars.next(); }
                     '|' => { tokens.push(Token::Pipe); chars.next(); }
                     ',' => { tokens.push(Token::Comma); chars.next(); }
+                    '"' => {
+                        chars.next();
+                        let mut text = String::new();
+                        let mut closed = false;
+                        for d in chars.by_ref() {
+                            if d == '"' {
+                                closed = true;
+                                break;
+                            }
+                            text.push(d);
+                        }
+                        if !closed {
+                            errors.push(LexError::UnterminatedString { line: line_no });
+                        }
+                        tokens.push(Token::Str(text));
+                    }
                     '=' => {
-                        // Support '=>' as Arrow, otherwise Assign
+                        // Support '=>' as Arrow and '==' as Equal, otherwise Assign
                         chars.next();
                         if let Some('>') = chars.peek() {
                             chars.next();
                             tokens.push(Token::Arrow);
+                        } else if let Some('=') = chars.peek() {
+                            chars.next();
+                            tokens.push(Token::Operator(BinaryOperator::Equal));
                         } else {
                             tokens.push(Token::Assign);
                         }
@@ -116,7 +374,9 @@ pub fn tokenize(input: &str) -> Vec<Vec<Token>> {
                     c if c.is_alphabetic() => {
                         let mut ident = String::new();
                         while let Some(&d) = chars.peek() {
-                            if d.is_alphanumeric() || d == '_' {
+                            // log_b(x) names a base, so `log` ends at the underscore
+                            let underscore = d == '_' && !ident.eq_ignore_ascii_case("log");
+                            if (d.is_alphanumeric() && superscript_digit(d).is_none()) || underscore {
                                 ident.push(d);
                                 chars.next();
                             } else {
@@ -126,45 +386,44 @@ pub fn tokenize(input: &str) -> Vec<Vec<Token>> {
                         match ident.to_ascii_lowercase().as_str() {
                             "sum" => tokens.push(Token::Sum),
                             "product" => tokens.push(Token::Product),
+                            "solve" => tokens.push(Token::Solve),
                             "def" => tokens.push(Token::Def),
                             "end" => tokens.push(Token::EndDef),
+                            "return" => tokens.push(Token::Return),
+                            "if" => tokens.push(Token::If),
                             "var" => tokens.push(Token::Var),
-                            "sin" => tokens.push(Token::Function(SpecialFunction::Sin)),
-                            "cos" => tokens.push(Token::Function(SpecialFunction::Cos)),
-                            "tan" => tokens.push(Token::Function(SpecialFunction::Tan)),
-                            "cot" => tokens.push(Token::Function(SpecialFunction::Cot)),
-                            "sec" => tokens.push(Token::Function(SpecialFunction::Sec)),
-                            "csc" => tokens.push(Token::Function(SpecialFunction::Csc)),
-                            "sinh" => tokens.push(Token::Function(SpecialFunction::Sinh)),
-                            "cosh" => tokens.push(Token::Function(SpecialFunction::Cosh)),
-                            "tanh" => tokens.push(Token::Function(SpecialFunction::Tanh)),
-                            "asinh" => tokens.push(Token::Function(SpecialFunction::Asinh)),
-                            "acosh" => tokens.push(Token::Function(SpecialFunction::Acosh)),
-                            "atanh" => tokens.push(Token::Function(SpecialFunction::Atanh)),
-                            "exp" => tokens.push(Token::Function(SpecialFunction::Exp)),
-                            "log" => tokens.push(Token::Function(SpecialFunction::Log)),
-                            "log10" => tokens.push(Token::Function(SpecialFunction::Log10)),
-                            "log2" => tokens.push(Token::Function(SpecialFunction::Log2)),
-                            "sqrt" => tokens.push(Token::Function(SpecialFunction::Sqrt)),
-                            "abs" => tokens.push(Token::Function(SpecialFunction::Abs)),
-                            "acos" => tokens.push(Token::Function(SpecialFunction::Acos)),
-                            "atan" => tokens.push(Token::Function(SpecialFunction::Atan)),
-                            "acot" => tokens.push(Token::Function(SpecialFunction::Acot)),
-                            "asec" => tokens.push(Token::Function(SpecialFunction::Asec)),
-                            "acsc" => tokens.push(Token::Function(SpecialFunction::Acsc)),
-                            "pow" => tokens.push(Token::Function(SpecialFunction::Pow)),
-                            "floor" => tokens.push(Token::Function(SpecialFunction::Floor)),
-                            "rand" => tokens.push(Token::Function(SpecialFunction::Rand)),
-                            "randint" => tokens.push(Token::Function(SpecialFunction::RandInt)),
-                            _ => tokens.push(Token::Ident(ident)),
+                            "const" => tokens.push(Token::Const),
+                            "output" => tokens.push(Token::Output),
+                            "let" => tokens.push(Token::Let),
+                            "in" => tokens.push(Token::In),
+                            "where" => tokens.push(Token::Where),
+                            "assert" => tokens.push(Token::Assert(line_no)),
+                            "assert_eq" => tokens.push(Token::AssertEq(line_no)),
+                            "inf" => tokens.push(Token::Number(f64::INFINITY)),
+                            "π" => tokens.push(Token::Number(std::f64::consts::PI)),
+                            "τ" => tokens.push(Token::Number(std::f64::consts::TAU)),
+                            // A builtin's name is free for variables and parameters
+                            // anywhere it is not called, as `log_b(x)` and `f(x)` call
+                            name => match crate::builtins::lookup(name) {
+                                Some(func) if matches!(chars.clone().find(|d| !d.is_whitespace()), Some('(')) || chars.peek() == Some(&'_') => {
+                                    tokens.push(Token::Function(func))
+                                }
+                                _ => tokens.push(Token::Ident(ident)),
+                            },
                         }
                     }
                     c if c.is_whitespace() => { chars.next(); }
-                    _ => { chars.next(); }
+                    _ => {
+                        errors.push(LexError::UnexpectedChar { line: line_no, ch: c });
+                        chars.next();
+                    }
                 }
             }
-            tokens
+            log::trace!(target: "lexer", "line {}: {:?}", line_no, tokens);
+            (line_no, tokens)
         })
-        .filter(|tokens| !tokens.is_empty())
-        .collect()
+        .filter(|(_, tokens)| !tokens.is_empty())
+        .collect::<Vec<_>>();
+    log::debug!(target: "lexer", "{} lines, {} tokens", lines.len(), lines.iter().map(|(_, t)| t.len()).sum::<usize>());
+    (lines, errors)
 }
@@ -0,0 +1,39 @@
+// Library crate for the fmath interpreter and compiler
+//! Core of the fmath math language: lexer, parser, AST, bytecode compiler, and VM.
+//! The `fmath` binary is a thin CLI over these modules, so the same core can be
+//! embedded in GUIs, graphing tools, or services.
+
+pub mod lexer;
+pub mod parser;
+pub mod ast;
+pub mod expr;
+pub mod interpreter;
+pub mod bytecode;
+pub mod compiler;
+pub mod builtins;
+pub mod analysis;
+pub mod config;
+pub mod engine;
+pub mod error;
+pub mod gpu;
+pub mod golden;
+pub mod build;
+pub mod mthc;
+pub mod stats;
+pub mod special;
+pub mod numtheory;
+pub mod poly;
+pub mod numeric;
+pub mod csv;
+pub mod text;
+pub mod plot;
+pub mod latex;
+pub mod report;
+pub mod template;
+pub mod session;
+#[cfg(feature = "repl")]
+pub mod repl;
+pub mod incremental;
+pub mod json;
+pub mod server;
+pub mod service;
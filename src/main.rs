@@ -1,16 +1,9 @@
-use std::fs::File;
-use std::io::Write;
-// Add bincode for compact binary serialization
-use bincode::encode_to_vec;
 use std::path::Path;
-use bincode::decode_from_slice;
 
-mod lexer;
-mod parser;
-mod ast;
-mod interpreter;
-mod bytecode;
-mod compiler;
+use fmath::{lexer, parser, interpreter, bytecode, compiler, golden};
+use fmath::analysis::{Diagnostic, Level};
+use fmath::config::Config;
+use fmath::error::{CompileError, FmathError, RuntimeError};
 
 /// Entry point for the math interpreter CLI.
 /// This main function is minimal and delegates all logic to modules, making it easy to reuse the core for GUI or graphing.
@@ -18,13 +11,109 @@ use std::fs;
 use std::env;
 
 fn main() -> Result<(), i32> {
-	let args: Vec<String> = env::args().collect();
-	let mut base_path = String::from("examples/math_example");
-	for arg in &args[1..] {
-		if arg != "--compile-only" {
-			base_path = arg.clone();
+	// -v logs each phase's summary, -vv every token line, statement, instruction list and call
+	let verbosity = env::args().map(|arg| match arg.as_str() {
+		"-v" => 1,
+		"-vv" => 2,
+		_ => 0,
+	}).max().unwrap_or(0);
+	let args: Vec<String> = env::args().filter(|arg| arg != "-v" && arg != "-vv").collect();
+	log::set_logger(&StderrLogger).map_err(|_| 2)?;
+	log::set_max_level([log::LevelFilter::Warn, log::LevelFilter::Debug, log::LevelFilter::Trace][verbosity]);
+	let config = Config::load().map_err(|e| {
+		eprintln!("Error: {}", e);
+		2
+	})?;
+	if args.get(1).map(String::as_str) == Some("test") {
+		return run_golden_tests(&args[2..], &config);
+	}
+	if args.get(1).map(String::as_str) == Some("table") {
+		return run_table(&args[2..], &config);
+	}
+	if args.get(1).map(String::as_str) == Some("plot") {
+		return run_plot(&args[2..], &config);
+	}
+	if args.get(1).map(String::as_str) == Some("field") {
+		return run_field(&args[2..], &config);
+	}
+	if args.get(1).map(String::as_str) == Some("hist") {
+		return run_hist(&args[2..], &config);
+	}
+	if args.get(1).map(String::as_str) == Some("solve") {
+		return run_solve(&args[2..], &config);
+	}
+	if args.get(1).map(String::as_str) == Some("sweep") {
+		return run_sweep(&args[2..], &config);
+	}
+	if args.get(1).map(String::as_str) == Some("report") {
+		return run_report(&args[2..]);
+	}
+	if args.get(1).map(String::as_str) == Some("template") {
+		return run_template(&args[2..]);
+	}
+	if args.get(1).map(String::as_str) == Some("build") {
+		return run_build(&args[2..], &config);
+	}
+	if args.get(1).map(String::as_str) == Some("serve") {
+		return run_serve(&args[2..]);
+	}
+	if args.get(1).map(String::as_str) == Some("--service") {
+		return run_service(&args[2..]);
+	}
+	if args.get(1).map(String::as_str) == Some("repl") {
+		return run_repl(&args[2..], &config);
+	}
+	let mut base_path = None;
+	// Positional arguments after the file, for a script to read with `arg(n)`
+	let mut script_args = Vec::new();
+	let mut config = config;
+	let mut lines = false;
+	let mut dump_vars = false;
+	let mut format = fmath::mthc::MthcFormat::default();
+	let mut version = fmath::mthc::FORMAT_VERSION;
+	let mut iter = args[1..].iter();
+	while let Some(arg) = iter.next() {
+		match arg.as_str() {
+			"--compile-only" => {}
+			"--lines" => lines = true,
+			"--dump-vars" => dump_vars = true,
+			"--quiet" => config.quiet = true,
+			"--deny-warnings" => set_lint(&mut config, "--deny", Some(&"warnings".to_string()))?,
+			"--allow" | "--warn" | "--deny" => set_lint(&mut config, arg, iter.next())?,
+			"--strict" => config.strict = true,
+			"--no-env" => config.no_env = true,
+			"--allow-fs" => config.allow_fs = true,
+			"--sandbox" => config.sandbox = true,
+			"--stats" => config.stats = true,
+			"--precision" | "--seed" | "--format" | "--target-version" | "--import-dir" | "--max-memory" => {
+				let value = iter.next().ok_or_else(|| {
+					eprintln!("Error: {} expects a value", arg);
+					2
+				})?;
+				let parsed = match arg.as_str() {
+					"--precision" => value.parse().map(|v| config.precision = Some(v)).is_ok(),
+					"--format" => value.parse().map(|v| format = v).is_ok(),
+					"--target-version" => value.parse().map(|v| version = v).is_ok(),
+					"--max-memory" => value.parse().map(|v| config.max_memory = Some(v)).is_ok(),
+					"--import-dir" => {
+						config.import_dirs.push(value.into());
+						true
+					}
+					_ => value.parse().map(|v| config.seed = Some(v)).is_ok(),
+				};
+				if !parsed {
+					eprintln!("Error: invalid value for {}: {}", arg, value);
+					return Err(2);
+				}
+			}
+			_ if base_path.is_none() => base_path = Some(arg.clone()),
+			_ => script_args.push(arg.clone()),
 		}
 	}
+	let base_path = base_path.unwrap_or_else(|| String::from("examples/math_example"));
+	if lines {
+		return run_lines(&config);
+	}
 
 	let (mthc_path, mth_src_path, run_mthc_direct) = if base_path.ends_with(".mthc") {
 		(base_path.clone(), base_path.trim_end_matches(".mthc").to_string() + ".mth", true)
@@ -36,72 +125,1184 @@ fn main() -> Result<(), i32> {
 
 	if run_mthc_direct && Path::new(&mthc_path).exists() {
 		// Always run .mthc file if specified
-		let bytes = fs::read(&mthc_path).expect("Failed to read .mthc file");
-		let program = decode_from_slice::<Vec<bytecode::Bytecode>, _>(&bytes, bincode::config::standard())
-			.expect("Failed to decode bytecode").0;
-		// Load function definitions from .mth file if available
-		let mut user_functions = std::collections::HashMap::new();
-		if Path::new(&mth_src_path).exists() {
-			let input = fs::read_to_string(&mth_src_path).expect("Failed to read .mth file");
-			let lines = lexer::tokenize(&input);
-			let (_, uf) = parser::parse(lines);
-			user_functions = uf;
-		}
-	// [DEBUG] Compiled bytecode output removed
-		return match interpreter::run_bytecode_with_functions(&program, &user_functions) {
-			   Ok(result) => {
-				   println!("Result: {}", result);
-				   Ok(())
-			   },
-			   Err(e) => {
-				   eprintln!("Error: {}", e);
-				   Err(1)
-			   }
-		   };
+		return run_mthc(&mthc_path, &mth_src_path, &script_args, &config, dump_vars);
 	}
 
 	if Path::new(&mth_src_path).exists() {
-		// Only compile .mth to .mthc, do not run .mth source
 		let input = fs::read_to_string(&mth_src_path).expect("Failed to read .mth file");
-		let lines = lexer::tokenize(&input);
-	let (ast, _user_functions) = parser::parse(lines);
-		let mut program = Vec::new();
-		compiler::compile(&ast, &mut program);
-		// Serialize bytecode to compact binary file
-		let encoded = encode_to_vec(&program, bincode::config::standard()).expect("Failed to serialize bytecode");
-		let mut file = File::create(&mthc_path).expect("Failed to create file");
-		file.write_all(&encoded).expect("Failed to write file");
-		println!("File saved to {}", mthc_path);
+		// An executable script (`#!/usr/bin/env fmath`) runs; other sources are only compiled to .mthc
+		if input.starts_with("#!") {
+			return run_script(&input, &script_args, &config, dump_vars);
+		}
+		let mut compiled = match compiler::CompiledProgram::try_from_source(&input) {
+			Ok((compiled, warnings)) => {
+				report_diagnostics(warnings, &config)?;
+				compiled
+			}
+			Err(diagnostics) => {
+				report_diagnostics(diagnostics, &config)?;
+				return Err(1);
+			}
+		};
+		// A program with a fixed result is stored as just that result
+		let precomputed = compiled.precompute();
+		fmath::mthc::check_version(&compiled.program, version).map_err(|e| {
+			eprintln!("Error: cannot compile {} for an older runtime: {}", mth_src_path, e);
+			1
+		})?;
+		fmath::build::write_mthc(&compiled, Path::new(&mthc_path), format, version).map_err(|e| {
+			eprintln!("Error: cannot write {}: {}", mthc_path, e);
+			2
+		})?;
+		if precomputed {
+			println!("File saved to {} (result precomputed)", mthc_path);
+		} else {
+			println!("File saved to {}", mthc_path);
+		}
 		return Ok(());
 	}
 
 	if Path::new(&mthc_path).exists() {
 		// Load and decode bytecode from .mthc file and run it
-		let bytes = fs::read(&mthc_path).expect("Failed to read .mthc file");
-		let program = decode_from_slice::<Vec<bytecode::Bytecode>, _>(&bytes, bincode::config::standard())
-			.expect("Failed to decode bytecode").0;
-		// Load function definitions from .mth file if available
-		let mut user_functions = std::collections::HashMap::new();
-		if Path::new(&mth_src_path).exists() {
-			let input = fs::read_to_string(&mth_src_path).expect("Failed to read .mth file");
-			let lines = lexer::tokenize(&input);
-			let (_, uf) = parser::parse(lines);
-			user_functions = uf;
-		}
-	// [DEBUG] Compiled bytecode output removed
-		return match interpreter::run_bytecode_with_functions(&program, &user_functions) {
-			Ok(result) => {
-				println!("Result: {}", result);
-				Ok(())
+		run_mthc(&mthc_path, &mth_src_path, &script_args, &config, dump_vars)
+	} else {
+		eprintln!("Error: neither {} nor {} found", mthc_path, mth_src_path);
+		Err(2)
+	}
+}
+
+// Recursively collect user-defined functions from the AST
+
+// Writes log records to stderr as `[LEVEL phase] message`
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+	fn enabled(&self, metadata: &log::Metadata) -> bool {
+		metadata.level() <= log::max_level()
+	}
+
+	fn log(&self, record: &log::Record) {
+		if self.enabled(record.metadata()) {
+			eprintln!("[{} {}] {}", record.level(), record.target(), record.args());
+		}
+	}
+
+	fn flush(&self) {}
+}
+
+// Runs a `.mthc` file and prints its result, with the user functions of its
+// `.mth` source if there is one (a precomputed result needs none), and with
+// `dump_vars` every variable the run left bound
+fn run_mthc(mthc_path: &str, mth_src_path: &str, script_args: &[String], config: &Config, dump_vars: bool) -> Result<(), i32> {
+	let report = |e: FmathError| {
+		eprintln!("Error: {}", e);
+		1
+	};
+	let bytes = fs::read(mthc_path).map_err(|e| {
+		eprintln!("Error: cannot read {}: {}", mthc_path, e);
+		2
+	})?;
+	// bincode, JSON or MessagePack, whichever the file was written in
+	let mut program = fmath::mthc::decode(&bytes).map_err(|e| report(CompileError::Bytecode(e).into()))?;
+	let mut user_functions = fmath::ast::UserFunctions::new();
+	// A precomputed result keeps no variables, so dumping them runs the source instead
+	let rerun = dump_vars && compiler::is_precomputed(&program);
+	if (rerun || !compiler::is_precomputed(&program)) && Path::new(mth_src_path).exists() {
+		let input = fs::read_to_string(mth_src_path).map_err(|e| {
+			eprintln!("Error: cannot read {}: {}", mth_src_path, e);
+			2
+		})?;
+		if rerun {
+			compiler::CompiledProgram { program, functions: user_functions } = compiler::CompiledProgram::from_source(&input).map_err(report)?;
+		} else {
+			(_, user_functions) = parser::parse(lexer::tokenize_numbered(&input)).map_err(report)?;
+		}
+	}
+	let (result, state) = run_program(&program, &user_functions, script_args, config, dump_vars).map_err(|e| report(e.into()))?;
+	let config = &with_program_precision(config, &state);
+	if let Some(result) = result {
+		println!("Result: {}", result.format(|x| config.format(x)));
+	}
+	print_vars(&state, config, dump_vars);
+	Ok(())
+}
+
+// Runs compiled bytecode, seeding its random draws from the config if it sets a
+// seed and printing what the VM did with `--stats`; returns the result with the
+// state holding the variables. A program may end without a result when it has
+// outputs to print or `dump_vars` prints its variables
+fn run_program(
+	program: &bytecode::Program,
+	user_functions: &fmath::ast::UserFunctions,
+	script_args: &[String],
+	config: &Config,
+	dump_vars: bool,
+) -> Result<(Option<interpreter::Value>, interpreter::VmState), RuntimeError> {
+	let mut state = config_state(config);
+	state.set_args(script_args.to_vec());
+	state.set_stats(config.stats);
+	if let Some(seed) = config.seed {
+		state.seed(seed);
+	}
+	let result = state.run_optional(program, user_functions)?;
+	if let Some(stats) = state.stats() {
+		eprintln!(
+			"stats: {} instructions, max stack depth {}, {} loop iterations, {} user calls",
+			stats.instructions, stats.max_stack_depth, stats.loop_iterations, stats.user_calls
+		);
+	}
+	if result.is_none() && !dump_vars && state.outputs().is_empty() {
+		return Err(RuntimeError::NoResult(Some("the last statement is not an expression")));
+	}
+	Ok((result, state))
+}
+
+// A state with the limits and the environment and file access of `config`,
+// as a file, `--lines` and the repl run on
+fn config_state(config: &Config) -> interpreter::VmState {
+	let mut state = config_engine(config, None).new_state();
+	if !config.sandbox {
+		state.set_env_access(!config.no_env);
+		state.set_fs_access(config.allow_fs);
+		state.set_import_dirs(config.import_dirs.clone());
+	}
+	state
+}
+
+// `config`, printing with the digits of the program's `#pragma precision`
+// unless the command line or a config file gives a precision
+fn with_program_precision(config: &Config, state: &interpreter::VmState) -> Config {
+	Config { precision: config.precision.or(state.precision()), ..config.clone() }
+}
+
+// Prints the named results of `state` as `name = value`, followed by the unit
+// of any labelled one, then with
+// `dump_vars` every variable bound in it, numbers first, then lists as
+// `name = [a, b, ...]`
+fn print_vars(state: &interpreter::VmState, config: &Config, dump_vars: bool) {
+	for (name, value) in state.outputs() {
+		match state.unit(name) {
+			Some(unit) => println!("{} = {} {}", name, config.format(value), unit),
+			None => println!("{} = {}", name, config.format(value)),
+		}
+	}
+	if !dump_vars {
+		return;
+	}
+	for (name, value) in state.vars() {
+		println!("{} = {}", name, config.format(value));
+	}
+	for (name, list) in state.lists() {
+		let items: Vec<String> = list.iter().map(|&x| config.format(x)).collect();
+		println!("{} = [{}]", name, items.join(", "));
+	}
+}
+
+// Compiles and runs a script's source with its command-line arguments,
+// printing just the result (and with `dump_vars` the variables after it)
+fn run_script(input: &str, script_args: &[String], config: &Config, dump_vars: bool) -> Result<(), i32> {
+	let (compiled, warnings) = compiler::CompiledProgram::try_from_source(input).map_err(|diagnostics| {
+		for d in diagnostics.iter().filter(|d| d.is_error()) {
+			eprintln!("Error: {}", d);
+		}
+		1
+	})?;
+	report_diagnostics(warnings, config)?;
+	let (result, state) = run_program(&compiled.program, &compiled.functions, script_args, config, dump_vars).map_err(|e| {
+		eprintln!("Error: {}", e);
+		1
+	})?;
+	let config = &with_program_precision(config, &state);
+	if let Some(result) = result {
+		println!("{}", result.format(|x| config.format(x)));
+	}
+	print_vars(&state, config, dump_vars);
+	Ok(())
+}
+
+// Prints `diagnostics` at the lint levels of `config`: allowed warnings are
+// dropped, and denied ones are errors, which fail the run
+fn report_diagnostics(diagnostics: Vec<Diagnostic>, config: &Config) -> Result<(), i32> {
+	let diagnostics = config.lints.apply(diagnostics);
+	for d in &diagnostics {
+		if d.is_error() {
+			eprintln!("Error: {}", d);
+		} else if !config.quiet {
+			eprintln!("Warning: {}", d);
+		}
+	}
+	if diagnostics.iter().any(Diagnostic::is_error) {
+		return Err(1);
+	}
+	Ok(())
+}
+
+// Applies `--allow name`, `--warn name` or `--deny name` to the lint levels of `config`
+fn set_lint(config: &mut Config, flag: &str, name: Option<&String>) -> Result<(), i32> {
+	let level = match flag {
+		"--allow" => Level::Allow,
+		"--warn" => Level::Warn,
+		_ => Level::Deny,
+	};
+	let name = name.ok_or_else(|| {
+		eprintln!("Error: {} expects a lint name", flag);
+		2
+	})?;
+	config.lints.set(name, level).map_err(|e| {
+		eprintln!("Error: {}", e);
+		2
+	})
+}
+
+// Reads and compiles a source file whose `inputs` are set per evaluation,
+// printing any errors, and its warnings at the lint levels of `config`
+fn compile_file(path: &str, inputs: &[&str], config: &Config) -> Result<compiler::CompiledProgram, i32> {
+	let input = fs::read_to_string(path).map_err(|e| {
+		eprintln!("Error: cannot read {}: {}", path, e);
+		2
+	})?;
+	match compiler::CompiledProgram::try_from_source_with_inputs(&input, inputs) {
+		Ok((compiled, warnings)) => {
+			report_diagnostics(warnings, config)?;
+			Ok(compiled)
+		}
+		Err(diagnostics) => {
+			for d in diagnostics.iter().filter(|d| d.is_error()) {
+				eprintln!("Error: {}", d);
+			}
+			Err(1)
+		}
+	}
+}
+
+/// `fmath table file.mth f --from a --to b [--steps n] [--seed s]`: prints
+/// `x<TAB>f(x)` at n + 1 evenly spaced points, sampled on all cores.
+fn run_table(args: &[String], config: &Config) -> Result<(), i32> {
+	let mut positional = Vec::new();
+	let (mut from, mut to): (Option<f64>, Option<f64>) = (None, None);
+	let (mut steps, mut seed) = (config.steps.unwrap_or(10), config.seed.unwrap_or_else(rand::random::<u64>));
+	let mut iter = args.iter();
+	while let Some(arg) = iter.next() {
+		match arg.as_str() {
+			"--from" | "--to" | "--steps" | "--seed" => {
+				let value = iter.next().ok_or_else(|| {
+					eprintln!("Error: {} expects a value", arg);
+					2
+				})?;
+				let parsed = match arg.as_str() {
+					"--from" => value.parse().map(|v| from = Some(v)).is_ok(),
+					"--to" => value.parse().map(|v| to = Some(v)).is_ok(),
+					"--steps" => value.parse().map(|v| steps = v).is_ok() && steps > 0,
+					_ => value.parse().map(|v| seed = v).is_ok(),
+				};
+				if !parsed {
+					eprintln!("Error: invalid value for {}: {}", arg, value);
+					return Err(2);
+				}
+			}
+			_ => positional.push(arg.clone()),
+		}
+	}
+	let ([path, function], Some(from), Some(to)) = (positional.as_slice(), from, to) else {
+		eprintln!("Usage: fmath table file.mth f --from a --to b [--steps n] [--seed s]");
+		return Err(2);
+	};
+	let compiled = compile_file(path, &[], config)?;
+	if !compiled.functions.contains_key(function) {
+		eprintln!("Error: {} defines no function `{}`", path, function);
+		return Err(1);
+	}
+	let xs: Vec<f64> = (0..=steps).map(|i| from + (to - from) * i as f64 / steps as f64).collect();
+	let results = config_engine(config, None).sample(&compiled, function, &xs, seed);
+	for (x, result) in xs.iter().zip(results) {
+		match result {
+			Ok(value) => println!("{}\t{}", x, config.format(value)),
+			Err(e) => println!("{}\tError: {}", x, e),
+		}
+	}
+	Ok(())
+}
+
+/// `fmath plot file.mth --vars x[,y] --range a..b[,c..d] [--steps n] [--style heatmap|surface] [-o out.svg]`:
+/// samples the program over its free variables and renders a line chart for one
+/// variable, or a heatmap (SVG or PNG) or isometric surface (SVG) for two.
+/// With `--annotate`, a line chart marks and lists the roots, local extrema
+/// and vertical asymptotes of the function.
+/// `--parametric "x(t) = ..., y(t) = ..."` and `--polar "r(theta) = ..."` take
+/// the place of the file and draw the curve over the parameter's `--range`.
+fn run_plot(args: &[String], config: &Config) -> Result<(), i32> {
+	const USAGE: &str = "Usage: fmath plot file.mth --vars x[,y] --range a..b[,c..d] [--steps n] [--style heatmap|surface] [--annotate] [-o out.svg]\n       fmath plot --parametric \"x(t) = ..., y(t) = ...\" | --polar \"r(theta) = ...\" [--range a..b] [--steps n] [-o out.svg]";
+	let (mut path, mut vars, mut ranges, mut curve) = (None, Vec::new(), Vec::new(), None);
+	let (mut steps, mut surface, mut annotate, mut output) = (config.steps, false, false, String::from("plot.svg"));
+	let mut iter = args.iter();
+	while let Some(arg) = iter.next() {
+		let mut value = || {
+			iter.next().cloned().ok_or_else(|| {
+				eprintln!("Error: {} expects a value", arg);
+				2
+			})
+		};
+		match arg.as_str() {
+			"--vars" => vars = value()?.split(',').map(str::to_string).collect(),
+			"--range" => ranges = parse_ranges(&value()?)?,
+			"--steps" => steps = Some(parse_steps(&value()?)?),
+			"--style" => match value()?.as_str() {
+				"heatmap" => surface = false,
+				"surface" => surface = true,
+				other => {
+					eprintln!("Error: unknown --style {} (expected heatmap or surface)", other);
+					return Err(2);
+				}
 			},
-			Err(e) => {
-				eprintln!("Error: {}", e);
-				Err(1)
+			"--annotate" => annotate = true,
+			"--parametric" => curve = Some((false, value()?)),
+			"--polar" => curve = Some((true, value()?)),
+			"-o" => output = value()?,
+			_ if arg.starts_with('-') => {
+				eprintln!("Error: unknown option {}", arg);
+				return Err(2);
+			}
+			_ => path = Some(arg.clone()),
+		}
+	}
+	if let Some((polar, spec)) = curve {
+		if path.is_some() || !vars.is_empty() || ranges.len() > 1 {
+			eprintln!("{}", USAGE);
+			return Err(2);
+		}
+		let range = ranges.first().copied().unwrap_or((0.0, std::f64::consts::TAU));
+		return plot_curve(&spec, polar, range, steps.unwrap_or(200), &output, config);
+	}
+	let Some(path) = path.filter(|_| !vars.is_empty() && vars.len() <= 2 && ranges.len() == vars.len()) else {
+		eprintln!("{}", USAGE);
+		return Err(2);
+	};
+	let png = output.ends_with(".png");
+	if png && (surface || vars.len() == 1) {
+		eprintln!("Error: only heatmaps can be written as PNG");
+		return Err(2);
+	}
+	let names: Vec<&str> = vars.iter().map(String::as_str).collect();
+	let compiled = compile_file(&path, &names, config)?;
+
+	let steps = steps.unwrap_or(if vars.len() == 1 { 200 } else { 50 });
+	let axis = |(lo, hi): (f64, f64)| -> Vec<f64> { (0..=steps).map(|i| lo + (hi - lo) * i as f64 / steps as f64).collect() };
+	let xs = axis(ranges[0]);
+	let ys = ranges.get(1).map(|&range| axis(range)).unwrap_or_default();
+	let points: Vec<f64> = if ys.is_empty() {
+		xs.clone()
+	} else {
+		ys.iter().flat_map(|&y| xs.iter().flat_map(move |&x| [x, y])).collect()
+	};
+	let results = config_engine(config, None).eval_batch(&compiled, &names, &points);
+	// Failed points are left out of the plot; report the error if none succeeded
+	if let Some(Err(e)) = results.iter().find(|r| r.is_err()).filter(|_| results.iter().all(Result::is_err)) {
+		eprintln!("Error: {}", e);
+		return Err(1);
+	}
+	let values: Vec<f64> = results.into_iter().map(|r| r.unwrap_or(f64::NAN)).collect();
+
+	let bytes = if ys.is_empty() && annotate {
+		// Refine on a fresh state per point, as the samples were evaluated
+		let engine = config_engine(config, None);
+		let features = fmath::plot::features(&xs, &values, |x| {
+			let mut state = engine.new_state();
+			state.set_var(names[0], x);
+			compiled.eval(&mut state).unwrap_or(f64::NAN)
+		});
+		for feature in &features {
+			println!("{}", feature);
+		}
+		fmath::plot::annotated_line_svg(&xs, &values, &features).into_bytes()
+	} else if ys.is_empty() {
+		fmath::plot::line_svg(&xs, &values).into_bytes()
+	} else {
+		let grid = fmath::plot::Grid { xs, ys, values };
+		if png {
+			fmath::plot::heatmap_png(&grid)
+		} else if surface {
+			fmath::plot::surface_svg(&grid).into_bytes()
+		} else {
+			fmath::plot::heatmap_svg(&grid).into_bytes()
+		}
+	};
+	fs::write(&output, bytes).map_err(|e| {
+		eprintln!("Error: cannot write {}: {}", output, e);
+		2
+	})?;
+	println!("Plot saved to {}", output);
+	Ok(())
+}
+
+// Draws the curve of `--parametric "x(t) = ..., y(t) = ..."`, or with `polar`
+// of `--polar "r(theta) = ..."`, sampled at steps + 1 values of the parameter
+fn plot_curve(spec: &str, polar: bool, (lo, hi): (f64, f64), steps: usize, output: &str, config: &Config) -> Result<(), i32> {
+	let names: &[&str] = if polar { &["r"] } else { &["x", "y"] };
+	let parts = split_top_level(spec);
+	if parts.len() != names.len() {
+		eprintln!("Error: expected {}, got {}", if polar { "r(theta) = expr" } else { "x(t) = expr, y(t) = expr" }, spec);
+		return Err(2);
+	}
+	let ts: Vec<f64> = (0..=steps).map(|i| lo + (hi - lo) * i as f64 / steps as f64).collect();
+	let engine = config_engine(config, None);
+	let mut coordinates = Vec::new();
+	for (part, name) in parts.iter().zip(names) {
+		let (param, expr) = curve_component(part, name).ok_or_else(|| {
+			eprintln!("Error: expected {}(param) = expr, got {}", name, part.trim());
+			2
+		})?;
+		let compiled = match compiler::CompiledProgram::try_from_source_with_inputs(expr, &[param]) {
+			Ok((compiled, _)) => compiled,
+			Err(diagnostics) => {
+				for d in diagnostics.iter().filter(|d| d.is_error()) {
+					eprintln!("Error: {}", d);
+				}
+				return Err(1);
 			}
 		};
+		let results = engine.eval_batch(&compiled, &[param], &ts);
+		// Failed points break the curve; report the error if none succeeded
+		if let Some(Err(e)) = results.iter().find(|r| r.is_err()).filter(|_| results.iter().all(Result::is_err)) {
+			eprintln!("Error: {}", e);
+			return Err(1);
+		}
+		coordinates.push(results.into_iter().map(|r| r.unwrap_or(f64::NAN)).collect::<Vec<f64>>());
+	}
+	let (xs, ys): (Vec<f64>, Vec<f64>) = if polar {
+		ts.iter().zip(&coordinates[0]).map(|(theta, r)| (r * theta.cos(), r * theta.sin())).unzip()
 	} else {
-		panic!("Neither {} nor {} found", mthc_path, mth_src_path);
+		(coordinates[0].clone(), coordinates[1].clone())
+	};
+	fs::write(output, fmath::plot::curve_svg(&xs, &ys)).map_err(|e| {
+		eprintln!("Error: cannot write {}: {}", output, e);
+		2
+	})?;
+	println!("Plot saved to {}", output);
+	Ok(())
+}
+
+// `spec` split at the commas outside parentheses
+fn split_top_level(spec: &str) -> Vec<&str> {
+	let (mut parts, mut depth, mut start) = (Vec::new(), 0usize, 0);
+	for (i, c) in spec.char_indices() {
+		match c {
+			'(' | '[' => depth += 1,
+			')' | ']' => depth = depth.saturating_sub(1),
+			',' if depth == 0 => {
+				parts.push(&spec[start..i]);
+				start = i + 1;
+			}
+			_ => {}
+		}
+	}
+	parts.push(&spec[start..]);
+	parts
+}
+
+// The parameter and expression of `name(param) = expr`
+fn curve_component<'a>(part: &'a str, name: &str) -> Option<(&'a str, &'a str)> {
+	let (lhs, expr) = part.split_once('=')?;
+	let param = lhs.trim().strip_prefix(name)?.trim_start().strip_prefix('(')?.strip_suffix(')')?.trim();
+	let is_name = param.chars().next().is_some_and(|c| c.is_alphabetic() || c == '_') && param.chars().all(|c| c.is_alphanumeric() || c == '_');
+	is_name.then_some((param, expr))
+}
+
+// The ranges of `--range a..b[,c..d]`
+fn parse_ranges(value: &str) -> Result<Vec<(f64, f64)>, i32> {
+	value
+		.split(',')
+		.map(|range| {
+			let (lo, hi) = range.split_once("..")?;
+			Some((lo.parse::<f64>().ok()?, hi.parse::<f64>().ok()?))
+		})
+		.collect::<Option<Vec<_>>>()
+		.ok_or_else(|| {
+			eprintln!("Error: invalid value for --range: {} (expected a..b[,c..d])", value);
+			2
+		})
+}
+
+fn parse_steps(value: &str) -> Result<usize, i32> {
+	value.parse::<usize>().ok().filter(|&n| n > 0).ok_or_else(|| {
+		eprintln!("Error: invalid value for --steps: {}", value);
+		2
+	})
+}
+
+/// `fmath field "dy/dx = expr" --range a..b,c..d [--steps n] [-o out.svg]`:
+/// samples the slope `expr` of x and y on a grid (`--steps` arrows per axis,
+/// 20 by default) and draws the direction field of the equation as SVG.
+fn run_field(args: &[String], config: &Config) -> Result<(), i32> {
+	const USAGE: &str = "Usage: fmath field \"dy/dx = expr\" --range a..b,c..d [--steps n] [-o out.svg]";
+	let (mut equation, mut ranges, mut steps, mut output) = (None, Vec::new(), 20, String::from("field.svg"));
+	let mut iter = args.iter();
+	while let Some(arg) = iter.next() {
+		let mut value = || {
+			iter.next().cloned().ok_or_else(|| {
+				eprintln!("Error: {} expects a value", arg);
+				2
+			})
+		};
+		match arg.as_str() {
+			"--range" => ranges = parse_ranges(&value()?)?,
+			"--steps" => steps = parse_steps(&value()?)?,
+			"-o" => output = value()?,
+			// A slope alone may start with a minus sign
+			_ if arg.starts_with("--") => {
+				eprintln!("Error: unknown option {}", arg);
+				return Err(2);
+			}
+			_ => equation = Some(arg.clone()),
+		}
+	}
+	let Some(equation) = equation.filter(|_| ranges.len() == 2) else {
+		eprintln!("{}", USAGE);
+		return Err(2);
+	};
+	// The slope alone is accepted too
+	let slope = match equation.split_once('=') {
+		Some((lhs, rhs)) if lhs.split_whitespace().collect::<String>() == "dy/dx" => rhs,
+		Some(_) => {
+			eprintln!("Error: expected an equation dy/dx = expr, got {}", equation);
+			return Err(2);
+		}
+		None => equation.as_str(),
+	};
+	let names = ["x", "y"];
+	let compiled = match compiler::CompiledProgram::try_from_source_with_inputs(slope, &names) {
+		Ok((compiled, _)) => compiled,
+		Err(diagnostics) => {
+			for d in diagnostics.iter().filter(|d| d.is_error()) {
+				eprintln!("Error: {}", d);
+			}
+			return Err(1);
+		}
+	};
+
+	// Arrows sit at the centers of a steps × steps grid of cells over the ranges
+	let axis = |(lo, hi): (f64, f64)| -> Vec<f64> { (0..steps).map(|i| lo + (hi - lo) * (i as f64 + 0.5) / steps as f64).collect() };
+	let (xs, ys) = (axis(ranges[0]), axis(ranges[1]));
+	let points: Vec<f64> = ys.iter().flat_map(|&y| xs.iter().flat_map(move |&x| [x, y])).collect();
+	let results = config_engine(config, None).eval_batch(&compiled, &names, &points);
+	if let Some(Err(e)) = results.iter().find(|r| r.is_err()).filter(|_| results.iter().all(Result::is_err)) {
+		eprintln!("Error: {}", e);
+		return Err(1);
+	}
+	let values = results.into_iter().map(|r| r.unwrap_or(f64::NAN)).collect();
+	let svg = fmath::plot::field_svg(&fmath::plot::Grid { xs, ys, values });
+	fs::write(&output, svg).map_err(|e| {
+		eprintln!("Error: cannot write {}: {}", output, e);
+		2
+	})?;
+	println!("Direction field saved to {}", output);
+	Ok(())
+}
+
+/// `fmath hist file.mth [--samples n] [--bins k] [--seed s] [-o out.svg]`: runs
+/// a program that draws random numbers n times (10000 by default), each run from
+/// its own seed, and prints a histogram of the results in k bins (20 by
+/// default), or with `-o` draws it as SVG.
+fn run_hist(args: &[String], config: &Config) -> Result<(), i32> {
+	const USAGE: &str = "Usage: fmath hist file.mth [--samples n] [--bins k] [--seed s] [-o out.svg]";
+	let (mut path, mut samples, mut bins, mut output) = (None, 10000, 20, None);
+	let mut seed = config.seed.unwrap_or_else(rand::random::<u64>);
+	let mut iter = args.iter();
+	while let Some(arg) = iter.next() {
+		match arg.as_str() {
+			"--samples" | "--bins" | "--seed" | "-o" => {
+				let value = iter.next().ok_or_else(|| {
+					eprintln!("Error: {} expects a value", arg);
+					2
+				})?;
+				let parsed = match arg.as_str() {
+					"--samples" => value.parse().map(|v| samples = v).is_ok() && samples > 0,
+					"--bins" => value.parse().map(|v| bins = v).is_ok() && bins > 0,
+					"--seed" => value.parse().map(|v| seed = v).is_ok(),
+					_ => {
+						output = Some(value.clone());
+						true
+					}
+				};
+				if !parsed {
+					eprintln!("Error: invalid value for {}: {}", arg, value);
+					return Err(2);
+				}
+			}
+			_ if path.is_none() => path = Some(arg.clone()),
+			_ => {
+				eprintln!("{}", USAGE);
+				return Err(2);
+			}
+		}
+	}
+	let Some(path) = path else {
+		eprintln!("{}", USAGE);
+		return Err(2);
+	};
+	let compiled = compile_file(&path, &[], config)?;
+	let results = config_engine(config, None).repeat(&compiled, samples, seed);
+	// Failed runs are left out; report the error if none succeeded
+	if let Some(Err(e)) = results.iter().find(|r| r.is_err()).filter(|_| results.iter().all(Result::is_err)) {
+		eprintln!("Error: {}", e);
+		return Err(1);
+	}
+	let values: Vec<f64> = results.into_iter().filter_map(Result::ok).collect();
+	let histogram = fmath::plot::Histogram::new(&values, bins);
+	if let Some(output) = output {
+		fs::write(&output, fmath::plot::histogram_svg(&histogram)).map_err(|e| {
+			eprintln!("Error: cannot write {}: {}", output, e);
+			2
+		})?;
+		println!("Histogram saved to {}", output);
+		return Ok(());
+	}
+	// One row per bin: its range, count, and a bar scaled to the largest count
+	let top = histogram.counts.iter().copied().max().unwrap_or(0).max(1);
+	for (k, &count) in histogram.counts.iter().enumerate() {
+		let (lo, hi) = histogram.bin(k);
+		println!("[{}, {})\t{}\t{}", fmath::plot::label(lo), fmath::plot::label(hi), count, "#".repeat(count * 50 / top));
+	}
+	let mean = values.iter().sum::<f64>() / values.len() as f64;
+	println!("{} runs ({} failed), mean {}", values.len(), samples - values.len(), mean);
+	Ok(())
+}
+
+/// `fmath solve "lhs = rhs" [--var x] [--range a..b]`: prints each root of the
+/// equation in the variable (`x` by default) within the range, as the `solve`
+/// expression finds them.
+fn run_solve(args: &[String], config: &Config) -> Result<(), i32> {
+	const USAGE: &str = "Usage: fmath solve \"lhs = rhs\" [--var x] [--range a..b]";
+	let (mut equation, mut var, mut range) = (None, String::from("x"), fmath::numeric::DEFAULT_SOLVE_RANGE);
+	let mut iter = args.iter();
+	while let Some(arg) = iter.next() {
+		match arg.as_str() {
+			"--var" | "--range" => {
+				let value = iter.next().ok_or_else(|| {
+					eprintln!("Error: {} expects a value", arg);
+					2
+				})?;
+				if arg == "--var" {
+					var = value.clone();
+					continue;
+				}
+				let bounds = value.split_once("..").and_then(|(lo, hi)| Some((lo.parse::<f64>().ok()?, hi.parse::<f64>().ok()?)));
+				range = bounds.filter(|(lo, hi)| lo < hi).ok_or_else(|| {
+					eprintln!("Error: invalid value for --range: {} (expected a..b with a < b)", value);
+					2
+				})?;
+			}
+			_ if equation.is_none() => equation = Some(arg.clone()),
+			_ => {
+				eprintln!("{}", USAGE);
+				return Err(2);
+			}
+		}
+	}
+	let Some(equation) = equation else {
+		eprintln!("{}", USAGE);
+		return Err(2);
+	};
+	// The one `=` that is not part of `==`, `<=`, `>=` or `!=`
+	let bytes = equation.as_bytes();
+	let signs: Vec<usize> = (0..bytes.len())
+		.filter(|&i| bytes[i] == b'=' && bytes.get(i + 1) != Some(&b'=') && (i == 0 || !b"=<>!".contains(&bytes[i - 1])))
+		.collect();
+	let [sign] = signs[..] else {
+		eprintln!("Error: expected an equation lhs = rhs, got {}", equation);
+		return Err(2);
+	};
+	let residual = format!("({}) - ({})", &equation[..sign], &equation[sign + 1..]);
+	let compiled = match compiler::CompiledProgram::try_from_source_with_inputs(&residual, &[var.as_str()]) {
+		Ok((compiled, _)) => compiled,
+		Err(diagnostics) => {
+			for d in diagnostics.iter().filter(|d| d.is_error()) {
+				eprintln!("Error: {}", d);
+			}
+			return Err(1);
+		}
+	};
+	let mut state = config_engine(config, None).new_state();
+	let mut error = None;
+	let roots = fmath::numeric::roots(
+		|x| {
+			state.set_var(&var, x);
+			compiled.eval(&mut state).unwrap_or_else(|e| {
+				error.get_or_insert(e);
+				f64::NAN
+			})
+		},
+		range.0,
+		range.1,
+	);
+	if let Some(e) = error.filter(|_| roots.is_empty()) {
+		eprintln!("Error: {}", e);
+		return Err(1);
+	}
+	if roots.is_empty() {
+		println!("No roots in [{}, {}]", range.0, range.1);
+	}
+	for root in roots {
+		println!("{} = {}", var, config.format(root));
+	}
+	Ok(())
+}
+
+/// `fmath sweep file.mth --var a=1..10 --var b=0..1:0.1 [--seed s] [--strict] [-o results.csv]`:
+/// evaluates the program for every combination of the variables' values (the
+/// last one varying fastest) and writes one CSV row per combination. Every
+/// combination draws the same random numbers, from `--seed` if given.
+fn run_sweep(args: &[String], config: &Config) -> Result<(), i32> {
+	let (mut path, mut vars, mut output) = (None, Vec::new(), None);
+	let mut config = config.clone();
+	let mut iter = args.iter();
+	while let Some(arg) = iter.next() {
+		match arg.as_str() {
+			"--strict" => config.strict = true,
+			"--var" | "-o" | "--seed" => {
+				let value = iter.next().ok_or_else(|| {
+					eprintln!("Error: {} expects a value", arg);
+					2
+				})?;
+				if arg == "-o" {
+					output = Some(value.clone());
+					continue;
+				}
+				if arg == "--seed" {
+					config.seed = Some(value.parse().map_err(|_| {
+						eprintln!("Error: invalid value for --seed: {}", value);
+						2
+					})?);
+					continue;
+				}
+				let (name, values) = value.split_once('=').and_then(|(name, range)| Some((name, sweep_values(range)?))).ok_or_else(|| {
+					eprintln!("Error: invalid --var {} (expected name=a, name=a..b, or name=a..b:step)", value);
+					2
+				})?;
+				vars.push((name.to_string(), values));
+			}
+			_ if arg.starts_with('-') => {
+				eprintln!("Error: unknown option {}", arg);
+				return Err(2);
+			}
+			_ => path = Some(arg.clone()),
+		}
+	}
+	let Some(path) = path.filter(|_| !vars.is_empty()) else {
+		eprintln!("Usage: fmath sweep file.mth --var name=a..b[:step] [--var ...] [--seed s] [--strict] [-o results.csv]");
+		return Err(2);
+	};
+	let names: Vec<&str> = vars.iter().map(|(name, _)| name.as_str()).collect();
+	let compiled = compile_file(&path, &names, &config)?;
+
+	// Cartesian product, one point after another
+	let mut points: Vec<f64> = vec![];
+	let mut combination = vec![0usize; vars.len()];
+	'product: loop {
+		points.extend(combination.iter().zip(&vars).map(|(&k, (_, values))| values[k]));
+		for d in (0..vars.len()).rev() {
+			combination[d] += 1;
+			if combination[d] < vars[d].1.len() {
+				continue 'product;
+			}
+			combination[d] = 0;
+		}
+		break;
+	}
+	let seed = config.seed.unwrap_or_else(rand::random::<u64>);
+	let results = config_engine(&config, None).with_seed(seed).eval_batch(&compiled, &names, &points);
+
+	let mut csv = format!("{},result,error\n", names.join(","));
+	for (point, result) in points.chunks(names.len()).zip(results) {
+		let values: Vec<String> = point.iter().map(f64::to_string).collect();
+		match result {
+			Ok(value) => csv += &format!("{},{},\n", values.join(","), config.format(value)),
+			Err(e) => csv += &format!("{},,\"{}\"\n", values.join(","), e.to_string().replace('"', "\"\"")),
+		}
+	}
+	match output {
+		Some(output) => {
+			fs::write(&output, csv).map_err(|e| {
+				eprintln!("Error: cannot write {}: {}", output, e);
+				2
+			})?;
+			println!("{} rows saved to {}", points.len() / names.len(), output);
+		}
+		None => print!("{}", csv),
+	}
+	Ok(())
+}
+
+// Values of a sweep range: `a`, `a..b` in steps of 1, or `a..b:step`, both ends included
+fn sweep_values(range: &str) -> Option<Vec<f64>> {
+	let Some((lo, rest)) = range.split_once("..") else {
+		return Some(vec![range.parse().ok()?]);
+	};
+	let (hi, step) = rest.split_once(':').unwrap_or((rest, "1"));
+	let (lo, hi, step): (f64, f64, f64) = (lo.parse().ok()?, hi.parse().ok()?, step.parse().ok()?);
+	// Allow for rounding in (hi - lo) / step, as in 0..1:0.1
+	let count = ((hi - lo) / step + 1e-9).floor();
+	if !(0.0..1e7).contains(&count) {
+		return None;
+	}
+	// Rounding to 12 significant digits drops noise such as 0.30000000000000004
+	let round = |v: f64| format!("{:.11e}", v).parse().unwrap_or(v);
+	Some((0..=count as usize).map(|k| round(lo + k as f64 * step)).collect())
+}
+
+/// `fmath report file.mth [-o report.md]`: writes the script as Markdown with
+/// LaTeX math and the value of each statement (to stdout without `-o`).
+fn run_report(args: &[String]) -> Result<(), i32> {
+	let (path, output) = match args {
+		[path] => (path, None),
+		[path, flag, output] if flag == "-o" => (path, Some(output)),
+		_ => {
+			eprintln!("Usage: fmath report file.mth [-o report.md]");
+			return Err(2);
+		}
+	};
+	let input = fs::read_to_string(path).map_err(|e| {
+		eprintln!("Error: cannot read {}: {}", path, e);
+		2
+	})?;
+	let title = Path::new(path).file_name().map_or(path.as_str(), |name| name.to_str().unwrap_or(path));
+	let markdown = fmath::report::markdown(&input, title).map_err(|diagnostics| {
+		for d in diagnostics.iter().filter(|d| d.is_error()) {
+			eprintln!("Error: {}", d);
+		}
+		1
+	})?;
+	match output {
+		Some(output) => {
+			fs::write(output, markdown).map_err(|e| {
+				eprintln!("Error: cannot write {}: {}", output, e);
+				2
+			})?;
+			println!("Report saved to {}", output);
+		}
+		None => print!("{}", markdown),
+	}
+	Ok(())
+}
+
+/// `fmath template input.txt [-o output.txt]`: replaces each `{{ expr }}` in the
+/// file with its value (to stdout without `-o`).
+fn run_template(args: &[String]) -> Result<(), i32> {
+	let (path, output) = match args {
+		[path] => (path, None),
+		[path, flag, output] if flag == "-o" => (path, Some(output)),
+		_ => {
+			eprintln!("Usage: fmath template input.txt [-o output.txt]");
+			return Err(2);
+		}
+	};
+	let input = fs::read_to_string(path).map_err(|e| {
+		eprintln!("Error: cannot read {}: {}", path, e);
+		2
+	})?;
+	let text = fmath::template::render(&input).map_err(|e| {
+		eprintln!("Error: {}: {}", path, e);
+		1
+	})?;
+	match output {
+		Some(output) => {
+			fs::write(output, text).map_err(|e| {
+				eprintln!("Error: cannot write {}: {}", output, e);
+				2
+			})?;
+			println!("Output saved to {}", output);
+		}
+		None => print!("{}", text),
 	}
+	Ok(())
 }
 
-// Recursively collect user-defined functions from the AST
\ No newline at end of file
+/// Steps an evaluation of `serve` or `--service` may take unless `--fuel` says otherwise
+const DEFAULT_FUEL: u64 = 10_000_000;
+
+// An engine as strict as `config`, within the sandbox and memory limit it
+// asks for, and limited to `fuel` steps if given
+fn config_engine(config: &Config, fuel: Option<u64>) -> fmath::engine::Engine {
+	let mut engine = fmath::engine::Engine::new().with_strict(config.strict).with_env_access(!config.no_env);
+	if config.sandbox {
+		let import_dirs = config.import_dirs.clone();
+		engine = engine.with_sandbox(fmath::engine::Sandbox { import_dirs, ..Default::default() });
+	}
+	if let Some(bytes) = config.max_memory {
+		engine = engine.with_memory_limit(bytes);
+	}
+	match fuel {
+		Some(fuel) => engine.with_fuel(fuel),
+		None => engine,
+	}
+}
+
+/// `fmath serve [--port 8080] [--host 127.0.0.1] [--fuel n] [--allow-env] [--sandbox [--import-dir d]]`:
+/// answers `POST /eval`, `/compile`, `/diagnose` and `/plot` requests with JSON (see
+/// `fmath::server::handle`), stopping each evaluation after `n` steps. `env` gives
+/// its default unless `--allow-env` lets requests read the server's environment.
+fn run_serve(args: &[String]) -> Result<(), i32> {
+	let (mut port, mut host, mut fuel) = (8080u16, String::from("127.0.0.1"), DEFAULT_FUEL);
+	let mut config = Config { no_env: true, ..Config::default() };
+	let mut iter = args.iter();
+	while let Some(arg) = iter.next() {
+		match arg.as_str() {
+			"--sandbox" => {
+				config.sandbox = true;
+				continue;
+			}
+			"--allow-env" => {
+				config.no_env = false;
+				continue;
+			}
+			_ => {}
+		}
+		let Some(value) = iter.next() else {
+			eprintln!("Usage: fmath serve [--port 8080] [--host 127.0.0.1] [--fuel n] [--allow-env] [--sandbox [--import-dir d]]");
+			return Err(2);
+		};
+		let parsed = match arg.as_str() {
+			"--port" => value.parse().map(|v| port = v).is_ok(),
+			"--fuel" => value.parse().map(|v| fuel = v).is_ok(),
+			"--host" => {
+				host = value.clone();
+				true
+			}
+			"--import-dir" => {
+				config.import_dirs.push(value.into());
+				true
+			}
+			_ => {
+				eprintln!("Error: unknown option {}", arg);
+				return Err(2);
+			}
+		};
+		if !parsed {
+			eprintln!("Error: invalid value for {}: {}", arg, value);
+			return Err(2);
+		}
+	}
+	let listener = std::net::TcpListener::bind((host.as_str(), port)).map_err(|e| {
+		eprintln!("Error: cannot listen on {}:{}: {}", host, port, e);
+		2
+	})?;
+	println!("Listening on http://{}:{}", host, port);
+	fmath::server::serve(listener, config_engine(&config, Some(fuel))).map_err(|e| {
+		eprintln!("Error: {}", e);
+		1
+	})
+}
+
+/// `fmath --service [--fuel n] [--allow-env] [--sandbox [--import-dir d]]`: answers newline-delimited
+/// JSON-RPC requests on stdin with one response line each on stdout (see
+/// `fmath::service::run`), for editors and GUIs driving fmath as a subprocess. As with
+/// `serve`, `env` gives its default unless `--allow-env` is given.
+fn run_service(args: &[String]) -> Result<(), i32> {
+	let mut fuel = DEFAULT_FUEL;
+	let mut config = Config { no_env: true, ..Config::default() };
+	let mut iter = args.iter();
+	while let Some(arg) = iter.next() {
+		let value = match arg.as_str() {
+			"--sandbox" => {
+				config.sandbox = true;
+				continue;
+			}
+			"--allow-env" => {
+				config.no_env = false;
+				continue;
+			}
+			"--fuel" | "--import-dir" => iter.next(),
+			_ => None,
+		};
+		let Some(value) = value else {
+			eprintln!("Usage: fmath --service [--fuel n] [--allow-env] [--sandbox [--import-dir d]]");
+			return Err(2);
+		};
+		if arg == "--import-dir" {
+			config.import_dirs.push(value.into());
+		} else {
+			fuel = value.parse().map_err(|_| {
+				eprintln!("Error: invalid value for --fuel: {}", value);
+				2
+			})?;
+		}
+	}
+	let engine = config_engine(&config, Some(fuel));
+	fmath::service::run(std::io::stdin().lock(), std::io::stdout().lock(), &engine).map_err(|e| {
+		eprintln!("Error: {}", e);
+		1
+	})
+}
+
+/// `fmath --lines [--precision n] [--seed s] [--strict] [--sandbox] [--no-env] [--allow-fs]`:
+/// evaluates each line of stdin on its own, with variables and functions shared by all
+/// lines, and prints one line for each: its value, nothing for an assignment or
+/// definition, or the error. Each line runs within the limits a file would.
+fn run_lines(config: &Config) -> Result<(), i32> {
+	use std::io::BufRead;
+	let mut session = fmath::session::Session::with_state(config_state(config));
+	if let Some(seed) = config.seed {
+		session.seed(seed);
+	}
+	for line in std::io::stdin().lock().lines() {
+		let line = line.map_err(|e| {
+			eprintln!("Error: {}", e);
+			2
+		})?;
+		match session.eval(&line, |value| config.format(value)) {
+			Ok(text) => println!("{}", text),
+			Err(e) => println!("Error: {}", e),
+		}
+	}
+	Ok(())
+}
+
+/// `fmath repl [--precision n] [--seed s] [--strict] [--sandbox] [--no-env] [--allow-fs]`:
+/// evaluates statements typed at a prompt like `--lines` does, with line editing,
+/// history kept across sessions, and tab completion of names (see `fmath::repl::run`).
+#[cfg(feature = "repl")]
+fn run_repl(args: &[String], config: &Config) -> Result<(), i32> {
+	let mut config = config.clone();
+	let mut iter = args.iter();
+	while let Some(arg) = iter.next() {
+		let parsed = match arg.as_str() {
+			"--strict" => {
+				config.strict = true;
+				true
+			}
+			"--sandbox" => {
+				config.sandbox = true;
+				true
+			}
+			"--no-env" => {
+				config.no_env = true;
+				true
+			}
+			"--allow-fs" => {
+				config.allow_fs = true;
+				true
+			}
+			"--precision" => iter.next().and_then(|v| v.parse().ok()).map(|v| config.precision = Some(v)).is_some(),
+			"--seed" => iter.next().and_then(|v| v.parse().ok()).map(|v| config.seed = Some(v)).is_some(),
+			_ => false,
+		};
+		if !parsed {
+			eprintln!("Usage: fmath repl [--precision n] [--seed s] [--strict] [--sandbox] [--no-env] [--allow-fs]");
+			return Err(2);
+		}
+	}
+	let mut session = fmath::session::Session::with_state(config_state(&config));
+	if let Some(seed) = config.seed {
+		session.seed(seed);
+	}
+	fmath::repl::run(&mut session, |value| config.format(value)).map_err(|e| {
+		eprintln!("Error: {}", e);
+		2
+	})
+}
+
+#[cfg(not(feature = "repl"))]
+fn run_repl(_: &[String], _: &Config) -> Result<(), i32> {
+	eprintln!("Error: fmath was built without the `repl` feature");
+	Err(2)
+}
+
+/// `fmath build dir [--force] [--format f] [--target-version n]`: compiles each
+/// `.mth` under `dir` whose `.mthc` is missing or older than it (every one with
+/// `--force`), reporting the rest as up to date.
+fn run_build(args: &[String], config: &Config) -> Result<(), i32> {
+	let (mut dir, mut force) = (None, false);
+	let mut format = fmath::mthc::MthcFormat::default();
+	let mut version = fmath::mthc::FORMAT_VERSION;
+	let mut config = config.clone();
+	let mut iter = args.iter();
+	while let Some(arg) = iter.next() {
+		match arg.as_str() {
+			"--force" => force = true,
+			"--format" => {
+				format = iter.next().ok_or_else(|| "--format expects a value".to_string()).and_then(|v| v.parse()).map_err(|e| {
+					eprintln!("Error: {}", e);
+					2
+				})?;
+			}
+			"--target-version" => {
+				version = iter.next().and_then(|v| v.parse().ok()).ok_or_else(|| {
+					eprintln!("Error: --target-version expects a format version");
+					2
+				})?;
+			}
+			"--quiet" => config.quiet = true,
+			"--deny-warnings" => set_lint(&mut config, "--deny", Some(&"warnings".to_string()))?,
+			"--allow" | "--warn" | "--deny" => set_lint(&mut config, arg, iter.next())?,
+			_ if dir.is_none() => dir = Some(arg.clone()),
+			_ => {
+				eprintln!(
+					"Usage: fmath build dir [--force] [--format bincode|json|msgpack] [--target-version n] [--quiet] [--deny-warnings] [--allow|--warn|--deny lint]"
+				);
+				return Err(2);
+			}
+		}
+	}
+	let dir = dir.unwrap_or_else(|| String::from("."));
+	let report = fmath::build::build_dir(Path::new(&dir), force, &config.lints, format, version).map_err(|e| {
+		eprintln!("Error: cannot read {}: {}", dir, e);
+		2
+	})?;
+	for (path, outcome) in &report.results {
+		match outcome {
+			fmath::build::BuildOutcome::Compiled { warnings, precomputed } => {
+				println!("Compiled {}{}", path.display(), if *precomputed { " (result precomputed)" } else { "" });
+				if !config.quiet {
+					for d in warnings {
+						eprintln!("Warning: {}: {}", path.display(), d);
+					}
+				}
+			}
+			fmath::build::BuildOutcome::UpToDate => {}
+			fmath::build::BuildOutcome::Failed(errors) => {
+				for e in errors {
+					eprintln!("Error: {}: {}", path.display(), e);
+				}
+			}
+		}
+	}
+	println!("{} compiled, {} up to date, {} failed", report.compiled(), report.up_to_date(), report.failed());
+	if report.failed() == 0 { Ok(()) } else { Err(1) }
+}
+
+/// `fmath test [dir] [--tol abs] [--rel-tol rel]`: runs golden files and reports pass/fail.
+fn run_golden_tests(args: &[String], config: &Config) -> Result<(), i32> {
+	let mut dir = String::from("examples");
+	let mut tolerance = golden::Tolerance::default();
+	tolerance.abs = config.tol.unwrap_or(tolerance.abs);
+	tolerance.rel = config.rel_tol.unwrap_or(tolerance.rel);
+	let mut iter = args.iter();
+	while let Some(arg) = iter.next() {
+		match arg.as_str() {
+			"--tol" | "--rel-tol" => {
+				let value = iter.next().and_then(|v| v.parse::<f64>().ok()).ok_or_else(|| {
+					eprintln!("Error: {} expects a number", arg);
+					2
+				})?;
+				if arg == "--tol" { tolerance.abs = value; } else { tolerance.rel = value; }
+			}
+			_ => dir = arg.clone(),
+		}
+	}
+	let report = golden::run_dir(Path::new(&dir), tolerance).map_err(|e| {
+		eprintln!("Error: cannot read {}: {}", dir, e);
+		2
+	})?;
+	for (path, outcome) in &report.results {
+		match outcome {
+			golden::Outcome::Pass => println!("PASS {}", path.display()),
+			golden::Outcome::Fail(reason) => println!("FAIL {}: {}", path.display(), reason),
+			golden::Outcome::Panic(message) => println!("PANIC {}: {}", path.display(), message),
+		}
+	}
+	if report.panicked() == 0 {
+		println!("{} passed, {} failed", report.passed(), report.failed());
+	} else {
+		println!("{} passed, {} failed, {} panicked", report.passed(), report.failed(), report.panicked());
+	}
+	if report.passed() == report.results.len() { Ok(()) } else { Err(1) }
+}
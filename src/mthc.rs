@@ -0,0 +1,447 @@
+// Formats of .mthc files: compact bincode, and JSON or MessagePack for tools written in other languages
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Number, Value};
+
+use crate::bytecode::{Bytecode, Program};
+
+/// Version of the .mthc layout, stored in each file: as `fmath` in JSON and
+/// MessagePack, after `BINCODE_MAGIC` in bincode.
+/// Every change that adds an instruction raises it, so that a runtime can
+/// tell a file it cannot run; `introduced_in` records which version added what.
+/// Version 1 is the original layout, headerless bincode of the first
+/// instructions only; version 2 is the first to record its version.
+pub const FORMAT_VERSION: u64 = 17;
+
+/// The oldest format version a file can be written in. Every program starts
+/// with a `Frame`, which version 1 does not have.
+const OLDEST_TARGET_VERSION: u64 = 2;
+
+/// The format version that first had `instruction`, with its name, if it is
+/// not one of version 1 (or has changed its operands since).
+fn introduced_in(instruction: &Bytecode) -> Option<(&'static str, u64)> {
+    use Bytecode::*;
+    Some(match instruction {
+        StoreVar(..) => ("StoreVar", 2),
+        LoadVar(..) => ("LoadVar", 2),
+        SumLoop { .. } => ("SumLoop", 2),
+        ProductLoop { .. } => ("ProductLoop", 2),
+        Assert(..) => ("Assert", 2),
+        AssertEq(..) => ("AssertEq", 2),
+        StoreConst(..) => ("StoreConst", 2),
+        Less => ("Less", 2),
+        LessEq => ("LessEq", 2),
+        Greater => ("Greater", 2),
+        GreaterEq => ("GreaterEq", 2),
+        Equal => ("Equal", 2),
+        NotEqual => ("NotEqual", 2),
+        JumpIfFalse(..) => ("JumpIfFalse", 2),
+        Jump(..) => ("Jump", 2),
+        LetIn { .. } => ("LetIn", 2),
+        Atan2 => ("Atan2", 2),
+        Root => ("Root", 2),
+        Expm1 => ("Expm1", 2),
+        Ln1p => ("Ln1p", 2),
+        Exp2 => ("Exp2", 2),
+        Round => ("Round", 2),
+        RoundTo => ("RoundTo", 2),
+        RoundEven => ("RoundEven", 2),
+        RoundEvenTo => ("RoundEvenTo", 2),
+        MakeList(..) => ("MakeList", 2),
+        StoreList(..) => ("StoreList", 2),
+        LoadList(..) => ("LoadList", 2),
+        Quantile => ("Quantile", 2),
+        QuantileType => ("QuantileType", 2),
+        Percentile => ("Percentile", 2),
+        Erf => ("Erf", 2),
+        Erfc => ("Erfc", 2),
+        BesselJ => ("BesselJ", 2),
+        BesselY => ("BesselY", 2),
+        Zeta => ("Zeta", 2),
+        LambertW => ("LambertW", 2),
+        Beta => ("Beta", 2),
+        BetaInc => ("BetaInc", 2),
+        GammaInc => ("GammaInc", 2),
+        NormCdf => ("NormCdf", 2),
+        NormPdf => ("NormPdf", 2),
+        NormInv => ("NormInv", 2),
+        NormCdfScaled => ("NormCdfScaled", 2),
+        NormPdfScaled => ("NormPdfScaled", 2),
+        NormInvScaled => ("NormInvScaled", 2),
+        TCdf => ("TCdf", 2),
+        TInv => ("TInv", 2),
+        Chi2Cdf => ("Chi2Cdf", 2),
+        Chi2Inv => ("Chi2Inv", 2),
+        IsPrime => ("IsPrime", 2),
+        NextPrime => ("NextPrime", 2),
+        PrimePi => ("PrimePi", 2),
+        Factorint => ("Factorint", 2),
+        Len => ("Len", 2),
+        At => ("At", 2),
+        Mod => ("Mod", 2),
+        PowMod => ("PowMod", 2),
+        InvMod => ("InvMod", 2),
+        Fib => ("Fib", 2),
+        Recurrence => ("Recurrence", 2),
+        PolyAdd => ("PolyAdd", 2),
+        PolySub => ("PolySub", 2),
+        PolyMul => ("PolyMul", 2),
+        PolyDer => ("PolyDer", 2),
+        PolyVal => ("PolyVal", 2),
+        Roots => ("Roots", 2),
+        QuadRoots => ("QuadRoots", 2),
+        CubicRoots => ("CubicRoots", 2),
+        Fit => ("Fit", 2),
+        FitExp => ("FitExp", 2),
+        FitLog => ("FitLog", 2),
+        PowI(..) => ("PowI", 2),
+        Precomputed(..) => ("Precomputed", 2),
+        Frame(..) => ("Frame", 2),
+        Choose => ("Choose", 2),
+        DFact => ("DFact", 2),
+        SubFact => ("SubFact", 2),
+        Deg => ("Deg", 2),
+        Rad => ("Rad", 2),
+        Grad => ("Grad", 2),
+        CallUserFunctionN(..) => ("CallUserFunctionN", 2),
+        Return => ("Return", 2),
+        LoopWhile => ("LoopWhile", 2),
+        PushFunction(..) => ("PushFunction", 2),
+        StoreFunction(..) => ("StoreFunction", 2),
+        CallIndirect(..) => ("CallIndirect", 2),
+        Bind(..) => ("Bind", 2),
+        Arg => ("Arg", 2),
+        Argc => ("Argc", 2),
+        Pop => ("Pop", 3),
+        Dup => ("Dup", 4),
+        Swap => ("Swap", 4),
+        Neg => ("Neg", 5),
+        StoreOutput(..) => ("StoreOutput", 6),
+        Timed(..) => ("Timed", 7),
+        Seed => ("Seed", 8),
+        RandState => ("RandState", 8),
+        Env(..) => ("Env", 9),
+        LoadCsv(..) => ("LoadCsv", 10),
+        SaveCsv(..) => ("SaveCsv", 10),
+        Print(..) => ("Print", 11),
+        Unit(..) => ("Unit", 12),
+        Precision(..) => ("Precision", 13),
+        Solve { .. } => ("Solve", 14),
+        SolveN { .. } => ("SolveN", 15),
+        Extremum { .. } => ("Extremum", 16),
+        Derivatives { .. } => ("Derivatives", 17),
+        _ => return None,
+    })
+}
+
+/// Checks that every instruction of `program`, including those of loop and
+/// `let` bodies, exists in format `version`, so that a runtime reading only
+/// that version can run the file.
+pub fn check_version(program: &Program, version: u64) -> Result<(), String> {
+    if version == 1 {
+        return Err(format!("format version 1 predates `Frame`, which every program needs (the oldest target is {})", OLDEST_TARGET_VERSION));
+    }
+    if !(OLDEST_TARGET_VERSION..=FORMAT_VERSION).contains(&version) {
+        return Err(format!("format version {} does not exist (the latest is {})", version, FORMAT_VERSION));
+    }
+    for instruction in program {
+        if let Some((name, since)) = introduced_in(instruction)
+            && since > version
+        {
+            return Err(format!("`{}` needs format version {}, newer than the target {}", name, since, version));
+        }
+        match instruction {
+            Bytecode::SumLoop { from, to, eps, body, .. } => {
+                for part in [Some(from), Some(to), eps.as_ref(), Some(body)].into_iter().flatten() {
+                    check_version(part, version)?;
+                }
+            }
+            Bytecode::ProductLoop { from, to, body, .. } | Bytecode::Solve { from, to, body, .. } | Bytecode::Extremum { from, to, body, .. } => {
+                for part in [from, to, body] {
+                    check_version(part, version)?;
+                }
+            }
+            Bytecode::SolveN { equations, .. } => {
+                for part in equations {
+                    check_version(part, version)?;
+                }
+            }
+            Bytecode::LetIn { body, .. } | Bytecode::Timed(body) | Bytecode::Derivatives { body, .. } => check_version(body, version)?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// How a compiled program is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MthcFormat {
+    /// bincode's standard encoding, the smallest and fastest to load.
+    #[default]
+    Bincode,
+    Json,
+    MessagePack,
+}
+
+impl FromStr for MthcFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "bincode" => Ok(MthcFormat::Bincode),
+            "json" => Ok(MthcFormat::Json),
+            "msgpack" => Ok(MthcFormat::MessagePack),
+            _ => Err(format!("unknown format `{}` (expected bincode, json or msgpack)", s)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Document<'a> {
+    fmath: u64,
+    program: &'a Program,
+}
+
+#[derive(Deserialize)]
+struct OwnedDocument {
+    fmath: u64,
+    program: Program,
+}
+
+// The bytes a MessagePack file starts with: a map of two entries whose first key is "fmath"
+const MSGPACK_MAGIC: &[u8] = b"\x82\xa5fmath";
+
+// The bytes a bincode file starts with, before its version. A program's own
+// bincode starts with a zero byte only when it is empty, and is then that byte
+// alone, so files written before the header cannot be mistaken for it.
+const BINCODE_MAGIC: &[u8] = b"\0fmath";
+
+/// Encodes `program` in `format`, marked as format `version` (see
+/// `check_version`). Bincode is `BINCODE_MAGIC`, the version and the program;
+/// JSON and MessagePack hold the map
+/// `{"fmath": 17, "program": [...]}`, each instruction written as its name
+/// (`"Add"`) or a map from its name to its operands (`{"PushNumber": 2.0}`,
+/// `{"SumLoop": {"from": [...], ...}}`); numbers JSON cannot hold are the
+/// strings `"inf"`, `"-inf"` and `"NaN"`.
+pub fn encode(program: &Program, format: MthcFormat, version: u64) -> Vec<u8> {
+    let document = Document { fmath: version, program };
+    match format {
+        MthcFormat::Bincode => {
+            let mut out = BINCODE_MAGIC.to_vec();
+            out.extend(bincode::encode_to_vec((version, program), bincode::config::standard()).expect("bytecode encodes"));
+            out
+        }
+        MthcFormat::Json => serde_json::to_vec(&document).expect("bytecode serializes"),
+        MthcFormat::MessagePack => {
+            let mut out = Vec::new();
+            write_msgpack(&serde_json::to_value(&document).expect("bytecode serializes"), &mut out);
+            out
+        }
+    }
+}
+
+/// Most bytes the containers of a bincode program may claim, so that a
+/// corrupt or hostile length prefix fails instead of exhausting memory.
+const BINCODE_LIMIT: usize = 1 << 26;
+
+/// Decodes a program written in any of the formats, telling them apart by
+/// their first bytes: JSON starts with `{`, MessagePack with the key `fmath`.
+/// Files of any version from 2 to `FORMAT_VERSION` are read, and bincode files
+/// without the header (version 1, and files written before the header) are
+/// read as they are.
+pub fn decode(bytes: &[u8]) -> Result<Program, String> {
+    let document = if bytes.trim_ascii_start().starts_with(b"{") {
+        serde_json::from_slice::<OwnedDocument>(bytes).map_err(|e| e.to_string())?
+    } else if bytes.starts_with(MSGPACK_MAGIC) {
+        let (value, rest) = read_msgpack(bytes)?;
+        if !rest.is_empty() {
+            return Err("trailing bytes after MessagePack document".to_string());
+        }
+        serde_json::from_value::<OwnedDocument>(value).map_err(|e| e.to_string())?
+    } else if let Some(rest) = bytes.strip_prefix(BINCODE_MAGIC) {
+        let ((fmath, program), _) = bincode::decode_from_slice(rest, bincode::config::standard().with_limit::<BINCODE_LIMIT>()).map_err(|e| e.to_string())?;
+        OwnedDocument { fmath, program }
+    } else {
+        let (program, _) = bincode::decode_from_slice(bytes, bincode::config::standard().with_limit::<BINCODE_LIMIT>()).map_err(|e| e.to_string())?;
+        return Ok(program);
+    };
+    if !(OLDEST_TARGET_VERSION..=FORMAT_VERSION).contains(&document.fmath) {
+        return Err(format!("format version {} is not supported (expected {} to {})", document.fmath, OLDEST_TARGET_VERSION, FORMAT_VERSION));
+    }
+    Ok(document.program)
+}
+
+/// Serde representation of an instruction's number operand: a number when
+/// finite, else `"inf"`, `"-inf"` or `"NaN"`, since JSON has no literal for those.
+pub(crate) mod number {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        match *value {
+            v if v.is_finite() => serializer.serialize_f64(v),
+            v if v.is_nan() => serializer.serialize_str("NaN"),
+            v if v > 0.0 => serializer.serialize_str("inf"),
+            _ => serializer.serialize_str("-inf"),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Number(f64),
+            Text(String),
+        }
+        match Repr::deserialize(deserializer)? {
+            Repr::Number(v) => Ok(v),
+            Repr::Text(text) => match text.as_str() {
+                "inf" => Ok(f64::INFINITY),
+                "-inf" => Ok(f64::NEG_INFINITY),
+                "NaN" => Ok(f64::NAN),
+                _ => Err(serde::de::Error::custom(format!("`{}` is not a number", text))),
+            },
+        }
+    }
+}
+
+// MessagePack encoding of the JSON data model, in the smallest form of each value
+fn write_msgpack(value: &Value, out: &mut Vec<u8>) {
+    // A length in the fix form (`fix` ORed with it, below `limit`), else after `marker` as u16 or u32
+    fn header(len: usize, fix: u8, limit: usize, marker16: u8, out: &mut Vec<u8>) {
+        if len < limit {
+            out.push(fix | len as u8);
+        } else if let Ok(len) = u16::try_from(len) {
+            out.push(marker16);
+            out.extend(len.to_be_bytes());
+        } else {
+            out.push(marker16 + 1);
+            out.extend((len as u32).to_be_bytes());
+        }
+    }
+    match value {
+        Value::Null => out.push(0xc0),
+        Value::Bool(b) => out.push(if *b { 0xc3 } else { 0xc2 }),
+        Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+            (Some(u), _) if u < 0x80 => out.push(u as u8),
+            (Some(u), _) => {
+                out.push(0xcf);
+                out.extend(u.to_be_bytes());
+            }
+            (None, Some(i)) if i >= -32 => out.push(i as u8),
+            (None, Some(i)) => {
+                out.push(0xd3);
+                out.extend(i.to_be_bytes());
+            }
+            (None, None) => {
+                out.push(0xcb);
+                out.extend(n.as_f64().unwrap_or(f64::NAN).to_be_bytes());
+            }
+        },
+        Value::String(s) => {
+            if s.len() < 32 {
+                out.push(0xa0 | s.len() as u8);
+            } else if let Ok(len) = u8::try_from(s.len()) {
+                out.extend([0xd9, len]);
+            } else {
+                header(s.len(), 0, 0, 0xda, out);
+            }
+            out.extend(s.as_bytes());
+        }
+        Value::Array(items) => {
+            header(items.len(), 0x90, 16, 0xdc, out);
+            items.iter().for_each(|item| write_msgpack(item, out));
+        }
+        Value::Object(members) => {
+            header(members.len(), 0x80, 16, 0xde, out);
+            for (key, value) in members {
+                write_msgpack(&Value::String(key.clone()), out);
+                write_msgpack(value, out);
+            }
+        }
+    }
+}
+
+// One MessagePack value from the start of `bytes`, and the bytes after it
+fn read_msgpack(bytes: &[u8]) -> Result<(Value, &[u8]), String> {
+    fn take(bytes: &[u8], n: usize) -> Result<(&[u8], &[u8]), String> {
+        if bytes.len() < n { Err("MessagePack document ends early".to_string()) } else { Ok(bytes.split_at(n)) }
+    }
+    fn uint(bytes: &[u8], n: usize) -> Result<(u64, &[u8]), String> {
+        let (digits, rest) = take(bytes, n)?;
+        Ok((digits.iter().fold(0, |acc, &b| acc << 8 | u64::from(b)), rest))
+    }
+    fn items(mut rest: &[u8], len: usize) -> Result<(Vec<Value>, &[u8]), String> {
+        let mut items = Vec::with_capacity(len.min(rest.len()));
+        for _ in 0..len {
+            let (item, after) = read_msgpack(rest)?;
+            items.push(item);
+            rest = after;
+        }
+        Ok((items, rest))
+    }
+    fn string(bytes: &[u8], len: usize) -> Result<(Value, &[u8]), String> {
+        let (text, rest) = take(bytes, len)?;
+        let text = std::str::from_utf8(text).map_err(|_| "MessagePack string is not UTF-8".to_string())?;
+        Ok((Value::String(text.to_string()), rest))
+    }
+    fn map(rest: &[u8], len: usize) -> Result<(Value, &[u8]), String> {
+        let (items, rest) = items(rest, 2 * len)?;
+        let mut members = serde_json::Map::new();
+        for pair in items.chunks(2) {
+            let Value::String(key) = &pair[0] else {
+                return Err("MessagePack map key is not a string".to_string());
+            };
+            members.insert(key.clone(), pair[1].clone());
+        }
+        Ok((Value::Object(members), rest))
+    }
+    let float = |f: f64| Number::from_f64(f).map(Value::Number).ok_or("non-finite MessagePack float".to_string());
+    let (&marker, rest) = bytes.split_first().ok_or("MessagePack document ends early")?;
+    Ok(match marker {
+        0x00..=0x7f => (Value::from(marker), rest),
+        0x80..=0x8f => map(rest, usize::from(marker & 0x0f))?,
+        0x90..=0x9f => {
+            let (items, rest) = items(rest, usize::from(marker & 0x0f))?;
+            (Value::Array(items), rest)
+        }
+        0xa0..=0xbf => string(rest, usize::from(marker & 0x1f))?,
+        0xc0 => (Value::Null, rest),
+        0xc2 => (Value::Bool(false), rest),
+        0xc3 => (Value::Bool(true), rest),
+        0xca => {
+            let (bits, rest) = uint(rest, 4)?;
+            (float(f64::from(f32::from_bits(bits as u32)))?, rest)
+        }
+        0xcb => {
+            let (bits, rest) = uint(rest, 8)?;
+            (float(f64::from_bits(bits))?, rest)
+        }
+        0xcc..=0xcf => {
+            let (n, rest) = uint(rest, 1 << (marker - 0xcc))?;
+            (Value::from(n), rest)
+        }
+        0xd0..=0xd3 => {
+            let width = 1 << (marker - 0xd0);
+            let (n, rest) = uint(rest, width)?;
+            // Sign-extend from the width read
+            let shift = 64 - 8 * width as u32;
+            (Value::from(((n << shift) as i64) >> shift), rest)
+        }
+        0xd9..=0xdb => {
+            let (len, rest) = uint(rest, 1 << (marker - 0xd9))?;
+            string(rest, len as usize)?
+        }
+        0xdc | 0xdd => {
+            let (len, rest) = uint(rest, 2 << (marker - 0xdc))?;
+            let (items, rest) = items(rest, len as usize)?;
+            (Value::Array(items), rest)
+        }
+        0xde | 0xdf => {
+            let (len, rest) = uint(rest, 2 << (marker - 0xde))?;
+            map(rest, len as usize)?
+        }
+        0xe0..=0xff => (Value::from(marker as i8), rest),
+        _ => return Err(format!("unsupported MessagePack type 0x{:02x}", marker)),
+    })
+}
@@ -0,0 +1,261 @@
+// Numerical methods over functions the VM evaluates: roots of equations and systems, extrema and derivatives
+
+/// The interval `solve` searches when it is given none.
+pub const DEFAULT_SOLVE_RANGE: (f64, f64) = (-100.0, 100.0);
+
+/// Subintervals `roots` samples its interval in before refining each root.
+pub const ROOT_SAMPLES: usize = 10_000;
+
+// Bisection and golden-section steps when refining a root or extremum; each
+// halves the bracket or shrinks it by the golden ratio, so 60 reach the
+// precision of f64 from any sample spacing
+const REFINE_STEPS: usize = 60;
+
+/// Where `f` changes sign within a bracket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Crossing {
+    /// `f` passes through zero at x.
+    Root(f64),
+    /// `f` jumps across a pole at x, as `tan` does at π/2.
+    Pole(f64),
+}
+
+/// Bisects the bracket from `a` to `b`, where `f` is `fa` and `fb` of opposite
+/// signs. The sign change is a pole rather than a root if `f` grows instead of
+/// vanishing as the bracket narrows.
+pub fn bisect(f: &mut impl FnMut(f64) -> f64, (mut a, mut fa): (f64, f64), (mut b, mut fb): (f64, f64)) -> Crossing {
+    let scale = fa.abs().max(fb.abs());
+    for _ in 0..REFINE_STEPS {
+        let m = (a + b) / 2.0;
+        let fm = f(m);
+        if fm == 0.0 {
+            return Crossing::Root(m);
+        }
+        if !fm.is_finite() {
+            return if fm.is_infinite() { Crossing::Pole(m) } else { Crossing::Root(m) };
+        }
+        if fm.signum() == fa.signum() {
+            (a, fa) = (m, fm);
+        } else {
+            (b, fb) = (m, fm);
+        }
+    }
+    if fa.abs().min(fb.abs()) > scale {
+        Crossing::Pole((a + b) / 2.0)
+    } else {
+        Crossing::Root(a - fa * (b - a) / (fb - fa))
+    }
+}
+
+/// The x in `[lo, hi]` where `f` is least, by golden-section search, for an
+/// `f` with a single minimum there.
+pub fn golden_min(f: &mut impl FnMut(f64) -> f64, (mut lo, mut hi): (f64, f64)) -> f64 {
+    let ratio = (5f64.sqrt() - 1.0) / 2.0;
+    let (mut c, mut d) = (hi - ratio * (hi - lo), lo + ratio * (hi - lo));
+    let (mut fc, mut fd) = (f(c), f(d));
+    for _ in 0..REFINE_STEPS {
+        if fc < fd {
+            (hi, d, fd) = (d, c, fc);
+            c = hi - ratio * (hi - lo);
+            fc = f(c);
+        } else {
+            (lo, c, fc) = (c, d, fd);
+            d = lo + ratio * (hi - lo);
+            fd = f(d);
+        }
+    }
+    (lo + hi) / 2.0
+}
+
+/// Samples `minimize` takes of its interval before refining the least.
+pub const MINIMIZE_SAMPLES: usize = 1_000;
+
+/// Where `f` is least on `[lo, hi]` and its value there: the least of
+/// `MINIMIZE_SAMPLES` evenly spaced samples (the ends included), refined by a
+/// golden-section search between its neighbours. A dip narrower than the
+/// sample spacing may be missed; points where `f` is NaN are skipped.
+pub fn minimize(mut f: impl FnMut(f64) -> f64, lo: f64, hi: f64) -> (f64, f64) {
+    let x_at = |i: usize| lo + (hi - lo) * i as f64 / MINIMIZE_SAMPLES as f64;
+    let (mut best, mut least) = (lo, f64::NAN);
+    let mut best_i = 0;
+    for i in 0..=MINIMIZE_SAMPLES {
+        let y = f(x_at(i));
+        if y < least || least.is_nan() {
+            (best, least, best_i) = (x_at(i), y, i);
+        }
+    }
+    if least.is_nan() {
+        return (f64::NAN, f64::NAN);
+    }
+    let range = (x_at(best_i.saturating_sub(1)), x_at((best_i + 1).min(MINIMIZE_SAMPLES)));
+    let x = golden_min(&mut f, range);
+    let y = f(x);
+    if y < least { (x, y) } else { (best, least) }
+}
+
+/// The roots of `f` in `[lo, hi]` in increasing order, found by sampling
+/// `ROOT_SAMPLES` subintervals: each sign change is bisected (poles left
+/// out), and each sample nearer zero than both neighbours without a sign
+/// change, as where `x^2` touches zero, is kept if a golden-section search
+/// for the least `|f|` there comes to zero. Roots closer together than a
+/// subinterval may be missed.
+pub fn roots(mut f: impl FnMut(f64) -> f64, lo: f64, hi: f64) -> Vec<f64> {
+    let xs: Vec<f64> = (0..=ROOT_SAMPLES).map(|i| lo + (hi - lo) * i as f64 / ROOT_SAMPLES as f64).collect();
+    let ys: Vec<f64> = xs.iter().map(|&x| f(x)).collect();
+    let mut found = Vec::new();
+    for i in 0..xs.len() {
+        let y = ys[i];
+        if y == 0.0 {
+            found.push(xs[i]);
+            continue;
+        }
+        if !y.is_finite() {
+            continue;
+        }
+        if let Some(&next) = ys.get(i + 1)
+            && next.is_finite()
+            && next != 0.0
+            && next.signum() != y.signum()
+            && let Crossing::Root(x) = bisect(&mut f, (xs[i], y), (xs[i + 1], next))
+        {
+            found.push(x);
+        }
+        if i > 0
+            && let Some(&next) = ys.get(i + 1)
+            && [ys[i - 1], next].iter().all(|v| v.is_finite() && v.signum() == y.signum() && v.abs() > y.abs())
+        {
+            let x = golden_min(&mut |x| f(x).abs(), (xs[i - 1], xs[i + 1]));
+            if f(x).abs() <= 1e-9 * ys[i - 1].abs().max(next.abs()).max(1.0) {
+                found.push(x);
+            }
+        }
+    }
+    found.sort_by(f64::total_cmp);
+    found.dedup_by(|a, b| (*a - *b).abs() <= 1e-9 * a.abs().max(1.0));
+    found
+}
+
+/// Newton steps `newton` takes at most before giving up.
+pub const NEWTON_STEPS: usize = 100;
+
+/// A zero of the system `f` (one value per equation for a point holding one
+/// value per unknown, as many of each) near `guess`, by Newton's method. The
+/// Jacobian comes from central differences: the 2n points around each
+/// iterate are evaluated as one batch before the step. Fails where the
+/// system is not finite, where the Jacobian is singular, or if the steps do
+/// not settle within `NEWTON_STEPS`.
+pub fn newton(mut f: impl FnMut(&[f64]) -> Vec<f64>, guess: &[f64]) -> Result<Vec<f64>, String> {
+    let n = guess.len();
+    let mut x = guess.to_vec();
+    for _ in 0..NEWTON_STEPS {
+        let fx = f(&x);
+        if fx.iter().any(|v| !v.is_finite()) {
+            return Err(format!("the equations are not finite at {:?}", x));
+        }
+        if fx.iter().all(|&v| v == 0.0) {
+            return Ok(x);
+        }
+        // The batch: x moved by ±h along each unknown in turn
+        let steps: Vec<f64> = x.iter().map(|&v| step(v, 3)).collect();
+        let batch: Vec<Vec<f64>> = (0..2 * n)
+            .map(|k| {
+                let mut point = x.clone();
+                point[k / 2] += if k % 2 == 0 { steps[k / 2] } else { -steps[k / 2] };
+                f(&point)
+            })
+            .collect();
+        // Row i of the Jacobian, then -f_i, for the elimination
+        let rows: Vec<Vec<f64>> = (0..n)
+            .map(|i| (0..n).map(|j| (batch[2 * j][i] - batch[2 * j + 1][i]) / (2.0 * steps[j])).chain([-fx[i]]).collect())
+            .collect();
+        let dx = linear_solve(rows).ok_or_else(|| format!("the Jacobian is singular at {:?}", x))?;
+        for (v, d) in x.iter_mut().zip(&dx) {
+            *v += d;
+        }
+        if x.iter().zip(&dx).all(|(v, d)| d.abs() <= 1e-12 * v.abs().max(1.0)) {
+            return Ok(x);
+        }
+    }
+    Err(format!("Newton's method did not converge from {:?}", guess))
+}
+
+/// The gradient of `f` at `point` (one value per variable), by central
+/// differences.
+pub fn gradient(mut f: impl FnMut(&[f64]) -> f64, point: &[f64]) -> Vec<f64> {
+    let mut x = point.to_vec();
+    (0..x.len())
+        .map(|j| {
+            let h = step(point[j], 3);
+            x[j] = point[j] + h;
+            let ahead = f(&x);
+            x[j] = point[j] - h;
+            let behind = f(&x);
+            x[j] = point[j];
+            (ahead - behind) / (2.0 * h)
+        })
+        .collect()
+}
+
+/// The Hessian of `f` at `point`, row by row (n² values for n variables),
+/// by central differences; the matrix is symmetric, each entry above the
+/// diagonal computed once.
+pub fn hessian(mut f: impl FnMut(&[f64]) -> f64, point: &[f64]) -> Vec<f64> {
+    let n = point.len();
+    let steps: Vec<f64> = point.iter().map(|&v| step(v, 4)).collect();
+    let mut x = point.to_vec();
+    let center = f(&x);
+    let mut h = vec![0.0; n * n];
+    for i in 0..n {
+        // f(x + a·h_i e_i + b·h_j e_j)
+        let mut at = |a: f64, j: usize, b: f64| {
+            x[i] += a * steps[i];
+            x[j] += b * steps[j];
+            let value = f(&x);
+            x[i] = point[i];
+            x[j] = point[j];
+            value
+        };
+        h[i * n + i] = (at(1.0, i, 0.0) - 2.0 * center + at(-1.0, i, 0.0)) / (steps[i] * steps[i]);
+        for j in i + 1..n {
+            let mixed = at(1.0, j, 1.0) - at(1.0, j, -1.0) - at(-1.0, j, 1.0) + at(-1.0, j, -1.0);
+            h[i * n + j] = mixed / (4.0 * steps[i] * steps[j]);
+            h[j * n + i] = h[i * n + j];
+        }
+    }
+    h
+}
+
+// The difference step for a variable at `v` in a formula whose truncation
+// error grows as the step to the power `order` - 1: the `order`th root of
+// the machine epsilon balances it against rounding, scaled to `v`
+fn step(v: f64, order: i32) -> f64 {
+    f64::EPSILON.powf(1.0 / order as f64) * v.abs().max(1.0)
+}
+
+// The solution of the square system whose augmented rows are `rows`, by
+// Gaussian elimination with partial pivoting; `None` if it is singular
+fn linear_solve(mut rows: Vec<Vec<f64>>) -> Option<Vec<f64>> {
+    let n = rows.len();
+    let largest = rows.iter().flat_map(|r| &r[..n]).fold(0.0, |m: f64, v| m.max(v.abs()));
+    for k in 0..n {
+        let p = (k..n).max_by(|&a, &b| rows[a][k].abs().total_cmp(&rows[b][k].abs()))?;
+        if rows[p][k].is_nan() || rows[p][k].abs() <= largest * n as f64 * f64::EPSILON {
+            return None;
+        }
+        rows.swap(k, p);
+        let (above, below) = rows.split_at_mut(k + 1);
+        let pivot = &above[k];
+        for row in below {
+            let factor = row[k] / pivot[k];
+            for (v, p) in row[k..].iter_mut().zip(&pivot[k..]) {
+                *v -= factor * p;
+            }
+        }
+    }
+    let mut x = vec![0.0; n];
+    for k in (0..n).rev() {
+        let known: f64 = (k + 1..n).map(|j| rows[k][j] * x[j]).sum();
+        x[k] = (rows[k][n] - known) / rows[k][k];
+    }
+    Some(x)
+}
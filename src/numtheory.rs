@@ -0,0 +1,352 @@
+// Number theory on integer values: primality, factorization, prime counting,
+// modular arithmetic, and linear recurrences
+
+/// Largest `n` accepted by `prime_pi` (the count takes about n^(3/4) steps).
+pub const PRIME_PI_LIMIT: f64 = 1e12;
+
+/// The value as a `u64` if it is a non-negative integer that fits.
+pub fn as_u64(x: f64) -> Option<u64> {
+    (x >= 0.0 && x.fract() == 0.0 && x < 18_446_744_073_709_551_616.0).then_some(x as u64)
+}
+
+/// The value as an `i128` if it is an integer in the `u64` range or its negation.
+pub fn as_int(x: f64) -> Option<i128> {
+    (x.fract() == 0.0 && x.abs() < 18_446_744_073_709_551_616.0).then_some(x as i128)
+}
+
+/// Floored modulo: the result has the sign of `m` (`mod(-7, 3) = 2`). Exact
+/// for all finite inputs, since the IEEE remainder is exact. `m = 0` gives NaN.
+pub fn mod_floor(a: f64, m: f64) -> f64 {
+    let r = a % m;
+    if r != 0.0 && (r < 0.0) != (m < 0.0) { r + m } else { r }
+}
+
+/// `a^b mod m` in `[0, m)`; a negative `b` uses the inverse of `a`.
+/// `None` if `b < 0` and `a` has no inverse modulo `m`.
+pub fn pow_mod_int(a: i128, b: i128, m: u64) -> Option<u64> {
+    let base = if b < 0 { inv_mod(a, m)? } else { a.rem_euclid(m as i128) as u64 };
+    Some(pow_mod(base, b.unsigned_abs() as u64, m))
+}
+
+/// The inverse of `a` modulo `m` in `[0, m)`, if `gcd(a, m) = 1`.
+pub fn inv_mod(a: i128, m: u64) -> Option<u64> {
+    // Extended Euclid on (a mod m, m)
+    let m = m as i128;
+    let (mut r0, mut r1) = (a.rem_euclid(m), m);
+    let (mut s0, mut s1) = (1i128, 0i128);
+    while r1 != 0 {
+        let q = r0 / r1;
+        (r0, r1) = (r1, r0 - q * r1);
+        (s0, s1) = (s1, s0 - q * s1);
+    }
+    (r0 == 1 || m == 1).then(|| s0.rem_euclid(m) as u64)
+}
+
+fn mul_mod(a: u64, b: u64, m: u64) -> u64 {
+    ((a as u128 * b as u128) % m as u128) as u64
+}
+
+fn pow_mod(mut base: u64, mut exp: u64, m: u64) -> u64 {
+    let mut result = 1 % m;
+    base %= m;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = mul_mod(result, base, m);
+        }
+        base = mul_mod(base, base, m);
+        exp >>= 1;
+    }
+    result
+}
+
+/// Deterministic Miller–Rabin: these bases decide primality for every `u64`.
+pub fn is_prime(n: u64) -> bool {
+    const BASES: [u64; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+    if n < 2 {
+        return false;
+    }
+    for p in BASES {
+        if n.is_multiple_of(p) {
+            return n == p;
+        }
+    }
+    let s = (n - 1).trailing_zeros();
+    let d = (n - 1) >> s;
+    'bases: for a in BASES {
+        let mut x = pow_mod(a, d, n);
+        if x == 1 || x == n - 1 {
+            continue;
+        }
+        for _ in 1..s {
+            x = mul_mod(x, x, n);
+            if x == n - 1 {
+                continue 'bases;
+            }
+        }
+        return false;
+    }
+    true
+}
+
+/// Smallest prime greater than `n`, or `None` past the largest `u64` prime.
+pub fn next_prime(n: u64) -> Option<u64> {
+    let mut candidate = n.checked_add(1)?;
+    while !is_prime(candidate) {
+        candidate = candidate.checked_add(1)?;
+    }
+    Some(candidate)
+}
+
+/// Number of primes `<= n`, by the Lucy_Hedgehog / Legendre-style sieve over
+/// the values `n / k`.
+pub fn prime_pi(n: u64) -> u64 {
+    if n < 2 {
+        return 0;
+    }
+    let r = isqrt(n);
+    // small[v] = count for v <= r, large[i] = count for n / i
+    let mut small: Vec<u64> = (0..=r).map(|v| v.saturating_sub(1)).collect();
+    let mut large: Vec<u64> = (0..=r).map(|i| n.checked_div(i).map_or(0, |q| q - 1)).collect();
+    for p in 2..=r {
+        if small[p as usize] == small[p as usize - 1] {
+            continue; // not prime
+        }
+        let primes_below = small[p as usize - 1];
+        let p2 = p * p;
+        let limit = r.min(n / p2);
+        for i in 1..=limit {
+            let d = i * p;
+            let count = if d <= r { large[d as usize] } else { small[(n / d) as usize] };
+            large[i as usize] -= count - primes_below;
+        }
+        for v in (p2..=r).rev() {
+            small[v as usize] -= small[(v / p) as usize] - primes_below;
+        }
+    }
+    large[1]
+}
+
+fn isqrt(n: u64) -> u64 {
+    let mut r = (n as f64).sqrt() as u64;
+    while r.checked_mul(r).is_none_or(|sq| sq > n) {
+        r -= 1;
+    }
+    while (r + 1).checked_mul(r + 1).is_some_and(|sq| sq <= n) {
+        r += 1;
+    }
+    r
+}
+
+/// Prime factors of `n` in increasing order, repeated by multiplicity
+/// (`360 -> [2, 2, 2, 3, 3, 5]`). Empty for `n < 2`.
+pub fn factorize(n: u64) -> Vec<u64> {
+    let mut factors = Vec::new();
+    let mut n = n;
+    for p in [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37] {
+        while n.is_multiple_of(p) && n > 1 {
+            factors.push(p);
+            n /= p;
+        }
+    }
+    let mut stack = vec![n];
+    while let Some(m) = stack.pop() {
+        if m < 2 {
+            continue;
+        }
+        if is_prime(m) {
+            factors.push(m);
+            continue;
+        }
+        let d = pollard_brent(m);
+        stack.push(d);
+        stack.push(m / d);
+    }
+    factors.sort_unstable();
+    factors
+}
+
+/// A nontrivial factor of the odd composite `n` (Pollard's rho, Brent's variant).
+fn pollard_brent(n: u64) -> u64 {
+    let gcd = |mut a: u64, mut b: u64| {
+        while b != 0 {
+            (a, b) = (b, a % b);
+        }
+        a
+    };
+    for c in 1.. {
+        let f = |x: u64| ((mul_mod(x, x, n) as u128 + c) % n as u128) as u64;
+        let (mut y, mut r, mut q, mut g) = (2u64, 1u64, 1u64, 1u64);
+        let (mut x, mut ys) = (y, y);
+        while g == 1 {
+            x = y;
+            for _ in 0..r {
+                y = f(y);
+            }
+            let mut k = 0;
+            while k < r && g == 1 {
+                ys = y;
+                for _ in 0..128.min(r - k) {
+                    y = f(y);
+                    q = mul_mod(q, x.abs_diff(y), n);
+                }
+                g = gcd(q, n);
+                k += 128;
+            }
+            r *= 2;
+        }
+        if g == n {
+            // Batched gcd overshot; retrace one step at a time
+            loop {
+                ys = f(ys);
+                g = gcd(x.abs_diff(ys), n);
+                if g > 1 {
+                    break;
+                }
+            }
+        }
+        if g != n {
+            return g;
+        }
+    }
+    unreachable!("some constant c always finds a factor")
+}
+
+/// The Fibonacci number `F(n)` for integer `n` (negative `n` too), by fast
+/// doubling: exact while the doubling fits in a `u64` (`n <= 92`), rounded above that.
+/// Non-integer `n` gives NaN.
+pub fn fib(n: f64) -> f64 {
+    if n.fract() != 0.0 || n.is_nan() {
+        return f64::NAN;
+    }
+    // F(-n) = (-1)^(n+1) F(n)
+    let sign = if n < 0.0 && n % 2.0 == 0.0 { -1.0 } else { 1.0 };
+    let n = n.abs();
+    if n > 1476.0 {
+        // F(1477) overflows f64
+        return sign * f64::INFINITY;
+    }
+    let n = n as u64;
+    if n <= 92 {
+        let (mut a, mut b) = (0u64, 1u64); // F(k), F(k + 1)
+        for bit in (0..64 - n.leading_zeros()).rev() {
+            let (c, d) = (a * (2 * b - a), a * a + b * b);
+            (a, b) = if (n >> bit) & 1 == 1 { (d, c + d) } else { (c, d) };
+        }
+        return sign * a as f64;
+    }
+    let (mut a, mut b) = (0.0f64, 1.0f64);
+    for bit in (0..64 - n.leading_zeros()).rev() {
+        let (c, d) = (a * (2.0 * b - a), a * a + b * b);
+        (a, b) = if (n >> bit) & 1 == 1 { (d, c + d) } else { (c, d) };
+    }
+    sign * a
+}
+
+/// The binomial coefficient `C(n, k)` as the running product of
+/// `(n - k + i) / i` for `i = 1..k`, over the smaller of `k` and `n - k`, so it
+/// stays finite wherever the result does (unlike `n! / (k! (n - k)!)`). Exact
+/// while the result fits in 53 bits. `n` may be any real number; `k` must be
+/// an integer, and a negative `k` (or `k > n` for integer `n >= 0`) gives 0.
+pub fn binomial(n: f64, k: f64) -> f64 {
+    if k.fract() != 0.0 || k.is_nan() || n.is_nan() {
+        return f64::NAN;
+    }
+    if k < 0.0 || (n.fract() == 0.0 && n >= 0.0 && k > n) {
+        return 0.0;
+    }
+    // C(n, k) = C(n, n - k) for integer n >= 0
+    let k = if n.fract() == 0.0 && n >= 0.0 { k.min(n - k) } else { k };
+    let mut result = 1.0;
+    for i in 1..=k as u64 {
+        let i = i as f64;
+        result = result * (n - k + i) / i;
+        if !result.is_finite() || result == 0.0 {
+            break;
+        }
+    }
+    // The running product can land just off an integer
+    if n.fract() == 0.0 { result.round() } else { result }
+}
+
+/// The double factorial `n!! = n (n - 2) (n - 4) ...` down to 1 or 2, with
+/// `0!! = (-1)!! = 1`. Negative odd `n` follow `(n - 2)!! = n!! / n`, so
+/// `(-3)!! = -1`; negative even and non-integer `n` give NaN.
+pub fn double_factorial(n: f64) -> f64 {
+    if n.fract() != 0.0 || n.is_nan() {
+        return f64::NAN;
+    }
+    if n < -1.0 {
+        if n % 2.0 == 0.0 {
+            return f64::NAN;
+        }
+        let mut result = 1.0f64;
+        let mut k = -1.0;
+        while k > n {
+            result /= k;
+            k -= 2.0;
+        }
+        return result;
+    }
+    let mut result = 1.0f64;
+    let mut k = n;
+    while k > 1.0 && result.is_finite() {
+        result *= k;
+        k -= 2.0;
+    }
+    result
+}
+
+/// The subfactorial `!n`, the number of derangements of `n` items, by the
+/// recurrence `!n = n !(n - 1) + (-1)^n` from `!0 = 1`. Exact while the result
+/// fits in 53 bits (`n <= 18`); negative and non-integer `n` give NaN.
+pub fn subfactorial(n: f64) -> f64 {
+    if n.fract() != 0.0 || n.is_nan() || n < 0.0 {
+        return f64::NAN;
+    }
+    let mut result = 1.0f64;
+    let mut k = 1.0;
+    while k <= n && result.is_finite() {
+        let sign = if k % 2.0 == 0.0 { 1.0 } else { -1.0 };
+        result = k * result + sign;
+        k += 1.0;
+    }
+    result
+}
+
+/// Term `a_n` of the linear recurrence `a_k = c_1 a_(k-1) + ... + c_d a_(k-d)`
+/// with `initial = [a_0, ..., a_(d-1)]`, by raising the companion matrix to a
+/// power in O(d^3 log n) steps.
+pub fn linear_recurrence(coeffs: &[f64], initial: &[f64], n: u64) -> f64 {
+    let d = coeffs.len();
+    if (n as usize) < d {
+        return initial[n as usize];
+    }
+    // Row i of the state vector holds a_(k-i); the matrix advances k by one
+    let mut step = vec![vec![0.0; d]; d];
+    step[0].copy_from_slice(coeffs);
+    for i in 1..d {
+        step[i][i - 1] = 1.0;
+    }
+    let mut power = identity(d);
+    let mut e = n - (d as u64 - 1);
+    while e > 0 {
+        if e & 1 == 1 {
+            power = mat_mul(&power, &step);
+        }
+        step = mat_mul(&step, &step);
+        e >>= 1;
+    }
+    // State at k = d - 1 is [a_(d-1), ..., a_0]
+    power[0].iter().zip(initial.iter().rev()).map(|(m, a)| m * a).sum()
+}
+
+fn identity(d: usize) -> Vec<Vec<f64>> {
+    (0..d).map(|i| (0..d).map(|j| if i == j { 1.0 } else { 0.0 }).collect()).collect()
+}
+
+fn mat_mul(a: &[Vec<f64>], b: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    let d = a.len();
+    (0..d)
+        .map(|i| (0..d).map(|j| (0..d).map(|k| a[i][k] * b[k][j]).sum()).collect())
+        .collect()
+}
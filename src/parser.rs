@@ -1,160 +1,657 @@
-// sum(from: a, to: b, para: para_name, expr)
-fn parse_sum_product(tokens: &[Token], pos: usize) -> Option<(Expr, usize)> {
+// sum(from: a, to: b, para: para_name, expr), optionally with `eps: tol` after `to`
+// and `while: cond` after `para`
+fn parse_sum_product(tokens: &[Token], pos: usize) -> Result<Option<(Expr, usize)>, ParseError> {
     let (is_sum, start) = match tokens.get(pos) {
         Some(Token::Sum) => (true, pos + 1),
         Some(Token::Product) => (false, pos + 1),
-        _ => return None,
+        _ => return Ok(None),
     };
-    if let Some(Token::LParen) = tokens.get(start) {
-        // sum(product)(from: a, to: b, para: para_name, expr)
-        let mut idx = start + 1;
+    // sum(product)(from: a, to: b, para: para_name, expr)
+    let mut idx = start + 1;
+    if let Some(Token::LParen) = tokens.get(start)
         // from: expr
-        if let Some(Token::Ident(from_kw)) = tokens.get(idx) {
-            if from_kw == "from" {
-                idx += 1;
-                let (from_expr, next_idx) = parse_expr(tokens, idx);
+        && let Some(Token::Ident(from_kw)) = tokens.get(idx)
+        && from_kw == "from"
+    {
+        idx = skip_colon(tokens, idx + 1);
+        let (from_expr, next_idx) = parse_expr(tokens, idx)?;
+        idx = next_idx;
+        // to: expr
+        if let Some(Token::Comma) = tokens.get(idx)
+            && let Some(Token::Ident(to_kw)) = tokens.get(idx + 1)
+            && to_kw == "to"
+        {
+            idx = skip_colon(tokens, idx + 2);
+            let (to_expr, next_idx) = parse_expr(tokens, idx)?;
+            idx = next_idx;
+            // eps: expr (sum only)
+            let mut eps_expr = None;
+            if is_sum
+                && let Some(Token::Comma) = tokens.get(idx)
+                && let Some(Token::Ident(eps_kw)) = tokens.get(idx + 1)
+                && eps_kw == "eps"
+            {
+                idx = skip_colon(tokens, idx + 2);
+                let (eps, next_idx) = parse_expr(tokens, idx)?;
+                eps_expr = Some(Box::new(eps));
                 idx = next_idx;
-                if let Some(Token::Comma) = tokens.get(idx) {
-                    idx += 1;
-                    // to: expr
-                    if let Some(Token::Ident(to_kw)) = tokens.get(idx) {
-                        if to_kw == "to" {
-                            idx += 1;
-                            let (to_expr, next_idx) = parse_expr(tokens, idx);
-                            idx = next_idx;
-                            if let Some(Token::Comma) = tokens.get(idx) {
-                                idx += 1;
-                                // para: para_name
-                                if let Some(Token::Ident(para_kw)) = tokens.get(idx) {
-                                    if para_kw == "para" {
-                                        idx += 1;
-                                        if let Some(Token::Ident(param_name)) = tokens.get(idx) {
-                                            idx += 1;
-                                            if let Some(Token::Comma) = tokens.get(idx) {
-                                                idx += 1;
-                                                // expr
-                                                let (body_expr, next_idx) = parse_expr(tokens, idx);
-                                                idx = next_idx;
-                                                if let Some(Token::RParen) = tokens.get(idx) {
-                                                    let expr = if is_sum {
-                                                        Expr::Sum {
-                                                            from: Box::new(from_expr),
-                                                            to: Box::new(to_expr),
-                                                            param: param_name.clone(),
-                                                            body: Box::new(body_expr),
-                                                        }
-                                                    } else {
-                                                        Expr::Product {
-                                                            from: Box::new(from_expr),
-                                                            to: Box::new(to_expr),
-                                                            param: param_name.clone(),
-                                                            body: Box::new(body_expr),
-                                                        }
-                                                    };
-                                                    return Some((expr, idx + 1));
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
+            }
+            // para: para_name
+            if let Some(Token::Comma) = tokens.get(idx)
+                && let Some(Token::Ident(para_kw)) = tokens.get(idx + 1)
+                && para_kw == "para"
+                && let name_idx = skip_colon(tokens, idx + 2)
+                && let Some(Token::Ident(param_name)) = tokens.get(name_idx)
+                && let Some(Token::Comma) = tokens.get(name_idx + 1)
+            {
+                idx = name_idx + 2;
+                // while: cond
+                let mut while_expr = None;
+                if let Some(Token::Ident(while_kw)) = tokens.get(idx)
+                    && while_kw == "while"
+                    && let Some(Token::Colon) = tokens.get(idx + 1)
+                {
+                    let (cond, next_idx) = parse_expr(tokens, idx + 2)?;
+                    if !matches!(tokens.get(next_idx), Some(Token::Comma)) {
+                        return Err(ParseError::Expected("comma after the `while:` condition"));
                     }
+                    while_expr = Some(Box::new(cond));
+                    idx = next_idx + 1;
+                }
+                // expr
+                let (body_expr, next_idx) = parse_expr(tokens, idx)?;
+                idx = next_idx;
+                if let Some(Token::RParen) = tokens.get(idx) {
+                    let expr = if is_sum {
+                        Expr::Sum {
+                            from: Box::new(from_expr),
+                            to: Box::new(to_expr),
+                            eps: eps_expr,
+                            param: param_name.clone(),
+                            cond: while_expr,
+                            body: Box::new(body_expr),
+                        }
+                    } else {
+                        Expr::Product {
+                            from: Box::new(from_expr),
+                            to: Box::new(to_expr),
+                            param: param_name.clone(),
+                            cond: while_expr,
+                            body: Box::new(body_expr),
+                        }
+                    };
+                    return Ok(Some((expr, idx + 1)));
                 }
             }
         }
     }
-    None
+    let name = if is_sum { "sum" } else { "product" };
+    Err(ParseError::Malformed { construct: name, expected: format!("{}(from: a, to: b, para: name, expr)", name) })
+}
+
+// The colon after a keyword like `from:` is optional
+fn skip_colon(tokens: &[Token], pos: usize) -> usize {
+    if let Some(Token::Colon) = tokens.get(pos) { pos + 1 } else { pos }
 }
 // ...existing code...
 
 // Parser for math interpreter
-use crate::lexer::{Token, BinaryOperator};
-use crate::ast::Expr;
+use std::collections::HashSet;
+
+use crate::lexer::{Token, BinaryOperator, Pragma, SpecialFunction};
+use crate::analysis::{Diagnostic, Severity};
+use crate::error::{FmathError, ParseError};
+use crate::ast::{walk_fold, Expr, ExprFolder, Overloads, UnaryOperator, Param, Statements, UserFunction, UserFunctions};
 
 // Recursive descent parser for fast evaluation
-use std::collections::HashMap;
-/// Parses lines of tokens into (main expression, user function map)
-pub fn parse(lines: Vec<Vec<Token>>) -> (Expr, HashMap<String, (String, Expr)>) {
-    use crate::ast::Expr;
-    let mut exprs = Vec::new();
-    let mut user_functions = HashMap::new();
-    for tokens in lines {
-        if tokens.is_empty() { continue; }
-        // Filter out function definition lines from main exprs
-        let is_func_def = matches!(tokens.get(0), Some(Token::Def));
-        if is_func_def {
-            if let Some(Token::Ident(name)) = tokens.get(1) {
-                if let Some(Token::LParen) = tokens.get(2) {
-                    if let Some(Token::Ident(arg_name)) = tokens.get(3) {
-                        if let Some(Token::RParen) = tokens.get(4) {
-                            if let Some(Token::Assign) = tokens.get(5) {
-                                let (body, _) = parse_expr(&tokens, 6);
-                                user_functions.insert(name.clone(), (arg_name.clone(), body));
-                                continue;
-                            }
-                        }
-                    }
-                }
-            }
-        }
-        // Only push non-function-def lines to exprs
-        if !is_func_def {
-            let (expr, next_pos) = parse_statement(&tokens, 0);
-            if next_pos < tokens.len() {
-                panic!("Unexpected token: {:?}", tokens[next_pos]);
-            }
-            exprs.push(expr);
-        }
+/// A parsed item and the position of the first token after it, or the error.
+type ParseResult<T> = Result<(T, usize), ParseError>;
+
+/// Parses numbered lines of tokens into (main expression, user function map).
+/// Fails with the first line that does not parse; use `parse_statements` to
+/// get every error at once.
+pub fn parse(lines: Vec<(usize, Vec<Token>)>) -> Result<(Expr, UserFunctions), FmathError> {
+    let (statements, user_functions, mut errors) = parse_lines(lines, &UserFunctions::new());
+    if !errors.is_empty() {
+        let (line, error) = errors.remove(0);
+        return Err(FmathError::Parse { line, error });
     }
+    let mut exprs: Vec<Expr> = statements.into_iter().map(|(_, expr)| expr).collect();
     let main_expr = if exprs.len() == 1 {
         exprs.pop().unwrap()
     } else {
         Expr::Sequence(exprs)
     };
-    (main_expr, user_functions)
+    Ok((main_expr, user_functions))
 }
-// Parse a statement: assignment or expression
-fn parse_statement(tokens: &[Token], pos: usize) -> (Expr, usize) {
-    // function definition: def name(arg) = expr
-    if let Some(Token::Def) = tokens.get(pos) {
-        if let Some(Token::Ident(name)) = tokens.get(pos + 1) {
-            if let Some(Token::LParen) = tokens.get(pos + 2) {
-                if let Some(Token::Ident(arg_name)) = tokens.get(pos + 3) {
-                    if let Some(Token::RParen) = tokens.get(pos + 4) {
-                        if let Some(Token::Assign) = tokens.get(pos + 5) {
-                            let (body, next_pos) = parse_expr(tokens, pos + 6);
-                            return (Expr::FunctionDef {
-                                name: name.clone(),
-                                arg: arg_name.clone(),
-                                body: Box::new(body),
-                            }, next_pos);
-                        }
+
+/// Parses numbered lines of tokens into top-level statements tagged with their
+/// source line, plus the user function map.
+///
+/// A line that fails to parse is skipped and parsing resumes on the next line,
+/// so every syntax error in the input is reported at once.
+pub fn parse_statements(lines: Vec<(usize, Vec<Token>)>) -> Result<(Statements, UserFunctions), Vec<Diagnostic>> {
+    parse_statements_in(lines, &UserFunctions::new())
+}
+
+/// `parse_statements` for one piece of a script whose other pieces define the
+/// user functions `known`, so that `C(n, k)` calls a `C` defined there
+/// rather than being the binomial coefficient.
+pub fn parse_statements_in(lines: Vec<(usize, Vec<Token>)>, known: &UserFunctions) -> Result<(Statements, UserFunctions), Vec<Diagnostic>> {
+    let (statements, user_functions, errors) = parse_lines(lines, known);
+    if errors.is_empty() {
+        return Ok((statements, user_functions));
+    }
+    Err(errors
+        .into_iter()
+        .map(|(line, error)| Diagnostic {
+            severity: Severity::Error,
+            line: Some(line),
+            function: None,
+            message: error.to_string(),
+            lint: None,
+        })
+        .collect())
+}
+
+// Every line's statement or function, and the error of each line that failed;
+// `known` are the functions defined outside these lines
+fn parse_lines(lines: Vec<(usize, Vec<Token>)>, known: &UserFunctions) -> (Statements, UserFunctions, Vec<(usize, ParseError)>) {
+    let mut statements = Vec::new();
+    let mut user_functions = UserFunctions::new();
+    let mut errors = Vec::new();
+    // Set by `#pragma degrees` for the lines after it
+    let mut degrees = false;
+    let mut lines = lines.into_iter();
+    while let Some((line, tokens)) = lines.next() {
+        if tokens.is_empty() { continue; }
+        if let [Token::Pragma(pragma)] = tokens.as_slice() {
+            match pragma {
+                Pragma::Degrees => degrees = true,
+                Pragma::Radians => degrees = false,
+                Pragma::Precision(digits) => statements.push((line, Expr::Precision(*digits))),
+            }
+            continue;
+        }
+        match parse_line(&tokens) {
+            Ok(Line::FunctionDef(name, mut function)) => {
+                if degrees {
+                    in_degrees(&mut function.params, &mut function.body);
+                }
+                log::trace!(target: "parser", "line {}: def {}{:?} = {:?}", line, name, function.params, function.body);
+                user_functions.entry(name).or_default().insert(function);
+            }
+            Ok(Line::FunctionStart(name, mut params)) => match parse_body(&mut lines, line) {
+                Ok(mut body) => {
+                    if degrees {
+                        in_degrees(&mut params, &mut body);
                     }
+                    log::trace!(target: "parser", "line {}: def {}{:?} = {:?}", line, name, params, body);
+                    user_functions.entry(name).or_default().insert(UserFunction::new(params, body));
+                }
+                Err(body_errors) => errors.extend(body_errors),
+            },
+            Ok(Line::Statement(mut expr)) => {
+                if degrees {
+                    expr = Degrees.fold_expr(expr);
                 }
+                log::trace!(target: "parser", "line {}: {:?}", line, expr);
+                statements.push((line, expr));
             }
+            Err(error) => errors.push((line, error)),
         }
     }
-    // variable declaration/assignment: var Ident = expr
-    if let Some(Token::Var) = tokens.get(pos) {
-        if let Some(Token::Ident(name)) = tokens.get(pos + 1) {
-            if let Some(Token::Assign) = tokens.get(pos + 2) {
-                let (expr, next_pos) = parse_expr(tokens, pos + 3);
-                return (Expr::Assign { name: name.clone(), expr: Box::new(expr) }, next_pos);
+    // Names of functions only become values, and C(n, k) a binomial
+    // coefficient, once every definition is known
+    if !defines_binomial(&user_functions) && !defines_binomial(known) {
+        for (_, statement) in &mut statements {
+            *statement = Binomial.fold_expr(std::mem::replace(statement, Expr::Number(0.0)));
+        }
+        for function in user_functions.values_mut().flat_map(Overloads::iter_mut) {
+            Binomial.fold_body(&mut function.params, &mut function.body);
+        }
+    }
+    let names: HashSet<String> = user_functions.keys().cloned().collect();
+    let mut holders = HashSet::new();
+    for (_, statement) in &mut statements {
+        resolve_function_values(statement, &names, &mut holders);
+    }
+    for function in user_functions.values_mut().flat_map(Overloads::iter_mut) {
+        // Variables in a body hold numbers only
+        resolve_function_values(&mut function.body, &names, &mut HashSet::new());
+    }
+    log::debug!(
+        target: "parser",
+        "{} statements, {} functions, {} errors",
+        statements.len(),
+        user_functions.len(),
+        errors.len()
+    );
+    (statements, user_functions, errors)
+}
+
+// Under `#pragma degrees`, trig functions take degrees and inverse ones give
+// them: sin(x) becomes sin(rad(x)) and asin(x) becomes deg(asin(x))
+struct Degrees;
+
+impl ExprFolder for Degrees {
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        use SpecialFunction as F;
+        let one = |func, arg| Expr::Function { func, arg: Box::new(Expr::Sequence(vec![arg])) };
+        match walk_fold(self, expr) {
+            Expr::Function { func: func @ (F::Sin | F::Cos | F::Tan | F::Cot | F::Sec | F::Csc), arg } => {
+                let Expr::Sequence(mut args) = *arg else { unreachable!("builtin arguments are a sequence") };
+                let angle = args.remove(0);
+                args.insert(0, one(F::Rad, angle));
+                Expr::Function { func, arg: Box::new(Expr::Sequence(args)) }
+            }
+            Expr::Function { func: func @ (F::Asin | F::Acos | F::Atan | F::Acot | F::Asec | F::Acsc | F::Atan2), arg } => {
+                one(F::Deg, Expr::Function { func, arg })
+            }
+            expr => expr,
+        }
+    }
+}
+
+// Whether a user function `C` takes the two arguments of C(n, k)
+fn defines_binomial(functions: &UserFunctions) -> bool {
+    functions.get("C").is_some_and(|overloads| overloads.resolve(2).is_some())
+}
+
+// C(n, k) as the builtin binomial coefficient, unless a user function `C`
+// takes two arguments
+struct Binomial;
+
+impl Binomial {
+    fn fold_body(&mut self, params: &mut [Param], body: &mut Expr) {
+        for param in params {
+            param.default = param.default.take().map(|default| self.fold_expr(default));
+        }
+        *body = self.fold_expr(std::mem::replace(body, Expr::Number(0.0)));
+    }
+}
+
+impl ExprFolder for Binomial {
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        match walk_fold(self, expr) {
+            Expr::FunctionCall { name, args } if name == "C" && args.len() == 2 => {
+                Expr::Function { func: SpecialFunction::Choose, arg: Box::new(Expr::Sequence(args)) }
+            }
+            expr => expr,
+        }
+    }
+}
+
+// Applies `#pragma degrees` to a function's parameter defaults and body
+fn in_degrees(params: &mut [Param], body: &mut Expr) {
+    for param in params {
+        param.default = param.default.take().map(|default| Degrees.fold_expr(default));
+    }
+    *body = Degrees.fold_expr(std::mem::replace(body, Expr::Number(0.0)));
+}
+
+/// Turns the names of the user `functions`, and of the variables `holders`
+/// assigned one so far, into `FunctionRef` on the right of an assignment and
+/// as the function `apply` calls or `bind` fixes arguments of; a call of such a variable becomes an `Apply`.
+/// Parsing does this already; callers that parse a script piece by piece
+/// repeat it with the functions and holders of the whole script.
+pub fn resolve_function_values(expr: &mut Expr, functions: &HashSet<String>, holders: &mut HashSet<String>) {
+    let is_function = |name: &String, holders: &HashSet<String>| functions.contains(name) || holders.contains(name);
+    match expr {
+        Expr::Assign { name, expr: value } => {
+            resolve_function_values(value, functions, holders);
+            if let Expr::Ident(target) = value.as_ref()
+                && is_function(target, holders)
+            {
+                **value = Expr::FunctionRef(target.clone());
+            }
+            if value.is_function() {
+                holders.insert(name.clone());
+            } else {
+                holders.remove(name);
+            }
+        }
+        Expr::Apply { callee, args } | Expr::Bind { callee, args } => {
+            args.iter_mut().for_each(|arg| resolve_function_values(arg, functions, holders));
+            resolve_function_values(callee, functions, holders);
+            if let Expr::Ident(target) = callee.as_ref()
+                && is_function(target, holders)
+            {
+                **callee = Expr::FunctionRef(target.clone());
+            }
+        }
+        Expr::FunctionCall { name, args } => {
+            args.iter_mut().for_each(|arg| resolve_function_values(arg, functions, holders));
+            // A user function takes precedence over a variable of the same name
+            if !functions.contains(name) && holders.contains(name) {
+                let callee = Box::new(Expr::FunctionRef(name.clone()));
+                *expr = Expr::Apply { callee, args: std::mem::take(args) };
+            }
+        }
+        Expr::Const { expr, .. }
+        | Expr::Output { expr, .. }
+        | Expr::Function { arg: expr, .. }
+        | Expr::UnaryOp { operand: expr, .. } => resolve_function_values(expr, functions, holders),
+        Expr::BinaryOp { left, right, .. } => {
+            resolve_function_values(left, functions, holders);
+            resolve_function_values(right, functions, holders);
+        }
+        Expr::Sequence(exprs) | Expr::List(exprs) => {
+            exprs.iter_mut().for_each(|e| resolve_function_values(e, functions, holders));
+        }
+        Expr::Sum { from, to, eps, cond, body, .. } => {
+            for e in [Some(from), Some(to), eps.as_mut(), cond.as_mut(), Some(body)].into_iter().flatten() {
+                resolve_function_values(e, functions, holders);
+            }
+        }
+        Expr::Product { from, to, cond, body, .. } => {
+            for e in [Some(from), Some(to), cond.as_mut(), Some(body)].into_iter().flatten() {
+                resolve_function_values(e, functions, holders);
+            }
+        }
+        Expr::Conditional { cond, then, otherwise } => {
+            resolve_function_values(cond, functions, holders);
+            resolve_function_values(then, functions, holders);
+            resolve_function_values(otherwise, functions, holders);
+        }
+        Expr::Let { value, body, .. } => {
+            resolve_function_values(value, functions, holders);
+            resolve_function_values(body, functions, holders);
+        }
+        Expr::Return { cond, value } => {
+            if let Some(cond) = cond {
+                resolve_function_values(cond, functions, holders);
+            }
+            resolve_function_values(value, functions, holders);
+        }
+        Expr::Solve { range, lhs, rhs, .. } => {
+            if let Some((from, to)) = range {
+                resolve_function_values(from, functions, holders);
+                resolve_function_values(to, functions, holders);
+            }
+            resolve_function_values(lhs, functions, holders);
+            resolve_function_values(rhs, functions, holders);
+        }
+        Expr::SolveN { equations, guess, .. } => {
+            for e in equations {
+                resolve_function_values(e, functions, holders);
+            }
+            resolve_function_values(guess, functions, holders);
+        }
+        Expr::Extremum { body, from, to, .. } => {
+            resolve_function_values(body, functions, holders);
+            resolve_function_values(from, functions, holders);
+            resolve_function_values(to, functions, holders);
+        }
+        Expr::Derivatives { body, point, .. } => {
+            resolve_function_values(body, functions, holders);
+            resolve_function_values(point, functions, holders);
+        }
+        Expr::Assert { cond, .. } => resolve_function_values(cond, functions, holders),
+        Expr::AssertEq { left, right, eps, .. } => {
+            resolve_function_values(left, functions, holders);
+            resolve_function_values(right, functions, holders);
+            resolve_function_values(eps, functions, holders);
+        }
+        Expr::Number(_)
+        | Expr::Ident(_)
+        | Expr::Text(_)
+        | Expr::FunctionRef(_)
+        | Expr::FunctionDef { .. }
+        | Expr::Precision(_) => {}
+    }
+}
+
+enum Line {
+    FunctionDef(String, UserFunction),
+    /// `def name(params)` alone, opening a body that runs until `end`
+    FunctionStart(String, Vec<Param>),
+    Statement(Expr),
+}
+
+// Parse one complete line; function definitions are kept out of the main exprs
+fn parse_line(tokens: &[Token]) -> Result<Line, ParseError> {
+    if let Some(Token::Def) = tokens.first() {
+        let ((name, params), next_pos) = parse_def_header(tokens, 0)?;
+        if next_pos == tokens.len() {
+            return Ok(Line::FunctionStart(name, params));
+        }
+        let ((name, function), next_pos) = parse_def(tokens, 0)?;
+        expect_end(tokens, next_pos)?;
+        return Ok(Line::FunctionDef(name, function));
+    }
+    let (expr, next_pos) = parse_statement(tokens, 0)?;
+    expect_end(tokens, next_pos)?;
+    Ok(Line::Statement(expr))
+}
+
+/// Whether a line is `def name(params)` alone, so the function body follows
+/// on the lines up to `end`.
+pub fn opens_function_body(tokens: &[Token]) -> bool {
+    matches!(parse_def_header(tokens, 0), Ok((_, next_pos)) if next_pos == tokens.len())
+}
+
+// The statements of a multi-line function body, up to the line holding just
+// `end`; `def_line` is where the definition started
+fn parse_body(
+    lines: &mut impl Iterator<Item = (usize, Vec<Token>)>,
+    def_line: usize,
+) -> Result<Expr, Vec<(usize, ParseError)>> {
+    let mut statements = Vec::new();
+    let mut errors = Vec::new();
+    for (line, tokens) in lines.by_ref() {
+        if tokens == [Token::EndDef] {
+            // A guard that does not fire would leave the body without a value
+            if matches!(statements.last(), None | Some(Expr::Return { cond: Some(_), .. })) {
+                errors.push((line, ParseError::Malformed {
+                    construct: "function body",
+                    expected: "an expression or `return` on its last line".to_string(),
+                }));
+            }
+            return if errors.is_empty() { Ok(Expr::Sequence(statements)) } else { Err(errors) };
+        }
+        match parse_body_statement(&tokens) {
+            Ok(statement) => statements.push(statement),
+            Err(error) => errors.push((line, error)),
+        }
+    }
+    errors.push((def_line, ParseError::Expected("`end` after the function body")));
+    Err(errors)
+}
+
+// One line of a multi-line function body: `return expr`, `if cond return expr`,
+// or a statement
+fn parse_body_statement(tokens: &[Token]) -> Result<Expr, ParseError> {
+    let (statement, next_pos) = match tokens.first() {
+        Some(Token::Return) => {
+            let (value, next_pos) = parse_formula(tokens, 1)?;
+            (Expr::Return { cond: None, value: Box::new(value) }, next_pos)
+        }
+        Some(Token::If) => {
+            let (cond, next_pos) = parse_expr(tokens, 1)?;
+            if tokens.get(next_pos) != Some(&Token::Return) {
+                return Err(ParseError::Expected("`return` after the condition of `if`"));
             }
+            let (value, next_pos) = parse_formula(tokens, next_pos + 1)?;
+            (Expr::Return { cond: Some(Box::new(cond)), value: Box::new(value) }, next_pos)
+        }
+        // Definitions nest nowhere, and a body's variables are gone once the call returns
+        Some(token @ (Token::Def | Token::Output)) => return Err(ParseError::UnexpectedToken(token.clone())),
+        _ => parse_statement(tokens, 0)?,
+    };
+    expect_end(tokens, next_pos)?;
+    Ok(statement)
+}
+
+// def name(a, b = 2) = body, from the `def` token
+fn parse_def(tokens: &[Token], pos: usize) -> ParseResult<(String, UserFunction)> {
+    let ((name, params), next_pos) = parse_def_header(tokens, pos)?;
+    let Some(Token::Assign) = tokens.get(next_pos) else {
+        return Err(ParseError::Malformed {
+            construct: "function definition",
+            expected: "def name(a, b = default) = expr".to_string(),
+        });
+    };
+    let (body, next_pos) = parse_formula(tokens, next_pos + 1)?;
+    Ok(((name, UserFunction::new(params, body)), next_pos))
+}
+
+// def name(a, b = 2), up to and including the closing parenthesis; once a
+// parameter has a default, the ones after it need one too
+fn parse_def_header(tokens: &[Token], pos: usize) -> ParseResult<(String, Vec<Param>)> {
+    let malformed = |expected: String| ParseError::Malformed { construct: "function definition", expected };
+    let shape = || malformed("def name(a, b = default) = expr".to_string());
+    if let Some(Token::Function(func)) = tokens.get(pos + 1) {
+        return Err(ParseError::BuiltinName(crate::builtins::entry(*func).name));
+    }
+    let (Some(Token::Ident(name)), Some(Token::LParen)) = (tokens.get(pos + 1), tokens.get(pos + 2)) else {
+        return Err(shape());
+    };
+    let mut params: Vec<Param> = Vec::new();
+    let mut next_pos = pos + 3;
+    loop {
+        let Some(Token::Ident(param)) = tokens.get(next_pos) else {
+            return Err(shape());
+        };
+        if params.iter().any(|p| p.name == *param) {
+            return Err(malformed(format!("distinct parameter names, but `{}` repeats", param)));
+        }
+        let default = if let Some(Token::Assign) = tokens.get(next_pos + 1) {
+            let (default, np) = parse_expr(tokens, next_pos + 2)?;
+            next_pos = np;
+            Some(default)
+        } else if params.last().is_some_and(|p| p.default.is_some()) {
+            return Err(malformed(format!("a default for `{}`, which follows a parameter with one", param)));
+        } else {
+            next_pos += 1;
+            None
+        };
+        params.push(Param { name: param.clone(), default });
+        match tokens.get(next_pos) {
+            Some(Token::Comma) => next_pos += 1,
+            Some(Token::RParen) => break,
+            _ => return Err(shape()),
         }
     }
+    Ok(((name.clone(), params), next_pos + 1))
+}
+
+// The word a keyword token was lexed from, for the error when one is used as a name
+fn keyword_name(token: &Token) -> Option<&'static str> {
+    Some(match token {
+        Token::Sum => "sum",
+        Token::Product => "product",
+        Token::Solve => "solve",
+        Token::Def => "def",
+        Token::EndDef => "end",
+        Token::Return => "return",
+        Token::If => "if",
+        Token::Var => "var",
+        Token::Const => "const",
+        Token::Output => "output",
+        Token::Let => "let",
+        Token::In => "in",
+        Token::Where => "where",
+        Token::Assert(_) => "assert",
+        Token::AssertEq(_) => "assert_eq",
+        Token::Number(x) if *x == f64::INFINITY => "inf",
+        _ => return None,
+    })
+}
+
+fn expect_end(tokens: &[Token], pos: usize) -> Result<(), ParseError> {
+    match tokens.get(pos) {
+        Some(token) => Err(ParseError::UnexpectedToken(token.clone())),
+        None => Ok(()),
+    }
+}
+// Parse a statement: assignment or expression
+fn parse_statement(tokens: &[Token], pos: usize) -> ParseResult<Expr> {
+    // function definition: def name(a, b = 2) = expr
+    if let Some(Token::Def) = tokens.get(pos) {
+        let ((name, function), next_pos) = parse_def(tokens, pos)?;
+        return Ok((Expr::FunctionDef { name, params: function.params, body: Box::new(function.body) }, next_pos));
+    }
+    // `var end = 1` and the like name a binding after a keyword
+    if let Some(Token::Var | Token::Const | Token::Output) = tokens.get(pos)
+        && let Some(name) = tokens.get(pos + 1).and_then(keyword_name)
+        && let Some(Token::Assign) = tokens.get(pos + 2)
+    {
+        return Err(ParseError::KeywordName(name));
+    }
+    // variable declaration/assignment: var Ident = expr
+    if let Some(Token::Var) = tokens.get(pos)
+        && let Some(Token::Ident(name)) = tokens.get(pos + 1)
+        && let Some(Token::Assign) = tokens.get(pos + 2)
+    {
+        let (expr, next_pos) = parse_formula(tokens, pos + 3)?;
+        return Ok((Expr::Assign { name: name.clone(), expr: Box::new(expr) }, next_pos));
+    }
+    // constant definition: const Ident = expr
+    if let Some(Token::Const) = tokens.get(pos)
+        && let Some(Token::Ident(name)) = tokens.get(pos + 1)
+        && let Some(Token::Assign) = tokens.get(pos + 2)
+    {
+        let (expr, next_pos) = parse_formula(tokens, pos + 3)?;
+        return Ok((Expr::Const { name: name.clone(), expr: Box::new(expr) }, next_pos));
+    }
+    // named result: output Ident = expr, optionally labelled with @ "unit"
+    if let Some(Token::Output) = tokens.get(pos)
+        && let Some(Token::Ident(name)) = tokens.get(pos + 1)
+        && let Some(Token::Assign) = tokens.get(pos + 2)
+    {
+        let (expr, next_pos) = parse_formula(tokens, pos + 3)?;
+        let (unit, next_pos) = match tokens.get(next_pos) {
+            Some(Token::At) => match tokens.get(next_pos + 1) {
+                Some(Token::Str(unit)) => (Some(unit.clone()), next_pos + 2),
+                _ => return Err(ParseError::Expected("a string such as \"m/s\" after @")),
+            },
+            _ => (None, next_pos),
+        };
+        return Ok((Expr::Output { name: name.clone(), expr: Box::new(expr), unit }, next_pos));
+    }
     // Fallback: parse any expression (including sum/product) as a statement
-    parse_expr(tokens, pos)
+    parse_formula(tokens, pos)
 }
 
-fn parse_sequence(tokens: &[Token], pos: usize) -> (Expr, usize) {
+// A whole formula: an expression, optionally followed by `where a = ..., b = ...`.
+// The bindings run first and in order, each seeing the ones before it, and
+// hold only within the formula, so `r where d = 2` is `let d = 2 in r`.
+fn parse_formula(tokens: &[Token], pos: usize) -> ParseResult<Expr> {
+    let (expr, mut pos) = parse_expr(tokens, pos)?;
+    if tokens.get(pos) != Some(&Token::Where) {
+        return Ok((expr, pos));
+    }
+    let mut bindings = Vec::new();
+    loop {
+        let (Some(Token::Ident(name)), Some(Token::Assign)) = (tokens.get(pos + 1), tokens.get(pos + 2)) else {
+            return Err(ParseError::Malformed {
+                construct: "where",
+                expected: "expr where a = value, b = value".to_string(),
+            });
+        };
+        let (value, next_pos) = parse_expr(tokens, pos + 3)?;
+        bindings.push((name.clone(), value));
+        pos = next_pos;
+        if tokens.get(pos) != Some(&Token::Comma) {
+            break;
+        }
+    }
+    let expr = bindings
+        .into_iter()
+        .rev()
+        .fold(expr, |body, (name, value)| Expr::Let { name, value: Box::new(value), body: Box::new(body) });
+    Ok((expr, pos))
+}
+
+#[allow(dead_code)]
+fn parse_sequence(tokens: &[Token], pos: usize) -> ParseResult<Expr> {
     let mut exprs = Vec::new();
-    let (first, mut pos) = parse_expr(tokens, pos);
+    let (first, mut pos) = parse_expr(tokens, pos)?;
     exprs.push(first);
     while pos < tokens.len() {
         if let Token::Comma = tokens[pos] {
-            let (next, next_pos) = parse_expr(tokens, pos + 1);
+            let (next, next_pos) = parse_expr(tokens, pos + 1)?;
             exprs.push(next);
             pos = next_pos;
         } else {
@@ -162,163 +659,505 @@ fn parse_sequence(tokens: &[Token], pos: usize) -> (Expr, usize) {
         }
     }
     if exprs.len() == 1 {
-        (exprs.pop().unwrap(), pos)
+        Ok((exprs.pop().unwrap(), pos))
     } else {
-        (Expr::Sequence(exprs), pos)
+        Ok((Expr::Sequence(exprs), pos))
+    }
+}
+
+// The expression grammar, loosest binding first (a statement or function body
+// may end in a `where` clause, see `parse_formula`):
+//
+//   expr       = "let" name "=" expr "in" expr | solve | comparison ["?" expr ":" expr]
+//   solve      = "solve" name ["in" "[" expr "," expr "]"] ":" expr "=" expr
+//   comparison = additive {("<" | "<=" | ">" | ">=" | "==" | "!=") additive}   (chained, as in a < b < c)
+//   additive   = term {("+" | "-") term}
+//   term       = unary {("*" | "/") unary}
+//   unary      = "-" unary | power
+//   power      = postfix ["^" unary]
+//   postfix    = primary {"!" | "!!" | "%"}
+//   primary    = number | name | call | "(" expr ")" | "|" expr "|" | "√" radicand | list | sum | product
+//
+// So -x^2 is -(x^2), 2^-1 is 2^(-1), 2^3^2 is 2^(3^2), -3! is -(3!) and
+// 2^3! is 2^(3!).
+fn parse_expr(tokens: &[Token], pos: usize) -> ParseResult<Expr> {
+    // Scoped binding: let name = value in body (body extends as far right as possible)
+    if let Some(Token::Let) = tokens.get(pos) {
+        let (Some(Token::Ident(name)), Some(Token::Assign)) = (tokens.get(pos + 1), tokens.get(pos + 2)) else {
+            return Err(ParseError::Malformed { construct: "let", expected: "let name = value in expr".to_string() });
+        };
+        let (value, next_pos) = parse_expr(tokens, pos + 3)?;
+        let Some(Token::In) = tokens.get(next_pos) else {
+            return Err(ParseError::Expected("`in` after let binding"));
+        };
+        let (body, next_pos) = parse_expr(tokens, next_pos + 1)?;
+        return Ok((Expr::Let { name: name.clone(), value: Box::new(value), body: Box::new(body) }, next_pos));
+    }
+    // Equation: solve x: lhs = rhs, or solve x in [a, b]: lhs = rhs (rhs extends as far right as possible)
+    if let Some(Token::Solve) = tokens.get(pos) {
+        return parse_solve(tokens, pos + 1);
+    }
+    // Ternary conditional: cond ? a : b (right-associative)
+    let (cond, pos) = parse_comparison(tokens, pos)?;
+    if let Some(Token::Question) = tokens.get(pos) {
+        let (then, next_pos) = parse_expr(tokens, pos + 1)?;
+        let Some(Token::Colon) = tokens.get(next_pos) else {
+            return Err(ParseError::Expected(": in conditional expression cond ? a : b"));
+        };
+        let (otherwise, next_pos) = parse_expr(tokens, next_pos + 1)?;
+        return Ok((Expr::Conditional {
+            cond: Box::new(cond),
+            then: Box::new(then),
+            otherwise: Box::new(otherwise),
+        }, next_pos));
+    }
+    Ok((cond, pos))
+}
+
+// The rest of `solve name [in [a, b]]: lhs = rhs` from the name on
+fn parse_solve(tokens: &[Token], pos: usize) -> ParseResult<Expr> {
+    let malformed = || ParseError::Malformed { construct: "solve", expected: "solve x: lhs = rhs or solve x in [a, b]: lhs = rhs".to_string() };
+    let Some(Token::Ident(param)) = tokens.get(pos) else {
+        return Err(malformed());
+    };
+    let mut pos = pos + 1;
+    let mut range = None;
+    if let (Some(Token::In), Some(Token::LBracket)) = (tokens.get(pos), tokens.get(pos + 1)) {
+        let (from, next_pos) = parse_expr(tokens, pos + 2)?;
+        let Some(Token::Comma) = tokens.get(next_pos) else {
+            return Err(malformed());
+        };
+        let (to, next_pos) = parse_expr(tokens, next_pos + 1)?;
+        let Some(Token::RBracket) = tokens.get(next_pos) else {
+            return Err(malformed());
+        };
+        range = Some((Box::new(from), Box::new(to)));
+        pos = next_pos + 1;
     }
+    let Some(Token::Colon) = tokens.get(pos) else {
+        return Err(malformed());
+    };
+    let (lhs, next_pos) = parse_comparison(tokens, pos + 1)?;
+    let Some(Token::Assign) = tokens.get(next_pos) else {
+        return Err(ParseError::Expected("`=` between the two sides of the equation"));
+    };
+    let (rhs, next_pos) = parse_expr(tokens, next_pos + 1)?;
+    Ok((Expr::Solve { param: param.clone(), range, lhs: Box::new(lhs), rhs: Box::new(rhs) }, next_pos))
+}
+
+// Comparison operators (<, <=, >, >=, ==, !=) yield 1 or 0. A chain reads
+// the mathematical way: `0 <= x < 10` is `0 <= x and x < 10`, the later
+// links only checked while the earlier ones hold, and a shared operand such
+// as `x` is evaluated again by each link it appears in.
+fn parse_comparison(tokens: &[Token], pos: usize) -> ParseResult<Expr> {
+    let (mut operand, mut pos) = parse_additive(tokens, pos)?;
+    let mut links = Vec::new();
+    while let Some(Token::Operator(op)) = tokens.get(pos) {
+        if !op.is_comparison() {
+            break;
+        }
+        let (right, next_pos) = parse_additive(tokens, pos + 1)?;
+        let left = std::mem::replace(&mut operand, right.clone());
+        links.push(Expr::BinaryOp { left: Box::new(left), op: *op, right: Box::new(right) });
+        pos = next_pos;
+    }
+    let Some(last) = links.pop() else {
+        return Ok((operand, pos));
+    };
+    let chain = links.into_iter().rev().fold(last, |rest, link| Expr::Conditional {
+        cond: Box::new(link),
+        then: Box::new(rest),
+        otherwise: Box::new(Expr::Number(0.0)),
+    });
+    Ok((chain, pos))
 }
 
-fn parse_expr(tokens: &[Token], pos: usize) -> (Expr, usize) {
-    let (mut left, mut pos) = parse_term(tokens, pos);
+// Calculator-style percentages: `p%` on its own is p/100, but when a lone
+// percentage is the right operand of + or -, it is taken relative to the left
+// side, so `x + 10%` is x * (1 + 10/100) and `x - 10%` is x * (1 - 10/100).
+// A percentage inside a product stays plain: `x + 2 * 10%` is x + 0.2.
+fn parse_additive(tokens: &[Token], pos: usize) -> ParseResult<Expr> {
+    let (mut left, mut pos) = parse_term(tokens, pos)?;
     while pos < tokens.len() {
-        match &tokens[pos] {
-            Token::Operator(BinaryOperator::Plus) => {
-                let (right, next_pos) = parse_term(tokens, pos + 1);
-                left = Expr::BinaryOp { left: Box::new(left), op: BinaryOperator::Plus, right: Box::new(right) };
-                pos = next_pos;
-            }
-            Token::Operator(BinaryOperator::Minus) => {
-                let (right, next_pos) = parse_term(tokens, pos + 1);
-                left = Expr::BinaryOp { left: Box::new(left), op: BinaryOperator::Minus, right: Box::new(right) };
-                pos = next_pos;
-            }
+        let op = match &tokens[pos] {
+            Token::Operator(op @ (BinaryOperator::Plus | BinaryOperator::Minus)) => *op,
             _ => break,
-        }
+        };
+        let (right, next_pos) = parse_term(tokens, pos + 1)?;
+        left = if matches!(tokens.get(next_pos - 1), Some(Token::Percent)) && is_percentage(&right) {
+            let factor = Expr::BinaryOp { left: Box::new(Expr::Number(1.0)), op, right: Box::new(right) };
+            Expr::BinaryOp { left: Box::new(left), op: BinaryOperator::Star, right: Box::new(factor) }
+        } else {
+            Expr::BinaryOp { left: Box::new(left), op, right: Box::new(right) }
+        };
+        pos = next_pos;
     }
-    (left, pos)
+    Ok((left, pos))
 }
 
-fn parse_term(tokens: &[Token], pos: usize) -> (Expr, usize) {
-    let (mut left, mut pos) = parse_power(tokens, pos);
+// A term made of a single `p%`, which parses as p / 100
+fn is_percentage(expr: &Expr) -> bool {
+    matches!(expr, Expr::BinaryOp { op: BinaryOperator::Slash, right, .. } if matches!(**right, Expr::Number(n) if n == 100.0))
+}
+
+fn parse_term(tokens: &[Token], pos: usize) -> ParseResult<Expr> {
+    let (mut left, mut pos) = parse_unary(tokens, pos)?;
     while pos < tokens.len() {
         match &tokens[pos] {
             Token::Operator(BinaryOperator::Star) => {
-                let (right, next_pos) = parse_power(tokens, pos + 1);
+                let (right, next_pos) = parse_unary(tokens, pos + 1)?;
                 left = Expr::BinaryOp { left: Box::new(left), op: BinaryOperator::Star, right: Box::new(right) };
                 pos = next_pos;
             }
             Token::Operator(BinaryOperator::Slash) => {
-                let (right, next_pos) = parse_power(tokens, pos + 1);
+                let (right, next_pos) = parse_unary(tokens, pos + 1)?;
                 left = Expr::BinaryOp { left: Box::new(left), op: BinaryOperator::Slash, right: Box::new(right) };
                 pos = next_pos;
             }
             _ => break,
         }
     }
-    (left, pos)
+    Ok((left, pos))
 }
 
-// Parse power operator (right-associative)
-fn parse_power(tokens: &[Token], pos: usize) -> (Expr, usize) {
-    let (mut left, mut pos) = parse_factor(tokens, pos);
-    while pos < tokens.len() {
-        match &tokens[pos] {
-            Token::Operator(BinaryOperator::Pow) => {
-                let (right, next_pos) = parse_power(tokens, pos + 1);
-                left = Expr::BinaryOp { left: Box::new(left), op: BinaryOperator::Pow, right: Box::new(right) };
-                pos = next_pos;
-            }
-            _ => break,
+// Unary minus, weaker than ^ so -x^2 is -(x^2)
+fn parse_unary(tokens: &[Token], pos: usize) -> ParseResult<Expr> {
+    if let Some(Token::Operator(BinaryOperator::Minus)) = tokens.get(pos) {
+        let (operand, next_pos) = parse_unary(tokens, pos + 1)?;
+        return Ok((Expr::UnaryOp { op: UnaryOperator::Neg, operand: Box::new(operand) }, next_pos));
+    }
+    parse_power(tokens, pos)
+}
+
+// Power (right-associative); the exponent may carry a sign, as in 2^-x^2
+fn parse_power(tokens: &[Token], pos: usize) -> ParseResult<Expr> {
+    let (base, pos) = parse_postfix(tokens, pos)?;
+    if let Some(Token::Operator(BinaryOperator::Pow)) = tokens.get(pos) {
+        let (exponent, next_pos) = parse_unary(tokens, pos + 1)?;
+        return Ok((Expr::BinaryOp { left: Box::new(base), op: BinaryOperator::Pow, right: Box::new(exponent) }, next_pos));
+    }
+    Ok((base, pos))
+}
+
+// Postfix factorial: expr! and expr!!, and percent: expr% is expr / 100
+fn parse_postfix(tokens: &[Token], pos: usize) -> ParseResult<Expr> {
+    let (mut expr, mut pos) = parse_primary(tokens, pos)?;
+    while let Some(token) = tokens.get(pos) {
+        if let Token::Function(func @ (crate::lexer::SpecialFunction::Fact | crate::lexer::SpecialFunction::DFact)) = token {
+            expr = Expr::Function { func: *func, arg: Box::new(expr) };
+        } else if let Token::Percent = token {
+            expr = Expr::BinaryOp { left: Box::new(expr), op: BinaryOperator::Slash, right: Box::new(Expr::Number(100.0)) };
+        } else {
+            break;
         }
+        pos += 1;
     }
-    (left, pos)
+    Ok((expr, pos))
 }
 
-fn parse_factor(tokens: &[Token], pos: usize) -> (Expr, usize) {
+/// Tolerance used by `assert_eq(a, b)` when no eps is given.
+const DEFAULT_ASSERT_EPS: f64 = 1e-9;
+
+// Parse a parenthesized, comma-separated argument list starting at `(`
+fn parse_call_args(tokens: &[Token], pos: usize) -> ParseResult<Vec<Expr>> {
+    if !matches!(tokens.get(pos), Some(Token::LParen)) {
+        return Err(ParseError::Expected("opening parenthesis after function name"));
+    }
+    if let Some(Token::RParen) = tokens.get(pos + 1) {
+        return Ok((Vec::new(), pos + 2));
+    }
+    let (arg, mut next_pos) = parse_expr(tokens, pos + 1)?;
+    let mut args = vec![arg];
+    while let Some(Token::Comma) = tokens.get(next_pos) {
+        let (next_arg, np) = parse_expr(tokens, next_pos + 1)?;
+        args.push(next_arg);
+        next_pos = np;
+    }
+    if let Some(Token::RParen) = tokens.get(next_pos) {
+        Ok((args, next_pos + 1))
+    } else {
+        Err(ParseError::Expected("closing parenthesis after arguments"))
+    }
+}
+
+// Builtin call arguments, each either positional or named as in `max: 10`
+fn parse_builtin_args(tokens: &[Token], pos: usize) -> ParseResult<Vec<(Option<String>, Expr)>> {
+    if !matches!(tokens.get(pos), Some(Token::LParen)) {
+        return Err(ParseError::Expected("opening parenthesis after function name"));
+    }
+    if let Some(Token::RParen) = tokens.get(pos + 1) {
+        return Ok((Vec::new(), pos + 2));
+    }
+    let mut args = Vec::new();
+    let mut next_pos = pos;
+    let mut any_named = false;
+    loop {
+        let (name, start) = match (tokens.get(next_pos + 1), tokens.get(next_pos + 2)) {
+            (Some(Token::Ident(name)), Some(Token::Colon)) => (Some(name.clone()), next_pos + 3),
+            _ => (None, next_pos + 1),
+        };
+        if name.is_none() && any_named {
+            return Err(ParseError::Expected("positional arguments before named ones"));
+        }
+        any_named |= name.is_some();
+        // A string is a whole argument, which the catalog checks is a `Text` one
+        let (arg, np) = match tokens.get(start) {
+            Some(Token::Str(text)) => (Expr::Text(text.clone()), start + 1),
+            Some(Token::Function(SpecialFunction::Format)) => parse_builtin_call(tokens, start, SpecialFunction::Format)?,
+            _ => parse_expr(tokens, start)?,
+        };
+        args.push((name, arg));
+        next_pos = np;
+        if !matches!(tokens.get(next_pos), Some(Token::Comma)) {
+            break;
+        }
+    }
+    if let Some(Token::RParen) = tokens.get(next_pos) {
+        Ok((args, next_pos + 1))
+    } else {
+        Err(ParseError::Expected("closing parenthesis after arguments"))
+    }
+}
+
+// A builtin call from the name at `pos`. Arguments are validated against the
+// builtin catalog, which also picks the overload (e.g. log(x) vs log(base, x))
+// and the order of named arguments
+fn parse_builtin_call(tokens: &[Token], pos: usize, func: SpecialFunction) -> ParseResult<Expr> {
+    let (args, next_pos) = parse_builtin_args(tokens, pos + 1)?;
+    // grad(expr, [x, y], point) is a gradient; grad(x) converts radians
+    if func == SpecialFunction::Grad && args.len() == 3 && args.iter().all(|(name, _)| name.is_none()) {
+        let args = args.into_iter().map(|(_, arg)| arg).collect();
+        return Ok((parse_derivatives(false, args)?, next_pos));
+    }
+    let func = crate::builtins::resolve(func, args.len()).map_err(|e| match e {
+        ParseError::ArgumentCount { function, expected, got } if func == SpecialFunction::Grad => {
+            ParseError::ArgumentCount { function, expected: format!("{} or 3 arguments (expr, vars, point)", expected), got }
+        }
+        e => e,
+    })?;
+    let mut args = crate::builtins::order_args(func, args)?;
+    // print(format(t, values...)) is print(t, values...)
+    if func == SpecialFunction::Print
+        && let [Expr::Function { func: SpecialFunction::Format, arg }] = args.as_mut_slice()
+        && let Expr::Sequence(format_args) = &mut **arg
+    {
+        args = std::mem::take(format_args);
+    }
+    crate::builtins::check_args(func, &args)?;
+    if let (SpecialFunction::Format | SpecialFunction::Print, Some(Expr::Text(template))) = (func, args.first()) {
+        let places = crate::text::placeholders(template).map_err(ParseError::Format)?;
+        if places != args.len() - 1 {
+            return Err(ParseError::Format(format!("`{}` has places for {} values, but {} are given", template, places, args.len() - 1)));
+        }
+    }
+    Ok((Expr::Function { func, arg: Box::new(Expr::Sequence(args)) }, next_pos))
+}
+
+fn parse_primary(tokens: &[Token], pos: usize) -> ParseResult<Expr> {
     // sum/product
-    if let Some((sumprod, next_pos)) = parse_sum_product(tokens, pos) {
-        return (sumprod, next_pos);
-    }
-    // sum/product not supported in compiled mode
-    let (mut expr, mut pos) = match &tokens[pos] {
-        Token::Operator(BinaryOperator::Minus) => {
-            // Unary minus: -factor
-            let (expr, next_pos) = parse_factor(tokens, pos + 1);
-            (Expr::BinaryOp {
-                left: Box::new(Expr::Number(0.0)),
-                op: BinaryOperator::Minus,
-                right: Box::new(expr),
-            }, next_pos)
+    if let Some((sumprod, next_pos)) = parse_sum_product(tokens, pos)? {
+        return Ok((sumprod, next_pos));
+    }
+    let Some(token) = tokens.get(pos) else {
+        return Err(ParseError::UnexpectedEnd);
+    };
+    Ok(match token {
+        Token::Radical => {
+            // √ takes a postfix expression, so √x^2 is (√x)^2, √4! is √(4!) and
+            // √(x + 1) takes the whole sum; a sign goes with it, as in √-x
+            let (operand, next_pos) = parse_radicand(tokens, pos + 1)?;
+            let arg = Box::new(Expr::Sequence(vec![operand]));
+            (Expr::Function { func: crate::lexer::SpecialFunction::Sqrt, arg }, next_pos)
         }
         Token::Pipe => {
             // Absolute value: |expr|
-            let (inner, next_pos) = parse_expr(tokens, pos + 1);
+            let (inner, next_pos) = parse_expr(tokens, pos + 1)?;
             if let Some(Token::Pipe) = tokens.get(next_pos) {
                 (Expr::Function { func: crate::lexer::SpecialFunction::Abs, arg: Box::new(inner) }, next_pos + 1)
             } else {
-                panic!("Expected closing | for absolute value")
+                return Err(ParseError::Expected("closing | for absolute value"))
             }
         }
         Token::Number(n) => (Expr::Number(*n), pos + 1),
         // Function call: name(expr)
         Token::Ident(name) => {
             if let Some(Token::LParen) = tokens.get(pos + 1) {
-                let (arg, mut next_pos) = parse_expr(tokens, pos + 2);
-                let mut args = vec![arg];
-                while let Some(Token::Comma) = tokens.get(next_pos) {
-                    let (next_arg, np) = parse_expr(tokens, next_pos + 1);
-                    args.push(next_arg);
-                    next_pos = np;
-                }
-                if let Some(Token::RParen) = tokens.get(next_pos) {
-                    if args.len() == 1 {
-                        (Expr::FunctionCall { name: name.clone(), arg: Box::new(args.remove(0)) }, next_pos + 1)
-                    } else {
-                        (Expr::FunctionCall { name: name.clone(), arg: Box::new(Expr::Sequence(args)) }, next_pos + 1)
-                    }
+                let (args, next_pos) = parse_call_args(tokens, pos + 1)?;
+                // C(n, k) stays a call until the user functions are known (see `resolve_binomial`)
+                if name == "apply" && !args.is_empty() {
+                    // apply(f, args...) calls the function value f
+                    let mut args = args;
+                    let callee = Box::new(args.remove(0));
+                    (Expr::Apply { callee, args }, next_pos)
+                } else if name == "bind" && args.len() >= 2 {
+                    // bind(f, args...) fixes the first arguments of f
+                    let mut args = args;
+                    let callee = Box::new(args.remove(0));
+                    (Expr::Bind { callee, args }, next_pos)
+                } else if name == "solveN" {
+                    (parse_solve_n(args)?, next_pos)
+                } else if name == "minimize" || name == "maximize" {
+                    (parse_extremum(name == "maximize", args)?, next_pos)
+                } else if name == "hessian" {
+                    (parse_derivatives(true, args)?, next_pos)
                 } else {
-                    panic!("Expected closing parenthesis after function call arguments")
+                    (Expr::FunctionCall { name: name.clone(), args }, next_pos)
                 }
             } else {
                 (Expr::Ident(name.clone()), pos + 1)
             }
         }
-        Token::Function(func) => {
-            if let Some(Token::LParen) = tokens.get(pos + 1) {
-                // Support zero or more arguments (comma-separated)
-                if let Some(Token::RParen) = tokens.get(pos + 2) {
-                    // No arguments: f()
-                    (Expr::Function { func: *func, arg: Box::new(Expr::Sequence(vec![])) }, pos + 3)
-                } else {
-                    // One or more arguments: f(arg1, arg2, ...)
-                    let (arg, mut next_pos) = parse_expr(tokens, pos + 2);
-                    let mut args = vec![arg];
-                    while let Some(Token::Comma) = tokens.get(next_pos) {
-                        let (next_arg, np) = parse_expr(tokens, next_pos + 1);
-                        args.push(next_arg);
-                        next_pos = np;
-                    }
-                    if let Some(Token::RParen) = tokens.get(next_pos) {
-                        (Expr::Function { func: *func, arg: Box::new(Expr::Sequence(args)) }, next_pos + 1)
-                    } else {
-                        panic!("Expected closing parenthesis after function arguments")
+        Token::Function(crate::lexer::SpecialFunction::Log) if tokens.get(pos + 1) == Some(&Token::Underscore) => {
+            // log_b(x): the base is a number, a variable or a parenthesized
+            // expression, and the one argument follows it
+            let (base, next_pos) = match tokens.get(pos + 2) {
+                Some(Token::Number(n)) => (Expr::Number(*n), pos + 3),
+                Some(Token::Ident(name)) => (Expr::Ident(name.clone()), pos + 3),
+                Some(Token::LParen) => {
+                    let (base, next_pos) = parse_expr(tokens, pos + 3)?;
+                    if tokens.get(next_pos) != Some(&Token::RParen) {
+                        return Err(ParseError::Expected("closing parenthesis after log base"));
                     }
+                    (base, next_pos + 1)
                 }
+                _ => {
+                    return Err(ParseError::Malformed {
+                        construct: "log base",
+                        expected: "log_b(x) with a number, variable or (expression) as b".to_string(),
+                    })
+                }
+            };
+            let (mut args, next_pos) = parse_call_args(tokens, next_pos)?;
+            if args.len() != 1 {
+                return Err(ParseError::ArgumentCount {
+                    function: "log_b",
+                    expected: "1 argument (x)".to_string(),
+                    got: args.len(),
+                });
+            }
+            let arg = Box::new(Expr::Sequence(vec![base, args.remove(0)]));
+            (Expr::Function { func: crate::lexer::SpecialFunction::LogBase, arg }, next_pos)
+        }
+        // A string goes nowhere but into print
+        Token::Function(SpecialFunction::Format) => return Err(ParseError::StrayText("format")),
+        Token::Function(func) => parse_builtin_call(tokens, pos, *func)?,
+        Token::Assert(line) => {
+            let (mut args, next_pos) = parse_call_args(tokens, pos + 1)?;
+            if args.len() != 1 {
+                return Err(ParseError::ArgumentCount {
+                    function: "assert",
+                    expected: "1 argument (condition)".to_string(),
+                    got: args.len(),
+                })
+            }
+            (Expr::Assert { cond: Box::new(args.remove(0)), line: *line }, next_pos)
+        }
+        Token::AssertEq(line) => {
+            let (mut args, next_pos) = parse_call_args(tokens, pos + 1)?;
+            // eps is optional
+            if args.len() == 2 {
+                args.push(Expr::Number(DEFAULT_ASSERT_EPS));
+            }
+            if args.len() != 3 {
+                return Err(ParseError::ArgumentCount {
+                    function: "assert_eq",
+                    expected: "2 or 3 arguments (a, b, eps)".to_string(),
+                    got: args.len(),
+                })
+            }
+            let eps = args.pop().unwrap();
+            let right = args.pop().unwrap();
+            let left = args.pop().unwrap();
+            (Expr::AssertEq { left: Box::new(left), right: Box::new(right), eps: Box::new(eps), line: *line }, next_pos)
+        }
+        Token::LBracket => {
+            // List literal: [a, b, c]
+            let mut items = Vec::new();
+            let mut next_pos = pos + 1;
+            if let Some(Token::RBracket) = tokens.get(next_pos) {
+                (Expr::List(items), next_pos + 1)
             } else {
-                panic!("Expected opening parenthesis after function name")
+                loop {
+                    let (item, np) = parse_expr(tokens, next_pos)?;
+                    items.push(item);
+                    match tokens.get(np) {
+                        Some(Token::Comma) => next_pos = np + 1,
+                        Some(Token::RBracket) => break (Expr::List(items), np + 1),
+                        _ => return Err(ParseError::Expected(", or ] in list")),
+                    }
+                }
             }
         }
         Token::LParen => {
-            let (expr, next_pos) = parse_expr(tokens, pos + 1);
+            let (expr, next_pos) = parse_expr(tokens, pos + 1)?;
             if let Some(Token::RParen) = tokens.get(next_pos) {
                 (expr, next_pos + 1)
             } else {
-                panic!("Expected closing parenthesis")
+                return Err(ParseError::Expected("closing parenthesis"))
             }
         }
         Token::Operator(op) => {
-            panic!("Operator token {:?} in invalid position. Likely missing operand before or after operator.", op)
+            return Err(ParseError::MisplacedOperator(*op))
         }
-    _ => panic!("Unexpected token: {:?}. Only compiled mode is supported; sum/product are not allowed.", tokens[pos]),
+        _ => return Err(ParseError::UnexpectedToken(token.clone())),
+    })
+}
+
+// solveN([f1, f2], [x, y], [gx, gy]) from its arguments: as many equations
+// as unknowns, each unknown a name, and the guess any list expression
+fn parse_solve_n(mut args: Vec<Expr>) -> Result<Expr, ParseError> {
+    let malformed = || ParseError::Malformed { construct: "solveN", expected: "solveN([f1, f2], [x, y], [guess_x, guess_y])".to_string() };
+    let (Some(guess), Some(Expr::List(unknowns)), Some(Expr::List(equations)), None) = (args.pop(), args.pop(), args.pop(), args.pop()) else {
+        return Err(malformed());
     };
-    // Postfix factorial: expr!
-    while pos < tokens.len() {
-        if let Token::Function(crate::lexer::SpecialFunction::Fact) = &tokens[pos] {
-            expr = Expr::Function { func: crate::lexer::SpecialFunction::Fact, arg: Box::new(expr) };
-            pos += 1;
-        } else {
-            break;
-        }
+    let params = unknowns
+        .into_iter()
+        .map(|unknown| match unknown {
+            Expr::Ident(name) => Ok(name),
+            _ => Err(malformed()),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if params.is_empty() || equations.len() != params.len() {
+        return Err(ParseError::Expected("as many equations as unknowns in solveN"));
+    }
+    Ok(Expr::SolveN { equations, params, guess: Box::new(guess) })
+}
+
+// minimize(expr, x, a, b) or maximize(expr, x, a, b) from its arguments
+fn parse_extremum(maximize: bool, args: Vec<Expr>) -> Result<Expr, ParseError> {
+    let construct = if maximize { "maximize" } else { "minimize" };
+    let Ok([body, Expr::Ident(param), from, to]) = <[Expr; 4]>::try_from(args) else {
+        return Err(ParseError::Malformed { construct, expected: format!("{}(expr, x, a, b)", construct) });
+    };
+    Ok(Expr::Extremum { maximize, body: Box::new(body), param, from: Box::new(from), to: Box::new(to) })
+}
+
+// grad(expr, [x, y], point) or hessian(expr, [x, y], point) from its
+// arguments: each variable a name, and the point any list expression
+fn parse_derivatives(hessian: bool, args: Vec<Expr>) -> Result<Expr, ParseError> {
+    let construct = if hessian { "hessian" } else { "grad" };
+    let malformed = || ParseError::Malformed { construct, expected: format!("{}(expr, [x, y], point)", construct) };
+    let Ok([body, Expr::List(variables), point]) = <[Expr; 3]>::try_from(args) else {
+        return Err(malformed());
+    };
+    let params = variables
+        .into_iter()
+        .map(|variable| match variable {
+            Expr::Ident(name) => Ok(name),
+            _ => Err(malformed()),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if params.is_empty() {
+        return Err(malformed());
+    }
+    Ok(Expr::Derivatives { hessian, body: Box::new(body), params, point: Box::new(point) })
+}
+
+// The operand of √: a postfix expression after any signs
+fn parse_radicand(tokens: &[Token], pos: usize) -> ParseResult<Expr> {
+    if let Some(Token::Operator(BinaryOperator::Minus)) = tokens.get(pos) {
+        let (operand, next_pos) = parse_radicand(tokens, pos + 1)?;
+        return Ok((Expr::UnaryOp { op: UnaryOperator::Neg, operand: Box::new(operand) }, next_pos));
     }
-    (expr, pos)
+    parse_postfix(tokens, pos)
 }
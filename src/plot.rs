@@ -0,0 +1,568 @@
+// Rendering of sampled functions: line charts, plane curves, heatmaps, isometric surfaces, direction fields, and histograms
+use std::fmt::Write;
+
+use crate::numeric::{self, Crossing};
+
+const WIDTH: f64 = 640.0;
+const HEIGHT: f64 = 480.0;
+const MARGIN: f64 = 48.0;
+
+/// Samples of a function of two variables on a regular grid.
+#[derive(Debug, Clone)]
+pub struct Grid {
+    pub xs: Vec<f64>,
+    pub ys: Vec<f64>,
+    /// Row by row: `values[j * xs.len() + i]` is the value at `(xs[i], ys[j])`
+    pub values: Vec<f64>,
+}
+
+impl Grid {
+    fn at(&self, i: usize, j: usize) -> f64 {
+        self.values[j * self.xs.len() + i]
+    }
+}
+
+/// SVG line chart of `ys` against `xs`; non-finite values break the line.
+pub fn line_svg(xs: &[f64], ys: &[f64]) -> String {
+    path_svg(xs, ys, finite_range(xs), finite_range(ys), &[])
+}
+
+/// A point of interest on the graph of a function of one variable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Feature {
+    /// Zero crossing at x.
+    Root(f64),
+    /// Local minimum: x and the value there.
+    Minimum(f64, f64),
+    /// Local maximum: x and the value there.
+    Maximum(f64, f64),
+    /// Vertical asymptote at x.
+    Asymptote(f64),
+}
+
+impl Feature {
+    pub fn x(&self) -> f64 {
+        match *self {
+            Feature::Root(x) | Feature::Minimum(x, _) | Feature::Maximum(x, _) | Feature::Asymptote(x) => x,
+        }
+    }
+}
+
+impl std::fmt::Display for Feature {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Feature::Root(x) => write!(f, "root at x = {}", label(x)),
+            Feature::Minimum(x, y) => write!(f, "minimum at x = {}, y = {}", label(x), label(y)),
+            Feature::Maximum(x, y) => write!(f, "maximum at x = {}, y = {}", label(x), label(y)),
+            Feature::Asymptote(x) => write!(f, "asymptote at x = {}", label(x)),
+        }
+    }
+}
+
+/// The roots, local extrema and vertical asymptotes of `f`, from its samples
+/// `ys` at the increasing points `xs`, in order of x. Each sign change and
+/// each sample above or below both neighbours is refined by evaluating `f`:
+/// a sign change is bisected, and is an asymptote rather than a root if `f`
+/// grows instead of vanishing as the bracket narrows; an extremum is found by
+/// golden-section search, and is an asymptote if the value there runs away
+/// from the samples. Infinite samples are asymptotes as well.
+pub fn features(xs: &[f64], ys: &[f64], mut f: impl FnMut(f64) -> f64) -> Vec<Feature> {
+    let mut found: Vec<Feature> = Vec::new();
+    let n = xs.len().min(ys.len());
+    let spacing = if n > 1 { (xs[n - 1] - xs[0]) / (n - 1) as f64 } else { 0.0 };
+    // The samples beside a pole look like extrema too; they give one asymptote
+    let mut push = |feature: Feature| {
+        let seen = |other: &Feature| matches!(other, Feature::Asymptote(x) if (x - feature.x()).abs() <= spacing);
+        if !(matches!(feature, Feature::Asymptote(_)) && found.iter().any(seen)) {
+            found.push(feature);
+        }
+    };
+    for i in 0..n {
+        let y = ys[i];
+        if y.is_infinite() {
+            push(Feature::Asymptote(xs[i]));
+            continue;
+        }
+        if y == 0.0 {
+            if i == 0 || ys[i - 1] != 0.0 {
+                push(Feature::Root(xs[i]));
+            }
+            continue;
+        }
+        if i + 1 < n && ys[i + 1].is_finite() && y.signum() != ys[i + 1].signum() && ys[i + 1] != 0.0 && y.is_finite() {
+            push(crossing(&mut f, (xs[i], y), (xs[i + 1], ys[i + 1])));
+        }
+        if i > 0 && i + 1 < n && [ys[i - 1], ys[i + 1]].iter().all(|v| v.is_finite()) {
+            let (left, right) = (ys[i - 1], ys[i + 1]);
+            if left < y && y >= right {
+                push(extremum(&mut f, (xs[i - 1], xs[i + 1]), y, (left, right), false));
+            } else if left > y && y <= right {
+                push(extremum(&mut f, (xs[i - 1], xs[i + 1]), y, (left, right), true));
+            }
+        }
+    }
+    found.sort_by(|a, b| a.x().total_cmp(&b.x()));
+    found
+}
+
+// The root or asymptote between two samples of opposite sign
+fn crossing(f: &mut impl FnMut(f64) -> f64, a: (f64, f64), b: (f64, f64)) -> Feature {
+    match numeric::bisect(f, a, b) {
+        Crossing::Root(x) => Feature::Root(x),
+        Crossing::Pole(x) => Feature::Asymptote(x),
+    }
+}
+
+// The extremum of `f` on [lo, hi], around the sample `y` between `left` and
+// `right`, or the asymptote it turns out to be
+fn extremum(f: &mut impl FnMut(f64) -> f64, range: (f64, f64), y: f64, (left, right): (f64, f64), minimum: bool) -> Feature {
+    // Search for the minimum of -f for a maximum
+    let sign = if minimum { 1.0 } else { -1.0 };
+    let x = numeric::golden_min(&mut |x| sign * f(x), range);
+    let value = f(x);
+    if !value.is_finite() || (value - y).abs() > 10.0 * ((y - left).abs() + (y - right).abs()) {
+        Feature::Asymptote(x)
+    } else if minimum {
+        Feature::Minimum(x, value)
+    } else {
+        Feature::Maximum(x, value)
+    }
+}
+
+/// The line chart of `line_svg` with `features` marked and labelled: a dot on
+/// each root and extremum, and a dashed line along each asymptote.
+pub fn annotated_line_svg(xs: &[f64], ys: &[f64], features: &[Feature]) -> String {
+    path_svg(xs, ys, finite_range(xs), finite_range(ys), features)
+}
+
+/// SVG of the plane curve through the points `(xs[k], ys[k])` in order, as
+/// traced by a parametric or polar equation, with the same scale on both
+/// axes so that circles stay round; non-finite points break the curve.
+pub fn curve_svg(xs: &[f64], ys: &[f64]) -> String {
+    let (x_lo, x_hi) = finite_range(xs);
+    let (y_lo, y_hi) = finite_range(ys);
+    // Widen the range that is short for the frame's aspect, about its middle
+    let (kx, ky) = ((x_hi - x_lo) / (WIDTH - 2.0 * MARGIN), (y_hi - y_lo) / (HEIGHT - 2.0 * MARGIN));
+    let widen = |(lo, hi): (f64, f64), by: f64| ((lo + hi) / 2.0 - (hi - lo) * by / 2.0, (lo + hi) / 2.0 + (hi - lo) * by / 2.0);
+    let (x_range, y_range) = if kx > ky {
+        ((x_lo, x_hi), widen((y_lo, y_hi), kx / ky))
+    } else {
+        (widen((x_lo, x_hi), ky / kx), (y_lo, y_hi))
+    };
+    path_svg(xs, ys, x_range, y_range, &[])
+}
+
+// Line through the points in order, scaled so the ranges fill the frame, with
+// the features marked on it
+fn path_svg(xs: &[f64], ys: &[f64], (x_lo, x_hi): (f64, f64), (y_lo, y_hi): (f64, f64), features: &[Feature]) -> String {
+    let sx = |x: f64| MARGIN + (x - x_lo) / (x_hi - x_lo) * (WIDTH - 2.0 * MARGIN);
+    let sy = |y: f64| HEIGHT - MARGIN - (y - y_lo) / (y_hi - y_lo) * (HEIGHT - 2.0 * MARGIN);
+    let mut path = String::new();
+    let mut pen_down = false;
+    for (&x, &y) in xs.iter().zip(ys) {
+        if !(x.is_finite() && y.is_finite()) {
+            pen_down = false;
+            continue;
+        }
+        let _ = write!(path, "{}{:.2},{:.2} ", if pen_down { "L" } else { "M" }, sx(x), sy(y));
+        pen_down = true;
+    }
+    let mut svg = svg_start(WIDTH, HEIGHT);
+    axes(&mut svg, (x_lo, x_hi), (y_lo, y_hi));
+    let _ = writeln!(svg, r##"<path d="{}" fill="none" stroke="#1f77b4" stroke-width="1.5"/>"##, path.trim_end());
+    for feature in features {
+        let (x, y) = match *feature {
+            Feature::Asymptote(x) => {
+                let _ = writeln!(
+                    svg,
+                    r##"<line x1="{x:.2}" y1="{}" x2="{x:.2}" y2="{}" stroke="#d62728" stroke-dasharray="4 3"/>"##,
+                    MARGIN,
+                    HEIGHT - MARGIN,
+                    x = sx(x)
+                );
+                let _ = writeln!(svg, r##"<text x="{:.2}" y="{}" font-size="10" fill="#d62728">x = {}</text>"##, sx(x) + 3.0, MARGIN + 12.0, label(x));
+                continue;
+            }
+            Feature::Root(x) => (x, 0.0),
+            Feature::Minimum(x, y) | Feature::Maximum(x, y) => (x, y),
+        };
+        let text = match feature {
+            Feature::Minimum(..) => format!("min ({}, {})", label(x), label(y)),
+            Feature::Maximum(..) => format!("max ({}, {})", label(x), label(y)),
+            _ => format!("x = {}", label(x)),
+        };
+        let _ = writeln!(svg, r##"<circle cx="{:.2}" cy="{:.2}" r="3" fill="#d62728"/>"##, sx(x), sy(y));
+        let _ = writeln!(svg, r##"<text x="{:.2}" y="{:.2}" font-size="10" fill="#d62728">{}</text>"##, sx(x) + 5.0, sy(y) - 5.0, text);
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// Counts of values in equal-width bins between the smallest and largest
+/// finite value; non-finite values are not counted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    pub lo: f64,
+    pub hi: f64,
+    pub counts: Vec<usize>,
+}
+
+impl Histogram {
+    pub fn new(values: &[f64], bins: usize) -> Self {
+        let (lo, hi) = finite_range(values);
+        let bins = bins.max(1);
+        let mut counts = vec![0; bins];
+        for &value in values.iter().filter(|v| v.is_finite()) {
+            // The largest value closes the last bin
+            let k = ((value - lo) / (hi - lo) * bins as f64) as usize;
+            counts[k.min(bins - 1)] += 1;
+        }
+        Histogram { lo, hi, counts }
+    }
+
+    /// The lower and upper edge of bin `k`.
+    pub fn bin(&self, k: usize) -> (f64, f64) {
+        let width = (self.hi - self.lo) / self.counts.len() as f64;
+        (self.lo + k as f64 * width, self.lo + (k + 1) as f64 * width)
+    }
+}
+
+/// SVG bar chart of a histogram, each bar as tall as its count.
+pub fn histogram_svg(histogram: &Histogram) -> String {
+    let top = histogram.counts.iter().copied().max().unwrap_or(0).max(1) as f64;
+    let width = (WIDTH - 2.0 * MARGIN) / histogram.counts.len() as f64;
+    let mut svg = svg_start(WIDTH, HEIGHT);
+    let _ = writeln!(svg, r##"<g fill="#1f77b4" stroke="white" stroke-width="0.5">"##);
+    for (k, &count) in histogram.counts.iter().enumerate() {
+        let height = count as f64 / top * (HEIGHT - 2.0 * MARGIN);
+        let _ = writeln!(
+            svg,
+            r#"<rect x="{:.2}" y="{:.2}" width="{:.2}" height="{:.2}"/>"#,
+            MARGIN + k as f64 * width,
+            HEIGHT - MARGIN - height,
+            width,
+            height
+        );
+    }
+    svg.push_str("</g>\n");
+    axes(&mut svg, (histogram.lo, histogram.hi), (0.0, top));
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// SVG heatmap with one cell per sample, colored from the lowest value (dark
+/// purple) to the highest (yellow); non-finite values are left blank.
+pub fn heatmap_svg(grid: &Grid) -> String {
+    let (lo, hi) = finite_range(&grid.values);
+    let (nx, ny) = (grid.xs.len(), grid.ys.len());
+    let (cw, ch) = ((WIDTH - 2.0 * MARGIN) / nx as f64, (HEIGHT - 2.0 * MARGIN) / ny as f64);
+    let mut svg = svg_start(WIDTH, HEIGHT);
+    let _ = writeln!(svg, r#"<g shape-rendering="crispEdges">"#);
+    for j in 0..ny {
+        for i in 0..nx {
+            let value = grid.at(i, j);
+            if !value.is_finite() {
+                continue;
+            }
+            let (x, y) = (MARGIN + i as f64 * cw, HEIGHT - MARGIN - (j + 1) as f64 * ch);
+            let _ = writeln!(
+                svg,
+                r#"<rect x="{:.2}" y="{:.2}" width="{:.2}" height="{:.2}" fill="{}"/>"#,
+                x,
+                y,
+                cw + 0.05,
+                ch + 0.05,
+                hex(color((value - lo) / (hi - lo)))
+            );
+        }
+    }
+    svg.push_str("</g>\n");
+    axes(&mut svg, finite_range(&grid.xs), finite_range(&grid.ys));
+    legend(&mut svg, lo, hi);
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// The heatmap as a PNG image, each sample a square of pixels, rows of
+/// increasing y from the bottom up; non-finite values are transparent.
+pub fn heatmap_png(grid: &Grid) -> Vec<u8> {
+    let (lo, hi) = finite_range(&grid.values);
+    let (nx, ny) = (grid.xs.len(), grid.ys.len());
+    let scale = (512 / nx.max(ny).max(1)).max(1);
+    let (width, height) = (nx * scale, ny * scale);
+    let mut rgba = Vec::with_capacity(width * height * 4);
+    for row in 0..height {
+        let j = ny - 1 - row / scale;
+        for column in 0..width {
+            let value = grid.at(column / scale, j);
+            if value.is_finite() {
+                rgba.extend(color((value - lo) / (hi - lo)));
+                rgba.push(255);
+            } else {
+                rgba.extend([0, 0, 0, 0]);
+            }
+        }
+    }
+    png(width as u32, height as u32, &rgba)
+}
+
+/// SVG isometric view of the surface z = f(x, y), x running to the lower
+/// right and y to the lower left, each grid cell shaded by its mean height.
+/// Cells touching a non-finite value are left out.
+pub fn surface_svg(grid: &Grid) -> String {
+    let (lo, hi) = finite_range(&grid.values);
+    let (nx, ny) = (grid.xs.len(), grid.ys.len());
+    // Horizontal half-width of the base diamond; the base is tan 30° as tall
+    // and the tallest point rises 0.6 of it above the base
+    let tan30 = 30f64.to_radians().tan();
+    let a = ((WIDTH - 2.0 * MARGIN) / 2.0).min((HEIGHT - 2.0 * MARGIN) / (2.0 * tan30 + 0.6));
+    let (cx, top) = (WIDTH / 2.0, MARGIN + 0.6 * a);
+    let project = |i: usize, j: usize| {
+        let u = i as f64 / (nx - 1).max(1) as f64;
+        let v = j as f64 / (ny - 1).max(1) as f64;
+        let w = (grid.at(i, j) - lo) / (hi - lo);
+        (cx + (u - v) * a, top + (u + v) * a * tan30 - w * 0.6 * a)
+    };
+    let mut svg = svg_start(WIDTH, HEIGHT);
+    // Painter's order: cells further from the viewer (smaller i + j) first
+    let mut cells: Vec<(usize, usize)> = (0..ny.saturating_sub(1))
+        .flat_map(|j| (0..nx.saturating_sub(1)).map(move |i| (i, j)))
+        .collect();
+    cells.sort_by_key(|&(i, j)| i + j);
+    for (i, j) in cells {
+        let corners = [(i, j), (i + 1, j), (i + 1, j + 1), (i, j + 1)];
+        if corners.iter().any(|&(i, j)| !grid.at(i, j).is_finite()) {
+            continue;
+        }
+        let mean = corners.iter().map(|&(i, j)| grid.at(i, j)).sum::<f64>() / 4.0;
+        let points: Vec<String> = corners
+            .iter()
+            .map(|&(i, j)| {
+                let (x, y) = project(i, j);
+                format!("{:.2},{:.2}", x, y)
+            })
+            .collect();
+        let _ = writeln!(
+            svg,
+            r##"<polygon points="{}" fill="{}" stroke="#222" stroke-width="0.3"/>"##,
+            points.join(" "),
+            hex(color((mean - lo) / (hi - lo)))
+        );
+    }
+    let (x_lo, x_hi) = finite_range(&grid.xs);
+    let (y_lo, y_hi) = finite_range(&grid.ys);
+    let _ = writeln!(
+        svg,
+        r#"<text x="{:.0}" y="{:.0}" font-size="12">x: {} to {}, y: {} to {}</text>"#,
+        MARGIN,
+        HEIGHT - 12.0,
+        label(x_lo),
+        label(x_hi),
+        label(y_lo),
+        label(y_hi)
+    );
+    legend(&mut svg, lo, hi);
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// SVG direction field of dy/dx = f(x, y): at each grid point an arrow of
+/// fixed length along the slope `grid.values` holds there, so that solution
+/// curves can be traced by eye. Infinite slopes point straight up or down;
+/// points where the slope is undefined get no arrow.
+pub fn field_svg(grid: &Grid) -> String {
+    let (nx, ny) = (grid.xs.len(), grid.ys.len());
+    // Half a sample spacing of room around the outermost arrows
+    let padded = |values: &[f64], n: usize| {
+        let (lo, hi) = finite_range(values);
+        let half = (hi - lo) / (2 * n.max(2) - 2) as f64;
+        (lo - half, hi + half)
+    };
+    let (x_lo, x_hi) = padded(&grid.xs, nx);
+    let (y_lo, y_hi) = padded(&grid.ys, ny);
+    let (kx, ky) = ((WIDTH - 2.0 * MARGIN) / (x_hi - x_lo), (HEIGHT - 2.0 * MARGIN) / (y_hi - y_lo));
+    // Each arrow spans 70% of the spacing between samples
+    let length = 0.7 * ((WIDTH - 2.0 * MARGIN) / nx.max(1) as f64).min((HEIGHT - 2.0 * MARGIN) / ny.max(1) as f64);
+    let mut svg = svg_start(WIDTH, HEIGHT);
+    let _ = writeln!(
+        svg,
+        r##"<defs><marker id="head" viewBox="0 0 10 10" refX="10" refY="5" markerWidth="5" markerHeight="5" orient="auto"><path d="M0,0 L10,5 L0,10 z" fill="#1f77b4"/></marker></defs>"##
+    );
+    let _ = writeln!(svg, r##"<g stroke="#1f77b4" stroke-width="1.2" marker-end="url(#head)">"##);
+    for j in 0..ny {
+        for i in 0..nx {
+            let slope = grid.at(i, j);
+            // Screen direction of (1, slope); y grows downwards on screen
+            let (dx, dy) = match slope {
+                s if s.is_nan() => continue,
+                s if s.is_infinite() => (0.0, -s.signum()),
+                s => (kx, -ky * s),
+            };
+            let norm = dx.hypot(dy);
+            let (dx, dy) = (dx / norm * length / 2.0, dy / norm * length / 2.0);
+            let cx = MARGIN + (grid.xs[i] - x_lo) * kx;
+            let cy = HEIGHT - MARGIN - (grid.ys[j] - y_lo) * ky;
+            let _ = writeln!(svg, r#"<line x1="{:.2}" y1="{:.2}" x2="{:.2}" y2="{:.2}"/>"#, cx - dx, cy - dy, cx + dx, cy + dy);
+        }
+    }
+    svg.push_str("</g>\n");
+    axes(&mut svg, (x_lo, x_hi), (y_lo, y_hi));
+    svg.push_str("</svg>\n");
+    svg
+}
+
+// Smallest and largest finite value, widened when they coincide so that
+// scaling by hi - lo stays finite
+fn finite_range(values: &[f64]) -> (f64, f64) {
+    let finite = values.iter().copied().filter(|v| v.is_finite());
+    let lo = finite.clone().fold(f64::INFINITY, f64::min);
+    let hi = finite.fold(f64::NEG_INFINITY, f64::max);
+    if lo > hi {
+        (0.0, 1.0)
+    } else if lo == hi {
+        (lo - 0.5, hi + 0.5)
+    } else {
+        (lo, hi)
+    }
+}
+
+// Viridis-like colormap for t in [0, 1]
+fn color(t: f64) -> [u8; 3] {
+    const STOPS: [[f64; 3]; 5] = [
+        [68.0, 1.0, 84.0],
+        [59.0, 82.0, 139.0],
+        [33.0, 145.0, 140.0],
+        [94.0, 201.0, 98.0],
+        [253.0, 231.0, 37.0],
+    ];
+    let t = t.clamp(0.0, 1.0) * (STOPS.len() - 1) as f64;
+    let k = (t.floor() as usize).min(STOPS.len() - 2);
+    let f = t - k as f64;
+    let mix = |c: usize| (STOPS[k][c] + (STOPS[k + 1][c] - STOPS[k][c]) * f).round() as u8;
+    [mix(0), mix(1), mix(2)]
+}
+
+fn hex([r, g, b]: [u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+/// Short form of a number for axis labels, to at most 4 digits after the point.
+pub fn label(v: f64) -> String {
+    let rounded = (v * 1e4).round() / 1e4;
+    format!("{}", if rounded == 0.0 { 0.0 } else { rounded })
+}
+
+fn svg_start(width: f64, height: f64) -> String {
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}" font-family="sans-serif">"#,
+        w = width,
+        h = height
+    );
+    let _ = writeln!(svg, r#"<rect width="100%" height="100%" fill="white"/>"#);
+    svg
+}
+
+// Plot frame with the range of each axis at its ends
+fn axes(svg: &mut String, (x_lo, x_hi): (f64, f64), (y_lo, y_hi): (f64, f64)) {
+    let (right, bottom) = (WIDTH - MARGIN, HEIGHT - MARGIN);
+    let _ = writeln!(
+        svg,
+        r##"<rect x="{m}" y="{m}" width="{w}" height="{h}" fill="none" stroke="#444"/>"##,
+        m = MARGIN,
+        w = right - MARGIN,
+        h = bottom - MARGIN
+    );
+    let _ = writeln!(svg, r#"<text x="{}" y="{}" font-size="12">{}</text>"#, MARGIN, bottom + 16.0, label(x_lo));
+    let _ = writeln!(
+        svg,
+        r#"<text x="{}" y="{}" font-size="12" text-anchor="end">{}</text>"#,
+        right,
+        bottom + 16.0,
+        label(x_hi)
+    );
+    let _ = writeln!(svg, r#"<text x="{}" y="{}" font-size="12" text-anchor="end">{}</text>"#, MARGIN - 4.0, bottom, label(y_lo));
+    let _ = writeln!(
+        svg,
+        r#"<text x="{}" y="{}" font-size="12" text-anchor="end">{}</text>"#,
+        MARGIN - 4.0,
+        MARGIN + 12.0,
+        label(y_hi)
+    );
+}
+
+// Color bar from lo to hi along the right edge
+fn legend(svg: &mut String, lo: f64, hi: f64) {
+    let (x, height) = (WIDTH - MARGIN + 12.0, HEIGHT - 2.0 * MARGIN);
+    let _ = writeln!(svg, r#"<g shape-rendering="crispEdges">"#);
+    for k in 0..32 {
+        let y = MARGIN + height * (31 - k) as f64 / 32.0;
+        let _ = writeln!(
+            svg,
+            r#"<rect x="{}" y="{:.2}" width="10" height="{:.2}" fill="{}"/>"#,
+            x,
+            y,
+            height / 32.0 + 0.05,
+            hex(color(k as f64 / 31.0))
+        );
+    }
+    svg.push_str("</g>\n");
+    let _ = writeln!(svg, r#"<text x="{}" y="{}" font-size="10">{}</text>"#, x, MARGIN - 4.0, label(hi));
+    let _ = writeln!(svg, r#"<text x="{}" y="{}" font-size="10">{}</text>"#, x, HEIGHT - MARGIN + 12.0, label(lo));
+}
+
+// RGBA image as an uncompressed PNG (zlib stored blocks)
+fn png(width: u32, height: u32, rgba: &[u8]) -> Vec<u8> {
+    let mut raw = Vec::with_capacity(rgba.len() + height as usize);
+    for row in rgba.chunks(width as usize * 4) {
+        raw.push(0); // no filter
+        raw.extend_from_slice(row);
+    }
+    let mut zlib = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = raw.chunks(65535).collect();
+    for (k, block) in blocks.iter().enumerate() {
+        zlib.push(u8::from(k + 1 == blocks.len()));
+        let len = block.len() as u16;
+        zlib.extend(len.to_le_bytes());
+        zlib.extend((!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend(adler32(&raw).to_be_bytes());
+
+    let mut header = Vec::new();
+    header.extend(width.to_be_bytes());
+    header.extend(height.to_be_bytes());
+    header.extend([8, 6, 0, 0, 0]); // 8-bit RGBA, no interlace
+
+    let mut out = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+    for (kind, data) in [(b"IHDR", &header[..]), (b"IDAT", &zlib[..]), (b"IEND", &[][..])] {
+        out.extend((data.len() as u32).to_be_bytes());
+        let start = out.len();
+        out.extend_from_slice(kind);
+        out.extend_from_slice(data);
+        let crc = crc32(&out[start..]);
+        out.extend(crc.to_be_bytes());
+    }
+    out
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in bytes {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
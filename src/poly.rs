@@ -0,0 +1,232 @@
+// Polynomials as coefficient lists, highest degree first: [1, -3, 2] is x^2 - 3x + 2
+
+/// Drops leading zero coefficients (the zero polynomial becomes empty).
+pub fn trim(p: &[f64]) -> Vec<f64> {
+    let first = p.iter().position(|&c| c != 0.0).unwrap_or(p.len());
+    p[first..].to_vec()
+}
+
+/// Value at `x` by Horner's rule.
+pub fn eval(p: &[f64], x: f64) -> f64 {
+    p.iter().fold(0.0, |acc, &c| acc * x + c)
+}
+
+pub fn add(p: &[f64], q: &[f64]) -> Vec<f64> {
+    let n = p.len().max(q.len());
+    let coeff = |r: &[f64], i: usize| if i + r.len() >= n { r[i + r.len() - n] } else { 0.0 };
+    trim(&(0..n).map(|i| coeff(p, i) + coeff(q, i)).collect::<Vec<_>>())
+}
+
+pub fn sub(p: &[f64], q: &[f64]) -> Vec<f64> {
+    add(p, &q.iter().map(|c| -c).collect::<Vec<_>>())
+}
+
+pub fn mul(p: &[f64], q: &[f64]) -> Vec<f64> {
+    if p.is_empty() || q.is_empty() {
+        return Vec::new();
+    }
+    let mut out = vec![0.0; p.len() + q.len() - 1];
+    for (i, a) in p.iter().enumerate() {
+        for (j, b) in q.iter().enumerate() {
+            out[i + j] += a * b;
+        }
+    }
+    trim(&out)
+}
+
+pub fn derivative(p: &[f64]) -> Vec<f64> {
+    let p = trim(p);
+    let degree = p.len().saturating_sub(1);
+    trim(&p[..degree].iter().enumerate().map(|(i, c)| c * (degree - i) as f64).collect::<Vec<_>>())
+}
+
+/// Distinct real roots in increasing order. Roots lie between consecutive
+/// critical points (the roots of the derivative), so each interval is either
+/// bisected on a sign change or, for a root of even multiplicity, the critical
+/// point itself is the root.
+pub fn real_roots(p: &[f64]) -> Vec<f64> {
+    let p = trim(p);
+    if p.len() < 2 {
+        return Vec::new();
+    }
+    match *p.as_slice() {
+        [a, b] => return vec![-b / a],
+        [a, b, c] => return quadratic_roots(a, b, c),
+        [a, b, c, d] => return cubic_roots(a, b, c, d),
+        _ => {}
+    }
+    // Cauchy bound: every root has |x| < 1 + max |a_i / a_0|
+    let bound = 1.0 + p[1..].iter().map(|c| (c / p[0]).abs()).fold(0.0, f64::max);
+    let mut points = vec![-bound];
+    points.extend(real_roots(&derivative(&p)).into_iter().filter(|c| c.abs() < bound));
+    points.push(bound);
+
+    let mut roots: Vec<f64> = Vec::new();
+    let mut push = |r: f64| {
+        if roots.last().is_none_or(|&last| (r - last).abs() > 1e-12 * r.abs().max(1.0)) {
+            roots.push(r);
+        }
+    };
+    for pair in points.windows(2) {
+        let (lo, hi) = (pair[0], pair[1]);
+        let (f_lo, f_hi) = (eval(&p, lo), eval(&p, hi));
+        if is_negligible(&p, lo, f_lo) {
+            push(lo);
+        }
+        if (f_lo < 0.0) != (f_hi < 0.0) && !is_negligible(&p, lo, f_lo) && !is_negligible(&p, hi, f_hi) {
+            push(bisect(&p, lo, hi, f_lo));
+        }
+    }
+    roots
+}
+
+/// Distinct real roots of `ax^2 + bx + c` in increasing order. A discriminant
+/// within rounding of zero counts as a double root; `a = 0` solves the linear case.
+pub fn quadratic_roots(a: f64, b: f64, c: f64) -> Vec<f64> {
+    if a == 0.0 {
+        return if b == 0.0 { Vec::new() } else { vec![-c / b + 0.0] };
+    }
+    let disc = b * b - 4.0 * a * c;
+    if disc.abs() <= 4.0 * f64::EPSILON * (b * b + (4.0 * a * c).abs()) {
+        // Adding 0 turns -0 into 0
+        return vec![-b / (2.0 * a) + 0.0];
+    }
+    if disc < 0.0 {
+        return Vec::new();
+    }
+    // Avoid cancellation: take the larger root from q, the other from c / q
+    let q = -0.5 * (b + b.signum() * disc.sqrt());
+    if q == 0.0 {
+        return vec![0.0];
+    }
+    let (x1, x2) = (q / a, c / q);
+    if x1 < x2 { vec![x1, x2] } else { vec![x2, x1] }
+}
+
+/// Distinct real roots of `ax^3 + bx^2 + cx + d` in increasing order, by
+/// Cardano's formula for one real root and the trigonometric form for three,
+/// each refined with Newton steps. `a = 0` falls back to `quadratic_roots`.
+pub fn cubic_roots(a: f64, b: f64, c: f64, d: f64) -> Vec<f64> {
+    if a == 0.0 {
+        return quadratic_roots(b, c, d);
+    }
+    if d == 0.0 {
+        let mut roots = quadratic_roots(a, b, c);
+        roots.push(0.0);
+        return sorted_distinct(roots);
+    }
+    // Depressed cubic t^3 + pt + q with x = t - b / 3a
+    let (b, c, d) = (b / a, c / a, d / a);
+    let shift = b / 3.0;
+    let p = c - b * b / 3.0;
+    let q = 2.0 * b * b * b / 27.0 - b * c / 3.0 + d;
+    let (half_q, third_p) = (q / 2.0, p / 3.0);
+    let disc = half_q * half_q + third_p * third_p * third_p;
+    // Rounding error of disc, from the cancellation in forming p and q
+    let p_error = c.abs() + b * b / 3.0;
+    let q_error = (2.0 * b * b * b / 27.0).abs() + (b * c / 3.0).abs() + d.abs();
+    let tolerance = 8.0 * f64::EPSILON * (q.abs() * q_error + p * p * p_error);
+    let ts = if disc.abs() <= tolerance {
+        if p == 0.0 { vec![0.0] } else { vec![3.0 * q / p, -1.5 * q / p] }
+    } else if disc > 0.0 {
+        let u = (-half_q - half_q.signum() * disc.sqrt()).cbrt();
+        vec![if u == 0.0 { 0.0 } else { u - third_p / u }]
+    } else {
+        let r = 2.0 * (-third_p).sqrt();
+        let phi = (3.0 * q / (p * r)).clamp(-1.0, 1.0).acos() / 3.0;
+        (0..3).map(|k| r * (phi - 2.0 * std::f64::consts::PI * k as f64 / 3.0).cos()).collect()
+    };
+    let cubic = [1.0, b, c, d];
+    sorted_distinct(ts.into_iter().map(|t| newton(&cubic, t - shift)).collect())
+}
+
+// A few Newton steps, kept only while they reduce |p(x)|
+fn newton(p: &[f64], mut x: f64) -> f64 {
+    let dp = derivative(p);
+    for _ in 0..4 {
+        let (fx, dfx) = (eval(p, x), eval(&dp, x));
+        if fx == 0.0 || dfx == 0.0 {
+            break;
+        }
+        let next = x - fx / dfx;
+        if eval(p, next).abs() >= fx.abs() {
+            break;
+        }
+        x = next;
+    }
+    x
+}
+
+fn sorted_distinct(mut roots: Vec<f64>) -> Vec<f64> {
+    roots.sort_by(f64::total_cmp);
+    roots.dedup_by(|a, b| (*a - *b).abs() <= 1e-12 * a.abs().max(1.0));
+    roots
+}
+
+// Whether p(x) is zero up to rounding in evaluating it
+fn is_negligible(p: &[f64], x: f64, value: f64) -> bool {
+    let scale = p.iter().fold(0.0, |acc, &c| acc * x.abs() + c.abs());
+    value.abs() <= 64.0 * f64::EPSILON * scale
+}
+
+fn bisect(p: &[f64], mut lo: f64, mut hi: f64, f_lo: f64) -> f64 {
+    let lo_negative = f_lo < 0.0;
+    for _ in 0..200 {
+        let mid = 0.5 * (lo + hi);
+        if mid <= lo || mid >= hi {
+            break;
+        }
+        if (eval(p, mid) < 0.0) == lo_negative {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    0.5 * (lo + hi)
+}
+
+/// Least-squares polynomial of the given degree through the points, by
+/// Householder QR of the Vandermonde matrix (better conditioned than the
+/// normal equations). `None` if the points cannot determine every coefficient.
+pub fn fit(xs: &[f64], ys: &[f64], degree: usize) -> Option<Vec<f64>> {
+    let n = degree.checked_add(1)?;
+    if xs.len().min(ys.len()) < n {
+        return None;
+    }
+    // Row i is [x_i^degree, ..., x_i, 1] followed by y_i
+    let mut rows: Vec<Vec<f64>> = xs
+        .iter()
+        .zip(ys)
+        .map(|(&x, &y)| (0..n).map(|j| x.powi((degree - j) as i32)).chain([y]).collect())
+        .collect();
+    for k in 0..n {
+        let norm = rows[k..].iter().map(|r| r[k] * r[k]).sum::<f64>().sqrt();
+        let alpha = if rows[k][k] > 0.0 { -norm } else { norm };
+        let mut v: Vec<f64> = rows[k..].iter().map(|r| r[k]).collect();
+        v[0] -= alpha;
+        let v_norm2: f64 = v.iter().map(|c| c * c).sum();
+        if v_norm2 == 0.0 {
+            continue;
+        }
+        // Reflect the remaining columns, including y
+        for j in k..=n {
+            let dot: f64 = rows[k..].iter().zip(&v).map(|(r, vi)| r[j] * vi).sum();
+            let factor = 2.0 * dot / v_norm2;
+            for (r, vi) in rows[k..].iter_mut().zip(&v) {
+                r[j] -= factor * vi;
+            }
+        }
+    }
+    // Back substitution on the triangular factor
+    let largest = (0..n).map(|k| rows[k][k].abs()).fold(0.0, f64::max);
+    let mut coeffs = vec![0.0; n];
+    for k in (0..n).rev() {
+        let pivot = rows[k][k];
+        if pivot.abs() <= largest * rows.len() as f64 * f64::EPSILON {
+            return None;
+        }
+        let known: f64 = (k + 1..n).map(|j| rows[k][j] * coeffs[j]).sum();
+        coeffs[k] = (rows[k][n] - known) / pivot;
+    }
+    Some(coeffs)
+}
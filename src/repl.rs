@@ -0,0 +1,174 @@
+// Interactive sessions: `fmath repl` reads statements with line editing, history and tab completion
+use std::path::PathBuf;
+
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+
+use crate::session::Session;
+
+/// File in the home directory keeping the lines of earlier sessions.
+pub const HISTORY_FILE: &str = ".fmath_history";
+
+/// Completes the word before the cursor with the builtins, keywords, and the
+/// names the session has defined so far.
+#[derive(Debug, Default)]
+struct NameCompleter {
+    /// Names of the session, refreshed after each line
+    defined: Vec<String>,
+}
+
+const KEYWORDS: &[&str] = &[
+    "var", "const", "output", "def", "end", "return", "if", "let", "in", "where", "sum", "product", "solve", "assert", "assert_eq", "inf",
+];
+
+impl Completer for NameCompleter {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos].rfind(|c: char| !(c.is_alphanumeric() || c == '_')).map_or(0, |i| i + 1);
+        let prefix = &line[start..pos];
+        if prefix.is_empty() {
+            return Ok((pos, Vec::new()));
+        }
+        let builtins = crate::builtins::BUILTINS.iter().map(|b| b.name);
+        let mut candidates: Vec<String> = builtins
+            .chain(KEYWORDS.iter().copied())
+            .chain(self.defined.iter().map(String::as_str))
+            .filter(|name| name.starts_with(prefix))
+            .map(str::to_string)
+            .collect();
+        candidates.sort();
+        candidates.dedup();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for NameCompleter {
+    type Hint = String;
+}
+
+impl Highlighter for NameCompleter {}
+
+impl Validator for NameCompleter {}
+
+impl Helper for NameCompleter {}
+
+/// Reads statements at a `> ` prompt until end of input (Ctrl-D) and
+/// evaluates each in `session` as `fmath --lines` does, printing its value
+/// written by `format` or its error. A statement left open (see
+/// `is_incomplete`) continues on the lines read at a `. ` prompt. Ctrl-C
+/// discards the statement being typed. The statements are kept in
+/// `HISTORY_FILE` in the home directory, if there is one.
+pub fn run(session: &mut Session, format: impl Fn(f64) -> String) -> rustyline::Result<()> {
+    let mut editor: Editor<NameCompleter, DefaultHistory> = Editor::new()?;
+    editor.set_helper(Some(NameCompleter::default()));
+    let history = std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+    if let Some(path) = &history {
+        // The first session has no history yet
+        let _ = editor.load_history(path);
+    }
+    let mut source = String::new();
+    loop {
+        let line = match editor.readline(if source.is_empty() { "> " } else { ". " }) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                source.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e),
+        };
+        if source.is_empty() && line.trim().is_empty() {
+            continue;
+        }
+        if !source.is_empty() {
+            // An open expression goes on as one line; a definition keeps its lines
+            source.push(if open_expression(&source) { ' ' } else { '\n' });
+        }
+        source.push_str(&line);
+        if is_incomplete(&source) {
+            continue;
+        }
+        editor.add_history_entry(source.as_str())?;
+        match session.eval(&source, &format) {
+            Ok(text) if text.is_empty() => {}
+            Ok(text) => println!("{}", text),
+            Err(e) => println!("Error: {}", e),
+        }
+        source.clear();
+        if let Some(helper) = editor.helper_mut() {
+            helper.defined = session.names();
+        }
+    }
+    match &history {
+        Some(path) => editor.save_history(path),
+        None => Ok(()),
+    }
+}
+
+/// Whether `source` stops partway through a statement: inside parentheses,
+/// brackets or `|...|`, after an operator that needs a right operand, or in
+/// the body of a multi-line `def` before its `end`.
+pub fn is_incomplete(source: &str) -> bool {
+    open_expression(source) || open_definitions(source) > 0
+}
+
+// Inside brackets or an absolute value, or after a binary operator, `=` or `,`
+fn open_expression(source: &str) -> bool {
+    let code: Vec<&str> = source.lines().filter(|line| !line.trim_start().starts_with('#')).collect();
+    let (mut depth, mut pipes) = (0, 0);
+    for c in code.iter().flat_map(|line| line.chars()) {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            '|' => pipes += 1,
+            _ => {}
+        }
+    }
+    let last = code.iter().rev().find_map(|line| line.trim_end().chars().last());
+    depth > 0 || pipes % 2 == 1 || last.is_some_and(|c| "+-*/^=<>,?:×·÷−≤≥≠".contains(c))
+}
+
+// `def name(params)` lines without `= body` not yet closed by an `end` line
+fn open_definitions(source: &str) -> i32 {
+    source
+        .lines()
+        .map(|line| {
+            let line = line.trim();
+            let first = line.split_whitespace().next().unwrap_or("");
+            if line.eq_ignore_ascii_case("end") {
+                -1
+            } else if first.eq_ignore_ascii_case("def") && !has_body(line) {
+                1
+            } else {
+                0
+            }
+        })
+        .sum()
+}
+
+// Whether a `def` line goes on with `= body` after its parameter list
+fn has_body(def: &str) -> bool {
+    let Some(open) = def.find('(') else {
+        // Malformed; the parser reports it
+        return true;
+    };
+    let mut depth = 0;
+    for (i, c) in def[open..].char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => {}
+        }
+        if depth == 0 {
+            return def[open + i + 1..].trim_start().starts_with('=');
+        }
+    }
+    // The parameter list itself is still open
+    false
+}
@@ -0,0 +1,96 @@
+// Markdown reports: each statement of a script as LaTeX math with its value
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use crate::analysis::Diagnostic;
+use crate::ast::{Expr, UserFunctions};
+use crate::compiler::{compile, CompiledProgram};
+use crate::error::RuntimeError;
+use crate::interpreter::VmState;
+use crate::latex;
+use crate::lexer::Token;
+
+/// Renders `source` as Markdown under the heading `title`. Comment lines become
+/// paragraphs (except `# expect:` lines), function definitions and statements
+/// become display math, and each statement shows the value it computes, all
+/// statements sharing one set of variables. Fails if the script has errors.
+pub fn markdown(source: &str, title: &str) -> Result<String, Vec<Diagnostic>> {
+    let (compiled, _) = CompiledProgram::try_from_source(source)?;
+    let mut lines = crate::lexer::tokenize_numbered(source).into_iter().peekable();
+    let mut state = VmState::new();
+    // Each block is parsed alone, so function values and C(n, k) are resolved against the whole script
+    let names: HashSet<String> = compiled.functions.keys().cloned().collect();
+    let mut holders = HashSet::new();
+    let mut out = format!("# {}\n", title);
+    for (index, text) in source.lines().enumerate() {
+        if let Some(comment) = text.trim().strip_prefix('#') {
+            let comment = comment.trim();
+            if !comment.is_empty() && !comment.starts_with("expect:") {
+                let _ = write!(out, "\n{}\n", comment);
+            }
+            continue;
+        }
+        let Some(line) = lines.next_if(|(line, _)| *line == index + 1) else {
+            continue;
+        };
+        let mut block = vec![line];
+        // A multi-line function body runs up to its `end` line
+        if crate::parser::opens_function_body(&block[0].1) {
+            for body_line in lines.by_ref() {
+                let end = body_line.1 == [Token::EndDef];
+                block.push(body_line);
+                if end {
+                    break;
+                }
+            }
+        }
+        let (mut statements, functions) = crate::parser::parse_statements_in(block, &compiled.functions)?;
+        for (_, expr) in &mut statements {
+            crate::parser::resolve_function_values(expr, &names, &mut holders);
+        }
+        for (name, function) in functions.iter().flat_map(|(name, overloads)| overloads.iter().map(move |f| (name, f))) {
+            let _ = write!(out, "\n$$ {} $$\n", latex::definition_to_latex(name, &function.params, &function.body));
+        }
+        for (_, expr) in &statements {
+            let math = latex::to_latex(expr);
+            match evaluate(&mut state, expr, &compiled.functions) {
+                // `a = 2` already shows its value
+                Ok(Some(value)) if math == value || math.ends_with(&format!("= {}", value)) => {
+                    let _ = write!(out, "\n$$ {} $$\n", math);
+                }
+                Ok(Some(value)) => {
+                    let _ = write!(out, "\n$$ {} = {} $$\n", math, value);
+                }
+                Ok(None) => {
+                    let _ = write!(out, "\n$$ {} $$\n", math);
+                }
+                Err(e) => {
+                    let _ = write!(out, "\n$$ {} $$\n\n> Error: {}\n", math, e);
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+// Runs one statement and returns its value as LaTeX: the result of an
+// expression, or the new value of an assigned variable
+fn evaluate(state: &mut VmState, expr: &Expr, functions: &UserFunctions) -> Result<Option<String>, RuntimeError> {
+    let mut program = Vec::new();
+    compile(expr, &mut program);
+    match expr {
+        Expr::Assign { name, .. } | Expr::Const { name, .. } | Expr::Output { name, .. } => {
+            state.exec(&program, functions)?;
+            Ok(match state.list(name) {
+                Some(list) => {
+                    let items: Vec<String> = list.iter().map(|&v| latex::number(v)).collect();
+                    Some(format!("\\left[{}\\right]", items.join(", ")))
+                }
+                None => state.var(name).map(latex::number),
+            })
+        }
+        Expr::Assert { .. } | Expr::AssertEq { .. } => state.exec(&program, functions).map(|_| None),
+        _ if expr.is_list() => state.exec(&program, functions).map(|_| None),
+        _ => state.run(&program, functions).map(|value| Some(latex::number(value))),
+    }
+}
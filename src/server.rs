@@ -0,0 +1,144 @@
+// HTTP evaluation server: a small JSON API so web calculators can use the engine directly
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use crate::engine::Engine;
+use crate::json::Json;
+use crate::service::{self, Failure};
+
+/// Largest request body accepted, in bytes.
+pub const MAX_BODY: usize = 1 << 20;
+
+/// An HTTP response before it is written out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    fn json(status: u16, body: Json) -> Self {
+        Response { status, content_type: "application/json", body: body.to_string() }
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> Self {
+        Response::json(status, Json::object([("error", Json::String(message.into()))]))
+    }
+}
+
+/// Answers connections on `listener`, each on its own thread, until
+/// accepting fails. Every evaluation runs on (a copy of) `engine`, so its
+/// fuel limit bounds how long one request can compute.
+pub fn serve(listener: TcpListener, engine: Engine) -> io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept()?;
+        let engine = engine.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, &engine) {
+                log::debug!(target: "server", "connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+// Reads one request from `stream` and writes its response; the connection is then closed
+fn handle_connection(stream: TcpStream, engine: &Engine) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+    let mut reader = BufReader::new(stream.try_clone()?.take(MAX_BODY as u64 + 64 * 1024));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':')
+            && name.trim().eq_ignore_ascii_case("content-length")
+        {
+            length = value.trim().parse().unwrap_or(usize::MAX);
+        }
+    }
+    let response = if length > MAX_BODY {
+        Response::error(413, format!("request body over {} bytes", MAX_BODY))
+    } else {
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+        match String::from_utf8(body) {
+            Ok(body) => handle(method, target, &body, engine),
+            Err(_) => Response::error(400, "request body is not UTF-8"),
+        }
+    };
+    log::info!(target: "server", "{} {} {}", method, target, response.status);
+    write_response(stream, &response)
+}
+
+fn write_response(mut stream: TcpStream, response: &Response) -> io::Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Content Too Large",
+        422 => "Unprocessable Content",
+        _ => "Error",
+    };
+    // Browsers may call from any page; the API keeps no state to protect
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nAccess-Control-Allow-Origin: *\r\n\
+         Access-Control-Allow-Methods: POST, OPTIONS\r\nAccess-Control-Allow-Headers: Content-Type\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        reason,
+        response.content_type,
+        response.body.len(),
+        response.body
+    )?;
+    stream.flush()
+}
+
+/// Answers one request given its method, target and body: `POST /eval`,
+/// `/compile` and `/diagnose` with the JSON of the `service` function of the
+/// same name, and `POST /plot` with its SVG image. A request may lower the
+/// engine's step limit with `"fuel"`. Errors are answered as
+/// `{"error": "..."}`, with the diagnostics when the source does not compile.
+pub fn handle(method: &str, target: &str, body: &str, engine: &Engine) -> Response {
+    let endpoint: fn(&Json, &Engine) -> Result<Response, Failure> = match target.split('?').next().unwrap_or("") {
+        "/eval" => |request, engine| service::eval(request, engine).map(|json| Response::json(200, json)),
+        "/compile" => |request, engine| service::compile(request, engine).map(|json| Response::json(200, json)),
+        "/diagnose" => |request, engine| service::diagnose(request, engine).map(|json| Response::json(200, json)),
+        "/plot" => |request, engine| {
+            service::plot(request, engine).map(|svg| Response { status: 200, content_type: "image/svg+xml", body: svg })
+        },
+        path => return Response::error(404, format!("no endpoint {}", path)),
+    };
+    match method {
+        "POST" => {}
+        // A browser's preflight check before a cross-origin POST
+        "OPTIONS" => return Response { status: 204, content_type: "text/plain", body: String::new() },
+        _ => return Response::error(405, format!("{} is not supported, use POST", method)),
+    }
+    let request = match Json::parse(body) {
+        Ok(request @ Json::Object(_)) => request,
+        Ok(_) => return Response::error(400, "request body must be a JSON object"),
+        Err(e) => return Response::error(400, format!("invalid JSON: {}", e)),
+    };
+    match service::request_engine(&request, engine).and_then(|engine| endpoint(&request, &engine)) {
+        Ok(response) => response,
+        Err(failure @ Failure::Invalid(_)) => Response::error(400, failure.message()),
+        Err(Failure::Compile(diagnostics)) => {
+            let body = Json::object([
+                ("error", Json::String(Failure::Compile(diagnostics.clone()).message())),
+                ("diagnostics", service::diagnostics_json(&diagnostics)),
+            ]);
+            Response::json(400, body)
+        }
+        Err(failure @ Failure::Runtime(_)) => Response::error(422, failure.message()),
+    }
+}
@@ -0,0 +1,262 @@
+// Requests of the service modes, JSON in and out, shared by `fmath serve` (HTTP) and `fmath --service` (stdio)
+use std::io::{self, BufRead, Write};
+
+use crate::analysis::{Diagnostic, Severity};
+use crate::compiler::CompiledProgram;
+use crate::engine::Engine;
+use crate::interpreter::Value;
+use crate::error::{CompileError, RuntimeError};
+use crate::json::Json;
+
+/// Most samples per axis a plot request may ask for.
+pub const MAX_PLOT_STEPS: usize = 500;
+
+/// Why a request failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Failure {
+    /// The request itself is malformed, as a missing `source`.
+    Invalid(String),
+    /// The source does not compile; every diagnostic, warnings included.
+    Compile(Vec<Diagnostic>),
+    /// The program failed while running.
+    Runtime(RuntimeError),
+}
+
+impl Failure {
+    pub fn message(&self) -> String {
+        match self {
+            Failure::Invalid(message) => message.clone(),
+            Failure::Compile(diagnostics) => CompileError::Analysis(diagnostics.clone()).to_string(),
+            Failure::Runtime(e) => e.to_string(),
+        }
+    }
+}
+
+/// `{"source": "...", "vars": {"x": 2}, "seed": 1}`: runs the program with
+/// `vars` bound (and `rand` seeded) and answers `{"value": ...}`, a number or
+/// for a list result an array, with `"printed": [...]` too if it printed
+/// lines, and `"outputs": {"name": ...}` if it has `output` statements, with
+/// `"units": {"name": "m/s"}` for those labelled with a unit.
+pub fn eval(request: &Json, engine: &Engine) -> Result<Json, Failure> {
+    let vars = match request.get("vars") {
+        None => Vec::new(),
+        Some(vars) => vars
+            .as_object()
+            .and_then(|members| members.iter().map(|(name, value)| Some((name.as_str(), value.as_f64()?))).collect())
+            .ok_or_else(|| Failure::Invalid("`vars` must map names to numbers".to_string()))?,
+    };
+    let names: Vec<&str> = vars.iter().map(|&(name, _)| name).collect();
+    let (compiled, _) = compile_source(request, &names)?;
+    let mut state = engine.new_state();
+    for &(name, value) in &vars {
+        state.set_var(name, value);
+    }
+    if let Some(seed) = request.get("seed") {
+        let seed = seed.as_f64().ok_or_else(|| Failure::Invalid("`seed` must be a number".to_string()))?;
+        state.seed(seed as u64);
+    }
+    state.set_print_capture(true);
+    let value = match compiled.eval_value(&mut state).map_err(Failure::Runtime)? {
+        Value::Number(x) => Json::Number(x),
+        Value::List(items) => Json::Array(items.into_iter().map(Json::Number).collect()),
+    };
+    let mut response = vec![("value", value)];
+    let printed = state.take_printed();
+    if !printed.is_empty() {
+        response.push(("printed", Json::Array(printed.into_iter().map(Json::String).collect())));
+    }
+    let outputs = state.outputs();
+    if !outputs.is_empty() {
+        let units: Vec<(&str, Json)> =
+            outputs.iter().filter_map(|&(name, _)| Some((name, Json::String(state.unit(name)?.to_string())))).collect();
+        response.push(("outputs", Json::object(outputs.into_iter().map(|(name, value)| (name, Json::Number(value))))));
+        if !units.is_empty() {
+            response.push(("units", Json::object(units)));
+        }
+    }
+    Ok(Json::object(response))
+}
+
+/// `{"source": "..."}`: answers the warnings and the size of the program, as
+/// `{"diagnostics": [...], "instructions": n}`.
+pub fn compile(request: &Json, _: &Engine) -> Result<Json, Failure> {
+    let (compiled, warnings) = compile_source(request, &[])?;
+    Ok(Json::object([
+        ("diagnostics", diagnostics_json(&warnings)),
+        ("instructions", Json::Number(compiled.program.len() as f64)),
+    ]))
+}
+
+/// `{"source": "...", "inputs": ["x"]}`: answers every diagnostic, errors
+/// included, as `{"diagnostics": [...]}`, for an editor to underline;
+/// `inputs` are variables the host binds.
+pub fn diagnose(request: &Json, _: &Engine) -> Result<Json, Failure> {
+    let inputs: Vec<&str> = match request.get("inputs") {
+        None => Vec::new(),
+        Some(inputs) => inputs
+            .as_array()
+            .and_then(|inputs| inputs.iter().map(Json::as_str).collect())
+            .ok_or_else(|| Failure::Invalid("`inputs` must be a list of names".to_string()))?,
+    };
+    let diagnostics = match compile_source(request, &inputs) {
+        Ok((_, warnings)) => warnings,
+        Err(Failure::Compile(diagnostics)) => diagnostics,
+        Err(failure) => return Err(failure),
+    };
+    Ok(Json::object([("diagnostics", diagnostics_json(&diagnostics))]))
+}
+
+/// `{"source": "...", "vars": ["x", "y"], "range": [[-3, 3], [-3, 3]],
+/// "steps": 50, "style": "heatmap"}`: answers an SVG image, a line chart for
+/// one variable and a heatmap (or `"surface"`) for two.
+pub fn plot(request: &Json, engine: &Engine) -> Result<String, Failure> {
+    let invalid = |message: &str| Failure::Invalid(message.to_string());
+    let vars: Vec<&str> = request
+        .get("vars")
+        .and_then(Json::as_array)
+        .and_then(|vars| vars.iter().map(Json::as_str).collect::<Option<_>>())
+        .filter(|vars: &Vec<&str>| (1..=2).contains(&vars.len()))
+        .ok_or_else(|| invalid("`vars` must be a list of one or two names"))?;
+    let ranges: Vec<(f64, f64)> = request
+        .get("range")
+        .and_then(Json::as_array)
+        .and_then(|ranges| {
+            ranges
+                .iter()
+                .map(|range| match range.as_array()? {
+                    [lo, hi] => Some((lo.as_f64()?, hi.as_f64()?)),
+                    _ => None,
+                })
+                .collect::<Option<_>>()
+        })
+        .filter(|ranges: &Vec<_>| ranges.len() == vars.len())
+        .ok_or_else(|| invalid("`range` must hold a [from, to] pair for each variable"))?;
+    let steps = match request.get("steps") {
+        None => if vars.len() == 1 { 200 } else { 50 },
+        Some(steps) => steps
+            .as_f64()
+            .filter(|&n| n >= 1.0 && n <= MAX_PLOT_STEPS as f64)
+            .ok_or_else(|| Failure::Invalid(format!("`steps` must be from 1 to {}", MAX_PLOT_STEPS)))?
+            as usize,
+    };
+    let surface = match request.get("style").map(|style| style.as_str()) {
+        None | Some(Some("line" | "heatmap")) => false,
+        Some(Some("surface")) => true,
+        Some(_) => return Err(invalid("`style` must be \"heatmap\" or \"surface\"")),
+    };
+    let (compiled, _) = compile_source(request, &vars)?;
+
+    let axis = |(lo, hi): (f64, f64)| -> Vec<f64> { (0..=steps).map(|i| lo + (hi - lo) * i as f64 / steps as f64).collect() };
+    let xs = axis(ranges[0]);
+    let ys = ranges.get(1).map(|&range| axis(range)).unwrap_or_default();
+    let points: Vec<f64> = if ys.is_empty() {
+        xs.clone()
+    } else {
+        ys.iter().flat_map(|&y| xs.iter().flat_map(move |&x| [x, y])).collect()
+    };
+    let results = engine.eval_batch(&compiled, &vars, &points);
+    // As `fmath plot` does: failed points are left out, unless all failed
+    if let Some(Err(e)) = results.iter().find(|r| r.is_err()).filter(|_| results.iter().all(Result::is_err)) {
+        return Err(Failure::Runtime(e.clone()));
+    }
+    let values: Vec<f64> = results.into_iter().map(|r| r.unwrap_or(f64::NAN)).collect();
+    Ok(if ys.is_empty() {
+        crate::plot::line_svg(&xs, &values)
+    } else if surface {
+        crate::plot::surface_svg(&crate::plot::Grid { xs, ys, values })
+    } else {
+        crate::plot::heatmap_svg(&crate::plot::Grid { xs, ys, values })
+    })
+}
+
+/// `engine` with the step limit a request's optional `fuel` asks for, which
+/// can only lower the engine's own.
+pub fn request_engine(request: &Json, engine: &Engine) -> Result<Engine, Failure> {
+    match request.get("fuel").map(|fuel| fuel.as_f64().filter(|&f| f >= 0.0)) {
+        None => Ok(engine.clone()),
+        Some(Some(fuel)) => Ok(engine.clone().with_fuel(engine.fuel().map_or(fuel as u64, |max| max.min(fuel as u64)))),
+        Some(None) => Err(Failure::Invalid("`fuel` must be a non-negative number".to_string())),
+    }
+}
+
+/// Answers newline-delimited JSON-RPC 2.0 requests from `input` on `output`,
+/// one line each, until `input` ends. The methods are `eval`, `compile` and
+/// `diagnose`, whose `params` are the request objects of the functions of the
+/// same name. A failure is answered with error code 1 when the source does not
+/// compile (with the diagnostics as `data`) and 2 when it fails to run.
+pub fn run(input: impl BufRead, mut output: impl Write, engine: &Engine) -> io::Result<()> {
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        writeln!(output, "{}", respond(&line, engine))?;
+        output.flush()?;
+    }
+    Ok(())
+}
+
+/// The JSON-RPC response to one request line.
+pub fn respond(line: &str, engine: &Engine) -> Json {
+    let request = match Json::parse(line) {
+        Ok(request) => request,
+        Err(e) => return rpc_error(Json::Null, -32700, format!("invalid JSON: {}", e), None),
+    };
+    let id = request.get("id").cloned().unwrap_or(Json::Null);
+    let Some(method) = request.get("method").and_then(Json::as_str) else {
+        return rpc_error(id, -32600, "request has no `method`".to_string(), None);
+    };
+    let handler: fn(&Json, &Engine) -> Result<Json, Failure> = match method {
+        "eval" => eval,
+        "compile" => compile,
+        "diagnose" => diagnose,
+        _ => return rpc_error(id, -32601, format!("no method `{}`", method), None),
+    };
+    let params = request.get("params").cloned().unwrap_or(Json::Object(Vec::new()));
+    match request_engine(&params, engine).and_then(|engine| handler(&params, &engine)) {
+        Ok(result) => Json::object([("jsonrpc", Json::from("2.0")), ("id", id), ("result", result)]),
+        Err(failure @ Failure::Invalid(_)) => rpc_error(id, -32602, failure.message(), None),
+        Err(Failure::Compile(diagnostics)) => {
+            let message = Failure::Compile(diagnostics.clone()).message();
+            rpc_error(id, 1, message, Some(Json::object([("diagnostics", diagnostics_json(&diagnostics))])))
+        }
+        Err(failure @ Failure::Runtime(_)) => rpc_error(id, 2, failure.message(), None),
+    }
+}
+
+fn rpc_error(id: Json, code: i32, message: String, data: Option<Json>) -> Json {
+    let mut error = vec![("code", Json::Number(code as f64)), ("message", Json::String(message))];
+    error.extend(data.map(|data| ("data", data)));
+    Json::object([("jsonrpc", Json::from("2.0")), ("id", id), ("error", Json::object(error))])
+}
+
+// The request's `source` compiled with its warnings
+fn compile_source(request: &Json, inputs: &[&str]) -> Result<(CompiledProgram, Vec<Diagnostic>), Failure> {
+    let source = request
+        .get("source")
+        .and_then(Json::as_str)
+        .ok_or_else(|| Failure::Invalid("`source` must be a string".to_string()))?;
+    CompiledProgram::try_from_source_with_inputs(source, inputs).map_err(Failure::Compile)
+}
+
+/// Diagnostics as a JSON array of `{"severity", "line", "function", "message", "lint"}`.
+pub fn diagnostics_json(diagnostics: &[Diagnostic]) -> Json {
+    Json::Array(
+        diagnostics
+            .iter()
+            .map(|d| {
+                let severity = match d.severity {
+                    Severity::Error => "error",
+                    Severity::Warning => "warning",
+                };
+                Json::object([
+                    ("severity", Json::from(severity)),
+                    ("line", d.line.map_or(Json::Null, |line| Json::Number(line as f64))),
+                    ("function", d.function.as_deref().map_or(Json::Null, Json::from)),
+                    ("message", Json::from(d.message.as_str())),
+                    ("lint", d.lint.map_or(Json::Null, |lint| Json::from(lint.name()))),
+                ])
+            })
+            .collect(),
+    )
+}
@@ -0,0 +1,94 @@
+// Sessions: snippets of source evaluated one after another against the same variables and functions
+use std::collections::HashSet;
+
+use crate::ast::{Expr, UserFunctions};
+use crate::compiler::compile;
+use crate::interpreter::{Value, VmState};
+
+/// Variables, user functions and function values that outlive one snippet of
+/// source, as the placeholders of a template or the lines of `fmath --lines`
+/// share them. Snippets are not analyzed, since each may read what an
+/// earlier one assigned.
+#[derive(Debug, Default)]
+pub struct Session {
+    state: VmState,
+    functions: UserFunctions,
+    // Variables an earlier snippet assigned a function
+    holders: HashSet<String>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A session whose snippets run on `state`, within its limits and with
+    /// its access to the environment and files, as `Engine::new_state` sets
+    /// them up.
+    pub fn with_state(state: VmState) -> Self {
+        Session { state, ..Self::default() }
+    }
+
+    /// Restarts the random number generator from `seed`.
+    pub fn seed(&mut self, seed: u64) {
+        self.state.seed(seed);
+    }
+
+    /// Makes a pole of `cot`, `sec` or `csc` an error (`VmState::set_strict`).
+    pub fn set_strict(&mut self, strict: bool) {
+        self.state.set_strict(strict);
+    }
+
+    /// Every name defined so far, sorted: number and list variables, function
+    /// variables and user functions, as an interactive session completes them.
+    pub fn names(&self) -> Vec<String> {
+        let variables = self.state.vars().into_keys().chain(self.state.lists().into_keys()).map(str::to_string);
+        let mut names: Vec<String> = variables.chain(self.holders.iter().cloned()).chain(self.functions.keys().cloned()).collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Runs the statements of `source` in order and returns the text of the
+    /// values they leave: each number written by `format`, a list as
+    /// `[a, b, ...]`. An assignment, function definition or assertion only
+    /// updates the session and leaves nothing. Fails on the first statement
+    /// that does not parse or evaluate, or whose value is a function, with the
+    /// error analysis reports for it in a file.
+    pub fn eval(&mut self, source: &str, format: impl Fn(f64) -> String) -> Result<String, String> {
+        let (mut statements, defined) = crate::parser::parse_statements_in(crate::lexer::tokenize_numbered(source), &self.functions)
+            .map_err(|diagnostics| diagnostics.iter().map(|d| d.message.clone()).collect::<Vec<_>>().join("; "))?;
+        for (name, overloads) in defined {
+            self.functions.entry(name).or_default().merge(overloads);
+        }
+        let names: HashSet<String> = self.functions.keys().cloned().collect();
+        for (_, expr) in &mut statements {
+            crate::parser::resolve_function_values(expr, &names, &mut self.holders);
+        }
+        let mut out = String::new();
+        for (_, expr) in &statements {
+            let mut program = Vec::new();
+            compile(expr, &mut program);
+            match expr {
+                Expr::Assign { .. }
+                | Expr::Const { .. }
+                | Expr::Output { .. }
+                | Expr::Assert { .. }
+                | Expr::AssertEq { .. }
+                | Expr::Precision(_) => {
+                    self.state.exec(&program, &self.functions).map_err(|e| e.to_string())?;
+                }
+                Expr::Ident(name) if let Some(list) = self.state.list(name) => {
+                    out.push_str(&Value::List(list.to_vec()).format(&format));
+                }
+                _ if expr.is_list() => out.push_str(&self.state.run_value(&program, &self.functions).map_err(|e| e.to_string())?.format(&format)),
+                Expr::Ident(name) if self.holders.contains(name) => {
+                    return Err(format!("function `{}` used where a number is expected", name));
+                }
+                _ if let Some(message) = crate::analysis::number_expected(expr) => return Err(message),
+                _ => out.push_str(&format(self.state.run(&program, &self.functions).map_err(|e| e.to_string())?)),
+            }
+        }
+        Ok(out)
+    }
+}
@@ -0,0 +1,339 @@
+// Statistics: quantiles of list values, error functions, and probability distributions
+// Coefficients are kept exactly as published, even past f64 precision
+#![allow(clippy::excessive_precision)]
+
+/// Interpolation type used by `quantile(data, p)` when none is given
+/// (type 7, the default of R and NumPy).
+pub const DEFAULT_QUANTILE_TYPE: u8 = 7;
+
+/// Sample quantile of `data` at probability `p` using Hyndman & Fan
+/// definition `kind` (1 to 9):
+///
+/// 1. inverse of the empirical distribution function
+/// 2. like 1, averaging at discontinuities
+/// 3. nearest even order statistic
+/// 4. linear interpolation of the empirical distribution function
+/// 5. piecewise linear, knots at the midpoints of the steps
+/// 6. linear, `p(k) = k / (n + 1)`
+/// 7. linear, `p(k) = (k - 1) / (n - 1)`
+/// 8. median-unbiased
+/// 9. approximately unbiased for normal data
+///
+/// Returns NaN for empty data or `p` outside `[0, 1]`.
+pub fn quantile(data: &[f64], p: f64, kind: u8) -> f64 {
+    if data.is_empty() || !(0.0..=1.0).contains(&p) {
+        return f64::NAN;
+    }
+    let mut sorted = data.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let n = sorted.len() as f64;
+    // 1-based order statistic, clamped to the sample
+    let x = |k: f64| sorted[(k.clamp(1.0, n) as usize) - 1];
+
+    let m = match kind {
+        1 | 2 | 4 => 0.0,
+        3 => -0.5,
+        5 => 0.5,
+        6 => p,
+        7 => 1.0 - p,
+        8 => (p + 1.0) / 3.0,
+        _ => p / 4.0 + 3.0 / 8.0,
+    };
+    let h = n * p + m;
+    let j = h.floor();
+    let g = h - j;
+    let gamma = match kind {
+        1 => if g > 0.0 { 1.0 } else { 0.0 },
+        2 => if g > 0.0 { 1.0 } else { 0.5 },
+        3 => if g > 0.0 || j % 2.0 != 0.0 { 1.0 } else { 0.0 },
+        _ => g,
+    };
+    (1.0 - gamma) * x(j) + gamma * x(j + 1.0)
+}
+
+// erf/erfc: rational approximations from fdlibm (s_erf.c), accurate to about
+// one ulp. Coefficients are grouped by interval of |x|.
+const ERX: f64 = 8.45062911510467529297e-01;
+const EFX: f64 = 1.28379167095512586316e-01;
+// |x| < 0.84375
+const PP: [f64; 5] = [
+    1.28379167095512558561e-01,
+    -3.25042107247001499370e-01,
+    -2.84817495755985104766e-02,
+    -5.77027029648944159157e-03,
+    -2.37630166566501626084e-05,
+];
+const QQ: [f64; 5] = [
+    3.97917223959155352819e-01,
+    6.50222499887672944485e-02,
+    5.08130628187576562776e-03,
+    1.32494738004321644526e-04,
+    -3.96022827877536812320e-06,
+];
+// 0.84375 <= |x| < 1.25
+const PA: [f64; 7] = [
+    -2.36211856075265944077e-03,
+    4.14856118683748331666e-01,
+    -3.72207876035701323847e-01,
+    3.18346619901161753674e-01,
+    -1.10894694282396677476e-01,
+    3.54783043256182359371e-02,
+    -2.16637559486879084300e-03,
+];
+const QA: [f64; 6] = [
+    1.06420880400844228286e-01,
+    5.40397917702171048937e-01,
+    7.18286544141962662868e-02,
+    1.26171219808761642112e-01,
+    1.36370839120290507362e-02,
+    1.19844998467991074170e-02,
+];
+// 1.25 <= |x| < 1/0.35
+const RA: [f64; 8] = [
+    -9.86494403484714822705e-03,
+    -6.93858572707181764372e-01,
+    -1.05586262253232909814e+01,
+    -6.23753324503260060396e+01,
+    -1.62396669462573470355e+02,
+    -1.84605092906711035994e+02,
+    -8.12874355063065934246e+01,
+    -9.81432934416914548592e+00,
+];
+const SA: [f64; 8] = [
+    1.96512716674392571292e+01,
+    1.37657754143519042600e+02,
+    4.34565877475229228821e+02,
+    6.45387271733267880336e+02,
+    4.29008140027567833386e+02,
+    1.08635005541779435134e+02,
+    6.57024977031928170135e+00,
+    -6.04244152148580987438e-02,
+];
+// 1/0.35 <= |x| < 28
+const RB: [f64; 7] = [
+    -9.86494292470009928597e-03,
+    -7.99283237680523006574e-01,
+    -1.77579549177547519889e+01,
+    -1.60636384855821916062e+02,
+    -6.37566443368389627722e+02,
+    -1.02509513161107724954e+03,
+    -4.83519191608651397019e+02,
+];
+const SB: [f64; 7] = [
+    3.03380607434824582924e+01,
+    3.25792512996573918826e+02,
+    1.53672958608443695994e+03,
+    3.19985821950859553908e+03,
+    2.55305040643316442583e+03,
+    4.74528541206955367215e+02,
+    -2.24409524465858183362e+01,
+];
+
+/// Evaluates `c[0] + c[1] z + c[2] z^2 + ...`.
+fn poly(c: &[f64], z: f64) -> f64 {
+    c.iter().rev().fold(0.0, |acc, &k| acc * z + k)
+}
+
+/// `1 + c[0] z + c[1] z^2 + ...`, the denominators of the approximations.
+fn poly1(c: &[f64], z: f64) -> f64 {
+    1.0 + z * poly(c, z)
+}
+
+/// `erfc(ax)` for `ax >= 1.25` (up to where it underflows).
+fn erfc_tail(ax: f64) -> f64 {
+    let s = 1.0 / (ax * ax);
+    let (r, q) = if ax < 1.0 / 0.35 { (poly(&RA, s), poly1(&SA, s)) } else { (poly(&RB, s), poly1(&SB, s)) };
+    // Split ax so z*z is exact and exp(-ax*ax) keeps full precision
+    let z = f64::from_bits(ax.to_bits() & 0xffff_ffff_0000_0000);
+    (-z * z - 0.5625).exp() * ((z - ax) * (z + ax) + r / q).exp() / ax
+}
+
+/// The error function `erf(x) = 2/sqrt(pi) * integral from 0 to x of exp(-t^2) dt`.
+pub fn erf(x: f64) -> f64 {
+    if x.is_nan() {
+        return x;
+    }
+    let ax = x.abs();
+    if ax < 0.84375 {
+        if ax < 2f64.powi(-28) {
+            return x + EFX * x;
+        }
+        let z = x * x;
+        return x + x * (poly(&PP, z) / poly1(&QQ, z));
+    }
+    let y = if ax < 1.25 {
+        let s = ax - 1.0;
+        ERX + poly(&PA, s) / poly1(&QA, s)
+    } else if ax < 6.0 {
+        1.0 - erfc_tail(ax)
+    } else {
+        1.0
+    };
+    y.copysign(x)
+}
+
+/// The complementary error function `erfc(x) = 1 - erf(x)`, without the loss
+/// of precision of computing `1 - erf(x)` for large `x`.
+pub fn erfc(x: f64) -> f64 {
+    if x.is_nan() {
+        return x;
+    }
+    let ax = x.abs();
+    if ax < 0.84375 {
+        let z = x * x;
+        let y = poly(&PP, z) / poly1(&QQ, z);
+        return if x < 0.25 { 1.0 - (x + x * y) } else { 0.5 - (x * y + (x - 0.5)) };
+    }
+    if ax < 1.25 {
+        let s = ax - 1.0;
+        let p = poly(&PA, s) / poly1(&QA, s);
+        return if x >= 0.0 { 1.0 - ERX - p } else { 1.0 + ERX + p };
+    }
+    if ax < 28.0 {
+        let tail = erfc_tail(ax);
+        return if x > 0.0 { tail } else { 2.0 - tail };
+    }
+    if x > 0.0 { 0.0 } else { 2.0 }
+}
+
+/// Standard normal density.
+pub fn norm_pdf(x: f64) -> f64 {
+    (-0.5 * x * x).exp() / (2.0 * std::f64::consts::PI).sqrt()
+}
+
+/// Standard normal cumulative distribution function.
+pub fn norm_cdf(x: f64) -> f64 {
+    0.5 * erfc(-x / std::f64::consts::SQRT_2)
+}
+
+/// Inverse of the standard normal CDF (the probit function): Acklam's rational
+/// approximation refined with one Halley step to full precision.
+/// `p` outside `[0, 1]` gives NaN.
+pub fn norm_inv(p: f64) -> f64 {
+    const A: [f64; 6] = [
+        -3.969683028665376e+01, 2.209460984245205e+02, -2.759285104469687e+02,
+        1.383577518672690e+02, -3.066479806614716e+01, 2.506628277459239e+00,
+    ];
+    const B: [f64; 5] = [
+        -5.447609879822406e+01, 1.615858368580409e+02, -1.556989798598866e+02,
+        6.680131188771972e+01, -1.328068155288572e+01,
+    ];
+    const C: [f64; 6] = [
+        -7.784894002430293e-03, -3.223964580411365e-01, -2.400758277161838e+00,
+        -2.549732539343734e+00, 4.374664141464968e+00, 2.938163982698783e+00,
+    ];
+    const D: [f64; 4] = [7.784695709041462e-03, 3.224671290700398e-01, 2.445134137142996e+00, 3.754408661907416e+00];
+    const P_LOW: f64 = 0.02425;
+
+    if !(0.0..=1.0).contains(&p) {
+        return f64::NAN;
+    }
+    if p == 0.0 {
+        return f64::NEG_INFINITY;
+    }
+    if p == 1.0 {
+        return f64::INFINITY;
+    }
+    let horner = |c: &[f64], z: f64| c.iter().fold(0.0, |acc, &k| acc * z + k);
+    let tail = |q: f64| horner(&C, q) / (horner(&D, q) * q + 1.0);
+    let x = if p < P_LOW {
+        tail((-2.0 * p.ln()).sqrt())
+    } else if p > 1.0 - P_LOW {
+        -tail((-2.0 * (-p).ln_1p()).sqrt())
+    } else {
+        let q = p - 0.5;
+        let r = q * q;
+        horner(&A, r) * q / (horner(&B, r) * r + 1.0)
+    };
+    // Halley refinement against the accurate CDF
+    let e = norm_cdf(x) - p;
+    let u = e * (2.0 * std::f64::consts::PI).sqrt() * (0.5 * x * x).exp();
+    x - u / (1.0 + x * u / 2.0)
+}
+
+/// CDF of Student's t distribution with `nu > 0` degrees of freedom.
+pub fn t_cdf(t: f64, nu: f64) -> f64 {
+    if nu.is_nan() || nu <= 0.0 || t.is_nan() {
+        return f64::NAN;
+    }
+    let tail = t_tail(t.abs(), nu);
+    if t > 0.0 { 1.0 - tail } else { tail }
+}
+
+// P(T > t) for t >= 0, without cancellation far in the tail
+fn t_tail(t: f64, nu: f64) -> f64 {
+    if t.is_infinite() {
+        return 0.0;
+    }
+    0.5 * crate::special::beta_inc(nu / 2.0, 0.5, nu / (nu + t * t))
+}
+
+/// Inverse of `t_cdf` in `t`.
+pub fn t_inv(p: f64, nu: f64) -> f64 {
+    if nu.is_nan() || nu <= 0.0 || !(0.0..=1.0).contains(&p) {
+        return f64::NAN;
+    }
+    if p == 0.5 {
+        return 0.0;
+    }
+    // Symmetric: find |t| from the smaller tail probability, which keeps its
+    // precision for p close to 0
+    let q = p.min(1.0 - p);
+    let t = if q == 0.0 { f64::INFINITY } else { solve_increasing(-q, |t| -t_tail(t, nu)) };
+    if p < 0.5 { -t } else { t }
+}
+
+/// CDF of the chi-square distribution with `k > 0` degrees of freedom.
+pub fn chi2_cdf(x: f64, k: f64) -> f64 {
+    if k.is_nan() || k <= 0.0 || x.is_nan() {
+        return f64::NAN;
+    }
+    if x <= 0.0 {
+        return 0.0;
+    }
+    crate::special::gamma_inc(k / 2.0, x / 2.0)
+}
+
+/// Inverse of `chi2_cdf` in `x`.
+pub fn chi2_inv(p: f64, k: f64) -> f64 {
+    if k.is_nan() || k <= 0.0 || !(0.0..=1.0).contains(&p) {
+        return f64::NAN;
+    }
+    match p {
+        0.0 => 0.0,
+        1.0 => f64::INFINITY,
+        _ => solve_increasing(p, |x| chi2_cdf(x, k)),
+    }
+}
+
+/// Solves `f(x) = target` for an increasing `f` on `(0, inf)`: brackets the
+/// root between neighbouring powers of two, then bisects to adjacent floats.
+fn solve_increasing(target: f64, f: impl Fn(f64) -> f64) -> f64 {
+    let (mut lo, mut hi) = (0.5, 1.0);
+    while f(hi) < target {
+        lo = hi;
+        hi *= 2.0;
+        if hi.is_infinite() {
+            return f64::INFINITY;
+        }
+    }
+    while f(lo) >= target {
+        hi = lo;
+        lo /= 2.0;
+        if lo == 0.0 {
+            return 0.0;
+        }
+    }
+    for _ in 0..200 {
+        let mid = 0.5 * (lo + hi);
+        if mid <= lo || mid >= hi {
+            break;
+        }
+        if f(mid) < target {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    0.5 * (lo + hi)
+}
@@ -0,0 +1,27 @@
+// Template substitution: `{{ expr }}` placeholders in plain text replaced by their values
+use crate::session::Session;
+
+/// Replaces each `{{ expr }}` in `text` with the value of `expr`, evaluating the
+/// placeholders in order with one set of variables. A placeholder holding an
+/// assignment, function definition or assertion only updates that state and
+/// leaves nothing behind, so `{{ a = 3 }}` sets `a` for later placeholders.
+/// Fails on the first placeholder that is unterminated, does not parse, or
+/// does not evaluate, naming its line.
+pub fn render(text: &str) -> Result<String, String> {
+    let mut session = Session::new();
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let line = text[..text.len() - rest.len() + start].matches('\n').count() + 1;
+        let after = &rest[start + 2..];
+        let end = after.find("}}").ok_or_else(|| format!("line {}: unterminated placeholder", line))?;
+        let value = session
+            .eval(after[..end].trim(), |value| value.to_string())
+            .map_err(|e| format!("line {}: {}", line, e))?;
+        out.push_str(&value);
+        rest = &after[end + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
@@ -0,0 +1,68 @@
+// Format strings of `format` and `print`: text with `{}` and `{:.3}` placeholders for numbers
+
+/// One piece of a format string: text as written, or the place of the next
+/// value, with the number of digits after the point if given.
+#[derive(Debug, Clone, PartialEq)]
+enum Piece<'a> {
+    Literal(&'a str),
+    Value(Option<usize>),
+}
+
+/// Splits `template` into text and placeholders. `{{` and `}}` stand for
+/// literal braces; a placeholder is `{}` or `{:.N}`.
+fn pieces(template: &str) -> Result<Vec<Piece<'_>>, String> {
+    let mut pieces = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        if start > 0 {
+            pieces.push(Piece::Literal(&rest[..start]));
+        }
+        let after = &rest[start + 1..];
+        if rest[start..].starts_with("{{") || rest[start..].starts_with("}}") {
+            pieces.push(Piece::Literal(&rest[start..start + 1]));
+            rest = &after[1..];
+            continue;
+        }
+        if rest[start..].starts_with('}') {
+            return Err("`}` without an opening `{` (write `}}` for a brace)".to_string());
+        }
+        let end = after.find('}').ok_or("`{` without a closing `}` (write `{{` for a brace)")?;
+        let digits = match &after[..end] {
+            "" => None,
+            spec => match spec.strip_prefix(":.").and_then(|n| n.parse().ok()) {
+                Some(digits) => Some(digits),
+                None => return Err(format!("`{{{}}}` is no placeholder; use `{{}}` or `{{:.3}}`", spec)),
+            },
+        };
+        pieces.push(Piece::Value(digits));
+        rest = &after[end + 1..];
+    }
+    if !rest.is_empty() {
+        pieces.push(Piece::Literal(rest));
+    }
+    Ok(pieces)
+}
+
+/// The number of values `template` has places for, or why it is not a
+/// format string.
+pub fn placeholders(template: &str) -> Result<usize, String> {
+    Ok(pieces(template)?.iter().filter(|piece| matches!(piece, Piece::Value(_))).count())
+}
+
+/// `template` with its placeholders replaced by `values` in order. The
+/// template is one `placeholders` accepted for as many values.
+pub fn format(template: &str, values: &[f64]) -> String {
+    let mut values = values.iter();
+    let mut out = String::with_capacity(template.len());
+    for piece in pieces(template).unwrap_or_default() {
+        match piece {
+            Piece::Literal(text) => out.push_str(text),
+            Piece::Value(digits) => match (digits, values.next()) {
+                (Some(digits), Some(value)) => out.push_str(&format!("{:.*}", digits, value)),
+                (None, Some(value)) => out.push_str(&value.to_string()),
+                (_, None) => {}
+            },
+        }
+    }
+    out
+}
@@ -0,0 +1,41 @@
+use fmath::ast::Expr;
+use fmath::compiler::CompiledProgram;
+use fmath::engine::Engine;
+
+fn parse(source: &str) -> Expr {
+    fmath::parser::parse(fmath::lexer::tokenize_numbered(source)).unwrap().0
+}
+
+#[test]
+fn specialize_matches_eval_with_the_variable_bound() {
+    let program = CompiledProgram::from_source("def f(t) = a * t + 1\nf(x) + (a > 1 ? a^2 : -a)").unwrap();
+    for a in [0.5, 2.0, 3.0] {
+        let specialized = program.specialize(&[("a", a)]);
+        for x in [-1.0, 0.0, 2.5] {
+            let mut state = Engine::new().new_state();
+            state.set_var("a", a);
+            state.set_var("x", x);
+            let expected = program.eval(&mut state).unwrap();
+            let mut state = Engine::new().new_state();
+            state.set_var("x", x);
+            assert_eq!(specialized.eval(&mut state), Ok(expected), "a = {a}, x = {x}");
+        }
+    }
+}
+
+#[test]
+fn commuted_operands_are_equal_with_equal_fingerprints() {
+    let (left, right) = (parse("x + 1 == 1 + x"), parse("1 + x == x + 1"));
+    assert_eq!(left, right);
+    assert_eq!(left.fingerprint(), right.fingerprint());
+    assert_eq!(parse("x + 1"), parse("1 + x"));
+    assert_eq!(parse("x + 1").fingerprint(), parse("1 + x").fingerprint());
+    assert_eq!(parse("a > b"), parse("b < a"));
+}
+
+#[test]
+fn different_expressions_differ() {
+    assert_ne!(parse("x - 1"), parse("1 - x"));
+    assert_ne!(parse("x + 1").fingerprint(), parse("x + 2").fingerprint());
+    assert_ne!(parse("x + 1").fingerprint(), parse("x * 1").fingerprint());
+}
@@ -0,0 +1,49 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use fmath::compiler::CompiledProgram;
+use fmath::engine::{CancellationToken, Engine};
+use fmath::error::RuntimeError;
+
+#[test]
+fn resolver_supplies_missing_variables() {
+    let program = CompiledProgram::from_source("var a = 2\na * width + height").unwrap();
+    let asked = Arc::new(Mutex::new(Vec::new()));
+    let mut state = Engine::new().new_state();
+    let log = Arc::clone(&asked);
+    state.set_resolver(move |name: &str| {
+        log.lock().unwrap().push(name.to_string());
+        match name {
+            "width" => Some(3.0),
+            "height" => Some(4.0),
+            _ => None,
+        }
+    });
+    assert_eq!(program.eval(&mut state), Ok(10.0));
+    assert_eq!(*asked.lock().unwrap(), ["width", "height"]);
+}
+
+#[test]
+fn resolver_is_not_asked_for_bound_variables() {
+    let program = CompiledProgram::from_source("x + 1").unwrap();
+    let mut state = Engine::new().new_state();
+    state.set_var("x", 1.0);
+    state.set_resolver(|name: &str| panic!("asked for {name}"));
+    assert_eq!(program.eval(&mut state), Ok(2.0));
+}
+
+#[test]
+fn cancelled_token_stops_a_long_sum() {
+    let program = CompiledProgram::from_source("sum(from: 1, to: 10^12, para: n, n)").unwrap();
+    let token = CancellationToken::new();
+    let canceller = {
+        let token = token.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            token.cancel();
+        })
+    };
+    assert_eq!(Engine::new().eval_cancellable(&program, token), Err(RuntimeError::Cancelled));
+    canceller.join().unwrap();
+}
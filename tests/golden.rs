@@ -0,0 +1,14 @@
+use fmath::golden::expected_value;
+use fmath::interpreter::Value;
+
+#[test]
+fn expected_value_reads_a_number_or_a_list() {
+    assert_eq!(expected_value("1 + 1\n# expect: 2"), Some(Ok(Value::Number(2.0))));
+    assert_eq!(expected_value("# expect: [1, 2.5]\n[1, 2.5]"), Some(Ok(Value::List(vec![1.0, 2.5]))));
+    assert_eq!(expected_value("1 + 1"), None);
+}
+
+#[test]
+fn expected_value_rejects_a_second_expect() {
+    assert!(matches!(expected_value("# expect: 1\n1\n# expect: 2"), Some(Err(_))));
+}
@@ -0,0 +1,30 @@
+use fmath::incremental::IncrementalEngine;
+
+#[test]
+fn set_input_reruns_only_dependent_statements() {
+    let source = "var a = x * 2\nvar b = y + 1\nvar c = b * 3\na + c";
+    let (mut engine, _) = IncrementalEngine::new(source, &["x", "y"]).unwrap();
+    engine.set_input("x", 1.0);
+    engine.set_input("y", 2.0);
+    assert_eq!(engine.eval(), Ok(11.0));
+    assert_eq!(engine.recomputed(), 4);
+
+    engine.set_input("x", 5.0);
+    assert_eq!(engine.eval(), Ok(19.0));
+    assert_eq!(engine.recomputed(), 2);
+
+    engine.set_input("y", 0.0);
+    assert_eq!(engine.eval(), Ok(13.0));
+    assert_eq!(engine.recomputed(), 3);
+    assert_eq!(engine.var("c"), Some(3.0));
+}
+
+#[test]
+fn unchanged_input_reruns_nothing() {
+    let (mut engine, _) = IncrementalEngine::new("var a = x + 1\na * 2", &["x"]).unwrap();
+    engine.set_input("x", 1.0);
+    assert_eq!(engine.eval(), Ok(4.0));
+    engine.set_input("x", 1.0);
+    assert_eq!(engine.eval(), Ok(4.0));
+    assert_eq!(engine.recomputed(), 0);
+}